@@ -3,9 +3,12 @@ use crate::matrix::create_session;
 use crate::pwsafe::PwsafeDb;
 
 use matrix_sdk::ruma::{
-    api::client::room::{
-        create_room,
-        Visibility,
+    api::client::{
+        error::ErrorKind,
+        room::{
+            create_room,
+            Visibility,
+        },
     },
     events::{
         EmptyStateKey,
@@ -13,6 +16,9 @@ use matrix_sdk::ruma::{
         room::encryption::RoomEncryptionEventContent,
     },
     EventEncryptionAlgorithm,
+    OwnedRoomAliasId,
+    RoomAliasId,
+    ServerName,
 };
 
 use eyre::Report;
@@ -30,6 +36,11 @@ pub async fn run(
 
     let cs = create_session(Some(&login), None, db.store()).await?;
 
+    let alias = match &room.alias {
+        Some(alias) => Some(parse_alias(alias, cs.session.meta.user_id.server_name())?),
+        None => None,
+    };
+
     let room_id = {
         let mut create = create_room::v3::Request::default();
 
@@ -43,17 +54,56 @@ pub async fn run(
 
         create.visibility = Visibility::Private;
         create.initial_state = initial_event;
+        create.room_alias_name = alias.as_ref().map(|alias| alias.alias().to_owned());
+
+        match cs.client.create_room(create).await {
+            Ok(response) => response.room_id().to_owned(),
+            Err(err) if err.client_api_error_kind() == Some(&ErrorKind::RoomInUse) => {
+                // Only reachable with an alias, the room id itself is chosen by the server.
+                let alias = alias.as_ref().unwrap();
 
-        let response = cs.client.create_room(create).await?;
-        response.room_id().to_owned()
+                if !room.force {
+                    return Err(Report::msg(format!(
+                        "The room alias {alias} is already in use, use `--force` to join the existing room instead"
+                    )));
+                }
+
+                tracing::info!("Room alias {alias} in use, joining the existing room");
+                let existing = cs.client.resolve_room_alias(alias).await?;
+                cs.client.join_room_by_id(&existing.room_id).await?;
+                existing.room_id
+            }
+            Err(err) => return Err(err.into()),
+        }
     };
 
+    println!("{room_id}");
+    if let Some(alias) = &alias {
+        println!("{alias}");
+    }
+
     db.set_session(cs.session);
     db.set_room(room_id);
 
+    if let Some(alias) = alias {
+        db.set_room_alias(alias);
+    }
+
     db.with_lock(|mut lock| {
         lock.rewrite()
     })?;
 
     Ok(())
 }
+
+/// Accept either a full alias `#name:server` or only the local part, which is then qualified with
+/// the server of the user creating the room.
+fn parse_alias(alias: &str, server: &ServerName) -> Result<OwnedRoomAliasId, Report> {
+    let full = if alias.starts_with('#') {
+        alias.to_owned()
+    } else {
+        format!("#{alias}:{server}")
+    };
+
+    Ok(RoomAliasId::parse(full)?)
+}
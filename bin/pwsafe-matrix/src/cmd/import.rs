@@ -0,0 +1,147 @@
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+use crate::ArgsPwsafe;
+use crate::diff::Diff;
+use crate::keepass::{self, Entry};
+use crate::pwsafe::{Fields, PwsafeDb};
+
+use eyre::Report;
+use pwsafer::PwsafeRecordField;
+use uuid::Uuid;
+
+/// What to do with an entry the database already has a record for.
+///
+/// Records are the same entry if they have the same group, title and username.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnDuplicate {
+    /// Keep the record as it is.
+    #[default]
+    Skip,
+    /// Set the fields of the record to the values of the entry.
+    Update,
+}
+
+/// The number of entries of each outcome of an import.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Imported {
+    pub added: usize,
+    pub updated: usize,
+    pub skipped: usize,
+}
+
+type RecordKey = (Vec<u8>, Vec<u8>, Vec<u8>);
+
+/// Import the entries of a KeePass XML export into the database.
+pub fn run(
+    pwsafe: ArgsPwsafe,
+    file: PathBuf,
+    on_duplicate: OnDuplicate,
+    history: bool,
+) -> Result<(), Report> {
+    let xml = std::fs::read_to_string(&file)?;
+    let entries = keepass::parse(&xml)?;
+
+    let mut db = PwsafeDb::open(&pwsafe)?;
+    let imported = import(&mut db, &entries, on_duplicate, history)?;
+
+    println!("added: {}", imported.added);
+    println!("updated: {}", imported.updated);
+    println!("skipped: {}", imported.skipped);
+
+    Ok(())
+}
+
+/// Add the entries to the database as one local edit.
+///
+/// The edit is written like those of other programs, a running sync publishes it into the room.
+pub fn import(
+    db: &mut PwsafeDb,
+    entries: &[Entry],
+    on_duplicate: OnDuplicate,
+    history: bool,
+) -> Result<Imported, Report> {
+    db.with_lock(|mut lock| {
+        // Edits of other programs since we opened the file stay separate from ours.
+        lock.refresh()?;
+        lock.push_diff_from_remote()?;
+
+        let records = lock.records()?;
+        let (diff, imported) = plan(lock.empty_diff(), &records, entries, on_duplicate, history);
+
+        if !diff.is_empty() {
+            lock.apply(&diff)?;
+            lock.rewrite()?;
+        }
+
+        Ok(imported)
+    })
+}
+
+fn plan(
+    mut diff: Diff,
+    records: &[Fields],
+    entries: &[Entry],
+    on_duplicate: OnDuplicate,
+    history: bool,
+) -> (Diff, Imported) {
+    let mut used = HashSet::new();
+    let mut known: HashMap<RecordKey, Uuid> = HashMap::new();
+
+    for fields in records {
+        let Some(uuid) = fields.get(&0x01).and_then(|uuid| Uuid::from_slice(uuid).ok()) else {
+            continue;
+        };
+
+        used.insert(uuid);
+        known.insert(record_key(fields), uuid);
+    }
+
+    let mut imported = Imported::default();
+
+    for entry in entries {
+        let key = (
+            entry.group.as_bytes().to_vec(),
+            entry.title.as_bytes().to_vec(),
+            entry.username.as_bytes().to_vec(),
+        );
+
+        let (uuid, update) = match known.get(&key) {
+            Some(_) if on_duplicate == OnDuplicate::Skip => {
+                imported.skipped += 1;
+                continue;
+            }
+            Some(&uuid) => {
+                imported.updated += 1;
+                (uuid, true)
+            }
+            None => {
+                // Keep the UUID of KeePass, unless a record already has it.
+                let uuid = entry.uuid
+                    .filter(|uuid| !used.contains(uuid))
+                    .unwrap_or_else(Uuid::new_v4);
+                used.insert(uuid);
+                known.insert(key, uuid);
+                imported.added += 1;
+                (uuid, false)
+            }
+        };
+
+        let edit = diff.edit.entry(uuid).or_default();
+        for field in entry.fields(history) {
+            if update && matches!(field, PwsafeRecordField::CreationTime(_)) {
+                continue;
+            }
+
+            edit.set(&field);
+        }
+    }
+
+    (diff, imported)
+}
+
+/// The group, title and username of a record, each empty if it has none.
+fn record_key(fields: &Fields) -> RecordKey {
+    let field = |ty| fields.get(&ty).cloned().unwrap_or_default();
+    (field(0x02), field(0x03), field(0x04))
+}
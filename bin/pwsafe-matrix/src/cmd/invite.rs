@@ -1,30 +1,83 @@
-use crate::ArgsPwsafe;
+use crate::{ArgsLogin, ArgsPwsafe};
+use crate::exit::ValidationError;
+use crate::matrix::create_session;
 use crate::pwsafe::PwsafeDb;
 
 use std::path::PathBuf;
-use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomId, OwnedUserId};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use matrix_sdk::{config::SyncSettings, RoomState};
+use matrix_sdk::ruma::{OwnedDeviceId, OwnedRoomAliasId, OwnedRoomId, OwnedUserId};
+use pwsafer::PwsafeKey;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 use eyre::Report;
 
-pub fn run(
+/// Invites written before they were signed, without a version.
+const UNSIGNED: u32 = 1;
+/// The version written, signed by the database and expiring.
+const SIGNED: u32 = 2;
+
+/// Salt of the key invites are authenticated with, never used for the database itself.
+const MAC_SALT: &[u8] = b"pwsafe-matrix invite v2";
+const MAC_ITER: u32 = 2048;
+
+pub async fn run(
     pwsafe: ArgsPwsafe,
-    invite: PathBuf,
+    login: Option<ArgsLogin>,
+    path: PathBuf,
+    user: Option<OwnedUserId>,
+    expires: Option<u64>,
 ) -> Result<(), Report> {
-    let db = PwsafeDb::open(&pwsafe)?;
+    let mut db = PwsafeDb::open(&pwsafe)?;
 
-    let Some(session) = db.session() else {
+    let Some(session) = db.session().cloned() else {
         let report = Report::msg("Not a pwsafe-matrix file, use `create` or `join` to link file into a Matrix Room.");
         return Err(report);
     };
 
-    let Some(room) = db.room() else {
+    let Some(room) = db.room().cloned() else {
         let report = Report::msg("Not a pwsafe-matrix file, use `create` or `join` to link file into a Matrix Room.");
         return Err(report);
     };
 
+    // The room is private, only the invited account is allowed to enter it.
+    if let Some(user) = &user {
+        let cs = create_session(login.as_ref(), Some(session.clone()), db.store()).await?;
+        cs.client.sync_once(SyncSettings::new()).await?;
+
+        let joined = cs.client.get_room(&room).filter(|room| room.state() == RoomState::Joined);
+        let Some(joined) = joined else {
+            return Err(Report::msg(format!("Not a member of {room}, can not invite into it")));
+        };
+
+        joined.invite_user_by_id(user).await?;
+        tracing::info!("Invited {user} into {room}");
+
+        // Keep the crypto state of the client, as any other command with a session.
+        db.with_lock(|mut lock| {
+            lock.rewrite()
+        })?;
+    }
+
+    let mut invite = Invite {
+        version: SIGNED,
+        room,
+        alias: db.room_alias().cloned(),
+        user: session.meta.user_id.clone(),
+        device: session.meta.device_id.clone(),
+        invitee: user,
+        created: Some(unix_now()),
+        expires: expires.map(|secs| unix_now() + secs),
+        namespace: db.namespace(),
+        mac: None,
+    };
+
+    invite.sign(db.key())?;
+
     let (stdout, mut lock, mut file);
     let output: &mut dyn std::io::Write = {
-        if let Some("-") = invite.to_str() {
+        if let Some("-") = path.to_str() {
             stdout = std::io::stdout();
             lock = stdout.lock();
             &mut lock
@@ -32,25 +85,43 @@ pub fn run(
             file = std::fs::OpenOptions::new()
                 .write(true)
                 .create(true)
-                .open(invite)?;
+                .truncate(true)
+                .open(path)?;
             &mut file
         }
     };
 
-    Invite {
-        room: room.clone(),
-        user: session.meta.user_id.clone(),
-        device: session.meta.device_id.clone(),
-    }.write(output)?;
+    invite.write(output)?;
 
     Ok(())
 }
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 pub struct Invite {
+    /// The format of the invite, unsigned ones had none.
+    #[serde(default = "Invite::unsigned")]
+    pub version: u32,
     pub room: OwnedRoomId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<OwnedRoomAliasId>,
+    /// The inviting user.
     pub user: OwnedUserId,
     pub device: OwnedDeviceId,
+    /// The user invited into the room, no other may use the invite.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub invitee: Option<OwnedUserId>,
+    /// Seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created: Option<u64>,
+    /// Seconds since the Unix epoch, after which the invite is rejected.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<u64>,
+    /// The database being shared, see [`PwsafeDb::namespace`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<Uuid>,
+    /// The HMAC of all other fields, keyed with the password of the database, in base64.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
 }
 
 impl Invite {
@@ -59,8 +130,65 @@ impl Invite {
         Ok(())
     }
 
-    pub fn read(from: &mut dyn std::io::Read) -> Result<Self, Report> {
-        let this = serde_json::from_reader(from)?;
+    /// Read an invite, checking that it was signed with the same database password and has not
+    /// expired. Invites from before signing are only accepted with `allow_unsigned`.
+    pub fn read(
+        from: &mut dyn std::io::Read,
+        key: &PwsafeKey,
+        allow_unsigned: bool,
+    ) -> Result<Self, Report> {
+        let this: Self = serde_json::from_reader(from)?;
+        this.verify(key, allow_unsigned, SystemTime::now())?;
         Ok(this)
     }
+
+    fn unsigned() -> u32 {
+        UNSIGNED
+    }
+
+    pub fn sign(&mut self, key: &PwsafeKey) -> Result<(), Report> {
+        self.mac = None;
+        let message = serde_json::to_vec(self)?;
+        self.mac = Some(STANDARD.encode(key.mac(MAC_SALT, MAC_ITER, &message)));
+        Ok(())
+    }
+
+    pub fn verify(&self, key: &PwsafeKey, allow_unsigned: bool, now: SystemTime) -> Result<(), Report> {
+        let invalid = |msg: String| Report::new(ValidationError(msg));
+
+        match (self.version, &self.mac) {
+            (UNSIGNED, None) if allow_unsigned => {
+                tracing::warn!("Accepting an unsigned invite into {}", self.room);
+                return Ok(());
+            }
+            (UNSIGNED, None) => {
+                return Err(invalid("The invite is not signed, use `--allow-unsigned-invite` to accept it anyways".into()));
+            }
+            (SIGNED, Some(_)) => {}
+            (SIGNED, None) => return Err(invalid("The invite is missing its signature".into())),
+            (version, _) => return Err(invalid(format!("Unsupported invite version {version}"))),
+        }
+
+        let mac = self.mac.as_deref().and_then(|mac| STANDARD.decode(mac).ok());
+        let unsigned = Invite { mac: None, ..self.clone() };
+        let message = serde_json::to_vec(&unsigned)?;
+
+        if !mac.is_some_and(|mac| key.verify_mac(MAC_SALT, MAC_ITER, &message, &mac)) {
+            return Err(invalid("The invite was not created for a database with this password".into()));
+        }
+
+        if let Some(expires) = self.expires {
+            if UNIX_EPOCH + Duration::from_secs(expires) < now {
+                return Err(invalid(format!("The invite expired at {expires} (seconds since the epoch)")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
 }
@@ -1,20 +1,21 @@
 use crate::{ArgsLogin, ArgsPwsafe};
 use crate::matrix::create_session;
 use crate::cmd::invite::Invite;
+use crate::exit::ValidationError;
 use crate::pwsafe::PwsafeDb;
 
 use std::path::PathBuf;
-use eyre::Report;
+use eyre::{Report, WrapErr as _};
+use matrix_sdk::{config::SyncSettings, RoomState};
 
 pub async fn run(
     pwsafe: ArgsPwsafe,
     login: ArgsLogin,
     invite: PathBuf,
+    force: bool,
+    allow_unsigned: bool,
 ) -> Result<(), Report> {
     let mut db = PwsafeDb::open(&pwsafe)?;
-    let session = db.session().cloned();
-
-    let cs = create_session(Some(&login), session, db.store()).await?;
 
     let (stdin, mut lock, mut file);
     let input: &mut dyn std::io::Read = {
@@ -30,8 +31,52 @@ pub async fn run(
         }
     };
 
-    let invite = Invite::read(input)?;
-    cs.client.join_room_by_id(&invite.room).await?;
+    let invite = Invite::read(input, db.key(), allow_unsigned)?;
+
+    if let Some(room) = db.room() {
+        if *room != invite.room && !force {
+            return Err(Report::msg(format!(
+                "Pwsafe file is already linked to {room}, use `--force` to join {} instead",
+                invite.room
+            )));
+        }
+    }
+
+    let session = db.session().cloned();
+    let cs = create_session(Some(&login), session, db.store()).await?;
+
+    if let Some(invitee) = &invite.invitee {
+        if *invitee != cs.session.meta.user_id {
+            return Err(ValidationError(format!("The invite is for {invitee}, not for this account")).into());
+        }
+    }
+
+    cs.client
+        .join_room_by_id(&invite.room)
+        .await
+        .wrap_err_with(|| format!("Could not join {}, ask for an invite with `invite --invite-user`", invite.room))?;
+
+    // Only record the room once the homeserver reflects our membership.
+    cs.client.sync_once(SyncSettings::new()).await?;
+
+    let joined = cs.client
+        .get_room(&invite.room)
+        .is_some_and(|room| room.state() == RoomState::Joined);
+
+    if !joined {
+        return Err(Report::msg(format!("Joining {} did not make us a member", invite.room)));
+    }
+
+    db.set_session(cs.session);
+
+    // Rejoining keeps what we know of the room, another one is considered anew.
+    if db.room() != Some(&invite.room) {
+        db.migrate_room(invite.room, invite.alias);
+    }
+
+    if let Some(namespace) = invite.namespace {
+        db.set_namespace(namespace);
+    }
 
     db.with_lock(|mut lock| {
         lock.rewrite()
@@ -0,0 +1,70 @@
+use crate::{ArgsLogin, ArgsPwsafe};
+use crate::event::DiffEventContent;
+use crate::matrix::create_session;
+use crate::pwsafe::PwsafeDb;
+
+use eyre::Report;
+use matrix_sdk::{
+    config::SyncSettings,
+    ruma::{
+        OwnedRoomAliasId,
+        OwnedRoomId,
+        RoomAliasId,
+        RoomId,
+    },
+    RoomState,
+};
+
+pub async fn run(
+    pwsafe: ArgsPwsafe,
+    login: Option<ArgsLogin>,
+    to: String,
+    snapshot: bool,
+) -> Result<(), Report> {
+    let mut db = PwsafeDb::open(&pwsafe)?;
+    let session = db.session().cloned();
+
+    if session.is_none() {
+        return Err(Report::msg("Pwsafe File does not contain matrix credentials"));
+    }
+
+    let cs = create_session(login.as_ref(), session, db.store()).await?;
+
+    let (room_id, alias): (OwnedRoomId, Option<OwnedRoomAliasId>) = if to.starts_with('#') {
+        let alias = RoomAliasId::parse(&to)?;
+        let resolved = cs.client.resolve_room_alias(&alias).await?;
+        (resolved.room_id, Some(alias))
+    } else {
+        (RoomId::parse(&to)?, None)
+    };
+
+    if db.room() == Some(&room_id) {
+        return Err(Report::msg(format!("Pwsafe file is already linked to {room_id}")));
+    }
+
+    // We only know about our memberships after having synchronized at least once.
+    cs.client.sync_once(SyncSettings::new()).await?;
+
+    let Some(room) = cs.client.get_room(&room_id) else {
+        return Err(Report::msg(format!("Not a member of {room_id}, join it before migrating")));
+    };
+
+    if room.state() != RoomState::Joined {
+        return Err(Report::msg(format!("Not a member of {room_id}, join it before migrating")));
+    }
+
+    if snapshot {
+        let diff = db.snapshot()?.serialize()?;
+        room.send(DiffEventContent { diff }).await?;
+        tracing::info!("Replayed a snapshot of the database into {room_id}");
+    }
+
+    tracing::info!("Migrating from {:?} to {room_id}", db.room());
+    db.migrate_room(room_id, alias);
+
+    db.with_lock(|mut lock| {
+        lock.rewrite()
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use crate::{ArgsPwsafe, ArgsServer, ServerAddress};
+use crate::communicator::{Connection, StatusSnapshot};
+use crate::paths::Paths;
+use crate::pwsafe::{LastSaved, PwsafeDb, Timestamp};
+
+use std::path::{Path, PathBuf};
+
+use axum::body::{Body, Bytes};
+use eyre::Report;
+use hyper_util::rt::TokioIo;
+use matrix_sdk::ruma::OwnedRoomId;
+use serde::Serialize;
+
+/// What the database tells about its synchronization, without a running sync to ask.
+#[derive(Serialize, Debug)]
+pub struct Offline {
+    pub room: Option<OwnedRoomId>,
+    pub remote_until: Option<Timestamp>,
+    /// Local edits of the file not yet part of the room.
+    pub pending_diffs: usize,
+    /// Whether the lock file of the database exists.
+    pub lock_exists: bool,
+    /// Whether a sync holds its lock for the database.
+    pub sync_running: bool,
+    pub last_saved: LastSaved,
+}
+
+/// Ask a running sync through its server for its status.
+pub async fn query(server: ArgsServer, json: bool) -> Result<(), Report> {
+    let status: StatusSnapshot = match &server.address {
+        ServerAddress::Tcp(address) => {
+            let mut request = reqwest::Client::new().get(format!("http://{address}/status"));
+
+            if let Some(secret) = &server.secret {
+                request = request.header("Authorization", secret);
+            }
+
+            request.send().await?.error_for_status()?.json().await?
+        },
+        ServerAddress::Unix(path) => {
+            let body = get_unix(path, "/status", server.secret.as_deref()).await?;
+            serde_json::from_slice(&body)?
+        },
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+    } else {
+        print_status(&status);
+    }
+
+    Ok(())
+}
+
+/// Send a `GET` request over a unix socket, which `reqwest` can not connect to.
+async fn get_unix(path: &Path, uri: &str, secret: Option<&str>) -> Result<Bytes, Report> {
+    let stream = tokio::net::UnixStream::connect(path).await?;
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await?;
+    tokio::spawn(connection);
+
+    let mut request = hyper::Request::get(uri).header("Host", "localhost");
+    if let Some(secret) = secret {
+        request = request.header("Authorization", secret);
+    }
+
+    let response = sender.send_request(request.body(Body::empty())?).await?;
+    if !response.status().is_success() {
+        return Err(Report::msg(format!("The server answered with {}", response.status())));
+    }
+
+    Ok(axum::body::to_bytes(Body::new(response.into_body()), usize::MAX).await?)
+}
+
+/// Print what can be told from the database and its auxiliary files alone.
+pub fn offline(
+    pwsafe: ArgsPwsafe,
+    state_dir: Option<PathBuf>,
+    json: bool,
+) -> Result<(), Report> {
+    let db = PwsafeDb::open(&pwsafe)?;
+    let paths = Paths::new(Path::new(&pwsafe.pwsafe), state_dir.as_deref());
+
+    let offline = Offline {
+        room: db.room().cloned(),
+        remote_until: db.remote_until().cloned(),
+        pending_diffs: db.pending_diffs(),
+        lock_exists: db.lock_exists(),
+        sync_running: paths.sync_lock().exists(),
+        last_saved: db.last_saved(),
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&offline)?);
+    } else {
+        print_offline(&offline);
+    }
+
+    Ok(())
+}
+
+fn print_status(status: &StatusSnapshot) {
+    print_room(status.room.as_ref(), status.remote_until.as_ref());
+    println!("pending diffs: {}", status.pending_diffs);
+    println!("unpublished diffs: {}", status.unpublished_diffs);
+    println!("applied diffs: {}", status.applied_diffs);
+    println!("lock file: {}", present(status.lock_exists));
+
+    if let Some(rewrite) = status.last_rewrite_ms {
+        println!("last rewrite: {rewrite}");
+    }
+
+    match status.matrix {
+        Connection::Connecting => println!("matrix: connecting"),
+        Connection::Connected => println!("matrix: connected"),
+    }
+
+    if let Some(sync) = status.last_sync_ms {
+        println!("last sync: {sync}");
+    }
+}
+
+fn print_offline(offline: &Offline) {
+    print_room(offline.room.as_ref(), offline.remote_until.as_ref());
+    println!("pending diffs: {}", offline.pending_diffs);
+    println!("lock file: {}", present(offline.lock_exists));
+    println!("sync: {}", if offline.sync_running { "running" } else { "stopped" });
+
+    if let Some(timestamp) = offline.last_saved.timestamp {
+        println!("last saved: {timestamp}");
+    }
+}
+
+fn print_room(room: Option<&OwnedRoomId>, remote_until: Option<&Timestamp>) {
+    match room {
+        Some(room) => println!("room: {room}"),
+        None => println!("room: none"),
+    }
+
+    if let Some(ts) = remote_until {
+        println!("remote until: {} at {}", ts.unique, ts.ts_ms);
+    }
+}
+
+fn present(present: bool) -> &'static str {
+    if present { "present" } else { "missing" }
+}
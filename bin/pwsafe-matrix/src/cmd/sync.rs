@@ -1,19 +1,36 @@
 use crate::{ArgsLogin, ArgsServer, ArgsPwsafe};
-use crate::communicator::{Communicator, Message, Station, SyncPoint, Id};
-use crate::matrix::create_session;
-use crate::pwsafe::{PwsafeDb, Timestamp};
+use crate::communicator::{
+    Communicator, Connection, Message, Query, QueryResponse, Station, StatusSnapshot, SyncPoint, Id,
+    unix_ms,
+};
+use crate::diff::ConflictPolicy;
+use crate::event::{DiffEventContent, OriginalSyncDiffEvent};
+use crate::lockfile::{LockFile, Takeover, UserInfo};
+use crate::matrix::{ask_password, create_session};
+use crate::paths::Paths;
+use crate::pwsafe::{combined_key, PwsafeDb, Timestamp};
 use crate::server::serve;
 
 use std::collections::{HashMap, VecDeque};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use eyre::Report;
+use pwsafer::{PwsafeKey, ReadError, SecretField};
 use matrix_sdk::{
     Client,
     LoopCtrl,
     config::SyncSettings,
+    deserialized_responses::{EncryptionInfo, VerificationState},
     ruma::{
-        events::room::message::SyncRoomMessageEvent,
+        events::room::{
+            encrypted::OriginalSyncRoomEncryptedEvent,
+            tombstone::OriginalSyncRoomTombstoneEvent,
+        },
+        OwnedEventId,
         OwnedRoomId,
+        RoomId,
+        UserId,
     },
 };
 use tokio::{
@@ -26,8 +43,23 @@ pub async fn run(
     pwsafe: ArgsPwsafe,
     login: Option<ArgsLogin>,
     server: Option<ArgsServer>,
+    follow_upgrades: bool,
+    senders: Senders,
+    state_dir: Option<PathBuf>,
+    on_conflict: ConflictPolicy,
+    incoming_group: String,
 ) -> Result<(), Report> {
-    let db = PwsafeDb::open(&pwsafe)?;
+    let mut db = PwsafeDb::open_async(&pwsafe).await?;
+    db.set_conflict_policy(on_conflict);
+    db.set_incoming_group(incoming_group);
+    // A crashed sync leaves its locks behind, which would keep us from ever writing the file.
+    db.set_lock_takeover(Takeover::Stale);
+
+    let paths = Paths::new(Path::new(&pwsafe.pwsafe), state_dir.as_deref());
+    paths.create_dir()?;
+    // Only one of us should be pushing the diffs of this file.
+    let userinfo = UserInfo::new()?;
+    let _sync_lock = LockFile::create_or_takeover(paths.sync_lock(), &userinfo, Takeover::Stale)?;
 
     let session = db.session().cloned();
     let mut join_set = JoinSet::<Result<(), Report>>::new();
@@ -47,7 +79,7 @@ pub async fn run(
     // This is 'first-task-finish' concurrency.
     join_set.spawn(async {
         signal::ctrl_c().await?;
-        eprintln!("Ctrl-C received");
+        tracing::info!("Ctrl-C received");
         Ok(())
     });
 
@@ -58,8 +90,10 @@ pub async fn run(
     }
 
     join_set.spawn(refresh(pwsafe.pwsafe.into(), inst_stream.clone()));
-    join_set.spawn(sync_on(client.clone(), room, inst_stream));
-    join_set.spawn(work_on(station, db));
+    join_set.spawn(sync_on(client.clone(), room, inst_stream, follow_upgrades, senders));
+    let with_passwd = pwsafe.passwd.is_some();
+    let status = paths.status();
+    join_set.spawn(work_on(station, db, client.clone(), status, pwsafe.passwd_file, with_passwd));
 
     join_set.join_next().await.unwrap()??;
 
@@ -78,6 +112,49 @@ pub async fn run(
     Ok(())
 }
 
+fn is_invalid_password(err: &Report) -> bool {
+    err.chain().any(|cause| {
+        matches!(cause.downcast_ref::<ReadError>(), Some(ReadError::InvalidPassword))
+    })
+}
+
+/// The wait before asking for the password again, doubling with each attempt up to a limit.
+fn backoff(attempts: u32) -> std::time::Duration {
+    const BASE: std::time::Duration = std::time::Duration::from_secs(2);
+    const MAX: std::time::Duration = std::time::Duration::from_secs(300);
+
+    let factor = 1u32
+        .checked_shl(attempts.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    BASE.saturating_mul(factor).min(MAX)
+}
+
+/// The key of the new password of the file.
+///
+/// A key file is read again, assuming it was changed along with the file. The password is asked
+/// for unless the key file alone unlocks the file.
+async fn ask_key(passwd_file: Option<&OsStr>, with_passwd: bool) -> Result<PwsafeKey, Report> {
+    let key_file = match passwd_file {
+        Some(path) => Some(SecretField::from(tokio::fs::read(path).await?)),
+        None => None,
+    };
+
+    if key_file.is_some() && !with_passwd {
+        return Ok(combined_key(key_file.as_deref(), None));
+    }
+
+    let Some(mut passwd) = ask_password(true).await? else {
+        return Err(Report::msg("The password of the file changed, but there is no way to ask for the new one"));
+    };
+
+    // The askpass program ends its output with a newline.
+    if passwd.last() == Some(&b'\n') {
+        passwd.pop();
+    }
+
+    Ok(combined_key(key_file.as_deref(), Some(passwd.as_slice())))
+}
+
 async fn refresh(
     // FIXME: we can detect file system changes (the removal of the lock-file) to determine an
     // intermediate event for rebase. It only costs energy (processor time and memory) to do this a
@@ -93,41 +170,138 @@ async fn refresh(
     }
 }
 
-async fn sync_on(
+pub(crate) async fn sync_on(
     client: Arc<Client>,
     room_id: OwnedRoomId,
     comm: Communicator,
+    follow_upgrades: bool,
+    senders: Senders,
 ) -> Result<(), Report> {
     let sync_settings = SyncSettings::new()
         .timeout(std::time::Duration::from_secs(30));
 
+    register_room(&client, &room_id, comm.clone(), follow_upgrades, senders);
+
+    client.sync_with_callback(sync_settings, |_response| {
+        let comm = comm.clone();
+
+        async move {
+            let _ = comm.synced().await;
+            LoopCtrl::Continue
+        }
+    }).await?;
+
+    Ok(())
+}
+
+/// Install the event handlers for the room we are synchronizing with.
+fn register_room(
+    client: &Client,
+    room_id: &RoomId,
+    comm: Communicator,
+    follow_upgrades: bool,
+    senders: Senders,
+) {
+    let diff_comm = comm.clone();
     client.add_room_event_handler(
-        &room_id,
-        move |event: SyncRoomMessageEvent| {
-            let comm = comm.clone();
+        room_id,
+        move |event: OriginalSyncDiffEvent, client: Client, encryption: Option<EncryptionInfo>| {
+            let comm = diff_comm.clone();
 
             async move {
-                tracing::debug!("Sync {event:?}");
+                // Not the event itself, the content contains the diff and its secrets.
+                tracing::debug!("Sync {}", event.event_id);
                 let ts = Timestamp {
-                    ts_ms: event.origin_server_ts().0.into(),
-                    unique: event.event_id().to_string(),
+                    ts_ms: event.origin_server_ts.0.into(),
+                    unique: event.event_id.to_string(),
                 };
 
-                let val: serde_json::Value = todo!();
-                let _ = comm.send_remote(val, ts).await;
+                // We have this diff already, it is only placed in the order of the room.
+                if is_own(&client, &event.sender, encryption.as_ref()) {
+                    let _ = comm.send_echo(ts).await;
+                    return;
+                }
+
+                if let Err(reason) = senders.check(encryption.as_ref()) {
+                    let (id, sender) = (&event.event_id, &event.sender);
+                    tracing::warn!("Rejecting diff {id} of {sender}, {reason}");
+                    return;
+                }
+
+                let _ = comm.send_remote(event.content.diff, ts).await;
             }
         });
 
-    client.sync_with_callback(sync_settings, |_event| async move {
-        LoopCtrl::Continue
-    }).await?;
+    client.add_room_event_handler(
+        room_id,
+        |event: OriginalSyncRoomEncryptedEvent| async move {
+            // Only delivered as such if decryption failed, for instance for lack of the keys.
+            tracing::warn!("Skipping event {} which could not be decrypted", event.event_id);
+        });
 
-    Ok(())
+    client.add_room_event_handler(
+        room_id,
+        move |event: OriginalSyncRoomTombstoneEvent, client: Client| {
+            let comm = comm.clone();
+
+            async move {
+                let successor = event.content.replacement_room;
+                tracing::warn!("Room has been upgraded to {successor}");
+
+                if !follow_upgrades {
+                    tracing::warn!("Not following the upgrade, use `--follow-upgrades` or `migrate-room`");
+                    return;
+                }
+
+                if let Err(err) = client.join_room_by_id(&successor).await {
+                    tracing::warn!("Failed to join the successor room {successor}: {err:?}");
+                    return;
+                }
+
+                register_room(&client, &successor, comm.clone(), follow_upgrades, senders);
+                let _ = comm.migrate(successor).await;
+            }
+        });
+}
+
+/// The senders whose diffs are taken from the room.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Senders {
+    /// Only devices that are verified.
+    Verified,
+    /// Any device, as long as the diff was encrypted.
+    Encrypted,
+    /// Anyone in the room, even with plaintext events. Only for reading the room.
+    Anyone,
+}
+
+impl Senders {
+    /// Check the encryption of an event, or the reason for rejecting it.
+    fn check(self, encryption: Option<&EncryptionInfo>) -> Result<(), String> {
+        match (self, encryption.map(|info| &info.verification_state)) {
+            (Senders::Anyone, _) => Ok(()),
+            (_, None) => Err("it was sent unencrypted".into()),
+            (Senders::Verified, Some(VerificationState::Unverified(level))) => {
+                Err(format!("its device is unverified: {level:?}"))
+            },
+            (_, Some(_)) => Ok(()),
+        }
+    }
+}
+
+/// Whether the event was sent by our own session, the same user and device.
+fn is_own(client: &Client, sender: &UserId, encryption: Option<&EncryptionInfo>) -> bool {
+    let device = encryption.and_then(|info| info.sender_device.as_deref());
+    client.user_id() == Some(sender) && device.is_some_and(|device| client.device_id() == Some(device))
 }
 
 async fn work_on(
     mut station: Station,
     mut db: PwsafeDb,
+    client: Arc<Client>,
+    status_file: PathBuf,
+    passwd_file: Option<OsString>,
+    with_passwd: bool,
 ) -> Result<(), Report> {
     const BATCH_SIZE: usize = 16;
 
@@ -205,18 +379,48 @@ async fn work_on(
     let mut locals = vec![];
     let mut remotes = vec![];
     let mut remote_ts = vec![];
+    let mut migration = None;
+    let mut requests = vec![];
+    // The events of our published diffs, until they are received back from the room. Of those,
+    // the number received since the last write.
+    let mut echoes = VecDeque::<OwnedEventId>::new();
+    let mut echoed = 0;
+    // The new passwords given since the file stopped opening with ours.
+    let mut password_attempts = 0;
+    let mut status = StatusSnapshot::default();
 
     loop {
         station.message.recv_many(&mut queue, BATCH_SIZE).await;
 
         for msg in queue.drain(..) {
             match msg {
-                Message::Diff(diff) => {
+                Message::Request(Query::ApplyDiffValidated(diff), answer) => {
                     tracing::info!("Local diff received");
 
-                    if let Ok(diff) = db.diff(diff) {
-                        locals.push(diff);
-                    }
+                    let diff = db.diff_serial(diff);
+                    let records = diff.records();
+                    locals.push(diff);
+
+                    let _ = answer.send(QueryResponse::Diff(records));
+                },
+                Message::Request(Query::GeneratePassword(uuid, source), answer) => {
+                    tracing::info!("Password generation requested");
+
+                    // Neither the password nor the diff are logged.
+                    let generated = match db.generate_password(uuid, &source) {
+                        Ok(Ok((password, diff))) => {
+                            locals.push(diff);
+                            Ok(password)
+                        },
+                        Ok(Err(rejected)) => Err(rejected),
+                        Err(err) => {
+                            tracing::warn!("Generating a password failed: {err:?}");
+                            let _ = answer.send(QueryResponse::Error(err.to_string()));
+                            continue;
+                        },
+                    };
+
+                    let _ = answer.send(QueryResponse::Password(generated));
                 },
                 Message::Remote(diff, ts) => {
                     tracing::info!("Remote diff received {ts:?}");
@@ -236,6 +440,23 @@ async fn work_on(
                     remotes.push(diff);
                     remote_ts.push(ts);
                 }
+                Message::Echo(ts) => {
+                    if echoes.get(echoed).map_or(true, |id| id.as_str() != ts.unique) {
+                        tracing::debug!("Ignoring own event {} not published by us", ts.unique);
+                        continue;
+                    }
+
+                    tracing::info!("Own diff received {ts:?}");
+                    let Some(diff) = db.published(echoed) else {
+                        continue;
+                    };
+
+                    pending.remote = Some(ts.clone());
+
+                    remotes.push(diff.clone());
+                    remote_ts.push(ts);
+                    echoed += 1;
+                }
                 Message::Sync(id, point) => {
                     tracing::info!("Sync request received {id:?} {point:?}");
 
@@ -245,6 +466,18 @@ async fn work_on(
                     tracing::info!("Rebase request received");
                     lock_exists = false;
                 },
+                Message::Migrate(room) => {
+                    tracing::info!("Migration to {room} received");
+                    migration = Some(room);
+                },
+                Message::Synced(now_ms) => {
+                    status.matrix = Connection::Connected;
+                    status.last_sync_ms = Some(now_ms);
+                },
+                Message::Request(query, answer) => {
+                    tracing::info!("Request received");
+                    requests.push((query, answer));
+                },
             }
         }
 
@@ -253,12 +486,23 @@ async fn work_on(
             // unstable and Drain's keep_rest was essentially closed we do this trick. Just use the
             // vector itself to keep the rest.
             locals.reverse();
+            let mut rewritten = false;
 
-            if let Err(err) = db.with_lock(|mut lock| {
+            if let Err(err) = db.with_lock_async(|mut lock| {
                 tracing::info!("Refreshing file");
-                lock.refresh()?;
-                tracing::info!("Finding new differences added in file");
-                lock.push_diff_from_remote()?;
+                let changed = lock.refresh()?;
+
+                if changed {
+                    tracing::info!("Finding new differences added in file");
+                    lock.push_diff_from_remote()?;
+                }
+
+                let modified = changed
+                    || !locals.is_empty()
+                    || !remotes.is_empty()
+                    || migration.is_some()
+                    || lock.store_changed()?
+                    || lock.iterations_outdated();
 
                 while let Some(diff) = locals.pop() {
                     tracing::info!("Applying diff {}", applied.local);
@@ -268,9 +512,17 @@ async fn work_on(
                 }
 
                 lock.rebase(&remotes, &remote_ts)?;
-                lock.rewrite()?;
+
+                if let Some(room) = &migration {
+                    lock.migrate_room(room.clone(), None);
+                }
+
+                if modified {
+                    rewritten = lock.rewrite()?;
+                }
+
                 Ok(())
-            }) {
+            }).await {
                 if let Some(io_err) = err.downcast_ref::<std::io::Error>() {
                     if io_err.kind() == std::io::ErrorKind::AlreadyExists {
                         tracing::warn!("Lock already exists: {io_err:?}");
@@ -279,18 +531,84 @@ async fn work_on(
                 }
 
                 tracing::warn!("Patch failed: {err:?}");
+
+                if is_invalid_password(&err) {
+                    // Another program changed the password of the file. Nothing is synchronized
+                    // until we are given the new one.
+                    if password_attempts > 0 {
+                        time::sleep(backoff(password_attempts)).await;
+                    }
+
+                    password_attempts += 1;
+                    tracing::warn!("The file no longer opens with its password, asking for the new one");
+                    db.set_key(ask_key(passwd_file.as_deref(), with_passwd).await?)?;
+                }
             } else {
+                password_attempts = 0;
+
                 if let Some(last) = remote_ts.last() {
                     applied.remote = Some(last.clone());
                 }
 
+                // Our own diffs are now part of the shared state, they no longer apply on top.
+                for _ in 0..echoed {
+                    echoes.pop_front();
+                    db.pop_diff();
+                }
+
+                echoed = 0;
+
                 remotes.clear();
                 remote_ts.clear();
+
+                if migration.take().is_some() {
+                    echoes.clear();
+                }
+
+                if rewritten {
+                    status.last_rewrite_ms = Some(unix_ms());
+                }
+
+                status.refresh(&db);
+                status.applied_diffs = applied.local;
+                status.lock_exists = false;
+
+                if let Err(err) = write_status(&status_file, &status) {
+                    let path = status_file.display();
+                    tracing::warn!("Failed to write status file {path}: {err:?}");
+                }
+
+                if let Err(err) = publish(&client, &mut db, &mut echoes).await {
+                    tracing::warn!("Publishing local diffs failed: {err:?}");
+                }
             }
 
             locals.reverse();
         }
 
+        status.refresh(&db);
+        status.applied_diffs = applied.local;
+        status.lock_exists = lock_exists;
+        station.publish_status(status.clone());
+
+        // Answered after the file was written, a failed answer is dropped. Both only copy
+        // records of the working copy, which are already decrypted.
+        for (query, answer) in requests.drain(..) {
+            let response = match query {
+                Query::EntryList => db.records().map(QueryResponse::EntryList),
+                Query::EntryByUuid(uuid) => db.record(uuid).map(QueryResponse::Entry),
+                // Answered as soon as they are received.
+                Query::ApplyDiffValidated(_) | Query::GeneratePassword(..) => continue,
+            };
+
+            match response {
+                Ok(response) => {
+                    let _ = answer.send(response);
+                },
+                Err(err) => tracing::warn!("Answering request failed: {err:?}"),
+            }
+        }
+
         for (id, points) in &mut acks {
             while let Some((need, point)) = points.front() {
                 if !(*need < applied) {
@@ -308,3 +626,44 @@ async fn work_on(
         pacing.tick().await;
     }
 }
+
+/// Send the local diffs into the room, in order. Stops at the first failure, to retry later.
+async fn publish(
+    client: &Client,
+    db: &mut PwsafeDb,
+    echoes: &mut VecDeque<OwnedEventId>,
+) -> Result<(), Report> {
+    let Some(room_id) = db.room() else {
+        return Ok(());
+    };
+
+    // Not known before the first sync response, the diffs are sent on a later round.
+    let Some(room) = client.get_room(room_id) else {
+        return Ok(());
+    };
+
+    while let Some(diff) = db.unpublished().next() {
+        let diff = diff.serialize()?;
+        // Encrypted by the client, with the keys of our crypto store.
+        let response = room.send(DiffEventContent { diff }).await?;
+
+        tracing::info!("Published local diff as {}", response.event_id);
+        echoes.push_back(response.event_id);
+        db.mark_published();
+    }
+
+    Ok(())
+}
+
+/// Record how far the database is synchronized, for other tools to inspect.
+fn write_status(path: &Path, status: &StatusSnapshot) -> Result<(), Report> {
+    let mut status = serde_json::to_value(status)?;
+    status["updated_ms"] = unix_ms().into();
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer(&mut file, &status)?;
+    file.persist(path)?;
+
+    Ok(())
+}
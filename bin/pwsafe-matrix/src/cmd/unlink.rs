@@ -0,0 +1,69 @@
+use crate::{ArgsLogin, ArgsPwsafe};
+use crate::lockfile::{LockFile, UserInfo};
+use crate::matrix::create_session;
+use crate::paths::Paths;
+use crate::pwsafe::PwsafeDb;
+
+use std::path::{Path, PathBuf};
+use eyre::{Report, WrapErr as _};
+use matrix_sdk::config::SyncSettings;
+
+pub async fn run(
+    pwsafe: ArgsPwsafe,
+    login: Option<ArgsLogin>,
+    state_dir: Option<PathBuf>,
+    leave_room: bool,
+    logout: bool,
+) -> Result<(), Report> {
+    let mut db = PwsafeDb::open(&pwsafe)?;
+
+    let paths = Paths::new(Path::new(&pwsafe.pwsafe), state_dir.as_deref());
+    paths.create_dir()?;
+    // A running sync would write the session and room right back.
+    let _sync_lock = LockFile::create(paths.sync_lock(), &UserInfo::new()?)
+        .wrap_err("The file is being synchronized, stop `sync` before unlinking it")?;
+
+    if db.session().is_none() && db.room().is_none() {
+        println!("Nothing to unlink, the file is not linked to a Matrix room");
+        return Ok(());
+    }
+
+    if leave_room || logout {
+        let Some(session) = db.session().cloned() else {
+            return Err(Report::msg("Pwsafe File does not contain matrix credentials"));
+        };
+
+        let cs = create_session(login.as_ref(), Some(session), db.store()).await?;
+
+        if let (true, Some(room)) = (leave_room, db.room()) {
+            cs.client.sync_once(SyncSettings::new()).await?;
+
+            match cs.client.get_room(room) {
+                Some(joined) => {
+                    joined.leave().await?;
+                    println!("Left room {room}");
+                }
+                None => tracing::warn!("Not a member of {room}, nothing to leave"),
+            }
+        }
+
+        if logout {
+            cs.client.matrix_auth().logout().await?;
+            println!("Logged out device {}", cs.session.meta.device_id);
+        }
+    }
+
+    if let Some(session) = db.clear_session() {
+        println!("Removed session of {} on device {}", session.meta.user_id, session.meta.device_id);
+    }
+
+    if let Some(room) = db.clear_room() {
+        println!("Removed room {room}");
+    }
+
+    db.with_lock(|mut lock| {
+        lock.rewrite()
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,61 @@
+use crate::ArgsPwsafe;
+use crate::exit::ValidationError;
+use crate::pwsafe::{PwsafeDb, StateCheck, Verification};
+
+use eyre::Report;
+
+/// Check the database, printing the findings. Fails if there was any problem.
+pub fn run(
+    pwsafe: ArgsPwsafe,
+    json: bool,
+) -> Result<(), Report> {
+    let verification = PwsafeDb::verify(&pwsafe)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&verification)?);
+    } else {
+        print_text(&verification);
+    }
+
+    if !verification.is_ok() {
+        return Err(ValidationError("The database has structural problems".into()).into());
+    }
+
+    Ok(())
+}
+
+fn print_text(verification: &Verification) {
+    let present = |present: bool| if present { "present" } else { "missing" };
+
+    println!("records: {}", verification.records);
+    println!("records without uuid: {}", verification.missing_uuid);
+
+    for uuid in &verification.duplicate_uuid {
+        println!("duplicate uuid: {uuid}");
+    }
+
+    match &verification.state {
+        StateCheck::Missing => println!("state: missing"),
+        StateCheck::Valid => println!("state: valid"),
+        StateCheck::Invalid(err) => println!("state: invalid, {err}"),
+    }
+
+    println!("session: {}", present(verification.session));
+    println!("room: {}", present(verification.room));
+
+    let saved = &verification.last_saved;
+    if let Some(timestamp) = saved.timestamp {
+        println!("last saved: {timestamp}");
+    }
+
+    if let Some(user) = &saved.user {
+        match &saved.host {
+            Some(host) => println!("last saved by: {user}@{host}"),
+            None => println!("last saved by: {user}"),
+        }
+    }
+
+    if let Some(application) = &saved.application {
+        println!("last saved with: {application}");
+    }
+}
@@ -0,0 +1,106 @@
+use crate::{ArgsLogin, ArgsPwsafe};
+use crate::cmd::sync::{sync_on, Senders};
+use crate::communicator::{Message, Station};
+use crate::matrix::create_session;
+use crate::pwsafe::PwsafeDb;
+
+use std::io::Write as _;
+use std::sync::Arc;
+use eyre::Report;
+use tokio::{
+    signal,
+    task::JoinSet,
+};
+
+/// Follow the room and print each diff, never touching the database file.
+pub async fn run(
+    pwsafe: ArgsPwsafe,
+    login: Option<ArgsLogin>,
+    show_secrets: bool,
+) -> Result<(), Report> {
+    let db = PwsafeDb::open(&pwsafe)?;
+
+    let session = db.session().cloned();
+    let mut join_set = JoinSet::<Result<(), Report>>::new();
+
+    if session.is_none() {
+        return Err(Report::msg("Pwsafe File does not contain matrix credentials"));
+    }
+
+    let Some(room) = db.room().cloned() else {
+        return Err(Report::msg("Pwsafe File does not contain matrix room"));
+    };
+
+    let cs = create_session(login.as_ref(), session, db.store()).await?;
+    let client = Arc::new(cs.client);
+
+    join_set.spawn(async {
+        signal::ctrl_c().await?;
+        tracing::info!("Ctrl-C received");
+        Ok(())
+    });
+
+    let (inst_stream, station) = Station::new();
+    // Nothing is applied, we show whatever is in the room.
+    join_set.spawn(sync_on(client.clone(), room, inst_stream, false, Senders::Anyone));
+    join_set.spawn(print_on(station, db, show_secrets));
+
+    join_set.join_next().await.unwrap()??;
+
+    tracing::debug!("Shutting down watch");
+    join_set.abort_all();
+
+    while let Some(next) = join_set.join_next().await {
+        match next {
+            Ok(task) => task?,
+            Err(err) if err.is_cancelled() => {},
+            Err(err) => Err(err)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Takes the place of `work_on`, without any lock or write.
+async fn print_on(
+    mut station: Station,
+    db: PwsafeDb,
+    show_secrets: bool,
+) -> Result<(), Report> {
+    const BATCH_SIZE: usize = 16;
+    let mut queue = vec![];
+
+    loop {
+        if station.message.recv_many(&mut queue, BATCH_SIZE).await == 0 {
+            return Ok(());
+        }
+
+        for msg in queue.drain(..) {
+            match msg {
+                Message::Remote(diff, ts) => {
+                    let diff = match db.diff(diff) {
+                        Ok(diff) => diff,
+                        Err(err) => {
+                            tracing::warn!("Invalid diff in {}: {err:?}", ts.unique);
+                            continue;
+                        }
+                    };
+
+                    let mut stdout = std::io::stdout().lock();
+                    writeln!(stdout, "# {} at {}", ts.unique, ts.ts_ms)?;
+                    write!(stdout, "{}", diff.render(show_secrets))?;
+                    stdout.flush()?;
+                },
+                // Nothing is ever pending, all messages are handled as they arrive.
+                Message::Sync(id, point) => station.ack(id, point),
+                Message::Migrate(room) => {
+                    tracing::warn!("Room has been upgraded to {room}, not following it");
+                },
+                Message::Echo(_)
+                | Message::Rebase
+                | Message::Synced(_)
+                | Message::Request(..) => {},
+            }
+        }
+    }
+}
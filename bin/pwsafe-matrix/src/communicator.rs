@@ -5,13 +5,22 @@ use core::sync::atomic::{AtomicU64, Ordering};
 use std::collections::HashMap;
 use std::sync::Arc;
 use eyre::Report;
-use tokio::sync::{mpsc, watch};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time;
+use uuid::Uuid;
 
-use crate::pwsafe::Timestamp;
+use crate::diff::DiffSerial;
+use crate::pwsafe::{Fields, PasswordRejected, PolicySource, PwsafeDb, Timestamp};
+use matrix_sdk::ruma::OwnedRoomId;
+
+/// How long a request waits for the database, which might be locked by another program.
+const REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
 pub struct Station {
     pub(crate) message: mpsc::Receiver<Message>,
     pub(crate) state: watch::Sender<State>,
+    pub(crate) status: watch::Sender<StatusSnapshot>,
     pub(crate) id_gen: Arc<AtomicU64>,
 }
 
@@ -21,6 +30,7 @@ pub struct Communicator {
     sync_point_next: AtomicU64,
     stream: mpsc::Sender<Message>,
     state: watch::Receiver<State>,
+    status: watch::Receiver<StatusSnapshot>,
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
@@ -38,21 +48,91 @@ pub(crate) struct State {
 }
 
 pub(crate) enum Message {
-    Diff(serde_json::Value),
     Sync(Id, SyncPoint),
     Remote(serde_json::Value, Timestamp),
+    /// One of our own diffs, received back from the room.
+    Echo(Timestamp),
     Rebase,
+    Migrate(OwnedRoomId),
+    /// A sync response arrived from the homeserver, at this time in milliseconds.
+    Synced(u64),
+    Request(Query, oneshot::Sender<QueryResponse>),
+}
+
+/// A question to the task modifying the database, see [`Communicator::request`].
+pub enum Query {
+    /// A copy of all records of the working copy.
+    EntryList,
+    /// A record of the file as last written.
+    EntryByUuid(Uuid),
+    /// Apply a local diff, validated by the server already.
+    ApplyDiffValidated(DiffSerial),
+    /// Generate a new password for a record, and apply a local diff setting it.
+    GeneratePassword(Uuid, PolicySource),
+}
+
+pub enum QueryResponse {
+    EntryList(Vec<Fields>),
+    Entry(Option<Fields>),
+    /// The number of records the diff touches.
+    Diff(usize),
+    Password(Result<String, PasswordRejected>),
+    /// The request could not be answered, for this reason.
+    Error(String),
+}
+
+/// How far the task modifying the database got, published after each of its rounds.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatusSnapshot {
+    pub room: Option<OwnedRoomId>,
+    /// The last event of the room that is part of the file.
+    pub remote_until: Option<Timestamp>,
+    /// Local edits not yet received back from the room, published or not.
+    pub pending_diffs: usize,
+    /// Local edits not yet sent into the room.
+    pub unpublished_diffs: usize,
+    /// Local edits applied to the file since the start.
+    pub applied_diffs: u64,
+    /// Whether the lock file of another program keeps us from writing.
+    pub lock_exists: bool,
+    /// When the file was last written by us, in milliseconds since the epoch.
+    pub last_rewrite_ms: Option<u64>,
+    pub matrix: Connection,
+    /// When the last sync response arrived, in milliseconds since the epoch.
+    pub last_sync_ms: Option<u64>,
+}
+
+/// The state of the sync with the homeserver.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Connection {
+    /// No sync response arrived yet.
+    #[default]
+    Connecting,
+    Connected,
+}
+
+impl StatusSnapshot {
+    /// Take over the state of the database, our progress is tracked by the caller.
+    pub(crate) fn refresh(&mut self, db: &PwsafeDb) {
+        self.room = db.room().cloned();
+        self.remote_until = db.remote_until().cloned();
+        self.pending_diffs = db.pending_diffs();
+        self.unpublished_diffs = db.unpublished().count();
+    }
 }
 
 impl Station {
     pub fn new() -> (Communicator, Self) {
         let (stream, message) = mpsc::channel(1 << 10);
         let (state, state_recv) = watch::channel(State::default());
+        let (status, status_recv) = watch::channel(StatusSnapshot::default());
 
         let id_gen = Arc::new(AtomicU64::new(1));
         let station = Station {
             message,
             state,
+            status,
             id_gen,
         };
 
@@ -62,6 +142,7 @@ impl Station {
             sync_point_next: AtomicU64::new(0),
             stream,
             state: state_recv,
+            status: status_recv,
         };
 
         (communicator, station)
@@ -72,17 +153,26 @@ impl Station {
             state.ack.insert(id, point);
         })
     }
+
+    /// Publish the status, see [`Communicator::status`].
+    pub(crate) fn publish_status(&mut self, status: StatusSnapshot) {
+        self.status.send_if_modified(|current| {
+            let modified = *current != status;
+            *current = status;
+            modified
+        });
+    }
 }
 
 impl Communicator {
-    pub async fn send_diff(&self, diff: serde_json::Value) -> Result<(), Report> {
-        self.stream.send(Message::Diff(diff)).await?;
+    pub async fn send_remote(&self, diff: serde_json::Value, ts: Timestamp) -> Result<(), Report> {
+        self.stream.send(Message::Remote(diff, ts)).await?;
         self._sync().await?;
         Ok(())
     }
 
-    pub async fn send_remote(&self, diff: serde_json::Value, ts: Timestamp) -> Result<(), Report> {
-        self.stream.send(Message::Remote(diff, ts)).await?;
+    pub async fn send_echo(&self, ts: Timestamp) -> Result<(), Report> {
+        self.stream.send(Message::Echo(ts)).await?;
         self._sync().await?;
         Ok(())
     }
@@ -93,6 +183,52 @@ impl Communicator {
         Ok(())
     }
 
+    pub async fn migrate(&self, room: OwnedRoomId) -> Result<(), Report> {
+        self.stream.send(Message::Migrate(room)).await?;
+        self._sync().await?;
+        Ok(())
+    }
+
+    /// Report a sync response of the homeserver, without waiting for it to be handled.
+    pub async fn synced(&self) -> Result<(), Report> {
+        self.stream.send(Message::Synced(unix_ms())).await?;
+        Ok(())
+    }
+
+    /// The status last published by the task modifying the database.
+    ///
+    /// Does not wait for the task, which might be blocked by the lock file of another program.
+    pub fn status(&self) -> StatusSnapshot {
+        self.status.borrow().clone()
+    }
+
+    /// Ask the task modifying the database, after all messages sent so far have been handled.
+    ///
+    /// A diff is answered as soon as it was parsed, the request returns after it was applied. The
+    /// same holds for the diff setting a generated password.
+    pub async fn request(&self, query: Query) -> Result<QueryResponse, Report> {
+        let applies = matches!(query, Query::ApplyDiffValidated(_) | Query::GeneratePassword(..));
+
+        let exchange = async {
+            self._sync().await?;
+
+            let (answer, response) = oneshot::channel();
+            self.stream.send(Message::Request(query, answer)).await?;
+            let response = response.await?;
+
+            if applies {
+                self._sync().await?;
+            }
+
+            Ok::<_, Report>(response)
+        };
+
+        match time::timeout(REQUEST_TIMEOUT, exchange).await {
+            Ok(response) => response,
+            Err(_) => Err(Report::msg("The request was not answered in time")),
+        }
+    }
+
     async fn _sync(&self) -> Result<(), Report> {
         let sync_id = self.sync_point_next.fetch_add(1, Ordering::Relaxed);
         self.stream.send(Message::Sync(self.id, SyncPoint(sync_id))).await?;
@@ -119,6 +255,14 @@ impl Clone for Communicator {
             sync_point_next: AtomicU64::new(0),
             stream: self.stream.clone(),
             state: self.state.clone(),
+            status: self.status.clone(),
         }
     }
 }
+
+/// The current time in milliseconds since the epoch.
+pub(crate) fn unix_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
@@ -0,0 +1,232 @@
+//! The configuration file, providing defaults for the arguments of all subcommands.
+//!
+//! ```toml
+//! [profile.default]
+//! pwsafe = "/home/user/passwords.psafe3"
+//! homeserver = "https://matrix.example.org"
+//! user = "user"
+//! ```
+//!
+//! Precedence is: command line flag, then environment variable, then the configuration file. The
+//! first two are merged by `clap` already, here we only fill in what is still missing.
+use crate::{ArgsLogin, ArgsPwsafe, ArgsServer, MaybeLogin, MaybePwsafe, MaybeServer, ServerAddress};
+use crate::exit::UsageError;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use eyre::Report;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default, Debug)]
+pub struct Config {
+    #[serde(default)]
+    profile: HashMap<String, Profile>,
+    #[serde(flatten)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+#[derive(Deserialize, Default, Clone, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    pwsafe: Option<PathBuf>,
+    key_file: Option<PathBuf>,
+    homeserver: Option<url::Url>,
+    user: Option<String>,
+    server_address: Option<std::net::SocketAddr>,
+    server_unix: Option<PathBuf>,
+    server_http_authorization: Option<String>,
+    server_max_diff_size: Option<usize>,
+    server_allow_field_types: Option<Vec<u8>>,
+    state_dir: Option<PathBuf>,
+    #[serde(flatten)]
+    unknown: HashMap<String, toml::Value>,
+}
+
+/// Merges the command line arguments with the selected profile.
+pub struct Resolver {
+    profile: Profile,
+}
+
+impl Config {
+    const DEFAULT_PROFILE: &'static str = "default";
+
+    pub fn from_str(data: &str) -> Result<Self, Report> {
+        Ok(toml::from_str(data)?)
+    }
+
+    /// Keys in the file that we do not understand, probably misspelled.
+    pub fn unknown_keys(&self) -> Vec<String> {
+        let mut keys: Vec<_> = self.unknown.keys().cloned().collect();
+
+        for (name, profile) in &self.profile {
+            keys.extend(profile.unknown.keys().map(|key| format!("profile.{name}.{key}")));
+        }
+
+        keys.sort();
+        keys
+    }
+
+    fn default_path() -> Option<PathBuf> {
+        let config = std::env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+
+        Some(config.join("pwsafe-matrix").join("config.toml"))
+    }
+}
+
+impl Resolver {
+    /// The body size of diffs accepted by the server, unless configured.
+    pub const DEFAULT_MAX_DIFF_SIZE: usize = 1 << 20;
+
+    /// Load the profile from a configuration file.
+    ///
+    /// An explicitly named file or profile must exist, the defaults are optional.
+    pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Self, Report> {
+        let config = match path {
+            Some(path) => Config::from_str(&std::fs::read_to_string(path)?)?,
+            None => match Config::default_path() {
+                Some(path) if path.exists() => Config::from_str(&std::fs::read_to_string(path)?)?,
+                _ => Config::default(),
+            },
+        };
+
+        for key in config.unknown_keys() {
+            tracing::warn!("Unknown key `{key}` in configuration file");
+        }
+
+        Self::with_profile(config, profile)
+    }
+
+    pub fn with_profile(mut config: Config, profile: Option<&str>) -> Result<Self, Report> {
+        let profile = match profile {
+            Some(name) => match config.profile.remove(name) {
+                Some(profile) => profile,
+                None => return Err(UsageError(format!("No profile `{name}` in configuration")).into()),
+            },
+            None => config.profile.remove(Config::DEFAULT_PROFILE).unwrap_or_default(),
+        };
+
+        Ok(Resolver { profile })
+    }
+
+    pub fn pwsafe(&self, args: MaybePwsafe) -> Result<ArgsPwsafe, Report> {
+        let Some(pwsafe) = args.pwsafe.or_else(|| self.profile.pwsafe.clone().map(Into::into)) else {
+            return Err(UsageError("No pwsafe database given, as argument or in the configuration".into()).into());
+        };
+
+        let passwd_file = args.passwd_file
+            .or_else(|| self.profile.key_file.clone().map(Into::into));
+
+        let passwd = match (args.passwd, args.password_from_stdin) {
+            (Some(_), true) => {
+                return Err(UsageError("Provide at most one of `--password` or `--password-from-stdin`".into()).into());
+            }
+            (Some(passwd), false) => Some(passwd),
+            (None, true) => Some(password_from_stdin()?),
+            (None, false) => None,
+        };
+
+        if passwd.is_none() && passwd_file.is_none() {
+            return Err(UsageError("Provide a key file, a password with `--password` or `--password-from-stdin`, or both".into()).into());
+        }
+
+        Ok(ArgsPwsafe {
+            pwsafe,
+            passwd_file,
+            passwd,
+        })
+    }
+
+    /// Login information, if any was provided.
+    pub fn login(&self, args: MaybeLogin) -> Result<Option<ArgsLogin>, Report> {
+        let homeserver = args.homeserver.or_else(|| self.profile.homeserver.clone());
+        let user = args.user.or_else(|| self.profile.user.clone());
+
+        match (homeserver, user) {
+            (Some(homeserver), Some(user)) => Ok(Some(ArgsLogin {
+                homeserver,
+                user,
+                password: args.password,
+                not_from_tty: args.not_from_tty,
+            })),
+            (None, None) => Ok(None),
+            _ => Err(UsageError("Provide both `--homeserver` and `--user`, or neither".into()).into()),
+        }
+    }
+
+    pub fn require_login(&self, args: MaybeLogin) -> Result<ArgsLogin, Report> {
+        match self.login(args)? {
+            Some(login) => Ok(login),
+            None => Err(UsageError("Provide `--homeserver` and `--user`, as arguments or in the configuration".into()).into()),
+        }
+    }
+
+    /// Server configuration, if any was provided.
+    pub fn server(&self, args: MaybeServer) -> Result<Option<ArgsServer>, Report> {
+        let secret = args.secret.or_else(|| self.profile.server_http_authorization.clone());
+        // The arguments replace the listener of the profile, whichever kind it is.
+        let address = match listen(args.address, args.unix)? {
+            Some(address) => Some(address),
+            None => listen(self.profile.server_address, self.profile.server_unix.clone())?,
+        };
+
+        let max_diff_size = args.max_diff_size
+            .or(self.profile.server_max_diff_size)
+            .unwrap_or(Self::DEFAULT_MAX_DIFF_SIZE);
+
+        let allow_field_types = match args.allow_field_types {
+            types if types.is_empty() => self.profile.server_allow_field_types.clone(),
+            types => Some(types),
+        };
+
+        if args.trust_uid && !matches!(address, Some(ServerAddress::Unix(_))) {
+            return Err(UsageError("`--server-trust-uid` only applies to `--server-unix`".into()).into());
+        }
+
+        match (secret, address) {
+            (secret, Some(address)) if secret.is_some() || args.trust_uid => Ok(Some(ArgsServer {
+                secret,
+                address,
+                ready: args.ready,
+                trust_uid: args.trust_uid,
+                max_diff_size,
+                allow_field_types: allow_field_types.unwrap_or_default(),
+            })),
+            (None, None) => Ok(None),
+            _ => Err(UsageError("Provide both `--server-address` or `--server-unix` and `--server-http-authorization`, or neither".into()).into()),
+        }
+    }
+
+    pub fn state_dir(&self, arg: Option<PathBuf>) -> Option<PathBuf> {
+        arg.or_else(|| self.profile.state_dir.clone())
+    }
+}
+
+/// The one listener of the server configured, at most.
+fn listen(address: Option<std::net::SocketAddr>, unix: Option<PathBuf>)
+    -> Result<Option<ServerAddress>, Report>
+{
+    match (address, unix) {
+        (Some(address), None) => Ok(Some(ServerAddress::Tcp(address))),
+        (None, Some(path)) => Ok(Some(ServerAddress::Unix(path))),
+        (None, None) => Ok(None),
+        (Some(_), Some(_)) => Err(UsageError("Provide only one of `--server-address` and `--server-unix`".into()).into()),
+    }
+}
+
+/// The password on the first line of stdin, without its newline.
+fn password_from_stdin() -> Result<String, Report> {
+    let mut line = String::new();
+
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        return Err(UsageError("No password on stdin for `--password-from-stdin`".into()).into());
+    }
+
+    if line.ends_with('\n') {
+        line.pop();
+    }
+
+    Ok(line)
+}
@@ -0,0 +1,135 @@
+//! The exit codes of the program, so that scripts can react to the class of failure.
+//!
+//! The code is determined from the chain of errors in a report, the first cause we recognize
+//! decides. Everything unrecognized exits with `1`, as `eyre` would.
+use core::fmt;
+
+use eyre::Report;
+use matrix_sdk::ruma::api::client::error::ErrorKind;
+
+/// Summary for `--help`, keep in sync with [`Exit`].
+pub const EXIT_CODES: &str = "\
+Exit codes:
+  0  Success
+  1  Other failure
+  2  Usage error, invalid or missing arguments
+  3  Wrong passphrase for the pwsafe database
+  4  I/O error, such as a missing file
+  5  Matrix authentication failed
+  6  Matrix homeserver not reachable
+  7  The database is locked by another program
+  8  Invalid data, in the database or received";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Exit {
+    Failure = 1,
+    Usage = 2,
+    Passphrase = 3,
+    Io = 4,
+    MatrixAuth = 5,
+    MatrixNetwork = 6,
+    LockContention = 7,
+    Validation = 8,
+}
+
+/// An error in the invocation, which clap could not detect on its own.
+#[derive(Debug)]
+pub struct UsageError(pub String);
+
+/// Invalid data found by us, rather than by a library with its own error type.
+#[derive(Debug)]
+pub struct ValidationError(pub String);
+
+impl Exit {
+    pub fn classify(report: &Report) -> Self {
+        report
+            .chain()
+            .find_map(Self::classify_cause)
+            .unwrap_or(Exit::Failure)
+    }
+
+    fn classify_cause(err: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        if err.is::<UsageError>() {
+            return Some(Exit::Usage);
+        }
+
+        if err.is::<ValidationError>() {
+            return Some(Exit::Validation);
+        }
+
+        if let Some(err) = err.downcast_ref::<pwsafer::ReadError>() {
+            return Some(match err {
+                pwsafer::ReadError::InvalidPassword => Exit::Passphrase,
+                pwsafer::ReadError::IoError(_) => Exit::Io,
+                _ => Exit::Validation,
+            });
+        }
+
+        if let Some(err) = err.downcast_ref::<std::io::Error>() {
+            // The only exclusive creation we do is the lock file.
+            return Some(match err.kind() {
+                std::io::ErrorKind::AlreadyExists => Exit::LockContention,
+                _ => Exit::Io,
+            });
+        }
+
+        if let Some(err) = err.downcast_ref::<matrix_sdk::Error>() {
+            if let matrix_sdk::Error::Http(err) = err {
+                return Some(Self::classify_http(err));
+            }
+
+            return Self::classify_kind(err.client_api_error_kind());
+        }
+
+        if let Some(err) = err.downcast_ref::<matrix_sdk::HttpError>() {
+            return Some(Self::classify_http(err));
+        }
+
+        if err.is::<serde_json::Error>() || err.is::<matrix_sdk::ruma::IdParseError>() {
+            return Some(Exit::Validation);
+        }
+
+        None
+    }
+
+    fn classify_http(err: &matrix_sdk::HttpError) -> Self {
+        if let matrix_sdk::HttpError::Reqwest(_) = err {
+            return Exit::MatrixNetwork;
+        }
+
+        Self::classify_kind(err.client_api_error_kind()).unwrap_or(Exit::Failure)
+    }
+
+    fn classify_kind(kind: Option<&ErrorKind>) -> Option<Self> {
+        match kind? {
+            ErrorKind::Forbidden
+            | ErrorKind::UnknownToken { .. }
+            | ErrorKind::MissingToken
+            | ErrorKind::UserDeactivated => Some(Exit::MatrixAuth),
+            _ => None,
+        }
+    }
+}
+
+impl From<Exit> for std::process::ExitCode {
+    fn from(exit: Exit) -> Self {
+        std::process::ExitCode::from(exit as u8)
+    }
+}
+
+impl fmt::Display for UsageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for UsageError {}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -0,0 +1,279 @@
+//! Read the entries of a KeePass 2.x XML export, see the `import` command.
+//!
+//! Groups nest in KeePass, their names are joined by dots into the group of pwsafe where a dot
+//! within a name is escaped as `\.`. The top group of an export is the database itself and not
+//! part of the group. Entries in the recycle bin are not read.
+use base64::Engine as _;
+use eyre::{eyre, Report};
+use pwsafer::PwsafeRecordField;
+use roxmltree::Node;
+use uuid::Uuid;
+
+/// The number of previous passwords Password Safe keeps by default.
+const MAX_HISTORY: usize = 3;
+
+/// Seconds from `0001-01-01`, where times of KDBX 4 start, to the epoch.
+const KDBX_EPOCH: i64 = 62_135_596_800;
+
+/// One entry of the export.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Entry {
+    /// The UUID in the export, if it has a valid one.
+    pub uuid: Option<Uuid>,
+    pub group: String,
+    pub title: String,
+    pub username: String,
+    pub password: String,
+    pub url: String,
+    /// The notes, followed by a `key: value` line for each custom string of the entry.
+    pub notes: String,
+    /// Seconds since the epoch.
+    pub created: Option<u32>,
+    /// The passwords of previous versions, oldest first, with the time each version was saved.
+    pub history: Vec<(u32, String)>,
+}
+
+/// Read all entries of the export.
+pub fn parse(xml: &str) -> Result<Vec<Entry>, Report> {
+    let document = roxmltree::Document::parse(xml)?;
+    let file = document.root_element();
+
+    if !file.has_tag_name("KeePassFile") {
+        let name = file.tag_name().name();
+        return Err(eyre!("Not a KeePass XML export, its root element is <{name}>"));
+    }
+
+    let recycle_bin = child(file, "Meta")
+        .filter(|meta| child_text(*meta, "RecycleBinEnabled") == Some("True"))
+        .and_then(|meta| child_text(meta, "RecycleBinUUID"))
+        .and_then(parse_uuid);
+
+    let root = child(file, "Root").ok_or_else(|| eyre!("The export has no <Root> element"))?;
+    let mut entries = vec![];
+
+    for database in children(root, "Group") {
+        read_group(database, "", recycle_bin, &mut entries)?;
+    }
+
+    Ok(entries)
+}
+
+impl Entry {
+    /// The fields of a pwsafe record with the values of this entry, except its UUID.
+    ///
+    /// Empty values are left out. With `history`, the distinct passwords of previous versions are
+    /// kept in the password history.
+    pub fn fields(&self, history: bool) -> Vec<PwsafeRecordField> {
+        let text: [(&String, fn(String) -> PwsafeRecordField); 6] = [
+            (&self.group, PwsafeRecordField::Group),
+            (&self.title, PwsafeRecordField::Title),
+            (&self.username, PwsafeRecordField::Username),
+            (&self.password, PwsafeRecordField::Password),
+            (&self.url, PwsafeRecordField::Url),
+            (&self.notes, PwsafeRecordField::Notes),
+        ];
+
+        let mut fields: Vec<_> = text
+            .into_iter()
+            .filter(|(value, _)| !value.is_empty())
+            .map(|(value, field)| field(value.clone()))
+            .collect();
+
+        if let Some(created) = self.created {
+            fields.push(PwsafeRecordField::CreationTime(created));
+        }
+
+        if let Some(history) = self.password_history().filter(|_| history) {
+            fields.push(PwsafeRecordField::PasswordHistory(history));
+        }
+
+        fields
+    }
+
+    /// The password history field, `fmmnn` followed by `TTTTTTTTLLLL<password>` entries.
+    fn password_history(&self) -> Option<String> {
+        let mut previous: Vec<&(u32, String)> = vec![];
+
+        for version in &self.history {
+            let (_, password) = version;
+            if password.is_empty() || previous.last().is_some_and(|(_, last)| last == password) {
+                continue;
+            }
+
+            previous.push(version);
+        }
+
+        // Versions which only changed other fields.
+        while previous.last().is_some_and(|(_, last)| *last == self.password) {
+            previous.pop();
+        }
+
+        if previous.is_empty() {
+            return None;
+        }
+
+        let previous = &previous[previous.len().saturating_sub(0xff)..];
+        let max = previous.len().max(MAX_HISTORY);
+        let mut field = format!("1{max:02x}{:02x}", previous.len());
+
+        for (time, password) in previous {
+            // Password Safe counts the characters, not the bytes.
+            field.push_str(&format!("{time:08x}{:04x}{password}", password.chars().count()));
+        }
+
+        Some(field)
+    }
+}
+
+fn read_group(
+    group: Node,
+    path: &str,
+    recycle_bin: Option<Uuid>,
+    entries: &mut Vec<Entry>,
+) -> Result<(), Report> {
+    for entry in children(group, "Entry") {
+        entries.push(read_entry(entry, path)?);
+    }
+
+    for subgroup in children(group, "Group") {
+        let uuid = child_text(subgroup, "UUID").and_then(parse_uuid);
+        if uuid.is_some() && uuid == recycle_bin {
+            continue;
+        }
+
+        let name = child_text(subgroup, "Name").unwrap_or_default().replace('.', "\\.");
+        let path = if path.is_empty() { name } else { format!("{path}.{name}") };
+        read_group(subgroup, &path, recycle_bin, entries)?;
+    }
+
+    Ok(())
+}
+
+fn read_entry(node: Node, group: &str) -> Result<Entry, Report> {
+    let mut entry = Entry {
+        uuid: child_text(node, "UUID").and_then(parse_uuid),
+        group: group.to_owned(),
+        created: child(node, "Times")
+            .and_then(|times| child_text(times, "CreationTime"))
+            .and_then(parse_time),
+        ..Entry::default()
+    };
+
+    let mut custom = vec![];
+    for (key, value) in strings(node)? {
+        match key {
+            "Title" => entry.title = value,
+            "UserName" => entry.username = value,
+            "Password" => entry.password = value,
+            "URL" => entry.url = value,
+            "Notes" => entry.notes = value,
+            _ if value.is_empty() => {}
+            _ => custom.push(format!("{key}: {value}")),
+        }
+    }
+
+    // pwsafe has no custom fields, they are kept in the notes.
+    for line in custom {
+        if !entry.notes.is_empty() {
+            entry.notes.push('\n');
+        }
+
+        entry.notes.push_str(&line);
+    }
+
+    for version in child(node, "History").iter().flat_map(|history| children(*history, "Entry")) {
+        let saved = child(version, "Times")
+            .and_then(|times| child_text(times, "LastModificationTime"))
+            .and_then(parse_time);
+
+        let password = strings(version)?
+            .into_iter()
+            .find_map(|(key, value)| (key == "Password").then_some(value));
+
+        entry.history.push((saved.unwrap_or(0), password.unwrap_or_default()));
+    }
+
+    Ok(entry)
+}
+
+/// The custom and standard strings of an entry, by their key.
+fn strings<'a>(entry: Node<'a, '_>) -> Result<Vec<(&'a str, String)>, Report> {
+    let mut strings = vec![];
+
+    for string in children(entry, "String") {
+        let key = child_text(string, "Key").unwrap_or_default();
+        let Some(value) = child(string, "Value") else {
+            continue;
+        };
+
+        // Only the database file itself encrypts them, an export has them in plain text.
+        if value.attribute("Protected") == Some("True") {
+            return Err(eyre!("The value of {key:?} is encrypted, this is not an XML export"));
+        }
+
+        strings.push((key, value.text().unwrap_or_default().to_owned()));
+    }
+
+    Ok(strings)
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str) -> Option<Node<'a, 'input>> {
+    node.children().find(|child| child.has_tag_name(name))
+}
+
+fn children<'a, 'input>(node: Node<'a, 'input>, name: &'static str)
+    -> impl Iterator<Item = Node<'a, 'input>>
+{
+    node.children().filter(move |child| child.has_tag_name(name))
+}
+
+fn child_text<'a>(node: Node<'a, '_>, name: &str) -> Option<&'a str> {
+    child(node, name)?.text()
+}
+
+/// A UUID in base64, as KeePass writes them. The nil UUID stands for none.
+fn parse_uuid(text: &str) -> Option<Uuid> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(text.trim()).ok()?;
+    Uuid::from_slice(&bytes).ok().filter(|uuid| !uuid.is_nil())
+}
+
+/// A time as `YYYY-MM-DDThh:mm:ssZ`, or as seconds since `0001-01-01` in base64 as KDBX 4 does.
+fn parse_time(text: &str) -> Option<u32> {
+    let text = text.trim();
+
+    let seconds = match parse_iso_time(text) {
+        Some(seconds) => seconds,
+        None => {
+            let bytes = base64::engine::general_purpose::STANDARD.decode(text).ok()?;
+            i64::from_le_bytes(bytes.try_into().ok()?) - KDBX_EPOCH
+        }
+    };
+
+    u32::try_from(seconds).ok()
+}
+
+fn parse_iso_time(text: &str) -> Option<i64> {
+    let (date, time) = text.strip_suffix('Z')?.split_once('T')?;
+
+    let mut date = date.splitn(3, '-').map(str::parse::<i64>);
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+
+    // Fractions of a second are dropped.
+    let time = time.split('.').next()?;
+    let mut time = time.splitn(3, ':').map(str::parse::<i64>);
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // The civil calendar to days, from Howard Hinnant's `days_from_civil`.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
@@ -1,126 +1,394 @@
 /// The command implementations.
 mod cmd {
     pub mod create;
+    pub mod import;
     pub mod join;
     pub mod invite;
+    pub mod migrate;
+    pub mod status;
     pub mod sync;
+    pub mod unlink;
+    pub mod verify;
+    pub mod watch;
 }
 
 mod communicator;
-pub mod diff;
-// Not using a crate, we want to mirror the pwsafe functionality here. In particular, exclusive
-// flags and the contents should be close to the original if possible.
-mod lockfile;
+mod config;
+mod exit;
+mod keepass;
 mod matrix;
-pub mod pwsafe;
+mod paths;
 mod server;
-mod store;
+#[cfg(test)]
+mod tests;
+
+use pwsafe_matrix_core::{diff, event, lockfile, pwsafe, store};
+use pwsafe_matrix_core::ArgsPwsafe;
 
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-use clap::Parser;
+use clap::{CommandFactory as _, Parser, Subcommand, ValueEnum};
+use matrix_sdk::ruma::OwnedUserId;
 use tokio::runtime;
 
-fn main() -> Result<(), eyre::Report> {
-    let args: Args = Args::parse();
+fn main() -> std::process::ExitCode {
+    match run() {
+        Ok(()) => std::process::ExitCode::SUCCESS,
+        Err(report) => {
+            eprintln!("Error: {report:?}");
+            exit::Exit::classify(&report).into()
+        }
+    }
+}
+
+fn run() -> Result<(), eyre::Report> {
+    let cli: Cli = Cli::parse();
+
+    if cli.dump_cli_schema {
+        let schema = cli_schema(&Cli::command());
+        println!("{}", serde_json::to_string_pretty(&schema)?);
+        return Ok(());
+    }
+
+    let Some(args) = cli.command else {
+        Cli::command()
+            .error(clap::error::ErrorKind::MissingSubcommand, "A subcommand is required")
+            .exit();
+    };
 
     use tracing_subscriber::prelude::*;
 
+    let (text, json) = match cli.log_format {
+        LogFormat::Text => (Some(tracing_subscriber::fmt::layer().with_writer(std::io::stderr)), None),
+        LogFormat::Json => (None, Some(tracing_subscriber::fmt::layer().json().with_writer(std::io::stderr))),
+    };
+
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(text)
+        .with(json)
         .with(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
+    let config = config::Resolver::load(cli.config.as_deref(), cli.profile.as_deref())?;
+
     match args {
         Args::Create { pwsafe, login, room } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.require_login(login)?;
             let rt = runtime::Runtime::new()?;
             rt.block_on(cmd::create::run(pwsafe, login, room))?;
             Ok(())
         }
-        Args::Join { pwsafe, login, invite } => {
+        Args::Join { pwsafe, login, invite, force, allow_unsigned } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.require_login(login)?;
             let rt = runtime::Runtime::new()?;
-            rt.block_on(cmd::join::run(pwsafe, login, invite))?;
+            rt.block_on(cmd::join::run(pwsafe, login, invite, force, allow_unsigned))?;
             Ok(())
         }
-        Args::Invite { pwsafe, invite } => {
-            cmd::invite::run(pwsafe, invite)?;
+        Args::Invite { pwsafe, login, invite, user, expires } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.login(login)?;
+            let rt = runtime::Runtime::new()?;
+            rt.block_on(cmd::invite::run(pwsafe, login, invite, user, expires))?;
             Ok(())
         }
-        Args::Sync { pwsafe, login, server } => {
+        Args::MigrateRoom { pwsafe, login, to, snapshot } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.login(login)?;
+            let rt = runtime::Runtime::new()?;
+            rt.block_on(cmd::migrate::run(pwsafe, login, to, snapshot))?;
+            Ok(())
+        }
+        Args::Sync {
+            pwsafe, login, server, follow_upgrades, allow_unverified_devices, state_dir,
+            on_conflict, incoming_group,
+        } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
             // We'll try to login via the session stored.
+            let login = config.login(login)?;
+            let server = config.server(server)?;
+            let state_dir = config.state_dir(state_dir);
+            let senders = if allow_unverified_devices {
+                cmd::sync::Senders::Encrypted
+            } else {
+                cmd::sync::Senders::Verified
+            };
+            let rt = runtime::Runtime::new()?;
+            rt.block_on(cmd::sync::run(
+                pwsafe,
+                login,
+                server,
+                follow_upgrades,
+                senders,
+                state_dir,
+                on_conflict,
+                incoming_group,
+            ))?;
+            Ok(())
+        }
+        Args::Unlink { pwsafe, login, state_dir, leave_room, logout } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.login(login)?;
+            let state_dir = config.state_dir(state_dir);
             let rt = runtime::Runtime::new()?;
-            rt.block_on(cmd::sync::run(pwsafe, login.into(), server.into()))?;
+            rt.block_on(cmd::unlink::run(pwsafe, login, state_dir, leave_room, logout))?;
+            Ok(())
+        }
+        Args::Watch { pwsafe, login, show_secrets } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let login = config.login(login)?;
+            let rt = runtime::Runtime::new()?;
+            rt.block_on(cmd::watch::run(pwsafe, login, show_secrets))?;
+            Ok(())
+        }
+        Args::Verify { pwsafe, json } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            cmd::verify::run(pwsafe, json)?;
+            Ok(())
+        }
+        Args::Status { pwsafe, server, state_dir, json } => {
+            // A running sync is asked, which does not need the password.
+            if let Some(server) = config.server(server)? {
+                let rt = runtime::Runtime::new()?;
+                rt.block_on(cmd::status::query(server, json))?;
+                return Ok(());
+            }
+
+            let pwsafe = config.pwsafe(pwsafe)?;
+            let state_dir = config.state_dir(state_dir);
+            cmd::status::offline(pwsafe, state_dir, json)?;
+            Ok(())
+        }
+        Args::Import { pwsafe, file, on_duplicate, no_history } => {
+            let pwsafe = config.pwsafe(pwsafe)?;
+            cmd::import::run(pwsafe, file, on_duplicate, !no_history)?;
+            Ok(())
+        }
+        Args::Completions { shell } => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_owned();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
             Ok(())
         }
     }
 }
 
+/// Describe the command line interface, for tools that drive this binary.
+///
+/// The test harness validates the flags it passes against this description.
+fn cli_schema(command: &clap::Command) -> serde_json::Value {
+    let args: Vec<_> = command
+        .get_arguments()
+        .map(|arg| {
+            serde_json::json!({
+                "id": arg.get_id().as_str(),
+                "long": arg.get_long(),
+                "short": arg.get_short().map(String::from),
+                "help": arg.get_help().map(ToString::to_string),
+                "required": arg.is_required_set(),
+                "positional": arg.is_positional(),
+                "hidden": arg.is_hide_set(),
+            })
+        })
+        .collect();
+
+    let subcommands: Vec<_> = command
+        .get_subcommands()
+        .map(cli_schema)
+        .collect();
+
+    serde_json::json!({
+        "name": command.get_name(),
+        "about": command.get_about().map(ToString::to_string),
+        "args": args,
+        "subcommands": subcommands,
+    })
+}
+
 #[derive(Parser, Debug)]
+#[command(name = "pwsafe-matrix", after_help = exit::EXIT_CODES)]
+struct Cli {
+    #[arg(long = "dump-cli-schema", hide = true, default_value_t = false)]
+    dump_cli_schema: bool,
+    /// The configuration file, defaults to `$XDG_CONFIG_HOME/pwsafe-matrix/config.toml`.
+    ///
+    /// Values given as flags take precedence over environment variables, which take precedence
+    /// over the configuration file.
+    #[arg(long = "config", global = true, env = "PWSAFE_MATRIX_CONFIG")]
+    config: Option<PathBuf>,
+    /// The profile of the configuration file to use.
+    #[arg(long = "profile", global = true, env = "PWSAFE_MATRIX_PROFILE")]
+    profile: Option<String>,
+    /// The format of log messages written to stderr.
+    #[arg(long = "log-format", global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    #[command(subcommand)]
+    command: Option<Args>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
 enum Args {
     Create {
         #[command(flatten)]
-        pwsafe: ArgsPwsafe,
+        pwsafe: MaybePwsafe,
         #[command(flatten)]
-        login: ArgsLogin,
+        login: MaybeLogin,
         #[command(flatten)]
         room: ArgsCreateRoom,
     },
 
     Join {
         #[command(flatten)]
-        pwsafe: ArgsPwsafe,
+        pwsafe: MaybePwsafe,
         #[command(flatten)]
-        login: ArgsLogin,
+        login: MaybeLogin,
         #[arg(short = 'f', long = "file", help = "An invitation file previously exported with the `invite` command")]
         invite: PathBuf,
+        #[arg(long = "force", default_value_t = false, help = "Join even if the pwsafe file is already linked to another room")]
+        force: bool,
+        #[arg(long = "allow-unsigned-invite", default_value_t = false, help = "Accept an invitation file from before they were signed")]
+        allow_unsigned: bool,
     },
 
     Invite {
         #[command(flatten)]
-        pwsafe: ArgsPwsafe,
+        pwsafe: MaybePwsafe,
+        #[command(flatten)]
+        login: MaybeLogin,
         #[arg(short = 'f', long = "file", help = "The path to export the invitation file into")]
         invite: PathBuf,
+        #[arg(long = "invite-user", help = "Invite this account into the room, and only allow it to use the invitation")]
+        user: Option<OwnedUserId>,
+        #[arg(long = "expires", help = "Reject the invitation after this many seconds")]
+        expires: Option<u64>,
+    },
+
+    MigrateRoom {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[command(flatten)]
+        login: MaybeLogin,
+        #[arg(long = "to", help = "The room id or alias of the room to link the file to")]
+        to: String,
+        #[arg(long = "snapshot", default_value_t = false, help = "Send all entries of the file into the new room")]
+        snapshot: bool,
     },
 
     Sync {
         #[command(flatten)]
-        pwsafe: ArgsPwsafe,
+        pwsafe: MaybePwsafe,
         #[command(flatten)]
         login: MaybeLogin,
         #[command(flatten)]
         server: MaybeServer,
-    }
+        #[arg(long = "follow-upgrades", default_value_t = false, help = "Switch to the successor room when the room is upgraded")]
+        follow_upgrades: bool,
+        #[arg(long = "allow-unverified-devices", default_value_t = false, help = "Apply diffs of devices that are not verified, as long as they are encrypted")]
+        allow_unverified_devices: bool,
+        #[arg(long = "state-dir", env = "PWSAFE_MATRIX_STATE_DIR", help = "Directory for the sync lock and status file, instead of next to the database")]
+        state_dir: Option<PathBuf>,
+        #[arg(long = "on-conflict", value_enum, default_value_t = diff::ConflictPolicy::Overwrite, help = "Keep values of the room that local edits overwrite")]
+        on_conflict: diff::ConflictPolicy,
+        #[arg(long = "incoming-group", default_value = diff::INCOMING_GROUP, help = "Group of entries from the room that have no title")]
+        incoming_group: String,
+    },
+
+    /// Remove the Matrix session and room from the file, it is no longer synchronized.
+    Unlink {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[command(flatten)]
+        login: MaybeLogin,
+        #[arg(long = "state-dir", env = "PWSAFE_MATRIX_STATE_DIR", help = "Directory for the sync lock and status file, instead of next to the database")]
+        state_dir: Option<PathBuf>,
+        #[arg(long = "leave-room", default_value_t = false, help = "Also leave the Matrix room")]
+        leave_room: bool,
+        #[arg(long = "logout", default_value_t = false, help = "Also log out the device of the session")]
+        logout: bool,
+    },
+
+    /// Print the diffs arriving in the room, without modifying the file.
+    Watch {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[command(flatten)]
+        login: MaybeLogin,
+        #[arg(long = "show-secrets", default_value_t = false, help = "Print the values of fields instead of redacting them")]
+        show_secrets: bool,
+    },
+
+    /// Check the database and the pwsafe-matrix state in it, exiting non-zero on any problem.
+    Verify {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[arg(long = "json", default_value_t = false, help = "Print the findings as JSON instead of text")]
+        json: bool,
+    },
+
+    /// Print how far the database is synchronized, asking a running sync if given its server.
+    Status {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[command(flatten)]
+        server: MaybeServer,
+        #[arg(long = "state-dir", env = "PWSAFE_MATRIX_STATE_DIR", help = "Directory for the sync lock and status file, instead of next to the database")]
+        state_dir: Option<PathBuf>,
+        #[arg(long = "json", default_value_t = false, help = "Print the status as JSON instead of text")]
+        json: bool,
+    },
+
+    /// Add the entries of a KeePass 2.x XML export to the database, as a local edit.
+    Import {
+        #[command(flatten)]
+        pwsafe: MaybePwsafe,
+        #[arg(short = 'f', long = "file", help = "The XML file exported from KeePass")]
+        file: PathBuf,
+        #[arg(long = "on-duplicate", value_enum, default_value_t = cmd::import::OnDuplicate::Skip, help = "What to do with entries of the same group, title and username as a record")]
+        on_duplicate: cmd::import::OnDuplicate,
+        #[arg(long = "no-history", default_value_t = false, help = "Do not keep the passwords of previous versions in the password history")]
+        no_history: bool,
+    },
+
+    /// Print a shell completion script to stdout.
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
 }
 
 #[derive(Parser, Debug)]
-pub struct ArgsPwsafe {
-    #[arg(help = "A pwsafe V3 database")]
-    pwsafe: OsString,
-    #[arg(short = 'd', long = "key-file")]
+pub struct MaybePwsafe {
+    #[arg(help = "A pwsafe V3 database", env = "PWSAFE_MATRIX_DB")]
+    pwsafe: Option<OsString>,
+    #[arg(short = 'd', long = "key-file", env = "PWSAFE_MATRIX_KEY_FILE", help = "Unlock with the contents of this file, followed by the password if one is given too")]
     passwd_file: Option<OsString>,
-    #[arg(long = "password")]
-    passwd: String,
+    #[arg(long = "password", env = "PWSAFE_MATRIX_PASSWORD", hide_env_values = true)]
+    passwd: Option<String>,
+    #[arg(long = "password-from-stdin", default_value_t = false, help = "Read the password from the first line of stdin, instead of the command line")]
+    password_from_stdin: bool,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Debug)]
 pub struct ArgsLogin {
-    #[arg(short = 'h', long = "homeserver")]
     homeserver: url::Url,
-    #[arg(long = "user")]
     user: String,
-    #[arg(long = "matrix-password")]
     password: Option<String>,
-    #[arg(long = "no-password-from-tty", default_value_t = false)]
     not_from_tty: bool,
 }
 
 #[derive(Parser, Debug)]
-#[group(requires_all = ["homeserver", "user"])]
 pub struct MaybeLogin {
-    #[arg(short = 'h', long = "homeserver")]
+    #[arg(short = 'h', long = "homeserver", env = "PWSAFE_MATRIX_HOMESERVER")]
     homeserver: Option<url::Url>,
-    #[arg(long = "user")]
+    #[arg(long = "user", env = "PWSAFE_MATRIX_USER")]
     user: Option<String>,
     #[arg(long = "matrix-password")]
     password: Option<String>,
@@ -130,58 +398,48 @@ pub struct MaybeLogin {
 
 #[derive(Parser, Debug)]
 pub struct ArgsCreateRoom {
-    #[arg(long = "room-alias")]
+    #[arg(long = "room-alias", help = "Register an alias for the room, either `#name:server` or only the local `name`")]
     alias: Option<String>,
-    #[arg(long = "force", default_value_t = false)]
+    #[arg(long = "force", default_value_t = false, help = "Overwrite existing pwsafe-matrix information, and join the room if the alias is already in use")]
     force: bool,
 }
 
-#[derive(Parser, Debug)]
+#[derive(Debug)]
 pub struct ArgsServer {
-    #[arg(long = "server-http-authorization")]
-    secret: String,
-    #[arg(long = "server-address")]
-    address: std::net::SocketAddr,
-    #[arg(long = "server-ready", default_value_t = false)]
+    /// The token of requests, which may be omitted only when trusting the user of a peer.
+    secret: Option<String>,
+    address: ServerAddress,
     ready: bool,
+    /// Authorize connections on the unix socket from our own user, without the token.
+    trust_uid: bool,
+    /// The largest body accepted for a diff, in bytes.
+    max_diff_size: usize,
+    /// Record field types that diffs may touch, besides the known ones.
+    allow_field_types: Vec<u8>,
+}
+
+/// Where the server listens.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServerAddress {
+    Tcp(std::net::SocketAddr),
+    /// A socket file, which only our own user may connect to.
+    Unix(PathBuf),
 }
 
 #[derive(Parser, Debug)]
-#[group(requires_all = ["address", "secret"])]
 pub struct MaybeServer {
-    #[arg(long = "server-http-authorization")]
+    #[arg(long = "server-http-authorization", env = "PWSAFE_MATRIX_SERVER_HTTP_AUTHORIZATION", hide_env_values = true)]
     secret: Option<String>,
-    #[arg(long = "server-address")]
+    #[arg(long = "server-address", env = "PWSAFE_MATRIX_SERVER_ADDRESS", conflicts_with = "unix")]
     address: Option<std::net::SocketAddr>,
+    #[arg(long = "server-unix", env = "PWSAFE_MATRIX_SERVER_UNIX", help = "Listen on a unix socket at this path, accessible only to this user, instead of TCP")]
+    unix: Option<PathBuf>,
     #[arg(long = "server-ready", default_value_t = false)]
     ready: bool,
-}
-
-impl MaybeLogin {
-    pub fn into(self) -> Option<ArgsLogin> {
-        if self.homeserver.is_some() {
-            Some(ArgsLogin {
-                homeserver: self.homeserver.unwrap(),
-                user: self.user.unwrap(),
-                password: self.password,
-                not_from_tty: self.not_from_tty,
-            })
-        } else {
-            None
-        }
-    }
-}
-
-impl MaybeServer {
-    pub fn into(self) -> Option<ArgsServer> {
-        if self.address.is_some() {
-            Some(ArgsServer {
-                secret: self.secret.unwrap(),
-                address: self.address.unwrap(),
-                ready: self.ready,
-            })
-        } else {
-            None
-        }
-    }
+    #[arg(long = "server-trust-uid", default_value_t = false, help = "Authorize requests on the unix socket from processes of this user, without the token")]
+    trust_uid: bool,
+    #[arg(long = "server-max-diff-size", env = "PWSAFE_MATRIX_SERVER_MAX_DIFF_SIZE", help = "Reject diffs with a larger body, in bytes [default: 1 MiB]")]
+    max_diff_size: Option<usize>,
+    #[arg(long = "server-allow-field-type", help = "Accept diffs touching this record field type, which is not one known to pwsafe")]
+    allow_field_types: Vec<u8>,
 }
@@ -59,25 +59,13 @@ pub async fn create_session(
 
     let passwd = match given_password {
         Some(passwd) => passwd.as_bytes().to_vec(),
-        None if enforce_tty && passterm::isatty(passterm::Stream::Stdin) => {
-            passterm::prompt_password_stdin(None, passterm::Stream::Stderr)?.into_bytes()
-        }
-        _ => {
-            if let Some(askpass) = std::env::var_os("PWSAFE_MATRIX_ASKPASS") {
-                let output = process::Command::new(askpass)
-                    .stdin(std::process::Stdio::piped())
-                    .output()
-                    .await?;
-
-                if !output.status.success() {
-                    return Err(Report::msg("Login via password failed to ask the `PWSAFE_MATRIX_ASKPASS` program"));
-                }
-
-                output.stdout
-            } else {
+        None => match ask_password(enforce_tty).await {
+            Ok(Some(passwd)) => passwd,
+            Ok(None) => {
                 return Err(Report::msg("Login via password required but no password provided"));
             }
-        }
+            Err(err) => return Err(err.wrap_err("Login via password failed")),
+        },
     };
 
     let passwd = core::str::from_utf8(&passwd)?;
@@ -99,3 +87,28 @@ pub async fn create_session(
         session,
     })
 }
+
+/// Ask for a password on the terminal, or else with the `PWSAFE_MATRIX_ASKPASS` program.
+///
+/// Returns `None` if neither is available.
+pub async fn ask_password(enforce_tty: bool) -> Result<Option<Vec<u8>>, Report> {
+    if enforce_tty && passterm::isatty(passterm::Stream::Stdin) {
+        let passwd = passterm::prompt_password_stdin(None, passterm::Stream::Stderr)?;
+        return Ok(Some(passwd.into_bytes()));
+    }
+
+    let Some(askpass) = std::env::var_os("PWSAFE_MATRIX_ASKPASS") else {
+        return Ok(None);
+    };
+
+    let output = process::Command::new(askpass)
+        .stdin(std::process::Stdio::piped())
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        return Err(Report::msg("Failed to ask the `PWSAFE_MATRIX_ASKPASS` program"));
+    }
+
+    Ok(Some(output.stdout))
+}
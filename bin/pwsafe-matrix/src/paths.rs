@@ -0,0 +1,96 @@
+//! Locations of the auxiliary files kept alongside a database.
+//!
+//! By default they are placed next to the database, named after it. If that directory is not
+//! writable, for instance a database on read-only medium, they go to `$XDG_STATE_HOME`. An
+//! explicit `--state-dir` overrides both. Note that pwsafe's own `.plk` lock file is not affected,
+//! it must be where pwsafe looks for it.
+//!
+//! In a shared state directory files are named by the database file name and a hash of its
+//! canonical path, so that two databases with the same name do not collide.
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+pub struct Paths {
+    dir: PathBuf,
+    prefix: String,
+}
+
+impl Paths {
+    const SYNC_LOCK: &'static str = "sync.lock";
+    const STATUS: &'static str = "status.json";
+
+    pub fn new(db: &Path, state_dir: Option<&Path>) -> Self {
+        let db = db.canonicalize().unwrap_or_else(|_| db.to_path_buf());
+
+        let db_dir_writable = db.parent().map_or(false, |dir| {
+            tempfile::Builder::new()
+                .prefix(".pwsafe-matrix-probe")
+                .tempfile_in(dir)
+                .is_ok()
+        });
+
+        Self::resolve(&db, state_dir, Self::xdg_state_home().as_deref(), db_dir_writable)
+    }
+
+    /// The decision, without looking at the environment or file system.
+    pub(crate) fn resolve(
+        db: &Path,
+        state_dir: Option<&Path>,
+        xdg_state_home: Option<&Path>,
+        db_dir_writable: bool,
+    ) -> Self {
+        let name = db
+            .file_name()
+            .map_or_else(|| "pwsafe".into(), |name| name.to_string_lossy().into_owned());
+
+        let shared = |dir: PathBuf| Paths {
+            dir,
+            prefix: format!("{name}-{}", Self::path_hash(db)),
+        };
+
+        match (state_dir, db.parent()) {
+            (Some(dir), _) => shared(dir.to_path_buf()),
+            (None, Some(parent)) if db_dir_writable => Paths {
+                dir: parent.to_path_buf(),
+                prefix: name,
+            },
+            (None, _) => {
+                let state = xdg_state_home
+                    .map_or_else(std::env::temp_dir, Path::to_path_buf);
+                shared(state.join("pwsafe-matrix"))
+            }
+        }
+    }
+
+    /// Create the directory holding the auxiliary files, if necessary.
+    pub fn create_dir(&self) -> std::io::Result<()> {
+        std::fs::create_dir_all(&self.dir)
+    }
+
+    /// Held by a running `sync`, so that only one process synchronizes a database.
+    pub fn sync_lock(&self) -> PathBuf {
+        self.sidecar(Self::SYNC_LOCK)
+    }
+
+    /// Written by `sync` after each successful update of the database.
+    pub fn status(&self) -> PathBuf {
+        self.sidecar(Self::STATUS)
+    }
+
+    fn sidecar(&self, name: &str) -> PathBuf {
+        self.dir.join(format!("{}.{name}", self.prefix))
+    }
+
+    fn xdg_state_home() -> Option<PathBuf> {
+        std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".local/state")))
+    }
+
+    fn path_hash(db: &Path) -> String {
+        let digest = Sha256::digest(db.as_os_str().as_bytes());
+        digest[..8].iter().map(|b| format!("{b:02x}")).collect()
+    }
+}
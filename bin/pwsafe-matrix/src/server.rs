@@ -6,14 +6,21 @@
 //! is not robust.
 //!
 //! Hence, it is absolutely necessary to use a Authorization Bearer token for **all** requests. The
-//! token is configured at launch time and should be completely random.
-use super::ArgsServer;
-use crate::communicator::Communicator;
+//! token is configured at launch time and should be completely random. On a unix socket, the user
+//! of the connecting process may be trusted instead, see `--server-trust-uid`.
+use super::{ArgsServer, ServerAddress};
+use crate::communicator::{self, Communicator, QueryResponse, StatusSnapshot};
+use crate::diff::DiffSerial;
+use crate::pwsafe::{PasswordRejected, PolicySource};
 
+use std::future::Future;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::sync::Arc;
 
 use axum::{
-    extract::{State, Request},
+    body::Body,
+    extract::{Path, Query, State, Request},
     http::{header::HeaderMap, StatusCode},
     middleware::{from_fn, Next},
     routing::{get, post},
@@ -23,28 +30,65 @@ use axum::{
 };
 
 use eyre::Report;
-use serde::Serialize;
+use hyper::body::Incoming;
+use hyper_util::rt::TokioIo;
+use pwsafer::policy::Policy;
+use serde::{Deserialize, Serialize};
 use tokio::{
-    net::TcpListener,
-    sync::Notify,
+    net::{unix::uid_t, TcpListener, UnixListener},
+    sync::{watch, Notify},
+    task::JoinSet,
 };
+use tower::Service as _;
+use uuid::Uuid;
+
+/// The record field types defined by pwsafe, see `PwsafeRecordField`.
+///
+/// This excludes the end of a record, which marks no field that a diff could set.
+const KNOWN_FIELD_TYPES: RangeInclusive<u8> = 0x01..=0x20;
 
 struct AppState {
-    authentication_token: String,
+    authentication_token: Option<String>,
+    /// Connections of this user are authorized without the token, if any.
+    trusted_uid: Option<uid_t>,
+    diff_limits: DiffLimits,
     stop: Notify,
     client: Communicator,
 }
 
+/// The user of the process at the other end of a connection on the unix socket.
+#[derive(Clone, Copy)]
+struct PeerUid(uid_t);
+
+enum Listener {
+    Tcp(TcpListener),
+    /// The socket, with the path of its file to remove after the shutdown.
+    Unix(UnixListener, PathBuf),
+}
+
+/// What the diff endpoint accepts.
+pub(crate) struct DiffLimits {
+    /// The largest body, in bytes.
+    pub(crate) max_size: usize,
+    /// Field types accepted in addition to [`KNOWN_FIELD_TYPES`].
+    pub(crate) allow_field_types: Vec<u8>,
+}
+
 pub async fn serve(
     server: ArgsServer,
     client: Communicator,
 ) -> Result<(), Report> {
-    if server.secret.len() < 16 {
+    if server.secret.as_ref().is_some_and(|secret| secret.len() < 16) {
         return Err(Report::msg("You must configure a stronger authorization secret, at least 16 characters"));
     }
 
     let state = Arc::new(AppState {
         authentication_token: server.secret,
+        trusted_uid: server.trust_uid.then(|| unsafe { uapi::c::geteuid() }),
+        diff_limits: DiffLimits {
+            max_size: server.max_diff_size,
+            allow_field_types: server.allow_field_types,
+        },
         stop: Notify::new(),
         client,
     });
@@ -55,14 +99,22 @@ pub async fn serve(
     let app = Router::<Arc<AppState>>::new()
         .route("/health", get(health))
         .route("/stop", post(stop))
+        .route("/status", get(status))
         .route("/diff", post(change))
+        .route("/generate", post(generate))
+        .route("/entry", get(entry))
+        .route("/entries", get(entries))
+        .route("/entries/:uuid", get(entry_summary))
         .layer(from_fn(move |header: HeaderMap, request: Request, next: Next| {
             let auth = state_auth.clone();
             is_authorized(auth, header, request, next)
         }))
         .with_state(state);
 
-    let listener = TcpListener::bind(&server.address).await?;
+    let listener = match &server.address {
+        ServerAddress::Tcp(address) => Listener::Tcp(TcpListener::bind(address).await?),
+        ServerAddress::Unix(path) => Listener::Unix(bind_unix(path).await?, path.clone()),
+    };
 
     if server.ready {
         if let Ok(nul) = std::fs::OpenOptions::new()
@@ -85,29 +137,276 @@ pub async fn serve(
         }
     }
 
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async move {
-            state_stop.stop.notified().await;
-            tracing::debug!("Shutdown notified");
-        })
-        .await?;
+    let shutdown = async move {
+        state_stop.stop.notified().await;
+        tracing::debug!("Shutdown notified");
+    };
+
+    match listener {
+        Listener::Tcp(listener) => {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown)
+                .await?;
+        },
+        Listener::Unix(listener, path) => {
+            let served = serve_unix(listener, app, shutdown).await;
+            let _ = tokio::fs::remove_file(path).await;
+            served?;
+        },
+    }
 
     tracing::debug!("Server shutdown gracefully");
     Ok(())
 }
 
+/// Bind the unix socket, accessible only to our own user.
+///
+/// A socket file left behind by an earlier server is replaced.
+async fn bind_unix(path: &std::path::Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let _ = tokio::fs::remove_file(path).await;
+    let listener = UnixListener::bind(path)?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+/// Serve the router on a unix socket, which `axum::serve` only does for TCP.
+///
+/// The requests of each connection carry the [`PeerUid`]. As with TCP, shutting down waits for
+/// the requests in progress to be answered.
+async fn serve_unix(
+    listener: UnixListener,
+    app: Router,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Report> {
+    let (closing, closed) = watch::channel(());
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+
+    loop {
+        let stream = tokio::select! {
+            accepted = listener.accept() => accepted?.0,
+            () = &mut shutdown => break,
+        };
+
+        let peer = stream.peer_cred().ok().map(|cred| PeerUid(cred.uid()));
+        let app = app.clone();
+        let service = hyper::service::service_fn(move |mut request: hyper::Request<Incoming>| {
+            if let Some(peer) = peer {
+                request.extensions_mut().insert(peer);
+            }
+
+            app.clone().call(request)
+        });
+
+        let mut closed = closed.clone();
+        connections.spawn(async move {
+            let connection = hyper::server::conn::http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+
+            loop {
+                tokio::select! {
+                    result = connection.as_mut() => {
+                        if let Err(err) = result {
+                            tracing::debug!("Connection on the unix socket failed: {err:?}");
+                        }
+
+                        break;
+                    },
+                    _ = closed.changed() => connection.as_mut().graceful_shutdown(),
+                }
+            }
+        });
+    }
+
+    drop(listener);
+    let _ = closing.send(());
+    while connections.join_next().await.is_some() {}
+
+    Ok(())
+}
+
 async fn health() -> Json<Health> {
     Json(Health { })
 }
 
-// FIXME: define a serialized form for Diff, which does not depend upon the client knowing the
-// pepper and other internal state. We need that for the CRDT as well, so define it in `Diff`.
+/// How far the sync got, as last published by the task modifying the database.
+///
+/// Answered right away, also while the lock file of another program blocks that task.
+async fn status(state: State<Arc<AppState>>) -> Json<StatusSnapshot> {
+    tracing::info!("Status endpoint called");
+    Json(state.client.status())
+}
+
+/// Apply a diff in the encoding of [`DiffSerial`].
+///
+/// Only a diff that passes [`validate_diff`] as a whole is handed to the task modifying the
+/// database, anything else is answered with a `400` right here.
 async fn change(
     state: State<Arc<AppState>>,
-    Json(change): Json<serde_json::Value>,
-) {
+    body: Body,
+) -> Result<Json<Applied>, (StatusCode, Json<Rejected>)> {
     tracing::info!("Diff endpoint called");
-    let _ = state.client.send_diff(change).await;
+
+    let limits = &state.diff_limits;
+    let validated = match axum::body::to_bytes(body, limits.max_size).await {
+        Ok(body) => validate_diff(&body, limits),
+        Err(_) => {
+            let error = format!("The body is not readable within {} bytes", limits.max_size);
+            Err(Rejected::new(error))
+        },
+    };
+
+    // Not logged, the error might quote values of the diff.
+    let change = validated.map_err(|rejected| {
+        tracing::warn!("Rejected invalid local diff");
+        (StatusCode::BAD_REQUEST, Json(rejected))
+    })?;
+
+    match request(&state, communicator::Query::ApplyDiffValidated(change)).await {
+        Ok(QueryResponse::Diff(records)) => Ok(Json(Applied { records })),
+        Ok(_) | Err(_) => {
+            let rejected = Rejected::new("The diff was not applied");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(rejected)))
+        },
+    }
+}
+
+/// Decode a diff, and check that it touches only allowed field types.
+///
+/// The rejection names the path of the offending value, such as `edit.<uuid>.set`.
+pub(crate) fn validate_diff(body: &[u8], limits: &DiffLimits) -> Result<DiffSerial, Rejected> {
+    let mut de = serde_json::Deserializer::from_slice(body);
+
+    let diff: DiffSerial = serde_path_to_error::deserialize(&mut de).map_err(|err| Rejected {
+        path: Some(err.path().to_string()),
+        error: err.into_inner().to_string(),
+    })?;
+
+    de.end().map_err(|err| Rejected::new(err.to_string()))?;
+
+    let allowed = |ty: &u8| KNOWN_FIELD_TYPES.contains(ty) || limits.allow_field_types.contains(ty);
+    let disallowed = |ty: &u8, path: String| Rejected {
+        error: format!("Field type {ty} is unknown, and not allowed by `--server-allow-field-type`"),
+        path: Some(path),
+    };
+
+    for (uuid, edit) in &diff.edit {
+        if let Some(ty) = edit.set.keys().find(|ty| !allowed(ty)) {
+            return Err(disallowed(ty, format!("edit.{uuid}.set.{ty}")));
+        }
+
+        if let Some(ty) = edit.delete.iter().find(|ty| !allowed(ty)) {
+            return Err(disallowed(ty, format!("edit.{uuid}.delete")));
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Set a new password for a record, generated to follow a policy, and respond with it.
+///
+/// Without a policy in the request, that of the record is used.
+async fn generate(
+    state: State<Arc<AppState>>,
+    Json(generate): Json<GenerateRequest>,
+) -> Result<Json<Generated>, (StatusCode, Json<Rejected>)> {
+    tracing::info!("Generate endpoint called");
+
+    let source = match generate.policy {
+        None => PolicySource::Record,
+        Some(PolicyRequest::Named(name)) => PolicySource::Named(name),
+        Some(PolicyRequest::Inline(inline)) => PolicySource::Inline(inline.policy()),
+    };
+
+    let query = communicator::Query::GeneratePassword(generate.uuid, source);
+    match request(&state, query).await {
+        Ok(QueryResponse::Password(Ok(password))) => Ok(Json(Generated { password })),
+        Ok(QueryResponse::Password(Err(PasswordRejected::UnknownRecord))) => {
+            Err((StatusCode::NOT_FOUND, Json(Rejected::new("No such record"))))
+        },
+        Ok(QueryResponse::Password(Err(PasswordRejected::Policy(error)))) => {
+            Err((StatusCode::BAD_REQUEST, Json(Rejected::new(error))))
+        },
+        Ok(QueryResponse::Error(error)) => {
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(Rejected::new(error))))
+        },
+        Ok(_) | Err(_) => {
+            let rejected = Rejected::new("No password was generated");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, Json(rejected)))
+        },
+    }
+}
+
+/// Read back a record with all its fields, to check the effect of diffs.
+///
+/// Secrets are among them, so `X-Reveal-Secrets: true` must be requested.
+async fn entry(
+    state: State<Arc<AppState>>,
+    Query(query): Query<EntryQuery>,
+    header: HeaderMap,
+) -> Result<Json<Entry>, StatusCode> {
+    tracing::info!("Entry endpoint called");
+
+    if !reveals_secrets(&header) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    match request(&state, communicator::Query::EntryByUuid(query.uuid)).await? {
+        QueryResponse::Entry(Some(fields)) => Ok(Json(Entry { uuid: query.uuid, fields })),
+        QueryResponse::Entry(None) => Err(StatusCode::NOT_FOUND),
+        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// List all records, without any of their secrets.
+async fn entries(state: State<Arc<AppState>>) -> Result<Json<Vec<Summary>>, StatusCode> {
+    tracing::info!("Entries endpoint called");
+
+    let records = entry_list(&state).await?;
+    Ok(Json(records.iter().filter_map(|fields| Summary::new(fields, false)).collect()))
+}
+
+/// A single record as listed, with its password only if `X-Reveal-Secrets: true` is requested.
+async fn entry_summary(
+    state: State<Arc<AppState>>,
+    Path(uuid): Path<Uuid>,
+    header: HeaderMap,
+) -> Result<Json<Summary>, StatusCode> {
+    tracing::info!("Entries endpoint called for a record");
+
+    let reveal = reveals_secrets(&header);
+
+    match request(&state, communicator::Query::EntryByUuid(uuid)).await? {
+        QueryResponse::Entry(Some(fields)) => {
+            Summary::new(&fields, reveal).map(Json).ok_or(StatusCode::NOT_FOUND)
+        },
+        QueryResponse::Entry(None) => Err(StatusCode::NOT_FOUND),
+        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+/// Whether the request asks for secrets, with `X-Reveal-Secrets: true`.
+pub(crate) fn reveals_secrets(header: &HeaderMap) -> bool {
+    header.get("X-Reveal-Secrets").is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"true"))
+}
+
+async fn entry_list(state: &AppState) -> Result<Vec<crate::pwsafe::Fields>, StatusCode> {
+    match request(state, communicator::Query::EntryList).await? {
+        QueryResponse::EntryList(records) => Ok(records),
+        _ => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn request(state: &AppState, query: communicator::Query)
+    -> Result<QueryResponse, StatusCode>
+{
+    state.client.request(query).await.map_err(|err| {
+        tracing::warn!("Request not answered: {err:?}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
 }
 
 async fn stop(state: State<Arc<AppState>>) {
@@ -119,6 +418,135 @@ async fn stop(state: State<Arc<AppState>>) {
 struct Health {
 }
 
+#[derive(Serialize)]
+struct Applied {
+    /// The number of records the diff edits or deletes.
+    records: usize,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct Rejected {
+    error: String,
+    /// Where in the request the error is, if it concerns a single value.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Generated {
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct GenerateRequest {
+    uuid: Uuid,
+    #[serde(default)]
+    policy: Option<PolicyRequest>,
+}
+
+/// A named policy of the database, or one given in the request.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PolicyRequest {
+    Named(String),
+    Inline(InlinePolicy),
+}
+
+/// The character classes with their minimum, each used only if given.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InlinePolicy {
+    length: u16,
+    lowercase: Option<u16>,
+    uppercase: Option<u16>,
+    digits: Option<u16>,
+    symbols: Option<u16>,
+    /// The symbols to use instead of the default ones, if not empty.
+    #[serde(default)]
+    symbol_set: String,
+    #[serde(default)]
+    easy_vision: bool,
+    #[serde(default)]
+    hex_digits: bool,
+}
+
+#[derive(Deserialize)]
+struct EntryQuery {
+    uuid: Uuid,
+}
+
+/// The fields by type, encoded like the `set` of a diff.
+#[derive(Serialize)]
+struct Entry {
+    uuid: Uuid,
+    fields: crate::pwsafe::Fields,
+}
+
+/// The commonly displayed fields of a record.
+#[derive(Serialize)]
+struct Summary {
+    uuid: Uuid,
+    title: Option<String>,
+    username: Option<String>,
+    group: Option<String>,
+    /// The last modification, in seconds since the epoch.
+    mtime: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl Rejected {
+    fn new(error: impl Into<String>) -> Self {
+        Rejected { error: error.into(), path: None }
+    }
+}
+
+impl InlinePolicy {
+    fn policy(self) -> Policy {
+        let flag = |bit, used: bool| if used { bit } else { 0 };
+
+        let flags = flag(Policy::USE_LOWERCASE, self.lowercase.is_some())
+            | flag(Policy::USE_UPPERCASE, self.uppercase.is_some())
+            | flag(Policy::USE_DIGITS, self.digits.is_some())
+            | flag(Policy::USE_SYMBOLS, self.symbols.is_some())
+            | flag(Policy::USE_EASY_VISION, self.easy_vision)
+            | flag(Policy::USE_HEX_DIGITS, self.hex_digits);
+
+        Policy {
+            flags,
+            length: self.length,
+            min_lowercase: self.lowercase.unwrap_or(0),
+            min_uppercase: self.uppercase.unwrap_or(0),
+            min_digits: self.digits.unwrap_or(0),
+            min_symbols: self.symbols.unwrap_or(0),
+            symbols: self.symbol_set,
+        }
+    }
+}
+
+impl Summary {
+    /// Records without a UUID can not be addressed and are skipped.
+    fn new(fields: &crate::pwsafe::Fields, reveal: bool) -> Option<Self> {
+        let uuid = Uuid::from_slice(fields.get(&0x01)?).ok()?;
+        let text = |ty: u8| fields.get(&ty).map(|data| String::from_utf8_lossy(data).into_owned());
+
+        let mtime = fields.get(&0x0c).and_then(|data| match data.len() {
+            4 => Some(u32::from_le_bytes(data.as_slice().try_into().unwrap()).into()),
+            8 => Some(u64::from_le_bytes(data.as_slice().try_into().unwrap())),
+            _ => None,
+        });
+
+        Some(Summary {
+            uuid,
+            title: text(0x03),
+            username: text(0x04),
+            group: text(0x02),
+            mtime,
+            password: if reveal { text(0x06) } else { None },
+        })
+    }
+}
+
 async fn is_authorized(
     state: Arc<AppState>,
     header: HeaderMap,
@@ -130,7 +558,14 @@ async fn is_authorized(
     let authorization = header.get("Authorization")
         .map(|v| v.as_bytes());
 
-    if authorization == Some(state.authentication_token.as_bytes()) {
+    let token = state.authentication_token
+        .as_ref()
+        .is_some_and(|token| authorization == Some(token.as_bytes()));
+
+    let peer = request.extensions().get::<PeerUid>();
+    let trusted = state.trusted_uid.is_some_and(|uid| peer.is_some_and(|peer| peer.0 == uid));
+
+    if token || trusted {
         Ok(next.run(request).await)
     } else {
         Err(StatusCode::UNAUTHORIZED)
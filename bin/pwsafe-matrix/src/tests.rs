@@ -0,0 +1,487 @@
+use clap::Parser;
+
+use crate::config::{Config, Resolver};
+use crate::{Args, Cli};
+
+const CONFIG: &str = r#"
+[profile.default]
+pwsafe = "/from/config.psafe3"
+homeserver = "https://config.example.org"
+user = "config-user"
+
+[profile.other]
+pwsafe = "/from/other.psafe3"
+key-file = "/from/other.key"
+homserver = "https://typo.example.org"
+"#;
+
+#[test]
+fn config_precedence() {
+    let cli = Cli::try_parse_from([
+        "pwsafe-matrix",
+        "sync",
+        "--user",
+        "cli-user",
+    ])
+    .unwrap();
+
+    let Some(Args::Sync { pwsafe, login, .. }) = cli.command else {
+        panic!("Parsed the wrong subcommand");
+    };
+
+    let config = Config::from_str(CONFIG).unwrap();
+    let resolver = Resolver::with_profile(config, None).unwrap();
+
+    // No password at all, neither flag nor the key file in the profile.
+    assert!(resolver.pwsafe(pwsafe).is_err());
+
+    let login = resolver.login(login).unwrap().unwrap();
+    assert_eq!(login.user, "cli-user");
+    assert_eq!(login.homeserver.as_str(), "https://config.example.org/");
+}
+
+#[test]
+fn config_profile() {
+    let cli = Cli::try_parse_from([
+        "pwsafe-matrix",
+        "invite",
+        "--file",
+        "-",
+    ])
+    .unwrap();
+
+    let Some(Args::Invite { pwsafe, .. }) = cli.command else {
+        panic!("Parsed the wrong subcommand");
+    };
+
+    let config = Config::from_str(CONFIG).unwrap();
+    let resolver = Resolver::with_profile(config, Some("other")).unwrap();
+
+    let pwsafe = resolver.pwsafe(pwsafe).unwrap();
+    assert_eq!(pwsafe.pwsafe, "/from/other.psafe3");
+    assert_eq!(pwsafe.passwd_file.as_deref(), Some("/from/other.key".as_ref()));
+    assert_eq!(pwsafe.passwd, None);
+
+    let config = Config::from_str(CONFIG).unwrap();
+    assert!(Resolver::with_profile(config, Some("missing")).is_err());
+}
+
+#[test]
+fn config_password_sources() {
+    let cli = Cli::try_parse_from([
+        "pwsafe-matrix",
+        "invite",
+        "--file",
+        "-",
+        "--password",
+        "password",
+        "--password-from-stdin",
+    ])
+    .unwrap();
+
+    let Some(Args::Invite { pwsafe, .. }) = cli.command else {
+        panic!("Parsed the wrong subcommand");
+    };
+
+    // Only one source of the password, before anything is read from stdin.
+    let config = Config::from_str(CONFIG).unwrap();
+    let resolver = Resolver::with_profile(config, Some("other")).unwrap();
+    assert!(resolver.pwsafe(pwsafe).is_err());
+
+    let cli = Cli::try_parse_from([
+        "pwsafe-matrix",
+        "invite",
+        "--file",
+        "-",
+        "--password",
+        "password",
+    ])
+    .unwrap();
+
+    let Some(Args::Invite { pwsafe, .. }) = cli.command else {
+        panic!("Parsed the wrong subcommand");
+    };
+
+    // Both the key file of the profile and the password.
+    let config = Config::from_str(CONFIG).unwrap();
+    let resolver = Resolver::with_profile(config, Some("other")).unwrap();
+    let pwsafe = resolver.pwsafe(pwsafe).unwrap();
+    assert_eq!(pwsafe.passwd_file.as_deref(), Some("/from/other.key".as_ref()));
+    assert_eq!(pwsafe.passwd.as_deref(), Some("password"));
+}
+
+/// The server arguments of `sync`.
+fn sync_server(args: &[&str]) -> crate::MaybeServer {
+    let cli = Cli::try_parse_from(["pwsafe-matrix", "sync"].iter().chain(args)).unwrap();
+
+    let Some(Args::Sync { server, .. }) = cli.command else {
+        panic!("Parsed the wrong subcommand");
+    };
+
+    server
+}
+
+#[test]
+fn config_server_diff_limits() {
+    let server = ["--server-address", "127.0.0.1:8080", "--server-http-authorization", "secret"];
+    let profile = r#"
+[profile.default]
+server-max-diff-size = 4096
+server-allow-field-types = [224, 225]
+"#;
+
+    let resolver = Resolver::with_profile(Config::default(), None).unwrap();
+    let args = resolver.server(sync_server(&server)).unwrap().unwrap();
+    assert_eq!(args.max_diff_size, Resolver::DEFAULT_MAX_DIFF_SIZE);
+    assert!(args.allow_field_types.is_empty());
+
+    let resolver = Resolver::with_profile(Config::from_str(profile).unwrap(), None).unwrap();
+    let args = resolver.server(sync_server(&server)).unwrap().unwrap();
+    assert_eq!(args.max_diff_size, 4096);
+    assert_eq!(args.allow_field_types, [224, 225]);
+
+    let flags = ["--server-max-diff-size", "512", "--server-allow-field-type", "240"];
+    let args = resolver.server(sync_server(&[&server[..], &flags].concat())).unwrap().unwrap();
+    assert_eq!(args.max_diff_size, 512);
+    assert_eq!(args.allow_field_types, [240]);
+}
+
+#[test]
+fn config_server_unix() {
+    use crate::ServerAddress;
+    use std::path::PathBuf;
+
+    let profile = r#"
+[profile.default]
+server-address = "127.0.0.1:8080"
+"#;
+
+    let resolver = Resolver::with_profile(Config::from_str(profile).unwrap(), None).unwrap();
+    let socket = ServerAddress::Unix(PathBuf::from("/run/sync.sock"));
+
+    // The socket of the arguments replaces the address of the profile.
+    let unix = ["--server-unix", "/run/sync.sock", "--server-trust-uid"];
+    let args = resolver.server(sync_server(&unix)).unwrap().unwrap();
+    assert_eq!(args.address, socket);
+    assert_eq!(args.secret, None);
+    assert!(args.trust_uid);
+
+    // Without trusting the user, the token is still required.
+    assert!(resolver.server(sync_server(&["--server-unix", "/run/sync.sock"])).is_err());
+
+    let tcp = ["--server-http-authorization", "secret", "--server-trust-uid"];
+    assert!(resolver.server(sync_server(&tcp)).is_err());
+
+    let both = ["--server-address", "127.0.0.1:8080", "--server-unix", "/run/sync.sock"];
+    assert!(Cli::try_parse_from(["pwsafe-matrix", "sync"].iter().chain(&both)).is_err());
+
+    let profile = r#"
+[profile.default]
+server-address = "127.0.0.1:8080"
+server-unix = "/run/sync.sock"
+server-http-authorization = "secret"
+"#;
+
+    let resolver = Resolver::with_profile(Config::from_str(profile).unwrap(), None).unwrap();
+    assert!(resolver.server(sync_server(&[])).is_err());
+}
+
+/// Secrets are only served when asked for explicitly.
+#[test]
+fn server_reveals_secrets() {
+    use crate::server::reveals_secrets;
+    use axum::http::{HeaderMap, HeaderValue};
+
+    let mut header = HeaderMap::new();
+    assert!(!reveals_secrets(&header));
+
+    header.insert("X-Reveal-Secrets", HeaderValue::from_static("false"));
+    assert!(!reveals_secrets(&header));
+
+    header.insert("X-Reveal-Secrets", HeaderValue::from_static("TRUE"));
+    assert!(reveals_secrets(&header));
+}
+
+/// Diffs are rejected by the server as a whole, naming where the problem is.
+#[test]
+fn server_validates_diffs() {
+    use crate::server::{validate_diff, DiffLimits};
+
+    let limits = DiffLimits { max_size: 1 << 20, allow_field_types: vec![0xe0] };
+    let entry = "0b7e2a51-8b6f-4c2e-9b59-4d7f5e3c2a10";
+    let validate = |diff: serde_json::Value| {
+        let body = serde_json::to_vec(&diff).unwrap();
+        validate_diff(&body, &limits).map_err(|err| serde_json::to_value(err).unwrap())
+    };
+
+    let diff = validate(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": "dGl0bGU=", "224": "" }, "delete": [5] } },
+    })).unwrap();
+    assert_eq!(diff.edit.len(), 1);
+
+    let wrong_type = validate(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": ["dGl0bGU="], "delete": [] } },
+    })).unwrap_err();
+    assert_eq!(wrong_type["path"], format!("edit.{entry}.set"));
+
+    let unknown_key = validate(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": {}, "delete": [], "move": "group" } },
+    })).unwrap_err();
+    assert_eq!(unknown_key["path"], format!("edit.{entry}.move"));
+
+    let end_of_record = validate(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "255": "" }, "delete": [] } },
+    })).unwrap_err();
+    assert_eq!(end_of_record["path"], format!("edit.{entry}.set.255"));
+
+    let not_allowed = validate(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": {}, "delete": [0xe1] } },
+    })).unwrap_err();
+    assert_eq!(not_allowed["path"], format!("edit.{entry}.delete"));
+
+    let trailing = validate_diff(br#"{ "delete": [], "edit": {} } {}"#, &limits).unwrap_err();
+    assert!(serde_json::to_value(trailing).unwrap().get("path").is_none());
+}
+
+#[test]
+fn config_unknown_key() {
+    let config = Config::from_str(CONFIG).unwrap();
+    assert_eq!(config.unknown_keys(), ["profile.other.homserver"]);
+}
+
+#[test]
+fn paths_state_dir_no_collision() {
+    use crate::paths::Paths;
+    use std::path::Path;
+
+    let state = Path::new("/state");
+    let a = Paths::resolve(Path::new("/home/a/passwords.psafe3"), Some(state), None, true);
+    let b = Paths::resolve(Path::new("/home/b/passwords.psafe3"), Some(state), None, true);
+
+    assert_ne!(a.sync_lock(), b.sync_lock());
+    assert_ne!(a.status(), b.status());
+    assert!(a.sync_lock().starts_with(state));
+    assert!(a.sync_lock().file_name().unwrap().to_str().unwrap().starts_with("passwords.psafe3-"));
+}
+
+#[test]
+fn paths_read_only_fallback() {
+    use crate::paths::Paths;
+    use std::path::Path;
+
+    let db = Path::new("/media/cdrom/passwords.psafe3");
+    let xdg = Path::new("/home/user/.local/state");
+
+    let beside = Paths::resolve(db, None, Some(xdg), true);
+    assert_eq!(beside.sync_lock(), Path::new("/media/cdrom/passwords.psafe3.sync.lock"));
+
+    let fallback = Paths::resolve(db, None, Some(xdg), false);
+    assert!(fallback.sync_lock().starts_with(xdg.join("pwsafe-matrix")), "{:?}", fallback.sync_lock());
+    assert!(fallback.status().starts_with(xdg.join("pwsafe-matrix")), "{:?}", fallback.status());
+
+    // An explicit state directory wins even over a writable database directory.
+    let explicit = Paths::resolve(db, Some(Path::new("/state")), Some(xdg), true);
+    assert!(explicit.status().starts_with("/state"));
+}
+
+/// Invites are bound to the password of the database, expire, and are unsigned only by choice.
+#[test]
+fn invite_signature() {
+    use crate::cmd::invite::Invite;
+    use matrix_sdk::ruma::{device_id, room_id, user_id};
+    use pwsafer::PwsafeKey;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    let key = PwsafeKey::new(b"password");
+    let other = PwsafeKey::new(b"other");
+
+    let read = |invite: &Invite, key: &PwsafeKey, allow_unsigned: bool| {
+        let mut file = vec![];
+        invite.write(&mut file).unwrap();
+        Invite::read(&mut file.as_slice(), key, allow_unsigned)
+    };
+
+    let mut invite: Invite = serde_json::from_value(serde_json::json!({
+        "room": room_id!("!room:example.org"),
+        "user": user_id!("@alice:example.org"),
+        "device": device_id!("ALICE"),
+    }))
+    .unwrap();
+
+    // As written before invites were signed.
+    assert_eq!(invite.version, 1);
+    assert!(read(&invite, &key, false).is_err());
+    assert!(read(&invite, &key, true).is_ok());
+
+    invite.version = 2;
+    invite.invitee = Some(user_id!("@bob:example.org").to_owned());
+    invite.expires = Some(1_000);
+    invite.sign(&key).unwrap();
+
+    let past = UNIX_EPOCH + Duration::from_secs(999);
+    assert!(invite.verify(&key, false, past).is_ok());
+    assert!(invite.verify(&other, false, past).is_err());
+    // Signed invites are never mistaken for unsigned ones.
+    assert!(invite.verify(&other, true, past).is_err());
+    assert!(invite.verify(&key, false, SystemTime::now()).is_err());
+    assert!(read(&invite, &key, false).is_err());
+
+    let mut tampered = invite.clone();
+    tampered.invitee = Some(user_id!("@mallory:example.org").to_owned());
+    assert!(tampered.verify(&key, false, past).is_err());
+
+    invite.expires = None;
+    invite.sign(&key).unwrap();
+    assert!(read(&invite, &key, false).is_ok());
+    assert!(read(&invite, &other, false).is_err());
+}
+
+/// Entries of a KeePass export, with their groups, history and escaped values.
+#[test]
+fn keepass_parse() {
+    use pwsafer::PwsafeRecordField;
+
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/keepass.xml");
+    let xml = std::fs::read_to_string(path).unwrap();
+    let entries = crate::keepass::sync_server(&xml).unwrap();
+
+    // Without the recycle bin, and the top group of the database itself.
+    let groups: Vec<_> = entries.iter().map(|entry| entry.group.as_str()).collect();
+    assert_eq!(groups, ["", "Work", "Work.example\\.com"]);
+
+    let mail = &entries[0];
+    assert_eq!(mail.uuid, Some(uuid::Uuid::from_bytes([1; 16])));
+    assert_eq!(mail.title, "Mail & Calendar");
+    assert_eq!(mail.password, "<p>äß\"'");
+    assert_eq!(mail.url, "https://mail.example.com/?a=1&b=2");
+    assert_eq!(mail.notes, "First line\nsecond line\nRecovery: codes in the safe");
+    assert_eq!(mail.created, Some(1680674828));
+
+    // The same password in two versions is one change, at the time it was first saved.
+    let history = PwsafeRecordField::PasswordHistory("103015fefe2a50005first".into());
+    assert!(mail.fields(true).contains(&history));
+    assert!(!mail.fields(false).contains(&history));
+
+    // Times of KDBX 4 are seconds since the year one, in base64.
+    assert_eq!(entries[1].created, Some(1680674828));
+    assert_eq!(entries[1].password, "<vpn&secret>");
+    assert_eq!(entries[2].uuid, None);
+
+    // The XML inside of a database file, not an export.
+    let protected = r#"Protected="True">aHVudGVyMg=="#;
+    let encrypted = xml.replace(r#"ProtectInMemory="True">hunter2"#, protected);
+    assert!(crate::keepass::sync_server(&encrypted).is_err());
+}
+
+/// Importing adds records for new entries, and finds the existing ones again.
+#[test]
+fn import_keepass() {
+    use crate::cmd::import::{import, Imported, OnDuplicate};
+    use crate::pwsafe::PwsafeDb;
+
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/keepass.xml");
+    let entries = crate::keepass::sync_server(&std::fs::read_to_string(path).unwrap()).unwrap();
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+    writer.write_record(&[
+        (0x01, &[7; 16]),
+        (0x02, b"Work"),
+        (0x03, b"VPN"),
+        (0x04, b"alice"),
+        (0x06, b"old"),
+    ]).unwrap();
+    writer.finish().unwrap();
+
+    let args = || crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    };
+
+    let mut db = PwsafeDb::open(&args()).unwrap();
+    let imported = import(&mut db, &entries, OnDuplicate::Skip, true).unwrap();
+    assert_eq!(imported, Imported { added: 2, updated: 0, skipped: 1 });
+
+    let mut db = PwsafeDb::open(&args()).unwrap();
+    let records = db.records().unwrap();
+    assert_eq!(records.len(), 3);
+
+    let mail = db.entry(uuid::Uuid::from_bytes([1; 16])).unwrap().unwrap();
+    assert_eq!(mail[&0x03], "Mail & Calendar".as_bytes());
+    assert!(!mail.contains_key(&0x02));
+
+    let vpn = db.entry(uuid::Uuid::from_bytes([7; 16])).unwrap().unwrap();
+    assert_eq!(vpn[&0x06], b"old");
+
+    // Without a UUID in the export, the record gets a fresh one.
+    let router = records.iter().find(|record| record[&0x03] == b"Router").unwrap();
+    assert_eq!(router[&0x02], b"Work.example\\.com");
+    assert_eq!(router[&0x01].len(), 16);
+
+    let imported = import(&mut db, &entries, OnDuplicate::Update, true).unwrap();
+    assert_eq!(imported, Imported { added: 0, updated: 3, skipped: 0 });
+
+    let mut db = PwsafeDb::open(&args()).unwrap();
+    assert_eq!(db.records().unwrap().len(), 3);
+    let vpn = db.entry(uuid::Uuid::from_bytes([7; 16])).unwrap().unwrap();
+    assert_eq!(vpn[&0x06], b"<vpn&secret>");
+}
+
+/// The status follows the local diffs, and is read without waiting for the database task.
+#[test]
+fn status_counts_local_diffs() {
+    use crate::communicator::{Station, StatusSnapshot};
+    use crate::pwsafe::PwsafeDb;
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+    writer.write_record(&[(0x01, &[1; 16]), (0x03, b"title")]).unwrap();
+    writer.finish().unwrap();
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    let (comm, mut station) = Station::new();
+    assert_eq!(comm.status(), StatusSnapshot::default());
+
+    let mut status = StatusSnapshot::default();
+    status.refresh(&db);
+    assert_eq!((status.pending_diffs, status.unpublished_diffs), (0, 0));
+    assert!(!db.lock_exists());
+
+    let uuid = uuid::Uuid::from_bytes([1; 16]);
+    let diff = db.diff(serde_json::json!({
+        "delete": [],
+        "edit": { uuid.to_string(): { "set": { "3": "ZWRpdGVk" }, "delete": [] } },
+    })).unwrap();
+
+    db.with_lock(|mut lock| {
+        lock.apply(&diff)?;
+        lock.rewrite()
+    }).unwrap();
+
+    status.refresh(&db);
+    assert_eq!((status.pending_diffs, status.unpublished_diffs), (1, 1));
+
+    // Published diffs are pending until they are received back from the room.
+    db.mark_published();
+    status.refresh(&db);
+    assert_eq!((status.pending_diffs, status.unpublished_diffs), (1, 0));
+
+    station.publish_status(status.clone());
+    assert_eq!(comm.status(), status);
+    assert!(!db.lock_exists());
+}
@@ -0,0 +1,76 @@
+//! Adopt sockets passed by systemd socket activation, see `sd_listen_fds(3)`.
+use std::ffi::OsString;
+use std::os::fd::{FromRawFd as _, RawFd};
+use std::os::unix::net::UnixListener;
+
+/// The first file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// The `FileDescriptorName=` of a passed socket to serve as the control socket.
+pub const CONTROL: &str = "control";
+
+/// Take the listening sockets passed to this process, if any, with their names.
+///
+/// The environment variables are removed so that child processes do not inherit them.
+pub fn listeners() -> std::io::Result<Option<Vec<(String, UnixListener)>>> {
+    let count = listen_fds(
+        std::env::var_os("LISTEN_PID"),
+        std::env::var_os("LISTEN_FDS"),
+        std::process::id(),
+    );
+    let names = std::env::var_os("LISTEN_FDNAMES");
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let Some(count) = count else {
+        return Ok(None);
+    };
+
+    // Safety: systemd passes us ownership of these file descriptors, and we only take them once
+    // as the environment has been cleared above.
+    let listeners = unsafe { adopt(SD_LISTEN_FDS_START, count) }?;
+    Ok(Some(listen_fd_names(names, count).into_iter().zip(listeners).collect()))
+}
+
+/// The number of passed file descriptors, if they were meant for this process.
+pub(crate) fn listen_fds(pid: Option<OsString>, fds: Option<OsString>, own: u32) -> Option<RawFd> {
+    let pid: u32 = pid?.to_str()?.parse().ok()?;
+
+    if pid != own {
+        return None;
+    }
+
+    let fds: RawFd = fds?.to_str()?.parse().ok()?;
+    (fds > 0).then_some(fds)
+}
+
+/// The names of the passed file descriptors, `unknown` where systemd did not give one.
+pub(crate) fn listen_fd_names(names: Option<OsString>, count: RawFd) -> Vec<String> {
+    let names = names.map(|names| names.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut names = names.split(':');
+
+    (0..count)
+        .map(|_| match names.next() {
+            Some(name) if !name.is_empty() => name.to_owned(),
+            _ => "unknown".to_owned(),
+        })
+        .collect()
+}
+
+/// Wrap consecutive file descriptors as listeners.
+///
+/// # Safety
+///
+/// The file descriptors must be open, listening sockets that are owned by the caller.
+pub(crate) unsafe fn adopt(start: RawFd, count: RawFd) -> std::io::Result<Vec<UnixListener>> {
+    (start..start + count)
+        .map(|fd| {
+            let listener = UnixListener::from_raw_fd(fd);
+            // Required to hand the socket to tokio.
+            listener.set_nonblocking(true)?;
+            Ok(listener)
+        })
+        .collect()
+}
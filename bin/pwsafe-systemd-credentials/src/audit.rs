@@ -0,0 +1,197 @@
+//! A record of every credential request, and what we answered, but never the secret itself.
+use std::collections::VecDeque;
+use std::io::Write as _;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::net::unix::{pid_t, uid_t};
+
+/// Where audit records are written to.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    /// Append one line of JSON per request to this file.
+    File(PathBuf),
+    /// Structured fields in the journal, see `systemd.journal-fields(7)`.
+    Journald,
+}
+
+/// The process that connected, as far as the kernel told us.
+#[derive(Clone, Copy, Debug)]
+pub struct Peer {
+    pub pid: Option<pid_t>,
+    pub uid: uid_t,
+}
+
+pub struct Event<'a> {
+    pub service: &'a str,
+    pub credential: &'a str,
+    /// How the credential selects its entry, `uuid` or `title`, if it is configured at all.
+    pub selector: Option<&'static str>,
+    /// The reason for refusing the request, or `None` if the credential was served.
+    pub refusal: Option<String>,
+    pub peer: Option<Peer>,
+}
+
+/// The most recent records, for the control socket, and the sink given on the command line.
+#[derive(Default)]
+pub struct Log {
+    recent: Mutex<VecDeque<serde_json::Value>>,
+    sink: Option<Sink>,
+}
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// How many records [`Log`] keeps in memory.
+const RECENT: usize = 256;
+
+impl Log {
+    pub fn new(sink: Option<Sink>) -> Self {
+        Log {
+            recent: Mutex::default(),
+            sink,
+        }
+    }
+
+    /// Keep the event in memory, then write it to our sink and the `configured` one.
+    pub async fn record(
+        &self,
+        event: &Event<'_>,
+        configured: Option<&Sink>,
+    ) -> std::io::Result<()> {
+        {
+            let mut recent = self.recent.lock().unwrap();
+
+            if recent.len() == RECENT {
+                recent.pop_front();
+            }
+
+            recent.push_back(event.to_json());
+        }
+
+        for sink in self.sink.iter().chain(configured) {
+            sink.record(event).await?;
+        }
+
+        Ok(())
+    }
+
+    /// The records kept in memory, oldest first.
+    pub fn recent(&self) -> Vec<serde_json::Value> {
+        self.recent.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Sink {
+    /// Write one event, durably before returning.
+    pub async fn record(&self, event: &Event<'_>) -> std::io::Result<()> {
+        match self {
+            Sink::File(path) => {
+                let mut line = event.to_json().to_string();
+                line.push('\n');
+
+                // Syncing may take long, the peers are answered on a single thread meanwhile.
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || append(&path, line.as_bytes()))
+                    .await
+                    .map_err(std::io::Error::other)?
+            }
+            Sink::Journald => {
+                let socket = tokio::net::UnixDatagram::unbound()?;
+                socket.send_to(&event.to_journal(), JOURNALD_SOCKET).await?;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn append(path: &Path, line: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    // One write, so that concurrent records do not interleave.
+    file.write_all(line)?;
+    file.sync_data()
+}
+
+impl Event<'_> {
+    fn result(&self) -> &'static str {
+        match self.refusal {
+            None => "served",
+            Some(_) => "refused",
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let timestamp_ms = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+
+        json!({
+            "timestamp_ms": timestamp_ms,
+            "service": self.service,
+            "credential": self.credential,
+            "selector": self.selector,
+            "result": self.result(),
+            "reason": self.refusal,
+            "peer_pid": self.peer.and_then(|peer| peer.pid),
+            "peer_uid": self.peer.map(|peer| peer.uid),
+        })
+    }
+
+    /// The native journal protocol, where values may contain newlines in a length-prefixed form.
+    fn to_journal(&self) -> Vec<u8> {
+        fn field(message: &mut Vec<u8>, key: &str, value: &str) {
+            message.extend_from_slice(key.as_bytes());
+
+            if value.contains('\n') {
+                message.push(b'\n');
+                message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                message.push(b'=');
+            }
+
+            message.extend_from_slice(value.as_bytes());
+            message.push(b'\n');
+        }
+
+        let mut message = vec![];
+        let summary = format!(
+            "Credential {:?} {} to {}",
+            self.credential,
+            self.result(),
+            self.service
+        );
+
+        field(&mut message, "MESSAGE", &summary);
+        field(
+            &mut message,
+            "SYSLOG_IDENTIFIER",
+            "pwsafe-systemd-credentials",
+        );
+        field(&mut message, "PWSAFE_SERVICE", self.service);
+        field(&mut message, "PWSAFE_CREDENTIAL", self.credential);
+        field(&mut message, "PWSAFE_RESULT", self.result());
+
+        if let Some(selector) = self.selector {
+            field(&mut message, "PWSAFE_SELECTOR", selector);
+        }
+
+        if let Some(reason) = &self.refusal {
+            field(&mut message, "PWSAFE_REASON", reason);
+        }
+
+        if let Some(peer) = self.peer {
+            if let Some(pid) = peer.pid {
+                field(&mut message, "PWSAFE_PEER_PID", &pid.to_string());
+            }
+
+            field(&mut message, "PWSAFE_PEER_UID", &peer.uid.to_string());
+        }
+
+        message
+    }
+}
@@ -1,19 +1,265 @@
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::secret::Secret;
 
 #[derive(Deserialize)]
 pub struct Configuration {
-    pub credentials: HashMap<String, CredentialSource>,
+    /// The credentials of the database given on the command line.
+    #[serde(default, deserialize_with = "unique_credentials")]
+    pub credentials: HashMap<String, Credential>,
+    /// Further databases, each with their own passphrase. Opened at startup, a reload that adds,
+    /// removes or moves one is refused.
+    #[serde(default)]
+    pub stores: Vec<Store>,
+    /// The delay after the first wrong passphrase, doubled for each further one.
     #[serde(default = "Configuration::default_retry")]
     pub password_retry: f32,
+    /// The longest delay between asking for the passphrase.
+    #[serde(default = "Configuration::default_retry_max")]
+    pub password_retry_max: f32,
+    /// Stop asking after this many wrong passphrases, until rearmed by `SIGHUP` or the control
+    /// socket's `UNLOCK`.
+    #[serde(default = "Configuration::default_unlock_attempts")]
+    pub max_unlock_attempts: u32,
     /// When to lock the database after it has been opened, removing any in-memory data.
     #[serde(default = "Configuration::default_lock")]
     pub password_lock: f32,
+    /// Whether `password_lock` counts from the unlock, or from the last credential served.
+    #[serde(default)]
+    pub password_lock_mode: LockMode,
+    /// Lock all databases before the system sleeps, see `suspend`.
+    #[serde(default = "Configuration::default_lock_on_suspend")]
+    pub lock_on_suspend: bool,
+    /// How often to check the database file for changes, in seconds.
+    #[serde(default = "Configuration::default_poll")]
+    pub database_poll: f32,
+    /// A credential served to any unit while the databases are unlocked, and refused while they
+    /// are locked, without asking for the passphrase. As `store/name` only for that store.
+    ///
+    /// `null` to serve no such credential.
+    #[serde(default = "Configuration::default_unlocked_credential")]
+    pub unlocked_credential: Option<String>,
+    /// Refuse credentials without `allowed_units` to all units.
+    #[serde(default)]
+    pub default_deny: bool,
+    /// Record each request and its result.
+    #[serde(default)]
+    pub audit: Option<crate::audit::Sink>,
+    /// How often each unit may request credentials, refused beyond that.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    /// How long answering a connection may take once the database is unlocked, in seconds.
+    #[serde(default = "Configuration::default_connection_timeout")]
+    pub connection_timeout: f32,
+    /// How many connections are answered at once, read only at startup.
+    #[serde(default = "Configuration::default_max_connections")]
+    pub max_connections: usize,
+    /// The program sealing credentials that are to be encrypted.
+    #[serde(default = "Configuration::default_systemd_creds")]
+    pub systemd_creds: PathBuf,
+    /// The largest credential we serve, in bytes. Larger ones are refused, as systemd would.
+    #[serde(default = "Configuration::default_max_credential_size")]
+    pub max_credential_size: usize,
+    /// How long writing a credential to the peer may take, in seconds.
+    #[serde(default = "Configuration::default_write_timeout")]
+    pub write_timeout: f32,
+    /// How long to keep a refused connection open for credentials that `hold`, in seconds.
+    #[serde(default = "Configuration::default_failure_hold")]
+    pub failure_hold: f32,
+}
+
+/// A database and the credentials it provides.
+///
+/// Credential names are unique across all stores, and can also be requested as
+/// `store/credential`.
+#[derive(Deserialize)]
+pub struct Store {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(deserialize_with = "unique_credentials")]
+    pub credentials: HashMap<String, Credential>,
 }
 
 #[derive(Deserialize)]
+pub struct Credential {
+    #[serde(flatten)]
+    pub source: CredentialSource,
+    /// The field of the entry that is served, unless a `format` is given.
+    #[serde(default)]
+    pub field: Output,
+    /// Combine several fields of the entry, e.g. `{username}:{password}`.
+    pub format: Option<Template>,
+    /// The units allowed to request the credential.
+    ///
+    /// If not given, any unit unless the configuration denies by default.
+    pub allowed_units: Option<Vec<UnitRule>>,
+    /// What the peer sees when the credential can not be served.
+    #[serde(default)]
+    pub on_failure: OnFailure,
+    /// How long a request waits for the database to be unlocked, in seconds.
+    #[serde(default = "Credential::default_wait_for_unlock")]
+    pub wait_for_unlock: f32,
+    /// Refuse right away while the database is locked, without asking for the passphrase.
+    #[serde(default)]
+    pub fail_fast_when_locked: bool,
+    /// Serve the credential encrypted for `LoadCredentialEncrypted=`, instead of in plain.
+    #[serde(default)]
+    pub encrypt: Option<crate::seal::Encrypt>,
+}
+
+/// How to end a connection that is refused.
+///
+/// Nothing is ever written before the credential has been found.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OnFailure {
+    /// Close the connection without any data.
+    #[default]
+    Close,
+    /// Write nothing and keep the connection, until the peer gives up.
+    Hold,
+}
+
+/// At most `requests` in any window of `seconds`, counted for each unit on its own.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub requests: usize,
+    pub seconds: f32,
+}
+
+/// When the relock timer of `password_lock` starts.
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LockMode {
+    /// From the unlock, regardless of use.
+    #[default]
+    Absolute,
+    /// From the last credential served, so a database in use stays unlocked.
+    Idle,
+}
+
+/// A unit allowed to request a credential.
+///
+/// Either a unit name, where a template such as `backup@.service` allows all its instances and
+/// `*` and `?` match like shell globs, or a template together with the allowed instances.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum UnitRule {
+    Unit(String),
+    Instances {
+        template: String,
+        instances: Vec<String>,
+    },
+}
+
+/// The entry of a credential, given by its `type` next to the other fields of the credential:
+///
+/// ```json
+/// { "type": "uuid", "uuid": "1209a0ac-5cd0-4afc-98f7-dfec6e165042" }
+/// { "type": "title", "title": "postgres", "group": "infra", "username": "app" }
+/// ```
+///
+/// The earlier form, `{ "ByUuid": "…" }` or `{ "ByTitle": { "title": "…" } }`, is still read.
+#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[serde(try_from = "SourceFields")]
 pub enum CredentialSource {
     ByUuid(uuid::Uuid),
+    /// The single entry with this title, optionally narrowed down further.
+    ///
+    /// The string `{instance}` is replaced with the instance of a templated unit.
+    ByTitle {
+        title: String,
+        group: Option<String>,
+        username: Option<String>,
+    },
+}
+
+/// The fields of either form of `CredentialSource`, before we know which one is meant.
+#[derive(Deserialize)]
+struct SourceFields {
+    #[serde(rename = "type")]
+    ty: Option<SourceType>,
+    uuid: Option<String>,
+    title: Option<String>,
+    group: Option<String>,
+    username: Option<String>,
+    #[serde(rename = "ByUuid")]
+    by_uuid: Option<String>,
+    #[serde(rename = "ByTitle")]
+    by_title: Option<TitleFields>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum SourceType {
+    Uuid,
+    Title,
+}
+
+#[derive(Deserialize)]
+struct TitleFields {
+    title: String,
+    group: Option<String>,
+    username: Option<String>,
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Field {
+    Group,
+    Title,
+    Username,
+    Notes,
+    #[default]
+    Password,
+    Url,
+}
+
+/// What is served of the entry, a single field or `urlencodedpair`.
+///
+/// The pair is `username=…&password=…`, form encoded, for services that take both in one
+/// credential.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Output {
+    Field(Field),
+    UrlEncodedPair,
+}
+
+/// A format string with `{field}` placeholders, where `{{` and `}}` are literal braces.
+#[derive(Deserialize, Debug)]
+#[serde(try_from = "String")]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(Field),
+}
+
+/// The supported formats of the configuration file.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml,
+}
+
+/// A configuration that can not be used, naming the field at fault where we can.
+#[derive(Debug)]
+pub struct Error(String);
+
+/// The entry does not have a field that is required by the credential.
+#[derive(Debug)]
+pub struct MissingField(pub Field);
+
+impl Credential {
+    fn default_wait_for_unlock() -> f32 {
+        90.0
+    }
 }
 
 impl Configuration {
@@ -21,11 +267,623 @@ impl Configuration {
         3.0
     }
 
+    fn default_retry_max() -> f32 {
+        300.0
+    }
+
+    fn default_unlock_attempts() -> u32 {
+        5
+    }
+
     fn default_lock() -> f32 {
         30.0
     }
 
-    pub fn from_str(data: &str) -> Result<Self, serde_json::Error> {
-        serde_json::from_str(data)
+    fn default_connection_timeout() -> f32 {
+        5.0
+    }
+
+    fn default_max_connections() -> usize {
+        64
+    }
+
+    fn default_systemd_creds() -> PathBuf {
+        "systemd-creds".into()
+    }
+
+    fn default_max_credential_size() -> usize {
+        // The limit of systemd's `CREDENTIAL_SIZE_MAX`.
+        1024 * 1024
+    }
+
+    fn default_write_timeout() -> f32 {
+        5.0
+    }
+
+    fn default_failure_hold() -> f32 {
+        30.0
+    }
+
+    fn default_lock_on_suspend() -> bool {
+        true
+    }
+
+    fn default_poll() -> f32 {
+        2.0
+    }
+
+    fn default_unlocked_credential() -> Option<String> {
+        Some("__unlocked".into())
+    }
+
+    #[cfg(test)]
+    pub fn from_str(data: &str) -> Result<Self, Error> {
+        Self::parse(data, Format::Json)
+    }
+
+    pub fn parse(data: &str, format: Format) -> Result<Self, Error> {
+        let cfg: Self = match format {
+            Format::Json => {
+                let mut json = serde_json::Deserializer::from_str(data);
+                let cfg = at_path(&mut json)?;
+                json.end().map_err(|err| Error(err.to_string()))?;
+                cfg
+            }
+            // Already names the path, and the position.
+            Format::Yaml => serde_yaml::from_str(data).map_err(|err| Error(err.to_string()))?,
+            Format::Toml => at_path(toml::Deserializer::new(data))?,
+        };
+
+        cfg.validate().map_err(Error)?;
+        Ok(cfg)
+    }
+
+    /// Parse the contents of the file at `path`, in the format of its extension.
+    pub fn from_file(path: &Path, data: &str) -> std::io::Result<Self> {
+        Self::parse(data, Format::of(path)).map_err(|err| {
+            let message = format!("configuration {}: {err}", path.display());
+            std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+        })
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if let Some(limit) = &self.rate_limit {
+            if !(limit.seconds.is_finite() && limit.seconds > 0.0) {
+                return Err(format!("invalid rate limit window of {} seconds", limit.seconds));
+            }
+        }
+
+        let mut names = std::collections::HashSet::new();
+
+        for store in &self.stores {
+            if store.name.is_empty() || store.name.contains('/') {
+                return Err(format!("invalid store name {:?}", store.name));
+            }
+
+            if !names.insert(&store.name) {
+                return Err(format!("duplicate store {:?}", store.name));
+            }
+        }
+
+        let mut credentials = std::collections::HashSet::new();
+        let all = self
+            .stores
+            .iter()
+            .flat_map(|store| store.credentials.keys())
+            .chain(self.credentials.keys());
+
+        for name in all {
+            if self.unlocked_credential.as_ref() == Some(name) {
+                return Err(format!(
+                    "credential {name:?} is reserved as the `unlocked_credential`"
+                ));
+            }
+
+            if !credentials.insert(name) {
+                return Err(format!(
+                    "credential {name:?} is given in more than one store"
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The credentials of the store with this name, or of the database on the command line.
+    pub fn credentials_of(&self, store: Option<&str>) -> Option<&HashMap<String, Credential>> {
+        match store {
+            None => Some(&self.credentials),
+            Some(name) => self
+                .stores
+                .iter()
+                .find(|store| store.name == name)
+                .map(|store| &store.credentials),
+        }
+    }
+
+    /// Find a credential, by its name or as `store/credential`, and the name of its store.
+    ///
+    /// The credentials of the database on the command line have no store name.
+    pub fn credential(&self, name: &str) -> Option<(Option<&str>, &Credential)> {
+        if let Some((store, name)) = name.split_once('/') {
+            let store = self.stores.iter().find(|s| s.name == store)?;
+            let credential = store.credentials.get(name)?;
+            return Some((Some(&store.name), credential));
+        }
+
+        if let Some(credential) = self.credentials.get(name) {
+            return Some((None, credential));
+        }
+
+        self.stores.iter().find_map(|store| {
+            let credential = store.credentials.get(name)?;
+            Some((Some(store.name.as_str()), credential))
+        })
+    }
+}
+
+/// Deserialize, with the path to the field at fault in the error.
+fn at_path<'de, D>(deserializer: D) -> Result<Configuration, Error>
+where
+    D: Deserializer<'de>,
+    D::Error: core::fmt::Display,
+{
+    serde_path_to_error::deserialize(deserializer).map_err(|err| Error(err.to_string()))
+}
+
+impl Format {
+    /// By the extension of the file, JSON unless it ends in `.yaml`, `.yml` or `.toml`.
+    pub fn of(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("toml") => Format::Toml,
+            _ => Format::Json,
+        }
+    }
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for std::io::Error {
+    fn from(err: Error) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Reject a credential name given twice, instead of silently serving the last one.
+fn unique_credentials<'de, D>(deserializer: D) -> Result<HashMap<String, Credential>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct Unique;
+
+    impl<'de> serde::de::Visitor<'de> for Unique {
+        type Value = HashMap<String, Credential>;
+
+        fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            f.write_str("a map of credential names")
+        }
+
+        fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            let mut credentials = HashMap::new();
+
+            while let Some((name, credential)) = map.next_entry::<String, Credential>()? {
+                if credentials.contains_key(&name) {
+                    return Err(serde::de::Error::custom(format_args!(
+                        "duplicate credential {name:?}"
+                    )));
+                }
+
+                credentials.insert(name, credential);
+            }
+
+            Ok(credentials)
+        }
+    }
+
+    deserializer.deserialize_map(Unique)
+}
+
+impl Credential {
+    pub fn allows(&self, unit: &str, default_deny: bool) -> bool {
+        match &self.allowed_units {
+            Some(rules) => rules.iter().any(|rule| rule.matches(unit)),
+            None => !default_deny,
+        }
+    }
+
+    /// Produce the credential from the fields of the matched entry.
+    pub fn render<'r>(
+        &self,
+        field: impl Fn(u8) -> Option<&'r [u8]>,
+    ) -> Result<Secret, MissingField> {
+        match (&self.format, self.field) {
+            (Some(format), _) => format.render(field),
+            (None, Output::Field(name)) => field(name.record_type())
+                .map(Secret::copy_from)
+                .ok_or(MissingField(name)),
+            (None, Output::UrlEncodedPair) => {
+                url_encoded(&[("username", Field::Username), ("password", Field::Password)], field)
+            }
+        }
+    }
+}
+
+impl CredentialSource {
+    /// Whether the entry with these fields is the one selected, after filling in the instance.
+    pub fn selects<'r>(&self, field: impl Fn(u8) -> Option<&'r [u8]>) -> bool {
+        match self {
+            CredentialSource::ByUuid(uuid) => field(0x1) == Some(uuid.as_bytes()),
+            CredentialSource::ByTitle {
+                title,
+                group,
+                username,
+            } => {
+                let is = |ty, expected: &str| field(ty) == Some(expected.as_bytes());
+                is(0x3, title)
+                    && group.as_deref().is_none_or(|group| is(0x2, group))
+                    && username.as_deref().is_none_or(|username| is(0x4, username))
+            }
+        }
+    }
+
+    /// How the entry is selected, for logs.
+    pub fn selector(&self) -> &'static str {
+        match self {
+            CredentialSource::ByUuid(_) => "uuid",
+            CredentialSource::ByTitle { .. } => "title",
+        }
+    }
+
+    /// Fill in the instance of the requesting unit, if the source refers to it.
+    pub fn for_instance(&self, instance: Option<&str>) -> Option<Self> {
+        const INSTANCE: &str = "{instance}";
+
+        let fill = |value: &str| -> Option<String> {
+            if !value.contains(INSTANCE) {
+                return Some(value.to_owned());
+            }
+
+            Some(value.replace(INSTANCE, instance?))
+        };
+
+        Some(match self {
+            &CredentialSource::ByUuid(uuid) => CredentialSource::ByUuid(uuid),
+            CredentialSource::ByTitle {
+                title,
+                group,
+                username,
+            } => CredentialSource::ByTitle {
+                title: fill(title)?,
+                group: match group {
+                    Some(group) => Some(fill(group)?),
+                    None => None,
+                },
+                username: match username {
+                    Some(username) => Some(fill(username)?),
+                    None => None,
+                },
+            },
+        })
+    }
+}
+
+impl TryFrom<SourceFields> for CredentialSource {
+    type Error = String;
+
+    fn try_from(fields: SourceFields) -> Result<Self, Self::Error> {
+        fn uuid(key: &str, value: &str) -> Result<uuid::Uuid, String> {
+            value
+                .parse()
+                .map_err(|err| format!("invalid `{key}` {value:?}: {err}"))
+        }
+
+        let SourceFields {
+            ty,
+            uuid: by_uuid,
+            title,
+            group,
+            username,
+            by_uuid: legacy_uuid,
+            by_title: legacy_title,
+        } = fields;
+
+        let Some(ty) = ty else {
+            let tagged = [("uuid", &by_uuid), ("title", &title)]
+                .into_iter()
+                .chain([("group", &group), ("username", &username)])
+                .find(|(_, value)| value.is_some());
+
+            if let Some((key, _)) = tagged {
+                return Err(format!("field `{key}` is given without a `type`"));
+            }
+
+            return match (legacy_uuid, legacy_title) {
+                (Some(value), None) => Ok(CredentialSource::ByUuid(uuid("ByUuid", &value)?)),
+                (
+                    None,
+                    Some(TitleFields {
+                        title,
+                        group,
+                        username,
+                    }),
+                ) => Ok(CredentialSource::ByTitle {
+                    title,
+                    group,
+                    username,
+                }),
+                (Some(_), Some(_)) => Err("only one of `ByUuid` and `ByTitle` can be given".into()),
+                (None, None) => Err("missing field `type`, either `uuid` or `title`".into()),
+            };
+        };
+
+        if legacy_uuid.is_some() || legacy_title.is_some() {
+            return Err("field `type` can not be combined with `ByUuid` or `ByTitle`".into());
+        }
+
+        match ty {
+            SourceType::Uuid => {
+                let unused = [
+                    ("title", &title),
+                    ("group", &group),
+                    ("username", &username),
+                ]
+                .into_iter()
+                .find(|(_, value)| value.is_some());
+
+                if let Some((key, _)) = unused {
+                    return Err(format!("field `{key}` is not used with `type` uuid"));
+                }
+
+                let value = by_uuid.ok_or("missing field `uuid` for `type` uuid")?;
+                Ok(CredentialSource::ByUuid(uuid("uuid", &value)?))
+            }
+            SourceType::Title => {
+                if by_uuid.is_some() {
+                    return Err("field `uuid` is not used with `type` title".into());
+                }
+
+                Ok(CredentialSource::ByTitle {
+                    title: title.ok_or("missing field `title` for `type` title")?,
+                    group,
+                    username,
+                })
+            }
+        }
+    }
+}
+
+impl UnitRule {
+    pub fn matches(&self, unit: &str) -> bool {
+        match self {
+            UnitRule::Unit(rule) if rule.contains(['*', '?']) => {
+                glob(rule.as_bytes(), unit.as_bytes())
+            }
+            UnitRule::Unit(rule) => match split_instance(rule) {
+                Some((template, "")) => split_instance(unit)
+                    .is_some_and(|(unit, instance)| unit == template && !instance.is_empty()),
+                _ => rule == unit,
+            },
+            UnitRule::Instances {
+                template,
+                instances,
+            } => split_instance(unit).is_some_and(|(unit, instance)| {
+                unit == *template && instances.iter().any(|allowed| allowed == instance)
+            }),
+        }
+    }
+}
+
+/// Match with `*` as any sequence and `?` as any single character.
+fn glob(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => {
+            glob(rest, name)
+                || name
+                    .split_first()
+                    .is_some_and(|(_, tail)| glob(pattern, tail))
+        }
+        (Some((b'?', rest)), Some((_, tail))) => glob(rest, tail),
+        (Some((p, rest)), Some((n, tail))) => p == n && glob(rest, tail),
+        _ => false,
+    }
+}
+
+/// Split `name@instance.type` into its template `name@.type` and the instance.
+pub fn split_instance(unit: &str) -> Option<(String, &str)> {
+    let (name, tail) = unit.split_once('@')?;
+    let (instance, ty) = tail.rsplit_once('.')?;
+    Some((format!("{name}@.{ty}"), instance))
+}
+
+impl Field {
+    /// The field type in a pwsafe V3 record.
+    pub fn record_type(self) -> u8 {
+        match self {
+            Field::Group => 0x2,
+            Field::Title => 0x3,
+            Field::Username => 0x4,
+            Field::Notes => 0x5,
+            Field::Password => 0x6,
+            Field::Url => 0xd,
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "group" => Field::Group,
+            "title" => Field::Title,
+            "username" => Field::Username,
+            "notes" => Field::Notes,
+            "password" => Field::Password,
+            "url" => Field::Url,
+            _ => return None,
+        })
+    }
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output::Field(Field::Password)
+    }
+}
+
+impl<'de> Deserialize<'de> for Output {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        const VARIANTS: &[&str] = &[
+            "group",
+            "title",
+            "username",
+            "notes",
+            "password",
+            "url",
+            "urlencodedpair",
+        ];
+
+        struct Name;
+
+        impl serde::de::Visitor<'_> for Name {
+            type Value = Output;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                f.write_str("the name of a field, or `urlencodedpair`")
+            }
+
+            // Raised in here, so that the error names the path to the field.
+            fn visit_str<E>(self, name: &str) -> Result<Output, E>
+            where
+                E: serde::de::Error,
+            {
+                if name == "urlencodedpair" {
+                    return Ok(Output::UrlEncodedPair);
+                }
+
+                Field::from_name(name)
+                    .map(Output::Field)
+                    .ok_or_else(|| E::unknown_variant(name, VARIANTS))
+            }
+        }
+
+        deserializer.deserialize_str(Name)
+    }
+}
+
+/// Fields as `application/x-www-form-urlencoded` pairs, in one allocation and without copies
+/// that outlive the credential.
+fn url_encoded<'r>(
+    pairs: &[(&str, Field)],
+    field: impl Fn(u8) -> Option<&'r [u8]>,
+) -> Result<Secret, MissingField> {
+    fn encode(value: &[u8]) -> impl Iterator<Item = u8> + Clone + '_ {
+        const HEX: &[u8; 16] = b"0123456789ABCDEF";
+
+        value.iter().flat_map(|&byte| {
+            let (escaped, len) = match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'*' | b'-' | b'.' | b'_' => {
+                    ([byte, 0, 0], 1)
+                }
+                b' ' => ([b'+', 0, 0], 1),
+                _ => ([b'%', HEX[usize::from(byte >> 4)], HEX[usize::from(byte & 0xf)]], 3),
+            };
+
+            escaped.into_iter().take(len)
+        })
+    }
+
+    let values = pairs
+        .iter()
+        .map(|&(key, name)| Ok((key, field(name.record_type()).ok_or(MissingField(name))?)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let bytes = values.iter().enumerate().flat_map(|(idx, (key, value))| {
+        let separator: &[u8] = if idx == 0 { b"" } else { b"&" };
+        let key = separator.iter().chain(key.as_bytes()).chain(b"=").copied();
+        key.chain(encode(value))
+    });
+
+    let mut data = Vec::with_capacity(bytes.clone().count());
+    data.extend(bytes);
+    Ok(Secret::from(data))
+}
+
+impl Template {
+    pub fn render<'r>(
+        &self,
+        field: impl Fn(u8) -> Option<&'r [u8]>,
+    ) -> Result<Secret, MissingField> {
+        let parts = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => Ok(literal.as_bytes()),
+                &Part::Field(name) => field(name.record_type()).ok_or(MissingField(name)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Secret::concat(parts.iter().copied()))
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = String;
+
+    fn try_from(format: String) -> Result<Self, Self::Error> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = format.chars();
+
+        while let Some(ch) = chars.next() {
+            match ch {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let Some((name, tail)) = chars.as_str().split_once('}') else {
+                        return Err(format!("Unterminated placeholder in format {format:?}"));
+                    };
+
+                    let Some(field) = Field::from_name(name) else {
+                        return Err(format!("Unknown field {name:?} in format {format:?}"));
+                    };
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(core::mem::take(&mut literal)));
+                    }
+
+                    parts.push(Part::Field(field));
+                    chars = tail.chars();
+                }
+                '}' => {
+                    return Err(format!(
+                        "Unmatched `}}` in format {format:?}, write `}}}}` for a literal brace"
+                    ));
+                }
+                other => literal.push(other),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Template { parts })
     }
 }
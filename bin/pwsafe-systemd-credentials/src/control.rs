@@ -0,0 +1,251 @@
+//! An administrative socket next to the credential socket, for operators and scripts.
+//!
+//! Each request is one line with a command, `LOCK`, `UNLOCK`, `STATUS`, `RELOAD` or `AUDIT`,
+//! answered by one line of JSON which always contains the boolean `ok`.
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::net::{unix::uid_t, UnixListener, UnixStream};
+use tokio::sync::watch;
+
+use crate::{audit, configuration, notify, pwfile, Refusal};
+
+/// Counters of the requests answered on the credential sockets.
+#[derive(Default)]
+pub struct Stats {
+    served: AtomicU64,
+    refused: AtomicU64,
+    /// By the discriminant of the reason.
+    refusals: [AtomicU64; Refusal::ALL.len()],
+    timed_out: AtomicU64,
+    rejected_peers: AtomicU64,
+    /// The times of the recent requests of each unit, see [`Stats::admit`].
+    requests: Mutex<HashMap<String, VecDeque<Instant>>>,
+    /// The records of the recent requests.
+    pub audit: audit::Log,
+}
+
+/// Everything the commands act upon.
+pub struct Control {
+    pub stores: Vec<pwfile::Passwords>,
+    pub stats: Arc<Stats>,
+    pub configuration: PathBuf,
+    pub reconfigure: Rc<watch::Sender<Arc<configuration::Configuration>>>,
+    pub notify: notify::Notifier,
+    /// Only this user may connect, unless `None`.
+    pub uid: Option<uid_t>,
+}
+
+/// The path of the control socket belonging to the credential socket at `socket`.
+pub fn socket_path(socket: &Path) -> PathBuf {
+    let mut path = socket.as_os_str().to_owned();
+    path.push(".ctl");
+    path.into()
+}
+
+/// Bind the control socket, accessible only to our own user.
+pub async fn bind(path: &Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let _ = tokio::fs::remove_file(path).await;
+    let listener = UnixListener::bind(path)?;
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600)).await?;
+    Ok(listener)
+}
+
+pub async fn serve(listener: UnixListener, control: Control) -> std::io::Result<()> {
+    let control = Rc::new(control);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        let Ok(cred) = stream.peer_cred() else {
+            eprintln!("Invalid peer creds on control socket");
+            continue;
+        };
+
+        if control.uid.is_some_and(|uid| uid != cred.uid()) {
+            eprintln!("Unprivileged peer on control socket, uid {}", cred.uid());
+            continue;
+        }
+
+        tokio::task::spawn_local(answer_commands(stream, control.clone()));
+    }
+}
+
+async fn answer_commands(stream: UnixStream, control: Rc<Control>) -> std::io::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        let mut answer = control.command(line.trim()).await.to_string();
+        answer.push('\n');
+        write.write_all(answer.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+impl Control {
+    async fn command(&self, command: &str) -> Value {
+        match command {
+            "LOCK" => {
+                eprintln!("Locking on request of the control socket");
+                // The unlock tasks notice and stop their relock timers.
+                self.stores.iter().for_each(pwfile::Passwords::lock);
+                json!({ "ok": true, "locked": true })
+            }
+            "UNLOCK" => {
+                // Only affects an unlock that gave up after wrong passphrases.
+                self.stores.iter().for_each(pwfile::Passwords::rearm);
+                json!({ "ok": true })
+            }
+            "STATUS" => json!({
+                "ok": true,
+                "locked": !self.stores.iter().any(pwfile::Passwords::is_unlocked),
+                "failed_unlocks": self
+                    .stores
+                    .iter()
+                    .map(pwfile::Passwords::failed_attempts)
+                    .sum::<u32>(),
+                "damaged": self.stores.iter().any(|store| store.damage().is_some()),
+                "waiting": self.stores.iter().map(pwfile::Passwords::waiting).sum::<usize>(),
+                "stores": self.stores.iter().map(store_status).collect::<Vec<_>>(),
+                "served": self.stats.served.load(Ordering::Relaxed),
+                "refused": self.stats.refused.load(Ordering::Relaxed),
+                "refused_by_reason": self.stats.refusals(),
+                "timed_out": self.stats.timed_out.load(Ordering::Relaxed),
+                "rejected_peers": self.stats.rejected_peers.load(Ordering::Relaxed),
+            }),
+            "AUDIT" => json!({ "ok": true, "recent": self.stats.audit.recent() }),
+            "RELOAD" => {
+                let reload = crate::reload(
+                    &self.configuration,
+                    &self.reconfigure,
+                    &self.notify,
+                    &self.stores,
+                );
+
+                match reload.await {
+                    Ok(()) => json!({ "ok": true }),
+                    Err(err) => json!({ "ok": false, "error": err.to_string() }),
+                }
+            }
+            other => json!({ "ok": false, "error": format!("unknown command {other:?}") }),
+        }
+    }
+}
+
+fn store_status(store: &pwfile::Passwords) -> Value {
+    let counters = store.counters();
+
+    json!({
+        "name": store.name(),
+        "uuid": store.database_uuid(),
+        "locked": !store.is_unlocked(),
+        "failed_unlocks": store.failed_attempts(),
+        "damage": store.damage(),
+        "waiting": store.waiting(),
+        "unresolved": store.unresolved().iter().map(|unresolved| json!({
+            "credential": unresolved.credential,
+            "problem": unresolved.problem.to_string(),
+        })).collect::<Vec<_>>(),
+        "unlocks": counters.unlocks,
+        "wrong_passphrases": counters.wrong_passphrases,
+        "relocks": counters.relocks,
+        "locked_seconds": counters.locked.as_secs_f64(),
+    })
+}
+
+impl Stats {
+    pub fn with_audit(audit: audit::Log) -> Self {
+        Stats {
+            audit,
+            ..Stats::default()
+        }
+    }
+
+    /// Count a request of the unit, whether it is within the limit.
+    ///
+    /// Refused requests count as well, a unit retrying in a loop stays limited.
+    pub fn admit(
+        &self,
+        service: &str,
+        limit: Option<&configuration::RateLimit>,
+        now: Instant,
+    ) -> bool {
+        let Some(limit) = limit else {
+            return true;
+        };
+
+        let window = std::time::Duration::from_secs_f32(limit.seconds);
+        let mut requests = self.requests.lock().unwrap();
+
+        // Also forgets units that have not asked in a while.
+        requests.retain(|_, times| {
+            while times.front().is_some_and(|&at| now.duration_since(at) >= window) {
+                times.pop_front();
+            }
+
+            !times.is_empty()
+        });
+
+        let times = requests.entry(service.to_owned()).or_default();
+        times.push_back(now);
+        times.len() <= limit.requests
+    }
+
+    pub fn served(&self) {
+        self.served.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn refused(&self, reason: Refusal) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+        self.refusals[reason as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The refusals so far, by their reason.
+    fn refusals(&self) -> serde_json::Map<String, Value> {
+        Refusal::ALL
+            .iter()
+            .map(|&reason| {
+                let count = self.refusals[reason as usize].load(Ordering::Relaxed);
+                (reason.to_string(), count.into())
+            })
+            .collect()
+    }
+
+    pub fn timed_out(&self) {
+        self.timed_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(test)]
+    pub fn timeouts(&self) -> u64 {
+        self.timed_out.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected_peer(&self) {
+        self.rejected_peers.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Send one command to the control socket, returning its answer.
+pub async fn request(path: &Path, command: &str) -> std::io::Result<Value> {
+    let stream = UnixStream::connect(path).await?;
+    let (read, mut write) = stream.into_split();
+
+    write.write_all(format!("{command}\n").as_bytes()).await?;
+    write.shutdown().await?;
+
+    let Some(line) = BufReader::new(read).lines().next_line().await? else {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    };
+
+    Ok(serde_json::from_str(&line)?)
+}
@@ -1,29 +1,135 @@
-use std::{ffi::OsString, sync::Arc};
+use std::{collections::HashMap, ffi::OsString, rc::Rc, sync::Arc};
 
 use clap::Parser;
 
 use pwsafer::PwsafeKey;
 use tokio::net::{
-    unix::{gid_t, uid_t, UCred},
+    unix::{gid_t, pid_t, uid_t},
     UnixListener, UnixStream,
 };
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
 
+use secret::Secret;
+
+mod activation;
+mod audit;
 mod configuration;
+mod control;
+mod notify;
+mod peer;
+mod privileges;
 mod pwfile;
+mod resolve;
+mod seal;
+mod secret;
+mod suspend;
 #[cfg(test)]
 mod tests;
 
 fn main() {
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "ctl") {
+        let ctl = Ctl::parse_from(std::env::args_os().skip(1));
+        let ok = with_ctl(ctl);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
+    if std::env::args_os().nth(1).is_some_and(|arg| arg == "check") {
+        let check = Check::parse_from(std::env::args_os().skip(1));
+        let ok = with_check(check);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     let app = App::parse();
+
+    if app.check_config {
+        let ok = check_configuration(&app.configuration);
+        std::process::exit(if ok { 0 } else { 1 });
+    }
+
     with_io(app).unwrap();
 }
 
+/// Parse and validate the configuration, without binding any socket or opening a database.
+fn check_configuration(path: &std::path::Path) -> bool {
+    let result = std::fs::read_to_string(path)
+        .and_then(|data| configuration::Configuration::from_file(path, &data));
+
+    match result {
+        Ok(_) => {
+            eprintln!("Configuration {} is valid", path.display());
+            true
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            false
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn with_ctl(ctl: Ctl) -> bool {
+    let command = match ctl.command {
+        CtlCommand::Lock => "LOCK",
+        CtlCommand::Unlock => "UNLOCK",
+        CtlCommand::Status => "STATUS",
+        CtlCommand::Reload => "RELOAD",
+        CtlCommand::Audit => "AUDIT",
+    };
+
+    match control::request(&control::socket_path(&ctl.socket), command).await {
+        Ok(answer) => {
+            println!("{answer}");
+            answer["ok"] == true
+        }
+        Err(err) => {
+            eprintln!("Error: {err}");
+            false
+        }
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn with_check(check: Check) -> bool {
+    match is_unlocked(&check.socket, check.store.as_deref()).await {
+        Ok(unlocked) => unlocked,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            false
+        }
+    }
+}
+
+/// Whether the store, or every store if none is given, of the daemon at `socket` is unlocked.
+async fn is_unlocked(socket: &std::path::Path, store: Option<&str>) -> std::io::Result<bool> {
+    let status = control::request(&control::socket_path(socket), "STATUS").await?;
+    let stores = status["stores"].as_array().map_or(&[][..], Vec::as_slice);
+
+    let mut selected = stores
+        .iter()
+        .filter(|status| store.is_none() || status["name"].as_str() == store)
+        .peekable();
+
+    if selected.peek().is_none() {
+        let message = format!("no store {store:?}");
+        return Err(std::io::Error::new(std::io::ErrorKind::NotFound, message));
+    }
+
+    Ok(selected.all(|status| status["locked"] == false))
+}
+
 #[tokio::main]
 async fn with_io(app: App) -> std::io::Result<()> {
-    let _ = tokio::fs::remove_file(&app.socket);
-    let listener = UnixListener::bind(&app.socket)?;
+    let (listeners, control_listener) = bind_listeners(&app.socket).await?;
+    let control_listener = match control_listener {
+        Some(passed) => passed,
+        None => control::bind(&control::socket_path(&app.socket)).await?,
+    };
+    let notify = notify::Notifier::from_env()?;
 
     let ask_pass = {
+        // Given explicitly.
+        let arg = app.askpass.clone().map(OsString::from);
         // Most specific but very unlikely to exist outright.
         let ours = std::env::var_os("PWSAFE_ASKPASS");
         // Unlikely to exist but we take it.
@@ -31,160 +137,1089 @@ async fn with_io(app: App) -> std::io::Result<()> {
         // Likely to exist.
         let ssh = std::env::var_os("SSH_ASKPASS");
 
-        ours.or(ask)
+        arg.or(ours)
+            .or(ask)
             .or(ssh)
             .unwrap_or_else(|| "/usr/lib/ssh/x11-ssh-askpass".into())
     };
 
-    let ask_pass = move || {
+    // Each store unlocks on its own, but the user answers one prompt at a time.
+    let asking = Rc::new(tokio::sync::Mutex::new(()));
+    let ask_pass = move |prompt| {
         let program = ask_pass.clone();
+        let asking = asking.clone();
 
-        async { read_password_ssh_askpass(program).await }
+        async move {
+            let _asking = asking.lock().await;
+            read_password_ssh_askpass(program, prompt).await
+        }
     };
 
     let cfg = tokio::fs::read_to_string(&app.configuration).await?;
-    let cfg = configuration::Configuration::from_str(&cfg)?;
-    let cfg = Arc::new(cfg);
+    let cfg = configuration::Configuration::from_file(&app.configuration, &cfg)?;
 
-    let store = pwfile::Passwords::new(app.pwsafe.clone()).await?;
-    let reader = store.reader();
+    // Nothing after this needs root, the databases are read as the user we continue as.
+    let run_as = privileges::RunAs::new(app.run_as_user.as_deref(), app.run_as_group.as_deref())?;
+    let databases: Vec<_> = app
+        .pwsafe
+        .iter()
+        .filter(|path| !path.as_os_str().is_empty())
+        .cloned()
+        .chain(cfg.stores.iter().map(|store| store.path.clone()))
+        .collect();
+    privileges::drop_to(run_as.as_ref(), app.allow_root, &databases)?;
 
+    let (reconfigure, cfg) = watch::channel(Arc::new(cfg));
+    let reconfigure = Rc::new(reconfigure);
+    let hangup = signal(SignalKind::hangup())?;
+    let sleep = signal(SignalKind::user_defined1())?;
+    let audit_log = app.audit_log.clone().map(audit::Sink::File);
+    let stats = Arc::new(control::Stats::with_audit(audit::Log::new(audit_log)));
+    let connections = Arc::new(Semaphore::new(cfg.borrow().max_connections));
+
+    let stores = open_stores(&app, &cfg.borrow()).await?;
+    let readers = Stores {
+        readers: stores
+            .iter()
+            .map(|store| (store.name().map(str::to_owned), store.reader()))
+            .collect(),
+    };
+
+    let app = Arc::new(app);
     let local = tokio::task::LocalSet::new();
-    local.spawn_local(unlock(store, cfg.clone(), ask_pass));
-    local.run_until(listen(app, cfg, listener, reader)).await
+    local.spawn_local(control::serve(
+        control_listener,
+        control::Control {
+            stores: stores.clone(),
+            stats: stats.clone(),
+            configuration: app.configuration.clone(),
+            reconfigure: reconfigure.clone(),
+            notify: notify.clone(),
+            uid: (!app.allow).then_some(app.uid),
+        },
+    ));
+    local.spawn_local(reload_on_hangup(
+        app.configuration.clone(),
+        hangup,
+        reconfigure,
+        notify.clone(),
+        stores.clone(),
+    ));
+
+    local.spawn_local(suspend::lock_on_sleep(sleep, stores.clone(), cfg.clone()));
+
+    #[cfg(feature = "logind")]
+    match suspend::logind::Logind::connect().await {
+        Ok(logind) => {
+            local.spawn_local(suspend::lock_on_sleep(logind, stores.clone(), cfg.clone()));
+        }
+        Err(err) => eprintln!("Not locking on suspend announced by logind: {err}"),
+    }
+
+    for store in stores.clone() {
+        local.spawn_local(unlock(
+            store,
+            cfg.clone(),
+            notify.clone(),
+            ask_pass.clone(),
+        ));
+    }
+
+    if let Some(interval) = notify::watchdog_interval() {
+        // On the same thread as all other tasks, a blocked unlock also stops the watchdog.
+        let notify = notify.clone();
+        local.spawn_local(async move {
+            let mut watchdog = tokio::time::interval(interval);
+
+            loop {
+                watchdog.tick().await;
+                notify.watchdog();
+            }
+        });
+    }
+
+    notify.ready();
+
+    local
+        .run_until(async move {
+            let mut listening = tokio::task::JoinSet::new();
+
+            for store in stores {
+                listening.spawn_local(resolve::check_credentials(
+                    store,
+                    cfg.clone(),
+                    app.strict_config,
+                ));
+            }
+
+            for listener in listeners {
+                listening.spawn_local(listen(
+                    app.clone(),
+                    cfg.clone(),
+                    listener,
+                    readers.clone(),
+                    stats.clone(),
+                    connections.clone(),
+                ));
+            }
+
+            // Any failing listener, or a credential not resolving in strict mode, shuts us down.
+            while let Some(result) = listening.join_next().await {
+                result??;
+            }
+
+            Ok(())
+        })
+        .await
+}
+
+/// The database on the command line, then those of the configured stores.
+async fn open_stores(
+    app: &App,
+    cfg: &configuration::Configuration,
+) -> std::io::Result<Vec<pwfile::Passwords>> {
+    let invalid = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    let mut stores = vec![];
+
+    match app
+        .pwsafe
+        .as_ref()
+        .filter(|path| !path.as_os_str().is_empty())
+    {
+        Some(path) => stores.push(pwfile::Passwords::new(path.clone()).await?),
+        None if !cfg.credentials.is_empty() => {
+            return Err(invalid(
+                "Top-level credentials require the database path argument",
+            ));
+        }
+        None => {}
+    }
+
+    for store in &cfg.stores {
+        let passwords = pwfile::Passwords::new(store.path.clone()).await?;
+        eprintln!(
+            "Store {:?} opened from {}",
+            store.name,
+            store.path.display()
+        );
+        stores.push(passwords.with_name(&store.name));
+    }
+
+    if stores.is_empty() {
+        return Err(invalid("No database, give its path or configure stores"));
+    }
+
+    Ok(stores)
+}
+
+/// The sockets passed by systemd socket activation, or otherwise our own socket at `path`.
+///
+/// A passed socket named [`activation::CONTROL`] is returned separately, as the control socket.
+async fn bind_listeners(
+    path: &std::path::Path,
+) -> std::io::Result<(Vec<UnixListener>, Option<UnixListener>)> {
+    if let Some(activated) = activation::listeners()? {
+        let mut listeners = vec![];
+        let mut control = None;
+
+        for (name, listener) in activated {
+            let listener = UnixListener::from_std(listener)?;
+
+            if name == activation::CONTROL {
+                control = Some(listener);
+            } else {
+                listeners.push(listener);
+            }
+        }
+
+        eprintln!("Serving {} sockets passed by systemd", listeners.len());
+        return Ok((listeners, control));
+    }
+
+    let _ = tokio::fs::remove_file(path).await;
+    Ok((vec![UnixListener::bind(path)?], None))
+}
+
+/// Replace the configuration whenever we receive `SIGHUP`, keeping the unlocked database.
+///
+/// Requests already being answered finish with the configuration they started with.
+async fn reload_on_hangup(
+    path: std::path::PathBuf,
+    mut hangup: Signal,
+    reconfigure: Rc<watch::Sender<Arc<configuration::Configuration>>>,
+    notify: notify::Notifier,
+    stores: Vec<pwfile::Passwords>,
+) {
+    while hangup.recv().await.is_some() {
+        let _ = reload(&path, &reconfigure, &notify, &stores).await;
+    }
+}
+
+/// Replace the configuration, or keep the previous one if the file is not valid.
+///
+/// Either way, the stores that gave up on their passphrase ask for it again.
+async fn reload(
+    path: &std::path::Path,
+    reconfigure: &watch::Sender<Arc<configuration::Configuration>>,
+    notify: &notify::Notifier,
+    stores: &[pwfile::Passwords],
+) -> std::io::Result<()> {
+    notify.reloading();
+    let result = reload_configuration(path, reconfigure).await;
+    stores.iter().for_each(pwfile::Passwords::rearm);
+
+    match &result {
+        Ok(()) => eprintln!("Reloaded configuration {}", path.display()),
+        Err(err) => eprintln!(
+            "Error: keeping previous configuration, reloading {} failed: {err}",
+            path.display()
+        ),
+    }
+
+    notify.ready();
+    result
+}
+
+async fn reload_configuration(
+    path: &std::path::Path,
+    reconfigure: &watch::Sender<Arc<configuration::Configuration>>,
+) -> std::io::Result<()> {
+    let cfg = tokio::fs::read_to_string(path).await?;
+    let cfg = configuration::Configuration::from_file(path, &cfg)?;
+
+    // The stores are opened at startup, a reload can only change their credentials.
+    let stores = |cfg: &configuration::Configuration| {
+        let mut stores: Vec<_> = cfg
+            .stores
+            .iter()
+            .map(|store| (store.name.clone(), store.path.clone()))
+            .collect();
+        stores.sort();
+        stores
+    };
+
+    if stores(&cfg) != stores(&reconfigure.borrow()) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "stores were added, removed or moved, which requires a restart",
+        ));
+    }
+
+    reconfigure.send_replace(Arc::new(cfg));
+    Ok(())
 }
 
 async fn unlock<WithMethod>(
     store: pwfile::Passwords,
-    cfg: Arc<configuration::Configuration>,
-    mut read_password_from_user: impl FnMut() -> WithMethod,
+    mut reconfigured: watch::Receiver<Arc<configuration::Configuration>>,
+    notify: notify::Notifier,
+    mut read_password_from_user: impl FnMut(String) -> WithMethod,
 ) where
     WithMethod: core::future::Future<Output = std::io::Result<pwsafer::PwsafeKey>>,
 {
-    let rate_limit = std::time::Duration::from_secs_f32(cfg.password_retry);
-    let mut frequency = tokio::time::interval(rate_limit);
-
-    let relock_time = std::time::Duration::from_secs_f32(cfg.password_lock);
+    let mut relock_time = std::time::Duration::from_secs_f32(reconfigured.borrow().password_lock);
     let relock_time_sleep = std::time::Duration::from_secs(u32::MAX as u64);
     let mut relock_at = tokio::time::interval(relock_time_sleep);
+    // Where the relock timer last started, to move it when `password_lock` is reloaded.
+    let mut relock_from = tokio::time::Instant::now();
+
+    // The file is replaced by editors while we run, pick up its changes with the cached key.
+    let mut poll_every = std::time::Duration::from_secs_f32(reconfigured.borrow().database_poll);
+    let mut poll = tokio::time::interval(poll_every);
+    poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut stamp = pwfile::Stamp::of(store.path()).await.ok();
+    let mut cached_key: Option<PwsafeKey> = None;
+    let mut lock_watch = store.watch_lock();
+
+    // With several stores, each reports its own state.
+    let status = |state: &str| match store.name() {
+        Some(name) => notify.status(&format!("{name}: {state}")),
+        None => notify.status(state),
+    };
 
     loop {
+        // Reloaded on `SIGHUP`, the current settings apply from here on.
+        let cfg = reconfigured.borrow_and_update().clone();
+        let retry = std::time::Duration::from_secs_f32(cfg.password_retry);
+        let retry_max = std::time::Duration::from_secs_f32(cfg.password_retry_max);
+
+        let lock = std::time::Duration::from_secs_f32(cfg.password_lock);
+        if lock != relock_time {
+            relock_time = lock;
+
+            if cached_key.is_some() {
+                relock_at.reset_at(relock_from + relock_time);
+            }
+        }
+
+        let every = std::time::Duration::from_secs_f32(cfg.database_poll);
+        if every != poll_every {
+            poll_every = every;
+            poll = tokio::time::interval_at(tokio::time::Instant::now() + every, every);
+            poll.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        }
+
         tokio::select! {
+            // Only to pick up the new settings above.
+            Ok(()) = reconfigured.changed() => {},
+            // Locked by someone else, such as the control socket.
+            () = lock_watch.locked(), if cached_key.is_some() => {
+                cached_key = None;
+                status("locked");
+                relock_at.reset_after(relock_time_sleep);
+            },
             _ = relock_at.tick() => {
                 store.lock();
+                cached_key = None;
+                status("locked");
                 relock_at.reset_after(relock_time_sleep);
             },
-            Some(req) = store.as_lock_request() => {
-                let key = match read_password_from_user().await {
-                    Ok(key) => key,
-                    Err(_err) => {
+            _ = poll.tick() => {
+                // Missing while being replaced, look again on the next tick.
+                let Ok(current) = pwfile::Stamp::of(store.path()).await else {
+                    continue;
+                };
+
+                if stamp == Some(current) {
+                    continue;
+                }
+
+                // Look again on the next tick, the change is still to be reloaded.
+                if store.is_held_by_pwsafe().await {
+                    continue;
+                }
+
+                match store.reload(cached_key.as_ref()).await {
+                    Err(err) => {
+                        eprintln!("Failed to reload {}: {err}", store.path().display());
                         continue;
                     }
+                    Ok(Ok(())) => {
+                        eprintln!("Reloaded {}", store.path().display());
+                    }
+                    Ok(Err(pwsafer::ReadError::InvalidPassword)) => {
+                        eprintln!("Changed database does not open with the passphrase, locking");
+                        cached_key = None;
+                        status("locked");
+                        relock_at.reset_after(relock_time_sleep);
+                    }
+                    Ok(Err(err)) => {
+                        eprintln!("Changed database {} is damaged, locking: {err}", store.path().display());
+                        cached_key = None;
+                        status(&format!("locked, database damaged: {err}"));
+                        relock_at.reset_after(relock_time_sleep);
+                    }
+                }
+
+                stamp = Some(current);
+            },
+            () = store.rearmed() => {
+                eprintln!("Asking for the passphrase again");
+            },
+            () = store.used(), if cfg.password_lock_mode == configuration::LockMode::Idle => {
+                if cached_key.is_some() {
+                    relock_from = tokio::time::Instant::now();
+                    relock_at.reset_after(relock_time);
+                }
+            },
+            Some(req) = store.as_lock_request(), if store.failed_attempts() < cfg.max_unlock_attempts && store.damage().is_none() => {
+                status("locked, waiting for passphrase");
+
+                let prompt = unlock_prompt(store.name(), store.path(), &req.requesters());
+                let key = match read_password_from_user(prompt).await {
+                    Ok(key) if !key.is_empty_password() => Some(key),
+                    Ok(_) => {
+                        eprintln!("No passphrase given, not trying to unlock");
+                        None
+                    }
+                    Err(err) => {
+                        eprintln!("Asking for the passphrase failed: {err}");
+                        None
+                    }
                 };
 
-                if let Err(_err) = req.unlock(&key) {
-                    eprintln!("This did not unlock!");
-                    frequency.reset();
-                    frequency.tick().await;
+                // Not a wrong passphrase, so neither counted nor delayed.
+                let Some(key) = key else {
+                    store.cancelled();
+                    status("locked, passphrase prompt cancelled");
+                    continue;
+                };
+
+                if let Err(err) = req.unlock(&key) {
+                    // No passphrase is going to help, until the file changes.
+                    if !matches!(err, pwsafer::ReadError::InvalidPassword) {
+                        eprintln!("Database {} is damaged: {err}", store.path().display());
+                        status(&format!("locked, database damaged: {err}"));
+                        continue;
+                    }
+
+                    let attempts = store.failed_attempts();
+                    eprintln!("This did not unlock! Wrong passphrase number {attempts}");
+
+                    if attempts >= cfg.max_unlock_attempts {
+                        eprintln!("Giving up on the passphrase until rearmed");
+                        status(&format!("locked, gave up after {attempts} wrong passphrases"));
+                        continue;
+                    }
+
+                    status(&format!("locked, wrong passphrase (attempt {attempts})"));
+                    tokio::time::sleep(backoff(retry, retry_max, attempts)).await;
                     continue;
                 }
 
+                let path = store.path().display();
+                match store.database_uuid() {
+                    Some(uuid) => eprintln!("Unlocked database {uuid} at {path}"),
+                    None => eprintln!("Unlocked database at {path}"),
+                }
+
+                status("unlocked");
+                cached_key = Some(key);
+                relock_from = tokio::time::Instant::now();
                 relock_at.reset_after(relock_time);
             }
         }
     }
 }
 
-async fn read_password_ssh_askpass(program: OsString) -> std::io::Result<pwsafer::PwsafeKey> {
+/// The delay after `attempts` wrong passphrases, doubling from `base` up to `max`.
+fn backoff(
+    base: std::time::Duration,
+    max: std::time::Duration,
+    attempts: u32,
+) -> std::time::Duration {
+    let factor = 1u32
+        .checked_shl(attempts.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    base.saturating_mul(factor).min(max)
+}
+
+/// The longest prompt we show, in characters.
+const PROMPT_LENGTH: usize = 120;
+
+/// The longest database path in the prompt, in characters, leaving room for the requester.
+const PROMPT_PATH_LENGTH: usize = 48;
+
+/// Tell the user which database we unlock, and on whose behalf we ask for the passphrase.
+///
+/// Service and credential names come from peers, so control characters are replaced.
+fn unlock_prompt(
+    store: Option<&str>,
+    path: &std::path::Path,
+    requesters: &[pwfile::Requester],
+) -> String {
+    // The end of a long path names the file, keep that.
+    let path = path.display().to_string();
+    let skip = path.chars().count().saturating_sub(PROMPT_PATH_LENGTH);
+    let path = match skip {
+        0 => path,
+        skip => format!("…{}", path.chars().skip(skip + 1).collect::<String>()),
+    };
+
+    let database = match store {
+        Some(name) => format!("store {name} ({path})"),
+        None => path,
+    };
+
+    let mut prompt = match requesters {
+        [] => format!("Unlock {database}"),
+        [first, more @ ..] => {
+            let mut prompt = format!(
+                "Unlock {database} for {} (credential {})",
+                first.service, first.credential
+            );
+
+            if !more.is_empty() {
+                prompt.push_str(&format!(" (+{} more)", more.len()));
+            }
+
+            prompt
+        }
+    };
+
+    prompt = prompt
+        .chars()
+        .map(|ch| if ch.is_control() { '?' } else { ch })
+        .collect();
+
+    if prompt.chars().count() > PROMPT_LENGTH {
+        prompt = prompt.chars().take(PROMPT_LENGTH - 1).collect();
+        prompt.push('…');
+    }
+
+    prompt
+}
+
+async fn read_password_ssh_askpass(
+    program: OsString,
+    prompt: String,
+) -> std::io::Result<pwsafer::PwsafeKey> {
     let mut output = tokio::process::Command::new(program)
-        .arg(format!("systemd-pwsafe for "))
+        .arg(prompt)
         .output()
         .await?;
+    // Cancelled, whatever it printed.
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "askpass was cancelled, {}",
+            output.status
+        )));
+    }
+
     // Always add a newline.. Hence, I hate using pipes for communicating structured information.
     let _ = output.stdout.pop();
     Ok(PwsafeKey::new(&output.stdout))
 }
 
 async fn listen(
-    app: App,
-    cfg: Arc<configuration::Configuration>,
+    app: Arc<App>,
+    cfg: watch::Receiver<Arc<configuration::Configuration>>,
     listener: UnixListener,
-    reader: pwfile::PasswordReader,
+    stores: Stores,
+    stats: Arc<control::Stats>,
+    connections: Arc<Semaphore>,
 ) -> std::io::Result<()> {
     loop {
+        // Wait for a free slot before accepting, the socket's backlog queues everyone else.
+        let permit = connections
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Never closed");
+
         let (stream, peer_addr) = listener.accept().await?;
         eprintln!("Connection attempt from {peer_addr:?}");
 
-        let Some(systemd) = filter_by_peer_addr(&stream) else {
-            eprintln!("Bad peer {peer_addr:?}");
+        // The cheaper checks first, so rejecting peers costs little. Only the last one reads
+        // `/proc` for the process of the peer.
+        let Ok(cred) = stream.peer_cred() else {
+            eprintln!("Invalid peer creds {peer_addr:?}");
+            stats.rejected_peer();
             continue;
         };
 
-        let Ok(cred) = stream.peer_cred() else {
-            eprintln!("Invalid peer creds {peer_addr:?}");
+        let Some(systemd) = filter_by_peer_addr(&stream) else {
+            eprintln!("Bad peer {peer_addr:?}");
+            stats.rejected_peer();
             continue;
         };
 
         if !app.allow && !verify_creds(&app, &cred) {
-            eprintln!("Unprivileged peer creds {peer_addr:?}");
+            let proc = std::path::Path::new("/proc");
+            let peer = peer::describe(&cred, proc);
+            eprintln!("Unprivileged peer creds {peer_addr:?}, {peer}");
+            stats.rejected_peer();
             continue;
         };
 
-        let reader = reader.clone();
-        let cfg = cfg.clone();
-        tokio::task::spawn_local(answer_stream(stream, systemd, reader, cfg));
+        let peer = audit::Peer {
+            pid: cred.pid(),
+            uid: cred.uid(),
+        };
+
+        let cfg = cfg.borrow().clone();
+        dispatch(
+            permit,
+            stream,
+            systemd,
+            Some(peer),
+            stores.clone(),
+            cfg,
+            stats.clone(),
+        );
     }
 }
 
+/// Answer on a connection in the background, occupying its slot until done or waiting for an
+/// unlock.
+fn dispatch(
+    permit: OwnedSemaphorePermit,
+    stream: UnixStream,
+    systemd: SystemdUnitSource,
+    peer: Option<audit::Peer>,
+    stores: impl Into<Stores>,
+    cfg: Arc<configuration::Configuration>,
+    stats: Arc<control::Stats>,
+) {
+    let stores = stores.into();
+
+    tokio::task::spawn_local(answer_stream(
+        Some(permit),
+        stream,
+        systemd,
+        peer,
+        stores,
+        cfg,
+        stats,
+    ));
+}
+
+/// Answer the peer, holding its connection slot `slot` until done or waiting for an unlock.
 async fn answer_stream(
+    mut slot: Option<OwnedSemaphorePermit>,
     mut stream: UnixStream,
     systemd: SystemdUnitSource,
-    store: pwfile::PasswordReader,
+    peer: Option<audit::Peer>,
+    stores: impl Into<Stores>,
     app: Arc<configuration::Configuration>,
+    stats: Arc<control::Stats>,
 ) -> std::io::Result<()> {
+    let stores = stores.into();
+
     eprintln!(
-        "Serving key from {} for {}",
-        systemd.service, systemd.credential
+        "Request for credential {:?} from {}",
+        systemd.credential, systemd.service
+    );
+
+    let admitted = stats.admit(
+        &systemd.service,
+        app.rate_limit.as_ref(),
+        std::time::Instant::now(),
     );
 
-    match answer_request(&systemd, store, app).await? {
-        Some(key) => {
-            eprintln!("Found valid passphrase for service {}", systemd.service);
-            // Then send out the recovered password field entry.
-            use tokio::io::AsyncWriteExt as _;
-            // FIXME: not the actual password.
-            stream.write_all(&key).await
-            // Closes the stream.
+    let mut found = if !admitted {
+        eprintln!(
+            "Warning: unit {} exceeds the rate limit of requests",
+            systemd.service
+        );
+        Err(Refusal::RateLimited)
+    } else {
+        // Waiting for the passphrase may take long, but not longer than the peer cares.
+        tokio::select! {
+            found = lookup(&systemd, &stores, app.clone(), &mut slot) => found?,
+            () = hung_up(&stream) => {
+                eprintln!("Peer {} hung up before an answer", systemd.service);
+                return Ok(());
+            }
+        }
+    };
+
+    let event = audit::Event {
+        service: &systemd.service,
+        credential: &systemd.credential,
+        selector: app
+            .credential(&systemd.credential)
+            .map(|(_, credential)| credential.source.selector()),
+        refusal: found.as_ref().err().map(Refusal::to_string),
+        peer,
+    };
+
+    // Nothing is disclosed without a record of it.
+    if let Err(err) = stats.audit.record(&event, app.audit.as_ref()).await {
+        eprintln!(
+            "Error: refusing credential {:?}, writing the audit record failed: {err}",
+            systemd.credential
+        );
+        stats.refused(Refusal::AuditFailed);
+        return Ok(());
+    }
+
+    let limit = std::time::Duration::from_secs_f32(app.connection_timeout);
+    let respond = respond(&mut stream, &systemd, &mut found, &app, &stats);
+
+    match tokio::time::timeout(limit, respond).await {
+        Ok(result) => result,
+        Err(_) => {
+            eprintln!(
+                "Timed out answering {} for {}",
+                systemd.service, systemd.credential
+            );
+            stats.timed_out();
+            Ok(())
         }
-        _ => return Ok(()),
     }
 }
 
+/// Resolves once the peer closed its end of the connection.
+async fn hung_up(stream: &UnixStream) {
+    let mut discard = [0; 64];
+
+    loop {
+        if stream.readable().await.is_err() {
+            return;
+        }
+
+        match stream.try_read(&mut discard) {
+            Ok(0) => return,
+            Ok(_) => continue,
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => continue,
+            Err(_) => return,
+        }
+    }
+}
+
+async fn respond(
+    stream: &mut UnixStream,
+    systemd: &SystemdUnitSource,
+    found: &mut Result<Secret, Refusal>,
+    app: &configuration::Configuration,
+    stats: &control::Stats,
+) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt as _;
+
+    let write_timeout = std::time::Duration::from_secs_f32(app.write_timeout);
+
+    let refusal = match found {
+        Ok(key) => {
+            eprintln!(
+                "Serving credential {:?} to {}",
+                systemd.credential, systemd.service
+            );
+            stats.served();
+
+            // Then send out the recovered password field entry, binary as it is.
+            let write = async {
+                stream.write_all(key).await?;
+                stream.flush().await?;
+                // The peer sees the end of the credential right away, not when we drop it.
+                stream.shutdown().await
+            };
+
+            let written = tokio::time::timeout(write_timeout, write).await;
+            // Whether it was written or not, we are done with it.
+            key.wipe();
+
+            let Ok(written) = written else {
+                eprintln!("Timed out writing credential to {}", systemd.service);
+                return Ok(());
+            };
+
+            return written;
+        }
+        &mut Err(refusal) => refusal,
+    };
+
+    stats.refused(refusal);
+    eprintln!(
+        "Refused credential={:?} unit={:?} reason={refusal}",
+        systemd.credential, systemd.service
+    );
+
+    let on_failure = app
+        .credential(&systemd.credential)
+        .map_or(configuration::OnFailure::default(), |(_, cred)| {
+            cred.on_failure
+        });
+
+    match on_failure {
+        // An explicit end of stream, with no data at all.
+        configuration::OnFailure::Close => {
+            let _ = tokio::time::timeout(write_timeout, stream.shutdown()).await;
+        }
+        // Nothing, until the peer gives up or our patience ends.
+        configuration::OnFailure::Hold => {
+            use tokio::io::AsyncReadExt as _;
+
+            let hold = std::time::Duration::from_secs_f32(app.failure_hold);
+            let mut discard = [0; 64];
+
+            let _ = tokio::time::timeout(hold, async {
+                while let Ok(1..) = stream.read(&mut discard).await {}
+            })
+            .await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Why we did not answer a request with a credential.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Refusal {
+    /// The configuration does not have this credential.
+    Unmapped,
+    /// The unit is not allowed this credential.
+    Denied,
+    /// The credential needs an instance, but the unit is not templated.
+    NoInstance,
+    /// The database is locked, and is not going to be unlocked for this request.
+    Locked,
+    /// The database was not unlocked within the time the credential waits.
+    UnlockTimeout,
+    /// No entry matches.
+    NotFound,
+    /// The entry lacks a field of the credential.
+    MissingField,
+    /// The credential is larger than we serve.
+    TooLarge,
+    /// Encrypting the credential failed.
+    SealFailed,
+    /// Writing the audit record failed.
+    AuditFailed,
+    /// The unit made more requests than the configured rate limit.
+    RateLimited,
+}
+
+impl Refusal {
+    /// Every reason, in the order of their discriminants.
+    const ALL: [Refusal; 11] = [
+        Refusal::Unmapped,
+        Refusal::Denied,
+        Refusal::NoInstance,
+        Refusal::Locked,
+        Refusal::UnlockTimeout,
+        Refusal::NotFound,
+        Refusal::MissingField,
+        Refusal::TooLarge,
+        Refusal::SealFailed,
+        Refusal::AuditFailed,
+        Refusal::RateLimited,
+    ];
+}
+
+impl core::fmt::Display for Refusal {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(match self {
+            Refusal::Unmapped => "unmapped",
+            Refusal::Denied => "denied",
+            Refusal::NoInstance => "no-instance",
+            Refusal::Locked => "locked",
+            Refusal::UnlockTimeout => "unlock-timeout",
+            Refusal::NotFound => "not-found",
+            Refusal::MissingField => "missing-field",
+            Refusal::TooLarge => "too-large",
+            Refusal::SealFailed => "seal-failed",
+            Refusal::AuditFailed => "audit-failed",
+            Refusal::RateLimited => "rate-limited",
+        })
+    }
+}
+
+/// The open databases, by the name of their store.
+///
+/// The database given on the command line has no name.
+#[derive(Clone, Default)]
+struct Stores {
+    readers: HashMap<Option<String>, pwfile::PasswordReader>,
+}
+
+impl From<pwfile::PasswordReader> for Stores {
+    fn from(reader: pwfile::PasswordReader) -> Self {
+        Stores {
+            readers: HashMap::from([(None, reader)]),
+        }
+    }
+}
+
+#[cfg(test)]
 async fn answer_request(
     systemd: &SystemdUnitSource,
-    mut store: pwfile::PasswordReader,
+    stores: impl Into<Stores>,
     app: Arc<configuration::Configuration>,
 ) -> std::io::Result<Option<Vec<u8>>> {
-    let Ok(mut unlocked) = store.as_unlocked().await else {
-        eprintln!("Store locked and not unlocking");
-        // Closing down, no more updates!
-        return Ok(None);
-    };
+    let found = lookup(systemd, &stores.into(), app, &mut None).await?;
+    Ok(found.ok().map(|key| key.to_vec()))
+}
+
+/// Find the credential the unit asks for, giving up `slot` while waiting for the unlock.
+async fn lookup(
+    systemd: &SystemdUnitSource,
+    stores: &Stores,
+    app: Arc<configuration::Configuration>,
+    slot: &mut Option<OwnedSemaphorePermit>,
+) -> std::io::Result<Result<Secret, Refusal>> {
+    if let Some(ready) = readiness(systemd, stores, &app) {
+        return Ok(ready);
+    }
 
     // Map the requested password to an internal UUID.
-    let Some(source) = app.credentials.get(&systemd.credential) else {
+    let Some((store_name, credential)) = app.credential(&systemd.credential) else {
         eprintln!("Store does not map credential {:?}", systemd.credential);
-        return Ok(None);
+        return Ok(Err(Refusal::Unmapped));
+    };
+
+    // Stores are opened at startup, a reload can not add any.
+    let Some(store) = stores.readers.get(&store_name.map(str::to_owned)) else {
+        eprintln!(
+            "Credential {:?} belongs to store {store_name:?}, which is not open",
+            systemd.credential
+        );
+        return Ok(Err(Refusal::Unmapped));
+    };
+
+    let mut store = store.clone();
+
+    // Before prompting for any unlock, the unit must be allowed to ask for it.
+    if !credential.allows(&systemd.service, app.default_deny) {
+        eprintln!(
+            "Warning: denied credential {:?} to unit {}",
+            systemd.credential, systemd.service
+        );
+        return Ok(Err(Refusal::Denied));
+    }
+
+    let Some(source) = credential.source.for_instance(systemd.instance()) else {
+        eprintln!(
+            "Credential {:?} refers to the instance, but {} is not a templated unit",
+            systemd.credential, systemd.service
+        );
+        return Ok(Err(Refusal::NoInstance));
+    };
+
+    let mut unlocked = if credential.fail_fast_when_locked {
+        let Some(unlocked) = store.try_unlocked() else {
+            eprintln!(
+                "Store locked, credential {:?} does not wait for it",
+                systemd.credential
+            );
+            return Ok(Err(Refusal::Locked));
+        };
+
+        unlocked
+    } else {
+        let requester = pwfile::Requester {
+            credential: systemd.credential.clone(),
+            service: systemd.service.clone(),
+        };
+
+        // Nobody may be around to unlock, such as at boot of a headless machine.
+        let wait = std::time::Duration::from_secs_f32(credential.wait_for_unlock);
+        // Peers that never read must not take all slots from those who can be answered.
+        if store.try_unlocked().is_none() {
+            drop(slot.take());
+        }
+
+        match tokio::time::timeout(wait, store.as_unlocked(requester)).await {
+            Ok(Ok(unlocked)) => unlocked,
+            Ok(Err(_)) => {
+                eprintln!("Store locked and not unlocking");
+                // Closing down, no more updates!
+                return Ok(Err(Refusal::Locked));
+            }
+            Err(_) => {
+                eprintln!(
+                    "Timed out waiting for unlock, credential {:?} for {}",
+                    systemd.credential, systemd.service
+                );
+                return Ok(Err(Refusal::UnlockTimeout));
+            }
+        }
     };
 
-    // Then search the password store for the UUID.
-    match source {
+    // Then search the password store for the entry.
+    let record = match &source {
         &configuration::CredentialSource::ByUuid(uuid) => {
             eprintln!("Searching store for UUID {:?}", uuid);
-            // Hm, no. Really this is a failure of the configuration? Should tell.
-            Ok(unlocked.search_by_uuid(uuid))
+            unlocked.search_by_uuid(uuid)
+        }
+        configuration::CredentialSource::ByTitle {
+            title,
+            group,
+            username,
+        } => {
+            eprintln!(
+                "Searching store for title {:?} (group {:?}, username {:?})",
+                title, group, username
+            );
+
+            let matches = unlocked.search_by_title(title, group.as_deref(), username.as_deref());
+
+            match matches {
+                Some(pwfile::Matches { first, count: 1 }) => {
+                    eprintln!(
+                        "Credential {:?} matched by title {:?}",
+                        systemd.credential, title
+                    );
+                    Some(first)
+                }
+                Some(pwfile::Matches { first, count }) => {
+                    eprintln!(
+                        "Warning: credential {:?} uses the first of {} entries titled {:?}",
+                        systemd.credential, count, title
+                    );
+                    Some(first)
+                }
+                None => None,
+            }
+        }
+    };
+
+    let Some(record) = record else {
+        return Ok(Err(Refusal::NotFound));
+    };
+
+    debug_assert!(
+        source.selects(|ty| record.field(ty)),
+        "Served an entry the credential does not select"
+    );
+
+    let key = match credential.render(|ty| record.field(ty)) {
+        Ok(key) => key,
+        Err(configuration::MissingField(field)) => {
+            eprintln!(
+                "Entry for credential {:?} has no {:?} field",
+                systemd.credential, field
+            );
+            return Ok(Err(Refusal::MissingField));
+        }
+    };
+
+    // The decrypted database is not needed while sealing.
+    drop(unlocked);
+    store.used();
+
+    let key = match credential.encrypt {
+        None => key,
+        Some(encrypt) => {
+            match seal::seal(&app.systemd_creds, encrypt, &systemd.credential, &key).await {
+                Ok(sealed) => sealed,
+                Err(err) => {
+                    eprintln!(
+                        "Error: refusing credential {:?}, encrypting it failed: {err}",
+                        systemd.credential
+                    );
+                    return Ok(Err(Refusal::SealFailed));
+                }
+            }
         }
+    };
+
+    if key.len() > app.max_credential_size {
+        eprintln!(
+            "Credential {:?} has {} bytes, more than the limit of {}",
+            systemd.credential,
+            key.len(),
+            app.max_credential_size
+        );
+        return Ok(Err(Refusal::TooLarge));
+    }
+
+    Ok(Ok(key))
+}
+
+/// Answer the `unlocked_credential`, from the lock state alone and without asking for an unlock.
+///
+/// `None` if another credential is requested.
+fn readiness(
+    systemd: &SystemdUnitSource,
+    stores: &Stores,
+    app: &configuration::Configuration,
+) -> Option<Result<Secret, Refusal>> {
+    let name = app.unlocked_credential.as_deref()?;
+
+    // Either every open store, or the one given as `store/name`.
+    let store = match systemd.credential.split_once('/') {
+        Some((store, credential)) if credential == name => Some(store),
+        None if systemd.credential == name => None,
+        _ => return None,
+    };
+
+    let mut selected = stores
+        .readers
+        .iter()
+        .filter(|(name, _)| store.is_none() || name.as_deref() == store)
+        .peekable();
+
+    if selected.peek().is_none() {
+        eprintln!("No store for credential {:?}", systemd.credential);
+        return Some(Err(Refusal::Unmapped));
     }
+
+    if !selected.all(|(_, reader)| reader.clone().try_unlocked().is_some()) {
+        return Some(Err(Refusal::Locked));
+    }
+
+    Some(Ok(Secret::copy_from(b"unlocked")))
 }
 
 struct SystemdUnitSource {
@@ -193,6 +1228,13 @@ struct SystemdUnitSource {
     credential: String,
 }
 
+impl SystemdUnitSource {
+    /// The instance, if the service is an instance of a template unit `name@instance.service`.
+    fn instance(&self) -> Option<&str> {
+        configuration::split_instance(&self.service).map(|(_, instance)| instance)
+    }
+}
+
 fn filter_by_peer_addr(stream: &UnixStream) -> Option<SystemdUnitSource> {
     use std::os::fd::AsRawFd as _;
     let fd = stream.as_raw_fd();
@@ -253,17 +1295,98 @@ fn parse_peer_addr(abstract_addr: &[u8]) -> Option<SystemdUnitSource> {
     })
 }
 
-fn verify_creds(app: &App, cred: &UCred) -> bool {
-    cred.uid() == app.uid && cred.gid() == app.gid
+fn verify_creds(app: &App, cred: &impl peer::PeerCred) -> bool {
+    let policy = peer::Policy {
+        uid: app.uid,
+        gid: app.gid,
+        pid: app.systemd_pid,
+        cgroup: app.systemd_cgroup.clone(),
+    };
+
+    policy.allows(cred, std::path::Path::new("/proc"))
+}
+
+/// Send a command to the control socket of a running daemon, as `ctl <command>`.
+#[derive(Parser)]
+pub struct Ctl {
+    #[arg(value_enum)]
+    command: CtlCommand,
+    /// The credential socket of the daemon, the control socket is next to it.
+    #[arg(default_value = "target/systemd-pwsafe-credentials.sock")]
+    socket: std::path::PathBuf,
+}
+
+/// Exit successfully only if the databases of a running daemon are unlocked, as `check`.
+///
+/// Meant for `ExecCondition=` of units that need the credentials.
+#[derive(Parser)]
+pub struct Check {
+    /// The credential socket of the daemon, the control socket is next to it.
+    #[arg(
+        long = "socket",
+        default_value = "target/systemd-pwsafe-credentials.sock"
+    )]
+    socket: std::path::PathBuf,
+    /// Only check this store, instead of all of them.
+    #[arg(long = "store")]
+    store: Option<String>,
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum CtlCommand {
+    /// Discard the decrypted database immediately.
+    Lock,
+    /// Ask for the passphrase again after giving up on wrong ones.
+    Unlock,
+    /// Print whether the database is locked and counts of requests.
+    Status,
+    /// Reread the configuration file.
+    Reload,
+    /// Print the records of the recent credential requests.
+    Audit,
 }
 
 #[derive(Parser)]
 pub struct App {
-    pwsafe: std::path::PathBuf,
+    /// The database of the top-level credentials. Optional when the configuration defines
+    /// `stores`, an empty path also omits it.
+    pwsafe: Option<std::path::PathBuf>,
+    /// The configuration, in JSON or by its extension in YAML (`.yaml`, `.yml`) or TOML (`.toml`).
     #[arg(long = "configuration")]
     configuration: std::path::PathBuf,
+    /// Only parse and validate the configuration, then exit.
+    #[arg(long = "check-config")]
+    check_config: bool,
+    /// Exit if a configured credential does not resolve to exactly one entry, once its database
+    /// is first unlocked. Otherwise this is only a warning.
+    #[arg(long = "strict-config")]
+    strict_config: bool,
+    /// The program asking for the passphrase, instead of `PWSAFE_ASKPASS`, `ASKPASS` or
+    /// `SSH_ASKPASS` from the environment.
+    #[arg(long = "askpass")]
+    askpass: Option<std::path::PathBuf>,
     #[arg(long = "no-permission-checks")]
     allow: bool,
+    /// Continue as this user, by name or uid, once the sockets are bound.
+    #[arg(long = "run-as-user")]
+    run_as_user: Option<String>,
+    /// Continue as this group, instead of the primary group of `--run-as-user`.
+    #[arg(long = "run-as-group")]
+    run_as_group: Option<String>,
+    /// Continue even if that means running as root.
+    #[arg(long = "allow-root")]
+    allow_root: bool,
+    /// Append a line of JSON for each credential request to this file, besides the configured
+    /// `audit` sink.
+    #[arg(long = "audit-log")]
+    audit_log: Option<std::path::PathBuf>,
+    /// The pid of the service manager allowed to request credentials.
+    #[arg(long = "systemd-pid", default_value = "1")]
+    systemd_pid: pid_t,
+    /// Also allow requests from processes in this cgroup, such as the `init.scope` of a user's
+    /// service manager.
+    #[arg(long = "systemd-cgroup")]
+    systemd_cgroup: Option<String>,
     #[arg(default_value = "target/systemd-pwsafe-credentials.sock")]
     socket: std::path::PathBuf,
     #[arg(default_value = "0")]
@@ -0,0 +1,99 @@
+//! Report readiness and status to systemd, see `sd_notify(3)`.
+use std::ffi::OsString;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Sends messages to the service manager, or does nothing when not run by one.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    socket: Option<Arc<(UnixDatagram, SocketAddr)>>,
+}
+
+impl Notifier {
+    pub fn from_env() -> std::io::Result<Self> {
+        match std::env::var_os("NOTIFY_SOCKET") {
+            Some(path) => Self::connect(Path::new(&path)),
+            None => Ok(Notifier::default()),
+        }
+    }
+
+    /// Notify the socket at this path, where a leading `@` denotes an abstract socket.
+    pub fn connect(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::ffi::OsStrExt as _;
+
+        let addr = match path.as_os_str().as_bytes() {
+            [b'@', name @ ..] => {
+                use std::os::linux::net::SocketAddrExt as _;
+                SocketAddr::from_abstract_name(name)?
+            }
+            _ => SocketAddr::from_pathname(path)?,
+        };
+
+        let socket = UnixDatagram::unbound()?;
+        Ok(Notifier {
+            socket: Some(Arc::new((socket, addr))),
+        })
+    }
+
+    pub fn ready(&self) {
+        self.send("READY=1");
+    }
+
+    /// Announce a reload of the configuration, to be followed by [`Self::ready`].
+    pub fn reloading(&self) {
+        match uapi::clock_gettime(uapi::c::CLOCK_MONOTONIC) {
+            Ok(now) => {
+                let usec = now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1_000;
+                self.send(&format!("RELOADING=1\nMONOTONIC_USEC={usec}"));
+            }
+            Err(_) => self.send("RELOADING=1"),
+        }
+    }
+
+    pub fn status(&self, status: &str) {
+        self.send(&format!("STATUS={status}"));
+    }
+
+    pub fn watchdog(&self) {
+        self.send("WATCHDOG=1");
+    }
+
+    fn send(&self, message: &str) {
+        let Some(socket) = &self.socket else {
+            return;
+        };
+
+        let (socket, addr) = &**socket;
+        if let Err(err) = socket.send_to_addr(message.as_bytes(), addr) {
+            eprintln!("Failed to notify service manager: {err}");
+        }
+    }
+}
+
+/// How often to pet the watchdog, half of the timeout configured for this process.
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_from(
+        std::env::var_os("WATCHDOG_USEC"),
+        std::env::var_os("WATCHDOG_PID"),
+        std::process::id(),
+    )
+}
+
+pub(crate) fn watchdog_interval_from(
+    usec: Option<OsString>,
+    pid: Option<OsString>,
+    own: u32,
+) -> Option<Duration> {
+    if let Some(pid) = pid {
+        let pid: u32 = pid.to_str()?.parse().ok()?;
+
+        if pid != own {
+            return None;
+        }
+    }
+
+    let usec: u64 = usec?.to_str()?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec / 2))
+}
@@ -0,0 +1,92 @@
+//! Decide whether a connecting peer is the service manager.
+//!
+//! The abstract address of a credential request is easily forged by any process, and any root
+//! process passes a uid and gid check. The process itself must be the service manager: pid 1 for
+//! the system instance, or a process in the cgroup of a user's manager.
+use std::path::Path;
+
+use tokio::net::unix::{gid_t, pid_t, uid_t, UCred};
+
+/// The credentials of a peer as reported by the kernel, see `SO_PEERCRED`.
+pub trait PeerCred {
+    fn uid(&self) -> uid_t;
+    fn gid(&self) -> gid_t;
+    fn pid(&self) -> Option<pid_t>;
+}
+
+/// Which peers are trusted to be the service manager.
+pub struct Policy {
+    pub uid: uid_t,
+    pub gid: gid_t,
+    pub pid: pid_t,
+    /// Also trust processes in this cgroup, or below it.
+    pub cgroup: Option<String>,
+}
+
+impl PeerCred for UCred {
+    fn uid(&self) -> uid_t {
+        UCred::uid(self)
+    }
+
+    fn gid(&self) -> gid_t {
+        UCred::gid(self)
+    }
+
+    fn pid(&self) -> Option<pid_t> {
+        UCred::pid(self)
+    }
+}
+
+impl Policy {
+    /// Check a peer, with the process information of the pid namespace mounted at `proc`.
+    pub fn allows(&self, cred: &impl PeerCred, proc: &Path) -> bool {
+        if cred.uid() != self.uid || cred.gid() != self.gid {
+            return false;
+        }
+
+        let Some(pid) = cred.pid() else {
+            return false;
+        };
+
+        if pid == self.pid {
+            return true;
+        }
+
+        let Some(expected) = &self.cgroup else {
+            return false;
+        };
+
+        cgroup(proc, pid).is_some_and(|cgroup| within(&cgroup, expected))
+    }
+}
+
+/// Name the peer process for logs, by pid and command.
+pub fn describe(cred: &impl PeerCred, proc: &Path) -> String {
+    let Some(pid) = cred.pid() else {
+        return format!("uid {} without pid", cred.uid());
+    };
+
+    let comm = std::fs::read_to_string(proc.join(pid.to_string()).join("comm"));
+    let comm = comm.as_deref().map_or("?", str::trim_end);
+    format!("pid {pid} ({comm}) uid {}", cred.uid())
+}
+
+/// The unified hierarchy cgroup of a process.
+fn cgroup(proc: &Path, pid: pid_t) -> Option<String> {
+    let cgroups = std::fs::read_to_string(proc.join(pid.to_string()).join("cgroup")).ok()?;
+
+    cgroups
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_owned)
+}
+
+/// If the `cgroup` is `expected` or one of its descendants.
+fn within(cgroup: &str, expected: &str) -> bool {
+    let expected = expected.trim_end_matches('/');
+
+    match cgroup.strip_prefix(expected) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
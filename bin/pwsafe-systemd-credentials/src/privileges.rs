@@ -0,0 +1,124 @@
+//! Give up root once the sockets are bound, and never leave decrypted secrets in a core dump.
+use std::ffi::CString;
+use std::path::PathBuf;
+
+use tokio::net::unix::{gid_t, uid_t};
+use uapi::c;
+
+/// The user and group to continue as.
+pub struct RunAs {
+    pub uid: uid_t,
+    pub gid: gid_t,
+}
+
+impl RunAs {
+    /// Resolve names or numeric ids, where the group defaults to the user's primary group.
+    pub fn new(user: Option<&str>, group: Option<&str>) -> std::io::Result<Option<Self>> {
+        let Some(user) = user else {
+            if group.is_some() {
+                return Err(invalid("--run-as-group requires --run-as-user".into()));
+            }
+
+            return Ok(None);
+        };
+
+        let (uid, primary) = resolve_user(user)?;
+        let gid = match group {
+            Some(group) => resolve_group(group)?,
+            None => primary,
+        };
+
+        Ok(Some(RunAs { uid, gid }))
+    }
+}
+
+/// Switch to `run_as`, then refuse to continue as root unless allowed, and disable core dumps.
+///
+/// Each of the `readable` files must be readable afterwards.
+pub fn drop_to(
+    run_as: Option<&RunAs>,
+    allow_root: bool,
+    readable: &[PathBuf],
+) -> std::io::Result<()> {
+    if let Some(run_as) = run_as {
+        let groups = [run_as.gid];
+        // Groups first, we can not change them anymore once we are not root.
+        check(unsafe { c::setgroups(groups.len(), groups.as_ptr()) })?;
+        check(unsafe { c::setgid(run_as.gid) })?;
+        check(unsafe { c::setuid(run_as.uid) })?;
+        eprintln!("Running as uid {} gid {}", run_as.uid, run_as.gid);
+    }
+
+    let euid = unsafe { c::geteuid() };
+
+    if euid == 0 && !allow_root {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "Refusing to run as root, give --run-as-user or --allow-root",
+        ));
+    }
+
+    // Neither core dumps nor a ptrace by the same user see the decrypted databases.
+    check(unsafe { c::prctl(c::PR_SET_DUMPABLE, 0 as c::c_ulong) })?;
+    let no_core = c::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    check(unsafe { c::setrlimit(c::RLIMIT_CORE, &no_core) })?;
+
+    for path in readable {
+        if let Err(err) = std::fs::File::open(path) {
+            let message = format!("{} is not readable as uid {euid}: {err}", path.display());
+            return Err(std::io::Error::new(err.kind(), message));
+        }
+    }
+
+    Ok(())
+}
+
+fn resolve_user(user: &str) -> std::io::Result<(uid_t, gid_t)> {
+    // Only called at startup, nothing else looks up users concurrently.
+    let entry = match user.parse::<uid_t>() {
+        Ok(uid) => unsafe { c::getpwuid(uid) },
+        Err(_) => {
+            let name = CString::new(user)?;
+            unsafe { c::getpwnam(name.as_ptr()) }
+        }
+    };
+
+    if let Some(entry) = unsafe { entry.as_ref() } {
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+
+    // A numeric id need not have an entry, and is then its own group.
+    match user.parse::<uid_t>() {
+        Ok(uid) => Ok((uid, uid)),
+        Err(_) => Err(invalid(format!("No such user {user:?}"))),
+    }
+}
+
+fn resolve_group(group: &str) -> std::io::Result<gid_t> {
+    if let Ok(gid) = group.parse::<gid_t>() {
+        return Ok(gid);
+    }
+
+    let name = CString::new(group)?;
+    let entry = unsafe { c::getgrnam(name.as_ptr()) };
+
+    match unsafe { entry.as_ref() } {
+        Some(entry) => Ok(entry.gr_gid),
+        None => Err(invalid(format!("No such group {group:?}"))),
+    }
+}
+
+fn invalid(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+fn check(result: c::c_int) -> std::io::Result<()> {
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
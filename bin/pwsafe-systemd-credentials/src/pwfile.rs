@@ -1,18 +1,100 @@
-use std::{io::Cursor, path::PathBuf, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::BuildHasher as _,
+    io::Cursor,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
 
-use pwsafer::{PwsafeKey, PwsafeReader, ReadError};
+use pwsafer::{PwsafeKey, PwsafeReader, ReadError, SecretBuffer};
 use tokio::sync::{watch, Notify};
+use tokio::time::Instant;
+
+use crate::resolve::Unresolved;
+use crate::secret::Secret;
 
 #[derive(Clone)]
 pub struct Passwords {
     inner: Arc<watch::Sender<Inner>>,
     notify: Arc<Notify>,
+    waiting: Arc<Mutex<Waiting>>,
+    path: Arc<PathBuf>,
+    /// The store of the configuration, `None` for the database on the command line.
+    name: Option<Arc<str>>,
+    /// Wrong passphrases since the last successful unlock.
+    failed: Arc<AtomicU32>,
+    /// Whether the last prompt was cancelled, see [`Self::cancelled`].
+    cancelled: Arc<AtomicBool>,
+    /// Why the file could not be decrypted regardless of the passphrase.
+    damaged: Arc<Mutex<Option<String>>>,
+    lock_stats: Arc<LockStats>,
+    /// The configured credentials without an entry, when last checked.
+    unresolved: Arc<Mutex<Vec<Unresolved>>>,
+    rearm: Arc<Notify>,
+    /// Signalled by readers for each credential served.
+    used: Arc<Notify>,
 }
 
 #[derive(Clone)]
 pub struct PasswordReader {
     inner: watch::Receiver<Inner>,
     notify: Arc<Notify>,
+    waiting: Arc<Mutex<Waiting>>,
+    used: Arc<Notify>,
+}
+
+/// Who is waiting for the database to be unlocked.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Requester {
+    pub credential: String,
+    pub service: String,
+}
+
+/// How the lock state of a database has changed so far.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Counters {
+    pub unlocks: u64,
+    pub wrong_passphrases: u64,
+    /// Locks of an unlocked database, for any reason.
+    pub relocks: u64,
+    /// The time the database spent locked.
+    pub locked: Duration,
+}
+
+struct LockStats {
+    unlocks: AtomicU64,
+    wrong_passphrases: AtomicU64,
+    relocks: AtomicU64,
+    /// Only changes when the lock state does.
+    locked: Mutex<LockedTime>,
+}
+
+struct LockedTime {
+    before: Duration,
+    /// When the database was last locked, if it is. On the clock of the runtime, like the relock
+    /// timer, so that tests with paused time see consistent durations.
+    since: Option<Instant>,
+}
+
+/// The requesters currently waiting, by order of arrival.
+#[derive(Default)]
+struct Waiting {
+    next: u64,
+    requesters: BTreeMap<u64, Requester>,
+}
+
+/// Removes a requester from the waiting ones when its request finishes or is dropped.
+struct WaitingGuard {
+    waiting: Arc<Mutex<Waiting>>,
+    id: u64,
+}
+
+/// Observes the database becoming locked, by any party.
+pub struct LockWatch {
+    inner: watch::Receiver<Inner>,
 }
 
 pub struct LockRequest<'pw> {
@@ -23,35 +105,193 @@ pub struct Unlocked<'pw> {
     inner: watch::Ref<'pw, Inner>,
 }
 
+/// The fields of one entry, in the order of the file.
+///
+/// Their data is copied back to back into a single buffer, overwritten when the record is dropped.
+pub struct Record {
+    data: Secret,
+    /// The fields by type and location in `data`.
+    fields: Vec<(u8, Range<usize>)>,
+}
+
+/// Identifies one version of the database file on disk.
+///
+/// A replacement by rename shows up as a different inode, in-place edits by size or mtime.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Stamp {
+    dev: u64,
+    ino: u64,
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+/// The entries matching a search, the first of them in the file and how many there are.
+pub struct Matches {
+    pub first: Record,
+    pub count: usize,
+}
+
+/// The smallest file: unencrypted header, end of file marker and HMAC, without any fields.
+const MIN_LENGTH: usize = 4 + 32 + 4 + 32 + 32 + 32 + 16 + 16 + 32;
+
 struct Inner {
     reader: PwsafeReader<Cursor<Vec<u8>>>,
     unlocked: bool,
+    /// Built on each unlock, so requests do not traverse the whole database.
+    index: Option<Index>,
+}
+
+/// The records of an unlocked database, searchable by uuid and title.
+struct Index {
+    /// The data of all fields back to back.
+    data: SecretBuffer,
+    /// The fields of each record, by type and location in `data`.
+    records: Vec<Vec<(u8, Range<usize>)>>,
+    by_uuid: HashMap<[u8; 16], usize>,
+    /// Records by a keyed hash of their title, the titles themselves stay in `data`.
+    by_title: HashMap<u64, Vec<usize>>,
+    hasher: std::collections::hash_map::RandomState,
 }
 
 impl Passwords {
     pub async fn new(from: PathBuf) -> std::io::Result<Self> {
-        let raw = tokio::fs::read(from).await?;
+        let raw = tokio::fs::read(&from).await?;
+        validate(&from, &raw)?;
         let reader = PwsafeReader::from_locked(Cursor::new(raw));
 
         let inner = Inner {
             reader,
             unlocked: false,
+            index: None,
         };
 
         let notify = Arc::default();
 
         let (sender, _) = watch::channel(inner);
         let inner = Arc::new(sender);
-        Ok(Passwords { inner, notify })
+        let path = Arc::new(from);
+        Ok(Passwords {
+            inner,
+            notify,
+            waiting: Arc::default(),
+            path,
+            name: None,
+            failed: Arc::default(),
+            cancelled: Arc::default(),
+            damaged: Arc::default(),
+            lock_stats: Arc::new(LockStats::new()),
+            unresolved: Arc::default(),
+            rearm: Arc::default(),
+            used: Arc::default(),
+        })
+    }
+
+    /// Name this database after its store in the configuration.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// The file the database is read from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
     }
 
     pub fn reader(&self) -> PasswordReader {
         PasswordReader {
             inner: self.inner.subscribe(),
             notify: self.notify.clone(),
+            waiting: self.waiting.clone(),
+            used: self.used.clone(),
         }
     }
 
+    pub fn watch_lock(&self) -> LockWatch {
+        LockWatch {
+            inner: self.inner.subscribe(),
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.inner.borrow().unlocked
+    }
+
+    /// The UUID in the header of the database, known while it is unlocked.
+    pub fn database_uuid(&self) -> Option<uuid::Uuid> {
+        let uuid = self.inner.borrow().reader.header().uuid()?;
+        Some(uuid::Uuid::from_bytes(uuid))
+    }
+
+    pub fn counters(&self) -> Counters {
+        let stats = &self.lock_stats;
+        let locked = stats.locked.lock().unwrap();
+
+        Counters {
+            unlocks: stats.unlocks.load(Ordering::Relaxed),
+            wrong_passphrases: stats.wrong_passphrases.load(Ordering::Relaxed),
+            relocks: stats.relocks.load(Ordering::Relaxed),
+            locked: locked.before + locked.since.map_or(Duration::ZERO, |since| since.elapsed()),
+        }
+    }
+
+    /// The number of requests waiting for the database to be unlocked.
+    pub fn waiting(&self) -> usize {
+        self.waiting.lock().unwrap().requesters.len()
+    }
+
+    /// The number of wrong passphrases since the database was last unlocked.
+    pub fn failed_attempts(&self) -> u32 {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    /// Why the database can not be unlocked with any passphrase, until the file changes.
+    pub fn damage(&self) -> Option<String> {
+        self.damaged.lock().unwrap().clone()
+    }
+
+    /// The configured credentials that did not resolve when last checked, see `resolve`.
+    pub fn unresolved(&self) -> Vec<Unresolved> {
+        self.unresolved.lock().unwrap().clone()
+    }
+
+    pub fn set_unresolved(&self, unresolved: Vec<Unresolved>) {
+        *self.unresolved.lock().unwrap() = unresolved;
+    }
+
+    /// Record that the user gave no passphrase, which is not a wrong one.
+    ///
+    /// Waiting readers are asked for again on the next request or [`Self::rearm`].
+    pub fn cancelled(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Forget about previous wrong passphrases and cancelled prompts.
+    ///
+    /// If there were any, some reader was waiting for the database and we ask again right away.
+    pub fn rearm(&self) {
+        let failed = self.failed.swap(0, Ordering::Relaxed) > 0;
+        let cancelled = self.cancelled.swap(false, Ordering::Relaxed);
+
+        if failed || cancelled {
+            self.rearm.notify_one();
+            self.notify.notify_one();
+        }
+    }
+
+    /// Wait for the next [`Self::rearm`].
+    pub async fn rearmed(&self) {
+        self.rearm.notified().await
+    }
+
+    /// Wait for the next [`PasswordReader::used`].
+    pub async fn used(&self) {
+        self.used.notified().await
+    }
+
     pub async fn as_lock_request(&self) -> Option<LockRequest<'_>> {
         self.notify.notified().await;
 
@@ -72,6 +312,8 @@ impl Passwords {
 
             inner.reader.lock();
             inner.unlocked = false;
+            inner.index = None;
+            self.lock_stats.locked();
             true
         });
     }
@@ -87,6 +329,22 @@ impl Passwords {
 
             err = inner.reader.reread(key);
             inner.unlocked |= err.is_ok();
+
+            if inner.unlocked {
+                inner.index = Some(Index::new(&inner.reader));
+                self.failed.store(0, Ordering::Relaxed);
+                self.cancelled.store(false, Ordering::Relaxed);
+                *self.damaged.lock().unwrap() = None;
+                self.lock_stats.unlocked();
+            } else if let Err(ReadError::InvalidPassword) = err {
+                self.failed.fetch_add(1, Ordering::Relaxed);
+                self.lock_stats
+                    .wrong_passphrases
+                    .fetch_add(1, Ordering::Relaxed);
+            } else if let Err(err) = &err {
+                *self.damaged.lock().unwrap() = Some(err.to_string());
+            }
+
             // Even if unlock failed, yield and 'update' the file. All interested parties will
             // retry the unlock if they still care.
             true
@@ -94,16 +352,175 @@ impl Passwords {
 
         err
     }
+
+    /// Whether pwsafe holds its lock file next to the database, `name.plk` for `name.psafe3`.
+    ///
+    /// The file may be in the middle of being written then, it is reloaded once released.
+    pub async fn is_held_by_pwsafe(&self) -> bool {
+        let lock = self.path.with_extension("plk");
+        tokio::fs::try_exists(lock).await.unwrap_or(false)
+    }
+
+    /// Read the database file again, replacing all contents.
+    ///
+    /// An unlocked database is decrypted with `key`, the one it was unlocked with. If that fails,
+    /// for instance because the passphrase was changed, the database becomes locked and the error
+    /// is returned. The new contents are swapped in at once, readers never observe a mix. A file
+    /// that is obviously not a complete database, such as one being written, is not swapped in.
+    pub async fn reload(&self, key: Option<&PwsafeKey>) -> std::io::Result<Result<(), ReadError>> {
+        let raw = tokio::fs::read(&*self.path).await?;
+        validate(&self.path, &raw)?;
+        let mut err: Result<(), ReadError> = Ok(());
+
+        self.inner.send_modify(|inner| {
+            let mut reader = PwsafeReader::from_locked(Cursor::new(raw));
+            let mut unlocked = false;
+
+            if let (true, Some(key)) = (inner.unlocked, key) {
+                err = reader.reread(key);
+                unlocked = err.is_ok();
+            }
+
+            if inner.unlocked && !unlocked {
+                self.lock_stats.locked();
+            }
+
+            let index = unlocked.then(|| Index::new(&reader));
+            inner.reader.lock();
+            *inner = Inner {
+                reader,
+                unlocked,
+                index,
+            };
+        });
+
+        // A new file deserves a new attempt, unless we can already tell it is broken.
+        *self.damaged.lock().unwrap() = match &err {
+            Err(ReadError::InvalidPassword) | Ok(()) => None,
+            Err(err) => Some(err.to_string()),
+        };
+
+        if err.is_err() {
+            // Waiting readers should not depend on another request to trigger the prompt.
+            self.notify.notify_one();
+        }
+
+        Ok(err)
+    }
+}
+
+impl LockStats {
+    /// A database starts out locked.
+    fn new() -> Self {
+        LockStats {
+            unlocks: AtomicU64::new(0),
+            wrong_passphrases: AtomicU64::new(0),
+            relocks: AtomicU64::new(0),
+            locked: Mutex::new(LockedTime {
+                before: Duration::ZERO,
+                since: Some(Instant::now()),
+            }),
+        }
+    }
+
+    fn unlocked(&self) {
+        self.unlocks.fetch_add(1, Ordering::Relaxed);
+        let mut locked = self.locked.lock().unwrap();
+
+        if let Some(since) = locked.since.take() {
+            locked.before += since.elapsed();
+        }
+    }
+
+    fn locked(&self) {
+        self.relocks.fetch_add(1, Ordering::Relaxed);
+        self.locked.lock().unwrap().since = Some(Instant::now());
+    }
+}
+
+/// Check what we can of the file without the passphrase: its tag, and a plausible length.
+fn validate(path: &Path, raw: &[u8]) -> std::io::Result<()> {
+    let invalid = |problem: &str| {
+        let message = format!("{} {problem}", path.display());
+        std::io::Error::new(std::io::ErrorKind::InvalidData, message)
+    };
+
+    if !raw.starts_with(b"PWS3") {
+        return Err(invalid("is not a pwsafe database"));
+    }
+
+    // Encrypted fields come in whole blocks.
+    if raw.len() < MIN_LENGTH || !(raw.len() - MIN_LENGTH).is_multiple_of(16) {
+        return Err(invalid("is truncated"));
+    }
+
+    Ok(())
+}
+
+impl Stamp {
+    pub async fn of(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::fs::MetadataExt as _;
+        let meta = tokio::fs::metadata(path).await?;
+
+        Ok(Stamp {
+            dev: meta.dev(),
+            ino: meta.ino(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+        })
+    }
+}
+
+impl LockWatch {
+    /// Wait for the next change that leaves the database locked.
+    pub async fn locked(&mut self) {
+        loop {
+            if self.inner.changed().await.is_err() {
+                // The database is gone and will not change anymore.
+                return core::future::pending().await;
+            }
+
+            if !self.inner.borrow_and_update().unlocked {
+                return;
+            }
+        }
+    }
+
+    /// Wait for the next change of the database, whether unlocked, reread or locked.
+    pub async fn changed(&mut self) {
+        if self.inner.changed().await.is_err() {
+            // The database is gone and will not change anymore.
+            core::future::pending().await
+        }
+    }
 }
 
 impl LockRequest<'_> {
+    /// The requests waiting for this unlock, the longest waiting first.
+    pub fn requesters(&self) -> Vec<Requester> {
+        let waiting = self.inner.waiting.lock().unwrap();
+        waiting.requesters.values().cloned().collect()
+    }
+
     pub fn unlock(self, key: &PwsafeKey) -> Result<(), ReadError> {
         self.inner.unlock(key)
     }
 }
 
 impl PasswordReader {
-    pub async fn as_unlocked(&mut self) -> Result<Unlocked<'_>, watch::error::RecvError> {
+    /// The database, if it is unlocked right now. Does not ask for an unlock.
+    pub fn try_unlocked(&mut self) -> Option<Unlocked<'_>> {
+        let inner = self.inner.borrow_and_update();
+        inner.unlocked.then_some(Unlocked { inner })
+    }
+
+    pub async fn as_unlocked(
+        &mut self,
+        requester: Requester,
+    ) -> Result<Unlocked<'_>, watch::error::RecvError> {
+        // Announce ourselves before asking for the unlock, so the prompt can name us.
+        let _waiting = WaitingGuard::new(&self.waiting, requester);
+
         let inner = self
             .inner
             .wait_for(|pw| {
@@ -118,25 +535,178 @@ impl PasswordReader {
 
         Ok(Unlocked { inner })
     }
+
+    /// Tell the unlock task that a credential was read, for relocking only when idle.
+    pub fn used(&self) {
+        self.used.notify_one();
+    }
+}
+
+impl WaitingGuard {
+    fn new(waiting: &Arc<Mutex<Waiting>>, requester: Requester) -> Self {
+        let mut list = waiting.lock().unwrap();
+        let id = list.next;
+        list.next += 1;
+        list.requesters.insert(id, requester);
+
+        WaitingGuard {
+            waiting: waiting.clone(),
+            id,
+        }
+    }
+}
+
+impl Drop for WaitingGuard {
+    fn drop(&mut self) {
+        if let Ok(mut list) = self.waiting.lock() {
+            list.requesters.remove(&self.id);
+        }
+    }
 }
 
 impl Unlocked<'_> {
-    pub fn search_by_uuid(&mut self, id: uuid::Uuid) -> Option<Vec<u8>> {
-        let mut fork = self.inner.reader.fork();
-        let mut keydata = None;
-        let mut in_matching_field = false;
-
-        while let Some((field, data)) = fork.read_field() {
-            if field == 0x1 {
-                in_matching_field = data == id.into_bytes();
+    /// Find the entry with this UUID.
+    pub fn search_by_uuid(&mut self, id: uuid::Uuid) -> Option<Record> {
+        let index = self.inner.index.as_ref()?;
+        let &record = index.by_uuid.get(&id.into_bytes())?;
+        Some(index.record(record))
+    }
+
+    /// Search the entry by its title, and group and username if given.
+    ///
+    /// If more than one matches, the caller decides whether the first of them is good enough.
+    pub fn search_by_title(
+        &mut self,
+        title: &str,
+        group: Option<&str>,
+        username: Option<&str>,
+    ) -> Option<Matches> {
+        let index = self.inner.index.as_ref()?;
+        let candidates = index
+            .by_title
+            .get(&index.hasher.hash_one(title.as_bytes()))?;
+
+        let found: Vec<usize> = index.data.with_buf(|data| {
+            let matches = |record: usize, ty: u8, expected: Option<&str>| {
+                expected.is_none_or(|expected| {
+                    index.field(record, ty).map(|range| &data[range]) == Some(expected.as_bytes())
+                })
+            };
+
+            candidates
+                .iter()
+                .copied()
+                .filter(|&record| {
+                    matches(record, 0x3, Some(title))
+                        && matches(record, 0x2, group)
+                        && matches(record, 0x4, username)
+                })
+                .collect()
+        });
+
+        let &first = found.first()?;
+
+        Some(Matches {
+            first: index.record(first),
+            count: found.len(),
+        })
+    }
+}
+
+impl Index {
+    fn new(reader: &PwsafeReader<Cursor<Vec<u8>>>) -> Self {
+        let mut index = Index {
+            data: SecretBuffer::new(),
+            records: vec![],
+            by_uuid: HashMap::new(),
+            by_title: HashMap::new(),
+            hasher: Default::default(),
+        };
+
+        // Each field is read into memory that is overwritten right after it was copied.
+        let mut fork = reader.fork();
+
+        // Without an end of header there are no records, only a truncated header.
+        loop {
+            match fork.read_field_secret() {
+                Some((0xff, _)) => break,
+                Some(_) => continue,
+                None => return index,
             }
+        }
 
-            if field == 0x6 && in_matching_field {
-                keydata = Some(data);
-                break;
+        let mut fields = vec![];
+        // A truncated record can only be the last one, it is left out.
+        while let Some((field, data)) = fork.read_field_secret() {
+            if field == 0xff {
+                index.insert(core::mem::take(&mut fields));
+                continue;
             }
+
+            let start = index.data.len();
+            index.data.extend_from_slice(&data);
+            fields.push((field, start..index.data.len()));
+        }
+
+        index
+    }
+
+    fn insert(&mut self, fields: Vec<(u8, Range<usize>)>) {
+        let id = self.records.len();
+        self.records.push(fields);
+
+        let (uuid, title) = self.data.with_buf(|data| {
+            let uuid = self
+                .field(id, 0x1)
+                .and_then(|range| <[u8; 16]>::try_from(&data[range]).ok());
+            let title = self
+                .field(id, 0x3)
+                .map(|range| self.hasher.hash_one(&data[range]));
+            (uuid, title)
+        });
+
+        // The first entry with a uuid wins, as when reading in order.
+        if let Some(uuid) = uuid {
+            self.by_uuid.entry(uuid).or_insert(id);
+        }
+
+        if let Some(title) = title {
+            self.by_title.entry(title).or_default().push(id);
         }
+    }
+
+    /// The location of the first field of this type.
+    fn field(&self, record: usize, ty: u8) -> Option<Range<usize>> {
+        self.records[record]
+            .iter()
+            .find_map(|(field, range)| (*field == ty).then(|| range.clone()))
+    }
+
+    /// A copy of the record, to be rendered into a credential.
+    fn record(&self, record: usize) -> Record {
+        let ranges = &self.records[record];
+
+        let mut len = 0;
+        let fields = ranges
+            .iter()
+            .map(|(ty, range)| {
+                len += range.len();
+                (*ty, len - range.len()..len)
+            })
+            .collect();
+
+        self.data.with_buf(|data| Record {
+            data: Secret::concat(ranges.iter().map(|(_, range)| &data[range.clone()])),
+            fields,
+        })
+    }
+}
 
-        keydata
+impl Record {
+    /// The first field of this type.
+    pub fn field(&self, ty: u8) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find_map(|(field, range)| (*field == ty).then(|| &self.data[range.clone()]))
     }
 }
@@ -0,0 +1,110 @@
+//! Find configured credentials that can not be served, before a service asks for them.
+//!
+//! Each time a database is unlocked or reread, and each time the configuration changes, every
+//! credential of its store is looked up. Those without any entry are reported in the log and the
+//! `STATUS` of the control socket.
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+use crate::{configuration, pwfile, Refusal};
+
+/// A credential that does not select an entry of its database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Unresolved {
+    pub credential: String,
+    /// So far always `NotFound`.
+    pub problem: Refusal,
+}
+
+/// Check the credentials of `store` whenever it is unlocked or reread, or the configuration changes.
+///
+/// When `strict`, fails if any credential does not resolve the first time they are checked.
+pub async fn check_credentials(
+    store: pwfile::Passwords,
+    mut cfg: watch::Receiver<Arc<configuration::Configuration>>,
+    mut strict: bool,
+) -> std::io::Result<()> {
+    let mut changes = store.watch_lock();
+    let mut reader = store.reader();
+
+    loop {
+        let current = cfg.borrow_and_update().clone();
+
+        if let Some(mut unlocked) = reader.try_unlocked() {
+            let none = HashMap::new();
+            let credentials = current.credentials_of(store.name()).unwrap_or(&none);
+            let unresolved = unresolved(&mut unlocked, credentials);
+            drop(unlocked);
+
+            for Unresolved {
+                credential,
+                problem,
+            } in &unresolved
+            {
+                eprintln!(
+                    "Warning: credential {credential:?} does not resolve in {}: {problem}",
+                    store.path().display()
+                );
+            }
+
+            if strict && !unresolved.is_empty() {
+                let message = format!(
+                    "{} configured credentials do not resolve in {}",
+                    unresolved.len(),
+                    store.path().display()
+                );
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    message,
+                ));
+            }
+
+            strict = false;
+            store.set_unresolved(unresolved);
+        }
+
+        tokio::select! {
+            () = changes.changed() => {},
+            Ok(()) = cfg.changed() => {},
+        }
+    }
+}
+
+/// The credentials which match no entry. Sorted by name.
+///
+/// Credentials referring to the instance of the requesting unit can not be checked ahead of time
+/// and are skipped.
+fn unresolved(
+    unlocked: &mut pwfile::Unlocked,
+    credentials: &HashMap<String, configuration::Credential>,
+) -> Vec<Unresolved> {
+    let mut unresolved: Vec<_> = credentials
+        .iter()
+        .filter_map(|(name, credential)| {
+            let problem = match credential.source.for_instance(None)? {
+                configuration::CredentialSource::ByUuid(uuid) => unlocked
+                    .search_by_uuid(uuid)
+                    .is_none()
+                    .then_some(Refusal::NotFound),
+                configuration::CredentialSource::ByTitle {
+                    title,
+                    group,
+                    username,
+                } => unlocked
+                    .search_by_title(&title, group.as_deref(), username.as_deref())
+                    .is_none()
+                    .then_some(Refusal::NotFound),
+            }?;
+
+            Some(Unresolved {
+                credential: name.clone(),
+                problem,
+            })
+        })
+        .collect();
+
+    unresolved.sort_by(|a, b| a.credential.cmp(&b.credential));
+    unresolved
+}
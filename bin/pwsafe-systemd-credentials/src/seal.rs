@@ -0,0 +1,69 @@
+//! Seal credentials with `systemd-creds`, so that only the TPM of this host can unseal them.
+//!
+//! Units receive them with `LoadCredentialEncrypted=`, and the plaintext never passes the socket.
+use std::path::Path;
+use std::process::Stdio;
+
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt as _;
+
+use crate::secret::Secret;
+
+/// How a credential is encrypted before it is served.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Encrypt {
+    /// Sealed to the TPM2 of this host.
+    Tpm2,
+}
+
+/// Encrypt `plain` as the credential `name`, in systemd's encrypted credential format.
+pub async fn seal(
+    program: &Path,
+    encrypt: Encrypt,
+    name: &str,
+    plain: &[u8],
+) -> std::io::Result<Secret> {
+    let with_key = match encrypt {
+        Encrypt::Tpm2 => "--with-key=tpm2",
+    };
+
+    let mut child = tokio::process::Command::new(program)
+        .arg("encrypt")
+        .arg(format!("--name={name}"))
+        .args([with_key, "-", "-"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|err| {
+            let message = format!("can not run {}: {err}", program.display());
+            std::io::Error::new(err.kind(), message)
+        })?;
+
+    let mut stdin = child.stdin.take().expect("Stdin is piped");
+    let write = async move {
+        stdin.write_all(plain).await?;
+        // Closing it ends the input.
+        drop(stdin);
+        Ok::<_, std::io::Error>(())
+    };
+
+    let (written, output) = tokio::join!(write, child.wait_with_output());
+    let output = output?;
+
+    if !output.status.success() {
+        // Such as a missing TPM, which systemd-creds explains well enough.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(std::io::Error::other(format!(
+            "{} failed with {}: {}",
+            program.display(),
+            output.status,
+            stderr.trim()
+        )));
+    }
+
+    written?;
+    Ok(output.stdout.into())
+}
@@ -0,0 +1,70 @@
+//! Plain bytes out of the database, which do not outlive their use.
+use core::ops::Deref;
+use core::sync::atomic::{compiler_fence, Ordering};
+
+/// Bytes that are overwritten with zeros before their memory is freed.
+///
+/// Never grows, so no reallocation leaves a copy behind. Formatting shows only the length, so
+/// that it can not end up in a log by accident.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn copy_from(data: &[u8]) -> Self {
+        Secret(data.to_vec())
+    }
+
+    /// Concatenate the parts, into a single allocation.
+    pub fn concat<'a>(parts: impl Iterator<Item = &'a [u8]> + Clone) -> Self {
+        let len = parts.clone().map(<[u8]>::len).sum();
+        let mut data = Vec::with_capacity(len);
+
+        for part in parts {
+            data.extend_from_slice(part);
+        }
+
+        Secret(data)
+    }
+
+    /// Overwrite all of the memory with zeros, keeping the length.
+    pub fn wipe(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Safety: a valid and aligned reference.
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+
+        for byte in self.0.spare_capacity_mut() {
+            // Safety: as above, and any byte is a valid `MaybeUninit<u8>`.
+            unsafe { core::ptr::write_volatile(byte.as_mut_ptr(), 0) };
+        }
+
+        // The writes are not elided because the memory is freed right after.
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+impl Deref for Secret {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Takes over the allocation, without a copy.
+impl From<Vec<u8>> for Secret {
+    fn from(data: Vec<u8>) -> Self {
+        Secret(data)
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl core::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Secret({} bytes)", self.0.len())
+    }
+}
@@ -0,0 +1,85 @@
+//! Drop the decrypted databases when the machine goes to sleep.
+//!
+//! Announced by logind's `PrepareForSleep` signal with the `logind` feature, and otherwise by
+//! `SIGUSR1`, for instance from a `systemd-suspend.service` hook. After resuming, the next request
+//! asks for the passphrase as usual.
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::signal::unix::Signal;
+use tokio::sync::watch;
+
+use crate::{configuration, pwfile};
+
+/// Announcements of sleep, `true` before the machine sleeps and `false` after it resumed.
+pub trait PrepareForSleep {
+    fn next(&mut self) -> impl Future<Output = Option<bool>>;
+}
+
+/// Every signal announces sleep, there is none for resuming.
+impl PrepareForSleep for Signal {
+    async fn next(&mut self) -> Option<bool> {
+        self.recv().await.map(|()| true)
+    }
+}
+
+/// Lock all stores whenever `events` announce sleep, if the configuration asks for it.
+///
+/// The unlock tasks notice and cancel their relock timers.
+pub async fn lock_on_sleep(
+    mut events: impl PrepareForSleep,
+    stores: Vec<pwfile::Passwords>,
+    cfg: watch::Receiver<Arc<configuration::Configuration>>,
+) {
+    while let Some(start) = events.next().await {
+        if !start || !cfg.borrow().lock_on_suspend {
+            continue;
+        }
+
+        eprintln!("Locking before the system sleeps");
+        stores.iter().for_each(pwfile::Passwords::lock);
+    }
+}
+
+#[cfg(feature = "logind")]
+pub mod logind {
+    use futures_util::StreamExt as _;
+
+    /// The `PrepareForSleep` signals of logind on the system bus.
+    pub struct Logind {
+        messages: zbus::MessageStream,
+    }
+
+    impl Logind {
+        pub async fn connect() -> zbus::Result<Self> {
+            let connection = zbus::Connection::system().await?;
+            let rule = zbus::MatchRule::builder()
+                .msg_type(zbus::message::Type::Signal)
+                .sender("org.freedesktop.login1")?
+                .path("/org/freedesktop/login1")?
+                .interface("org.freedesktop.login1.Manager")?
+                .member("PrepareForSleep")?
+                .build();
+
+            let messages = zbus::MessageStream::for_match_rule(rule, &connection, None).await?;
+            Ok(Logind { messages })
+        }
+    }
+
+    impl super::PrepareForSleep for Logind {
+        async fn next(&mut self) -> Option<bool> {
+            while let Some(message) = self.messages.next().await {
+                let Ok(message) = message else {
+                    continue;
+                };
+
+                match message.body().deserialize::<bool>() {
+                    Ok(start) => return Some(start),
+                    Err(err) => eprintln!("Malformed PrepareForSleep signal: {err}"),
+                }
+            }
+
+            None
+        }
+    }
+}
@@ -0,0 +1,1204 @@
+//! Create a diff, a local chain of edits, from a previous and new version of a pwsafe file.
+//!
+//! The CRDT works as follows: each change has a definitive timestamps the message communicating
+//! the diff. This is assigned by the associated homeserver. (So, for one homeserver it doesn't
+//! behave as much like a parallel CRDT but oh well). The full history of changes ordered by these
+//! timestamps defines the shared, remote state. On top of this remote state, a local set of
+//! changes can be applied which will be treated as always being ordered after all remote changes.
+//!
+//! The two operations to communicate state:
+//! - Consuming remote changes, which are applied to the shared state (and then we recover the new
+//!   local state by also applying the local changes as if *after* the remote timestamp).
+//! - Publishing local changes, atomically diffs the local state against the shared state and
+//!   pushes that changeset into the homeserver. The latter operation empties the local changeset
+//!   so that the new state after the transaction consists precisely of the shared state.
+//!
+//!   Since publishing changes to the homeserver might fail, this one will is tricky to do
+//!   atomically.
+use core::fmt;
+use core::ops::Range;
+use std::collections::{HashMap, HashSet, hash_map::Entry};
+use std::io::{Read, Write};
+
+use eyre::Report;
+use pwsafer::{PwsafeHeader, PwsafeReader, PwsafeHeaderField, PwsafeRecordField, PwsafeWriter};
+use pwsafer::SecretField;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::redacted::Redacted;
+
+/// The shape of the records as of the last diff, to find what changed since.
+///
+/// Holds salted hashes of the fields, never their values. The salt, the pepper, is local to each
+/// database and part of every diff created from it.
+#[derive(Default, Clone, PartialEq)]
+pub struct DiffableBase {
+    pepper: Box<[u8; 16]>,
+    fields: Vec<FieldMark>,
+    entries: HashMap<Uuid, Range<usize>>,
+}
+
+#[derive(Default, Clone)]
+pub struct RecordDescriptor {
+    pub uuid: Uuid,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Clone)]
+pub struct Field {
+    pub pwsafe: PwsafeRecordField,
+    raw_ty: u8,
+    /// Overwritten when dropped, working copies of the database hold every password.
+    raw_data: SecretField,
+    mark: FieldMark,
+}
+
+/// A decrypted database in memory, to apply several diffs without encrypting it in between.
+///
+/// See [`Diff::apply_plain`]. Holds the records up to the first without a UUID, as far as the
+/// diffs read a database.
+pub struct Plain {
+    header: PwsafeHeader,
+    records: Vec<RecordDescriptor>,
+    /// Whether a record without a UUID ends the records, [`DiffableBase::visit_plain`] fails.
+    missing_uuid: bool,
+}
+
+/// Changes to the records of a database: whole records deleted, and fields set or deleted.
+///
+/// Encoded as a [`DiffSerial`] by [`Self::serialize`].
+#[derive(Clone)] // Represents an empty diff.
+pub struct Diff {
+    pub pepper: Box<[u8; 16]>,
+    pub delete: HashSet<Uuid>,
+    pub edit: HashMap<Uuid, DiffEdit>,
+}
+
+/// One specific edit applied to a DB record.
+#[derive(Default, Clone)] // Represents an empty diff.
+pub struct DiffEdit {
+    set: HashMap<u8, Vec<u8>>,
+    delete: HashSet<u8>,
+}
+
+/// A diff as it is sent in room events and to the control server of a sync.
+///
+/// The encoding of [`Diff::serialize`] and [`DiffableBase::deserialize`]. It does not hold the
+/// pepper of any database, so other programs can construct it. Unknown keys are rejected rather
+/// than ignored, a diff is applied as a whole or not at all.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffSerial {
+    /// Records removed entirely.
+    pub delete: HashSet<Uuid>,
+    /// Changes to the fields of records, creating those that do not exist.
+    pub edit: HashMap<Uuid, DiffEditSerial>,
+}
+
+/// The changes to one record in a [`DiffSerial`].
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DiffEditSerial {
+    /// New values by field type. Encoded as base64 strings, arrays of bytes are also accepted.
+    #[serde(with = "field_values")]
+    pub set: HashMap<u8, Vec<u8>>,
+    /// Field types to remove from the record.
+    pub delete: HashSet<u8>,
+}
+
+/// The result of [`DiffableBase::visit`].
+pub struct Update {
+    pub new_base: DiffableBase,
+    pub diff: Diff,
+    /// The internal state record.
+    ///
+    /// This record is ignored by the diff algorithm itself, but consumed by the loader of the
+    /// program and any part restoring the encoded state from the fields contained in it.
+    pub state_record: RecordDescriptor,
+}
+
+/// The structure of a database, see [`DiffableBase::audit`].
+#[derive(Default)]
+pub struct Audit {
+    /// The number of records, including our state record.
+    pub records: usize,
+    /// The number of records without a UUID field.
+    pub missing_uuid: usize,
+    /// Each UUID that identifies more than one record.
+    pub duplicate_uuid: Vec<Uuid>,
+    /// The internal state record, as in [`Update::state_record`].
+    pub state_record: RecordDescriptor,
+}
+
+/// How a remote edit is kept when a pending local edit sets the same field, see [`Diff::apply`].
+///
+/// Local edits always apply after the remote state, so theirs is the value in the file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum ConflictPolicy {
+    /// The remote value is lost.
+    #[default]
+    Overwrite,
+    /// Append the remote value to the notes of the record.
+    Note,
+    /// Keep the record with the remote values as a copy, with a ` (conflict)` title suffix.
+    Duplicate,
+}
+
+/// The group of records created by diffs without a title, see [`Diff::apply`].
+///
+/// Groups of pwsafe nest by dots, this is below the group of the internal state record.
+pub const INCOMING_GROUP: &str = "pwsafe-matrix.incoming";
+
+/// The modification times written into the records a diff changes, see [`Diff::apply`].
+///
+/// Times the diff sets itself are kept, as the pwsafe GUI does set them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Modified {
+    /// Write the records as the diff sets them.
+    Keep,
+    /// The clock of this system.
+    Now,
+    /// The timestamp of the room event in milliseconds, the same on every device.
+    At(u64),
+}
+
+/// The conflicts of remote diffs with pending local ones, collected by [`Diff::apply`].
+pub struct Conflicts<'local> {
+    policy: ConflictPolicy,
+    /// The local diffs which are applied after the remote ones.
+    local: Vec<&'local Diff>,
+    /// The timestamp of the remote diff being applied, in milliseconds.
+    pub at: u64,
+    /// Keeps the remote values, to be applied after all local diffs.
+    resolution: Diff,
+}
+
+/// A human readable rendering of a [`Diff`], see [`Diff::render`].
+pub struct Rendered<'diff> {
+    diff: &'diff Diff,
+    show_secrets: bool,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+struct FieldMark {
+    /// The field type, also part of the hash. Kept to know which fields were deleted.
+    ty: u8,
+    hash: [u8; 32],
+}
+
+/// The records and base found so far by [`DiffableBase::visit`], one record at a time.
+struct Visit {
+    new_base: DiffableBase,
+    /// The records of the base not seen yet, deleted unless they come up.
+    prior_keys: HashSet<Uuid>,
+    diff: Diff,
+    state_record: RecordDescriptor,
+}
+
+/// The records a diff is applied to, those of a database or [`Plain`] ones.
+trait RecordSource {
+    /// The next record and its UUID, `None` at the end or at a record without one.
+    fn next_record(
+        &mut self,
+        entry: &mut RecordDescriptor,
+        pepper: &[u8; 16],
+    ) -> Result<Option<Uuid>, Report>;
+}
+
+/// Where the records with a diff applied go, an encrypted database or [`Plain`] records.
+trait RecordSink {
+    fn write_field(&mut self, ty: u8, data: &[u8]) -> Result<(), Report>;
+
+    /// Write a record the diff does not change, it is not used afterwards.
+    fn copy_record(&mut self, _: Uuid, entry: &mut RecordDescriptor) -> Result<(), Report> {
+        for field in &entry.fields {
+            self.write_field(field.raw_ty, &field.raw_data)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Collects the written fields into records, as a reader of them would find them.
+struct PlainRecords<'pepper> {
+    pepper: &'pepper [u8; 16],
+    records: Vec<RecordDescriptor>,
+    current: RecordDescriptor,
+    current_uuid: Option<Uuid>,
+    missing_uuid: bool,
+}
+
+impl DiffableBase {
+    /// This UUID is associated with the project, as a namespace UUID for UUIDv5.
+    ///
+    /// ```
+    /// $ uuidgen --name "https://github.com/HeroicKatora/pwsafe-matrix" -n "@dns" --sha1
+    /// f8052080-99ed-53ef-8f44-ae5621b31f46
+    /// ```
+    ///
+    /// Exists for mostly documentation purposes, we do not derive from this at runtime.
+    #[allow(dead_code)]
+    const BASE_UUID: Uuid = Uuid::from_bytes(*b"\xf8\x05\x20\x80\
+                                             \x99\xed\
+                                             \x53\xef\
+                                             \x8f\x44\
+                                             \xae\x56\x21\xb3\x1f\x46");
+
+    /// This UUID identifies the entry containing the V1 state of our CRDT.
+    /// ```
+    /// $ uuidgen --name "pwsafe-matrix-crdt-v1" -n "f8052080-99ed-53ef-8f44-ae5621b31f46" --sha1
+    /// 02e4d75b-5fde-582e-b10d-409f041c3d34
+    /// ```
+    ///
+    /// Might switch to const-derivation from `BASE_UUID` at a later point.
+    pub(crate) const CRDT_STATE: Uuid = Uuid::from_bytes(*b"\x02\xe4\xd7\x5b\
+                                              \x5f\xde\
+                                              \x58\x2e\
+                                              \xb1\x0d\
+                                              \x40\x9f\x04\x1c\x3d\x34");
+
+    /// Compare all records of the database to this base.
+    ///
+    /// The resulting [`Update`] holds the diff from this base to the records, and the base to
+    /// compare to the next time. Our own state record is never part of the diff, it is returned
+    /// separately. Fails if a record has no UUID, such a database can not be diffed.
+    pub fn visit(&self, reader: &mut PwsafeReader<impl Read>) -> Result<Update, Report> {
+        reader.restart();
+        Self::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let mut visit = Visit::new(self);
+        let mut entry = RecordDescriptor::default();
+
+        while let Some(uuid) = Self::fill_entry(reader, &mut entry, &self.pepper)? {
+            visit.record(uuid, &entry);
+        }
+
+        visit.finish(!entry.fields.is_empty())
+    }
+
+    /// Visit records in memory, as [`Self::visit`] does those of a database.
+    pub fn visit_plain(&self, plain: &Plain) -> Result<Update, Report> {
+        let mut visit = Visit::new(self);
+
+        for record in &plain.records {
+            visit.record(record.uuid, record);
+        }
+
+        visit.finish(plain.missing_uuid)
+    }
+
+    /// Walk all records, collecting the structural problems instead of stopping at the first.
+    ///
+    /// Unlike [`Self::visit`] this continues past records without a UUID, and reports UUIDs which
+    /// identify more than one record.
+    pub fn audit(reader: &mut PwsafeReader<impl Read>) -> Result<Audit, Report> {
+        reader.restart();
+        Self::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let pepper = <[u8; 16]>::default();
+        let mut audit = Audit::default();
+        let mut seen = HashSet::new();
+        let mut entry = RecordDescriptor::default();
+
+        loop {
+            let uuid = Self::fill_entry(reader, &mut entry, &pepper)?;
+
+            // A record without any fields is the end of the database.
+            if entry.fields.is_empty() {
+                break;
+            }
+
+            audit.records += 1;
+
+            let Some(uuid) = uuid else {
+                audit.missing_uuid += 1;
+                continue;
+            };
+
+            if !seen.insert(uuid) && !audit.duplicate_uuid.contains(&uuid) {
+                audit.duplicate_uuid.push(uuid);
+            }
+
+            if uuid == Self::CRDT_STATE {
+                core::mem::swap(&mut audit.state_record, &mut entry);
+            }
+        }
+
+        Ok(audit)
+    }
+
+    /// Decode a diff in the encoding of [`DiffSerial`], to apply it to a database with this base.
+    pub fn deserialize(&self, edit: serde_json::Value) -> Result<Diff, Report> {
+        let inner: DiffSerial = serde_json::from_value(edit)?;
+        Ok(self.diff_of(inner))
+    }
+
+    /// A diff already decoded, to apply it to a database with this base.
+    pub fn diff_of(&self, inner: DiffSerial) -> Diff {
+        Diff {
+            pepper: self.pepper.clone(),
+            delete: inner.delete,
+            edit: inner.edit
+                .into_iter()
+                .map(|(uuid, e)| {
+                    let e = DiffEdit {
+                        set: e.set
+                            .into_iter()
+                            .collect(),
+                        delete: e.delete,
+                    };
+
+                    (uuid, e)
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn skip_header<E>(
+        reader: &mut PwsafeReader<impl Read>,
+        mut with: impl FnMut(u8, &[u8]) -> Result<(), E>,
+    ) -> Result<(), Report>
+        where Report: From<E>,
+    {
+        while let Some((ty, data)) = reader.read_field()? {
+            with(ty, &data)?;
+
+            let field = PwsafeHeaderField::new(ty, data)?;
+            if matches!(field, PwsafeHeaderField::EndOfHeader) {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn fill_entry(
+        reader: &mut PwsafeReader<impl Read>,
+        entry: &mut RecordDescriptor,
+        pepper: &[u8; 16],
+    ) -> Result<Option<Uuid>, Report> {
+        let mut field_uuid = None;
+        *entry = RecordDescriptor::default();
+
+        loop {
+            match reader.read_field_secret() {
+                Some((field, data)) => {
+                    let field = Field::new(field, data, pepper)?;
+
+                    if let &PwsafeRecordField::Uuid(uuid) = &field.pwsafe {
+                        field_uuid = Some(Uuid::from_bytes(uuid));
+                    }
+
+                    let eof = matches!(field.pwsafe, PwsafeRecordField::EndOfRecord);
+                    entry.fields.push(field);
+
+                    if eof {
+                        break;
+                    }
+                },
+                None => {
+                    break;
+                }
+            }
+        }
+
+        entry.uuid = field_uuid.unwrap_or_default();
+        Ok(field_uuid)
+    }
+}
+
+impl Diff {
+    pub fn empty(base: &DiffableBase) -> Self {
+        Diff {
+            pepper: base.pepper.clone(),
+            delete: Default::default(),
+            edit: Default::default(),
+        }
+    }
+
+    /// The number of distinct records this diff edits or deletes.
+    pub fn records(&self) -> usize {
+        let deleted = self.delete.iter().filter(|uuid| !self.edit.contains_key(uuid)).count();
+        self.edit.len() + deleted
+    }
+
+    /// Create a diff which sets all fields of all entries contained in the reader.
+    pub fn snapshot(
+        base: &DiffableBase,
+        reader: &mut PwsafeReader<impl Read>,
+    ) -> Result<Self, Report> {
+        reader.restart();
+        DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let mut diff = Diff::empty(base);
+        let mut entry = RecordDescriptor::default();
+
+        while let Some(uuid) = DiffableBase::fill_entry(reader, &mut entry, &base.pepper)? {
+            if uuid == DiffableBase::CRDT_STATE {
+                continue;
+            }
+
+            let edit = diff.edit.entry(uuid).or_default();
+            for field in &entry.fields {
+                if field.raw_ty == 0xff {
+                    continue;
+                }
+
+                edit.set.insert(field.raw_ty, field.raw_data.to_vec());
+            }
+        }
+
+        Ok(diff)
+    }
+
+    /// Encode the diff in the same schema that is accepted by [`DiffableBase::deserialize`].
+    ///
+    /// The pepper is local to each database and deliberately not part of the encoding. The value
+    /// is a [`DiffSerial`].
+    pub fn serialize(&self) -> Result<serde_json::Value, Report> {
+        let serial = DiffSerial {
+            delete: self.delete.clone(),
+            edit: self.edit
+                .iter()
+                .map(|(uuid, e)| {
+                    let e = DiffEditSerial {
+                        set: e.set.clone(),
+                        delete: e.delete.clone(),
+                    };
+
+                    (*uuid, e)
+                })
+                .collect(),
+        };
+
+        Ok(serde_json::to_value(serial)?)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.delete.is_empty() && self.edit.is_empty()
+    }
+
+    /// Display the diff for a human, one line per record and field.
+    ///
+    /// Field values are redacted unless `show_secrets` is set. Our own state record is never
+    /// shown, it contains the Matrix session.
+    pub fn render(&self, show_secrets: bool) -> Rendered<'_> {
+        Rendered {
+            diff: self,
+            show_secrets,
+        }
+    }
+
+    pub fn add_state(&mut self, state: String) {
+        let edit = self.edit
+            .entry(DiffableBase::CRDT_STATE)
+            .or_default();
+
+        edit.set.insert(0x05, state.into_bytes());
+        edit.set.insert(0x04, "dummy".to_string().into_bytes());
+        edit.set.insert(0x03, "dummy".to_string().into_bytes());
+        edit.set.insert(0x02, "pwsafe-matrix".to_string().into_bytes());
+    }
+
+    /// Set the modification times of all records this diff changes, see [`Modified`].
+    pub fn stamp(&mut self, modified: Modified) {
+        for (uuid, edit) in &mut self.edit {
+            modified.stamp(*uuid, edit);
+        }
+    }
+
+    /// Write the database of the reader, with the diff applied, into the writer.
+    ///
+    /// The header is that of the reader, see [`Self::apply_records`] for the records.
+    pub fn apply(
+        &self,
+        reader: &mut PwsafeReader<impl Read>,
+        writer: &mut PwsafeWriter<impl Write>,
+        conflicts: Option<&mut Conflicts<'_>>,
+        modified: Modified,
+        incoming_group: &str,
+    ) -> Result<(), Report> {
+        writer.write_header(reader.header())?;
+        self.apply_records(reader, writer, conflicts, modified, incoming_group)
+    }
+
+    /// Write the records of the reader, with the diff applied, into a writer given its header.
+    ///
+    /// With `conflicts`, fields set differently by the pending local diffs are recorded according
+    /// to its policy. Changed records get their modification times from `modified`. Records that
+    /// the diff creates without a title get one from their UUID, in the `incoming_group` unless the
+    /// diff sets a group. Other clients would otherwise not show them.
+    pub fn apply_records(
+        &self,
+        reader: &mut PwsafeReader<impl Read>,
+        writer: &mut PwsafeWriter<impl Write>,
+        conflicts: Option<&mut Conflicts<'_>>,
+        modified: Modified,
+        incoming_group: &str,
+    ) -> Result<(), Report> {
+        reader.restart();
+        DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+        self.apply_to(reader, writer, conflicts, modified, incoming_group)
+    }
+
+    /// Apply the diff to records in memory, as [`Self::apply`] does to those of a database.
+    ///
+    /// The header is kept. Applying diffs one after another this way only decodes each field
+    /// once, instead of encrypting and reading back a database for each.
+    pub fn apply_plain(
+        &self,
+        plain: Plain,
+        conflicts: Option<&mut Conflicts<'_>>,
+        modified: Modified,
+        incoming_group: &str,
+    ) -> Result<Plain, Report> {
+        let mut records = PlainRecords::new(&self.pepper);
+        let mut source = plain.records.into_iter();
+        self.apply_to(&mut source, &mut records, conflicts, modified, incoming_group)?;
+        Ok(records.finish(plain.header))
+    }
+
+    /// Write records in memory, with the diff applied, into a writer given its header.
+    ///
+    /// As [`Self::apply_records`], but for records of [`Plain`].
+    pub fn apply_plain_records(
+        &self,
+        plain: Plain,
+        writer: &mut PwsafeWriter<impl Write>,
+        conflicts: Option<&mut Conflicts<'_>>,
+        modified: Modified,
+        incoming_group: &str,
+    ) -> Result<(), Report> {
+        let mut source = plain.records.into_iter();
+        self.apply_to(&mut source, writer, conflicts, modified, incoming_group)
+    }
+
+    fn apply_to(
+        &self,
+        source: &mut impl RecordSource,
+        sink: &mut impl RecordSink,
+        mut conflicts: Option<&mut Conflicts<'_>>,
+        modified: Modified,
+        incoming_group: &str,
+    ) -> Result<(), Report> {
+        let mut entry = RecordDescriptor::default();
+        let mut edits = self.edit.clone();
+
+        while let Some(uuid) = source.next_record(&mut entry, &self.pepper)? {
+            if self.delete.contains(&uuid) {
+                continue;
+            }
+
+            let Some(mut edit) = edits.remove(&uuid) else {
+                sink.copy_record(uuid, &mut entry)?;
+                continue;
+            };
+
+            if let Some(conflicts) = conflicts.as_deref_mut() {
+                conflicts.detect(uuid, &edit, &entry.fields);
+            }
+
+            modified.stamp(uuid, &mut edit);
+
+            let mut eof_written = false;
+            for (raw_ty, raw_data) in &edit.set {
+                tracing::trace!(%uuid, field = raw_ty, value = ?Redacted(raw_data), "Setting field");
+                eof_written |= *raw_ty == 0xff;
+                sink.write_field(*raw_ty, raw_data)?;
+            }
+
+            for field in &entry.fields {
+                if field.raw_ty != 0xff && edit.delete.contains(&field.raw_ty) {
+                    continue;
+                }
+
+                if field.raw_ty != 0xff && edit.set.contains_key(&field.raw_ty) {
+                    continue;
+                }
+
+                eof_written |= field.raw_ty == 0xff;
+                sink.write_field(field.raw_ty, &field.raw_data)?;
+            }
+
+            if !eof_written {
+                sink.write_field(0xff, &[])?;
+            }
+        }
+
+        for (uuid, mut remote_missing) in edits {
+            if let Some(conflicts) = conflicts.as_deref_mut() {
+                conflicts.detect(uuid, &remote_missing, &[]);
+            }
+
+            remote_missing.repair_title(uuid, incoming_group);
+            modified.stamp(uuid, &mut remote_missing);
+
+            sink.write_field(0x01, uuid.as_bytes())?;
+            for (raw_ty, raw_data) in remote_missing.set {
+                if raw_ty == 0x01 {
+                    continue;
+                }
+
+                if raw_ty == 0xff {
+                    continue;
+                }
+
+                tracing::trace!(%uuid, field = raw_ty, value = ?Redacted(&raw_data), "Setting field");
+                sink.write_field(raw_ty, &raw_data)?;
+            }
+            sink.write_field(0xff, &[])?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Plain {
+    /// Read the header and records of a database, marking fields with the pepper of the base.
+    pub fn read(
+        reader: &mut PwsafeReader<impl Read>,
+        base: &DiffableBase,
+    ) -> Result<Self, Report> {
+        reader.restart();
+        DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let mut records = vec![];
+        let mut entry = RecordDescriptor::default();
+
+        while DiffableBase::fill_entry(reader, &mut entry, &base.pepper)?.is_some() {
+            records.push(core::mem::take(&mut entry));
+        }
+
+        Ok(Plain {
+            header: reader.header().clone(),
+            records,
+            missing_uuid: !entry.fields.is_empty(),
+        })
+    }
+
+    pub fn header(&self) -> &PwsafeHeader {
+        &self.header
+    }
+}
+
+impl Visit {
+    fn new(base: &DiffableBase) -> Self {
+        let mut prior_keys: HashSet<_> = base.entries.keys().cloned().collect();
+        prior_keys.remove(&DiffableBase::CRDT_STATE);
+
+        Visit {
+            new_base: base.clone(),
+            prior_keys,
+            diff: Diff::empty(base),
+            state_record: RecordDescriptor::default(),
+        }
+    }
+
+    fn record(&mut self, uuid: Uuid, entry: &RecordDescriptor) {
+        let new_base = &mut self.new_base;
+
+        // We do not diff the UUID state itself.
+        if uuid == DiffableBase::CRDT_STATE {
+            self.state_record = entry.clone();
+            return;
+        }
+
+        self.prior_keys.remove(&uuid);
+
+        match new_base.entries.entry(uuid) {
+            Entry::Occupied(mut occupied) => {
+                let range = occupied.get().clone();
+                let edit = DiffEdit::between(&new_base.fields[range.clone()], &entry.fields);
+
+                if !edit.is_empty() {
+                    self.diff.edit.insert(uuid, edit);
+                }
+
+                // Reuse the space of the previous marks if the number of fields is the same.
+                if range.len() == entry.fields.len() {
+                    let marks = entry.fields.iter().map(|f| f.mark);
+                    for (old, new) in new_base.fields[range].iter_mut().zip(marks) {
+                        *old = new;
+                    }
+                } else {
+                    let start = new_base.fields.len();
+                    new_base.fields.extend(entry.fields.iter().map(|f| f.mark));
+                    let end = new_base.fields.len();
+                    occupied.insert(start..end);
+                }
+            },
+            Entry::Vacant(vacant) => {
+                let start = new_base.fields.len();
+                new_base.fields.extend(entry.fields.iter().map(|f| f.mark));
+                let end = new_base.fields.len();
+                vacant.insert(start..end);
+            },
+        }
+    }
+
+    fn finish(self, missing_uuid: bool) -> Result<Update, Report> {
+        let Visit { mut new_base, prior_keys, mut diff, state_record } = self;
+
+        // We've removed all entries that are still present. Everything not removed has been
+        // deleted in the new version of the DB.
+        for uuid in &prior_keys {
+            new_base.entries.remove(uuid);
+        }
+
+        diff.delete.extend(prior_keys);
+
+        if missing_uuid {
+            return Err(eyre::Report::msg("Database contains record without mandatory UUID field"))?;
+        }
+
+        Ok(Update {
+            new_base,
+            diff,
+            state_record,
+        })
+    }
+}
+
+impl<R: Read> RecordSource for PwsafeReader<R> {
+    fn next_record(
+        &mut self,
+        entry: &mut RecordDescriptor,
+        pepper: &[u8; 16],
+    ) -> Result<Option<Uuid>, Report> {
+        DiffableBase::fill_entry(self, entry, pepper)
+    }
+}
+
+impl RecordSource for std::vec::IntoIter<RecordDescriptor> {
+    fn next_record(&mut self, entry: &mut RecordDescriptor, _: &[u8; 16])
+        -> Result<Option<Uuid>, Report>
+    {
+        // The records of `Plain` all have a UUID, and their fields the same pepper.
+        *entry = self.next().unwrap_or_default();
+        Ok(Some(entry.uuid).filter(|_| !entry.fields.is_empty()))
+    }
+}
+
+impl<W: Write> RecordSink for PwsafeWriter<W> {
+    fn write_field(&mut self, ty: u8, data: &[u8]) -> Result<(), Report> {
+        Ok(PwsafeWriter::write_field(self, ty, data)?)
+    }
+}
+
+impl RecordSink for PlainRecords<'_> {
+    fn write_field(&mut self, ty: u8, data: &[u8]) -> Result<(), Report> {
+        // A reader stops at a record without a UUID, and so do we.
+        if self.missing_uuid {
+            return Ok(());
+        }
+
+        let field = Field::new(ty, SecretField::copy_from(data), self.pepper)?;
+
+        if let &PwsafeRecordField::Uuid(uuid) = &field.pwsafe {
+            self.current_uuid = Some(Uuid::from_bytes(uuid));
+        }
+
+        let eof = matches!(field.pwsafe, PwsafeRecordField::EndOfRecord);
+        self.current.fields.push(field);
+
+        if eof {
+            self.end_record();
+        }
+
+        Ok(())
+    }
+
+    fn copy_record(&mut self, uuid: Uuid, entry: &mut RecordDescriptor) -> Result<(), Report> {
+        let complete = matches!(entry.fields.last(), Some(field) if field.raw_ty == 0xff);
+
+        // Unchanged records are taken as they are, without decoding their fields again.
+        if complete && self.current.fields.is_empty() && !self.missing_uuid {
+            let mut record = core::mem::take(entry);
+            record.uuid = uuid;
+            self.records.push(record);
+            return Ok(());
+        }
+
+        for field in &entry.fields {
+            self.write_field(field.raw_ty, &field.raw_data)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'pepper> PlainRecords<'pepper> {
+    fn new(pepper: &'pepper [u8; 16]) -> Self {
+        PlainRecords {
+            pepper,
+            records: vec![],
+            current: RecordDescriptor::default(),
+            current_uuid: None,
+            missing_uuid: false,
+        }
+    }
+
+    fn end_record(&mut self) {
+        let mut record = core::mem::take(&mut self.current);
+
+        match self.current_uuid.take() {
+            Some(uuid) => {
+                record.uuid = uuid;
+                self.records.push(record);
+            },
+            None => self.missing_uuid = true,
+        }
+    }
+
+    fn finish(mut self, header: PwsafeHeader) -> Plain {
+        // The last record may end with the data instead of its end field.
+        if !self.current.fields.is_empty() && !self.missing_uuid {
+            self.end_record();
+        }
+
+        Plain {
+            header,
+            records: self.records,
+            missing_uuid: self.missing_uuid,
+        }
+    }
+}
+
+impl Field {
+    fn new(raw_ty: u8, raw_data: SecretField, pepper: &[u8; 16]) -> Result<Self, Report> {
+        let mark = FieldMark::new(raw_ty, &raw_data, pepper);
+        let pwsafe = PwsafeRecordField::new(raw_ty, raw_data.to_vec())?;
+
+        Ok(Field {
+            pwsafe,
+            raw_ty,
+            raw_data,
+            mark,
+        })
+    }
+}
+
+impl DiffEdit {
+    /// The edit turning a record with the previous marks into one with the new fields.
+    fn between(previous: &[FieldMark], fields: &[Field]) -> Self {
+        let mut edit = DiffEdit::default();
+
+        for field in fields {
+            if field.raw_ty == 0xff {
+                continue;
+            }
+
+            if !previous.contains(&field.mark) {
+                edit.set.insert(field.raw_ty, field.raw_data.to_vec());
+            }
+        }
+
+        for mark in previous {
+            if mark.ty != 0xff && fields.iter().all(|field| field.raw_ty != mark.ty) {
+                edit.delete.insert(mark.ty);
+            }
+        }
+
+        edit
+    }
+
+    fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.delete.is_empty()
+    }
+
+    /// Set a field, replacing the value it had.
+    pub fn set(&mut self, field: &PwsafeRecordField) {
+        let (ty, data) = field.to_raw();
+        self.delete.remove(&ty);
+        self.set.insert(ty, data);
+    }
+
+    /// Give a record created by this edit a title, and a group, if it does not set them.
+    ///
+    /// The title only depends on the UUID, so that each device synthesizes the same one.
+    fn repair_title(&mut self, uuid: Uuid, group: &str) {
+        if self.set.contains_key(&0x03) {
+            return;
+        }
+
+        let short = uuid.simple().to_string();
+        tracing::warn!(%uuid, group, "Record without a title, filing it as incoming");
+        self.set.insert(0x03, format!("synced-{}", &short[..8]).into_bytes());
+        self.set.entry(0x02).or_insert_with(|| group.as_bytes().to_vec());
+    }
+}
+
+impl Modified {
+    /// The time in seconds since the epoch, as pwsafe stores it.
+    fn time(self) -> Option<u32> {
+        let ms = match self {
+            Modified::Keep => return None,
+            Modified::Now => {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+                now.map_or(0, |since| since.as_millis().try_into().unwrap_or(u64::MAX))
+            },
+            Modified::At(ms) => ms,
+        };
+
+        Some(u32::try_from(ms / 1000).unwrap_or(u32::MAX))
+    }
+
+    /// Set the modification time of a record the edit changes, and that of its password if the
+    /// edit sets one.
+    fn stamp(self, uuid: Uuid, edit: &mut DiffEdit) {
+        // Rewritten with every change of the state, its times would only ever differ.
+        if uuid == DiffableBase::CRDT_STATE {
+            return;
+        }
+
+        let change = |ty: &u8| !matches!(ty, 0x08 | 0x0c | 0xff);
+        let changes = edit.set.keys().any(change) || edit.delete.iter().any(change);
+
+        let Some(time) = self.time().filter(|_| changes) else {
+            return;
+        };
+
+        let time = time.to_le_bytes().to_vec();
+        if edit.set.contains_key(&0x06) {
+            edit.set.entry(0x08).or_insert_with(|| time.clone());
+        }
+
+        edit.set.entry(0x0c).or_insert(time);
+    }
+}
+
+impl<'local> Conflicts<'local> {
+    pub fn new(policy: ConflictPolicy, local: Vec<&'local Diff>, base: &DiffableBase) -> Self {
+        Conflicts {
+            policy,
+            local,
+            at: 0,
+            resolution: Diff::empty(base),
+        }
+    }
+
+    /// The diff keeping the remote values, to apply after all local diffs.
+    pub fn into_resolution(self) -> Diff {
+        self.resolution
+    }
+
+    /// Record the fields of a remote edit that the local diffs set to another value.
+    ///
+    /// Our own diffs are received back from the room, a local diff setting the same value is thus
+    /// not a conflict.
+    fn detect(&mut self, uuid: Uuid, edit: &DiffEdit, fields: &[Field]) {
+        if self.policy == ConflictPolicy::Overwrite || uuid == DiffableBase::CRDT_STATE {
+            return;
+        }
+
+        // The modification times differ with any two edits, they are no conflict of their own.
+        let mut lost: Vec<_> = edit.set
+            .iter()
+            .filter(|(ty, _)| !matches!(ty, 0x01 | 0x08 | 0x0c | 0xff))
+            .filter(|&(ty, value)| {
+                let mut local = self.local
+                    .iter()
+                    .filter_map(|diff| diff.edit.get(&uuid)?.set.get(ty))
+                    .peekable();
+                local.peek().is_some() && local.all(|local| local != value)
+            })
+            .collect();
+
+        if lost.is_empty() {
+            return;
+        }
+
+        lost.sort_by_key(|(ty, _)| **ty);
+
+        for (ty, _) in &lost {
+            tracing::warn!(%uuid, field = **ty, at = self.at, "Remote edit conflicts with a local one");
+        }
+
+        match self.policy {
+            ConflictPolicy::Overwrite => {},
+            ConflictPolicy::Note => {
+                let mut notes = self.notes(uuid, edit, fields);
+
+                for (&ty, value) in lost {
+                    if !notes.is_empty() {
+                        notes.push(b'\n');
+                    }
+
+                    let value = String::from_utf8_lossy(value);
+                    let line = format!("conflicted value for {} at {}: {value}", FieldName(ty), self.at);
+                    notes.extend_from_slice(line.as_bytes());
+                }
+
+                self.resolution.edit.entry(uuid).or_default().set.insert(0x05, notes);
+            },
+            ConflictPolicy::Duplicate => {
+                let mut digest = Sha256::new();
+                digest.update(uuid.as_bytes());
+                digest.update(self.at.to_be_bytes());
+                let mut bytes = [0; 16];
+                bytes.copy_from_slice(&digest.finalize()[..16]);
+                let copy = uuid::Builder::from_custom_bytes(bytes).into_uuid();
+
+                let mut set: HashMap<u8, Vec<u8>> = fields
+                    .iter()
+                    .filter(|field| !matches!(field.raw_ty, 0x01 | 0xff))
+                    .filter(|field| !edit.delete.contains(&field.raw_ty))
+                    .map(|field| (field.raw_ty, field.raw_data.to_vec()))
+                    .collect();
+
+                set.extend(edit.set
+                    .iter()
+                    .filter(|(ty, _)| !matches!(ty, 0x01 | 0xff))
+                    .map(|(&ty, value)| (ty, value.clone())));
+
+                set.entry(0x03).or_default().extend_from_slice(b" (conflict)");
+                self.resolution.edit.insert(copy, DiffEdit { set, delete: HashSet::new() });
+            },
+        }
+    }
+
+    /// The notes of the record once the local diffs and previous conflicts are applied.
+    fn notes(&self, uuid: Uuid, edit: &DiffEdit, fields: &[Field]) -> Vec<u8> {
+        let resolved = self.resolution.edit.get(&uuid).and_then(|edit| edit.set.get(&0x05));
+        let local = self.local
+            .iter()
+            .rev()
+            .find_map(|diff| diff.edit.get(&uuid)?.set.get(&0x05));
+        let remote = edit.set.get(&0x05);
+        let existing = fields
+            .iter()
+            .find(|field| field.raw_ty == 0x05 && !edit.delete.contains(&0x05))
+            .map(|field| &*field.raw_data);
+
+        let pending = resolved.or(local).or(remote).map(Vec::as_slice);
+        pending.or(existing).map(<[u8]>::to_vec).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Rendered<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut delete: Vec<_> = self.diff.delete.iter().collect();
+        delete.sort();
+
+        for uuid in delete {
+            writeln!(f, "- {uuid}")?;
+        }
+
+        let mut edit: Vec<_> = self.diff.edit.iter()
+            .filter(|(uuid, _)| **uuid != DiffableBase::CRDT_STATE)
+            .collect();
+        edit.sort_by_key(|(uuid, _)| **uuid);
+
+        for (uuid, edit) in edit {
+            writeln!(f, "~ {uuid}")?;
+
+            let mut set: Vec<_> = edit.set.iter().collect();
+            set.sort_by_key(|(ty, _)| **ty);
+
+            for (&ty, data) in set {
+                if self.show_secrets {
+                    writeln!(f, "    set {}: {}", FieldName(ty), String::from_utf8_lossy(data))?;
+                } else {
+                    writeln!(f, "    set {}: {}", FieldName(ty), Redacted(data))?;
+                }
+            }
+
+            let mut delete: Vec<_> = edit.delete.iter().collect();
+            delete.sort();
+
+            for &ty in delete {
+                writeln!(f, "    delete {}", FieldName(ty))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The name of a record field type, as in the format specification.
+struct FieldName(u8);
+
+impl fmt::Display for FieldName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self.0 {
+            0x01 => "uuid",
+            0x02 => "group",
+            0x03 => "title",
+            0x04 => "username",
+            0x05 => "notes",
+            0x06 => "password",
+            0x07 => "creation-time",
+            0x08 => "password-modification-time",
+            0x09 => "last-access-time",
+            0x0a => "password-expiry-time",
+            0x0c => "last-modification-time",
+            0x0d => "url",
+            0x0e => "autotype",
+            0x0f => "password-history",
+            0x10 => "password-policy",
+            0x11 => "password-expiry-interval",
+            0x12 => "run-command",
+            0x13 => "double-click-action",
+            0x14 => "email",
+            0x15 => "protected",
+            0x16 => "own-symbols",
+            0x17 => "shift-double-click-action",
+            0x18 => "password-policy-name",
+            0x19 => "keyboard-shortcut",
+            other => return write!(f, "field-{other:#04x}"),
+        };
+
+        f.write_str(name)
+    }
+}
+
+/// The encoding of field values in a serialized diff, base64 strings.
+///
+/// Arrays of numbers, as written by earlier versions, are still accepted.
+mod field_values {
+    use std::collections::HashMap;
+
+    use base64::{engine::general_purpose::STANDARD, Engine as _};
+    use serde::{de::Error as _, Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(set: &HashMap<u8, Vec<u8>>, ser: S) -> Result<S::Ok, S::Error> {
+        ser.collect_map(set.iter().map(|(ty, data)| (ty, STANDARD.encode(data))))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<HashMap<u8, Vec<u8>>, D::Error> {
+        HashMap::<u8, Value>::deserialize(de)?
+            .into_iter()
+            .map(|(ty, value)| match value {
+                Value::Base64(encoded) => STANDARD
+                    .decode(encoded)
+                    .map(|data| (ty, data))
+                    .map_err(|err| D::Error::custom(format!("field {ty}: {err}"))),
+                Value::Bytes(data) => Ok((ty, data)),
+            })
+            .collect()
+    }
+}
+
+impl FieldMark {
+    fn new(ty: u8, data: &[u8], pepper: &[u8; 16]) -> Self {
+        let mut digest = Sha256::new();
+        digest.update(pepper);
+        digest.update(b"\x00");
+        digest.update(ty.to_be_bytes());
+        digest.update(b"\x01");
+        digest.update(data);
+        let hash = digest.finalize().into();
+
+        FieldMark { ty, hash }
+    }
+}
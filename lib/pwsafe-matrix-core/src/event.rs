@@ -0,0 +1,13 @@
+//! The room event carrying a diff.
+use matrix_sdk::ruma::events::macros::EventContent;
+use serde::{Deserialize, Serialize};
+
+/// A diff, as encoded by [`Diff::serialize`](crate::diff::Diff::serialize).
+///
+/// A custom event type, so chat clients in the room do not display it. It is encrypted like any
+/// other event of the room.
+#[derive(Clone, Debug, Deserialize, Serialize, EventContent)]
+#[ruma_event(type = "de.pwsafe.matrix.diff", kind = MessageLike)]
+pub struct DiffEventContent {
+    pub diff: serde_json::Value,
+}
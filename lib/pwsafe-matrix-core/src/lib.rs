@@ -0,0 +1,26 @@
+//! The engine of `pwsafe-matrix`: diffs between versions of a pwsafe database, and the database
+//! with the state of its synchronization.
+//!
+//! A [`Diff`] is computed against a [`DiffableBase`], the remembered shape of the shared state, see
+//! [`DiffableBase::visit`]. On the wire, in room events and on the control server of a sync, a
+//! diff is a [`DiffSerial`]. Tools talking to that server construct those directly.
+//!
+//! With the default `matrix` feature, [`PwsafeDb`] holds an opened database with the Matrix
+//! session and pending diffs stored in it, and guards writes with the lock file of pwsafe.
+pub mod diff;
+#[cfg(feature = "matrix")]
+pub mod event;
+// Not using a crate, we want to mirror the pwsafe functionality here. In particular, exclusive
+// flags and the contents should be close to the original if possible.
+pub mod lockfile;
+#[cfg(feature = "matrix")]
+pub mod pwsafe;
+mod redacted;
+#[cfg(feature = "matrix")]
+pub mod store;
+#[cfg(all(test, feature = "matrix"))]
+mod tests;
+
+pub use crate::diff::{Diff, DiffEdit, DiffEditSerial, DiffSerial, DiffableBase};
+#[cfg(feature = "matrix")]
+pub use crate::pwsafe::{ArgsPwsafe, PwsafeDb, PwsafeLock, Timestamp};
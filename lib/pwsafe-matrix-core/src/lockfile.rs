@@ -1,5 +1,6 @@
+use core::fmt;
 use std::path::PathBuf;
-use std::{fs, io::Write as _};
+use std::{fs, io, io::Write as _};
 
 use eyre::Report;
 
@@ -13,6 +14,18 @@ pub struct LockFile {
     path: PathBuf,
 }
 
+/// What to do about an existing lock file, see [`LockFile::create_or_takeover`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Takeover {
+    /// Fail, as [`LockFile::create`] does.
+    #[default]
+    Never,
+    /// Replace the lock of a process on this host that is no longer running.
+    Stale,
+    /// Replace any lock.
+    Always,
+}
+
 impl LockFile {
     pub fn create(path: PathBuf, info: &UserInfo) -> Result<Self, Report> {
         let mut options = fs::OpenOptions::new();
@@ -26,7 +39,7 @@ impl LockFile {
             std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
         }
 
-        // FIXME: error diagnosis for EEXIST could be done.
+        // Diagnosis of EEXIST is done by `create_or_takeover`.
         let mut file = options.open(&path)?;
 
         // pwsafe's handling of the identifier written to the lock file here is rather obscure. It
@@ -36,7 +49,7 @@ impl LockFile {
         // reported from each of 5 different calls and requires that the total is `> 0`.
         //
         // Let's do better.
-        write!(file, "{}@{}:{}", info.user, info.host, info.pid)?;
+        write!(file, "{info}")?;
         // File handle itself can be closed now.
         drop(file);
 
@@ -44,6 +57,57 @@ impl LockFile {
             path,
         })
     }
+
+    /// Create the lock file, replacing an existing one if the policy allows.
+    ///
+    /// A process that crashed leaves its lock file behind, which would otherwise need to be
+    /// removed by hand. Only one takeover is attempted.
+    pub fn create_or_takeover(path: PathBuf, info: &UserInfo, policy: Takeover)
+        -> Result<Self, Report>
+    {
+        let err = match Self::create(path.clone(), info) {
+            Ok(lock) => return Ok(lock),
+            Err(err) => err,
+        };
+
+        let exists = err
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::AlreadyExists);
+
+        if !exists || policy == Takeover::Never {
+            return Err(err);
+        }
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            // Released in the meantime.
+            Err(read) if read.kind() == io::ErrorKind::NotFound => return Self::create(path, info),
+            Err(_) => return Err(err),
+        };
+
+        let stale = match policy {
+            Takeover::Never => false,
+            Takeover::Stale => UserInfo::parse(&contents).is_some_and(|owner| owner.is_gone(info)),
+            Takeover::Always => true,
+        };
+
+        if !stale {
+            return Err(err);
+        }
+
+        tracing::warn!(
+            path = %path.display(),
+            owner = contents.trim(),
+            "Taking over the lock of a stale owner",
+        );
+
+        match fs::remove_file(&path) {
+            Err(remove) if remove.kind() != io::ErrorKind::NotFound => return Err(remove.into()),
+            _ => {},
+        }
+
+        Self::create(path, info)
+    }
 }
 
 impl Drop for LockFile {
@@ -52,7 +116,55 @@ impl Drop for LockFile {
     }
 }
 
+impl fmt::Display for UserInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}@{}:{}", self.user, self.host, self.pid)
+    }
+}
+
 impl UserInfo {
+    /// The name of the user running this process.
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    /// The host this process runs on.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Read the owner from the contents of a lock file, see [`LockFile::create`].
+    fn parse(contents: &str) -> Option<Self> {
+        let (user, rest) = contents.trim().split_once('@')?;
+        let (host, pid) = rest.rsplit_once(':')?;
+
+        Some(UserInfo {
+            user: user.to_owned(),
+            host: host.to_owned(),
+            pid: pid.parse().ok()?,
+        })
+    }
+
+    /// Whether this is a process on the host of `us` that no longer exists.
+    ///
+    /// Processes of other hosts, or of other users which we may not signal, are never gone.
+    fn is_gone(&self, us: &UserInfo) -> bool {
+        if self.host != us.host {
+            return false;
+        }
+
+        let Ok(pid) = uapi::c::pid_t::try_from(self.pid) else {
+            return false;
+        };
+
+        // Signal 0 only checks for the existence of the process.
+        if pid <= 0 || unsafe { uapi::c::kill(pid, 0) } == 0 {
+            return false;
+        }
+
+        io::Error::last_os_error().raw_os_error() == Some(uapi::c::ESRCH)
+    }
+
     pub fn new() -> Result<Self, Report> {
         let pid = {
             let pid_c = uapi::getpid();
@@ -0,0 +1,985 @@
+use crate::diff::{Audit, ConflictPolicy, Conflicts, Diff, DiffSerial, DiffableBase, Modified};
+use crate::diff::{Plain, RecordDescriptor, INCOMING_GROUP};
+use crate::lockfile::{LockFile, Takeover, UserInfo};
+use crate::store::PwsafeStore;
+
+use std::{io, fs};
+use std::collections::{HashMap, VecDeque};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
+use eyre::Report;
+
+use matrix_sdk::matrix_auth::MatrixSession;
+use matrix_sdk::ruma::{OwnedRoomAliasId, OwnedRoomId};
+use pwsafer::{PwsafeHeader, PwsafeKey, PwsafeReader, PwsafeWriter, PwsafeRecordField};
+use pwsafer::SecretField;
+use pwsafer::MIN_ITER;
+use pwsafer::policy::Policy;
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+use tempfile::NamedTempFile;
+use uuid::Uuid;
+
+/// What performed the last save, in the header of files we write.
+const APPLICATION: &str = concat!("pwsafe-matrix V", env!("CARGO_PKG_VERSION"));
+
+/// The database and how to unlock it: with a key file, a password, or both.
+///
+/// With both, the key is the contents of the key file followed by the password, see
+/// [`combined_key`].
+#[derive(Debug)]
+pub struct ArgsPwsafe {
+    /// The path of the database file.
+    pub pwsafe: OsString,
+    /// A file holding the key, or its first part.
+    pub passwd_file: Option<OsString>,
+    /// The password, or the part of the key after the contents of the key file.
+    pub passwd: Option<String>,
+}
+
+/// An opened database, with the state of its synchronization stored in it.
+///
+/// Holds the shared state of the room, the local diffs on top of it, and the working copy as it
+/// was last read from or written to the file. The file itself is only modified while holding
+/// its lock, see [`Self::with_lock`].
+pub struct PwsafeDb {
+    /// Cached version of the state as encoded, might be defaulted.
+    state: State,
+    remote: PwsafeReader<io::Cursor<Vec<u8>>>,
+    /// The local edits between the synchronized shared state received from the room.
+    local_diff: VecDeque<Diff>,
+    /// The number of local edits, from the front, already sent into the room.
+    published: usize,
+    /// The key, derived from the password and not yet salted & iterated.
+    ///
+    /// Used for reading and writing but does not contain the secret phrase itself.
+    key: PwsafeKey,
+    /// Runtime representation of the differential engine representing the state of the password
+    /// file.
+    local_diff_base: DiffableBase,
+    store: PwsafeStore,
+    reader_working_copy: PwsafeReader<io::Cursor<Vec<u8>>>,
+    path: PathBuf,
+    lock: PathBuf,
+    userinfo: UserInfo,
+    /// The UUID in the header of the file, if it has one.
+    header_uuid: Option<Uuid>,
+    /// How remote edits overwritten by our pending ones are kept.
+    conflict_policy: ConflictPolicy,
+    /// The group of records that diffs create without a title.
+    incoming_group: String,
+    /// The hash of the file as last read or written, to skip re-reading it when unchanged.
+    fingerprint: [u8; 32],
+    /// The hash of the crypto store as last read or written, see [`Self::store_changed`].
+    store_written: [u8; 32],
+    /// Whether to replace the lock file left behind by another process.
+    lock_takeover: Takeover,
+    /// The iterations of key stretching of the file as last read or written.
+    file_iter: u32,
+}
+
+/// The raw fields of a record, by their type.
+pub type Fields = HashMap<u8, Vec<u8>>;
+
+/// The password policy to generate a password with, see [`PwsafeDb::generate_password`].
+#[derive(Clone, Debug)]
+pub enum PolicySource {
+    /// The named policy the record refers to, or else its own.
+    Record,
+    /// A named policy of the database.
+    Named(String),
+    Inline(Policy),
+}
+
+/// Why no password was generated.
+#[derive(Debug)]
+pub enum PasswordRejected {
+    /// The working copy has no record with the UUID.
+    UnknownRecord,
+    /// There is no such policy, or no password can follow it.
+    Policy(String),
+}
+
+/// The findings of [`PwsafeDb::verify`], never containing any secrets.
+#[derive(Serialize, Debug)]
+pub struct Verification {
+    pub records: usize,
+    pub missing_uuid: usize,
+    pub duplicate_uuid: Vec<Uuid>,
+    pub state: StateCheck,
+    pub session: bool,
+    pub room: bool,
+    pub last_saved: LastSaved,
+}
+
+/// Who last saved the file, as its header tells.
+#[derive(Serialize, Debug, Default)]
+pub struct LastSaved {
+    /// In seconds since the epoch.
+    pub timestamp: Option<u32>,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    /// The program and its version.
+    pub application: Option<String>,
+}
+
+/// Whether the state record of pwsafe-matrix could be read.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", tag = "status", content = "error")]
+pub enum StateCheck {
+    /// Not a pwsafe-matrix file, or one that was never synchronized.
+    Missing,
+    Valid,
+    /// The class and position of the JSON error. Not its message, which might quote the session.
+    Invalid(String),
+}
+
+/// The position of a remote diff in the shared history of the room.
+///
+/// Serialized into the state record of the database, to resume from the last applied event.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    /// The relative timestamp order of the event.
+    pub ts_ms: u64,
+    /// A unique identifier for that event.
+    pub unique: String,
+}
+
+/// A pwsafe db file, holding a lock.
+///
+/// Allows running operations that would otherwise race, such as modifying the underlying file.
+/// The lock file is removed when this is dropped. Dereferences to the database.
+pub struct PwsafeLock<'lt> {
+    inner: &'lt mut PwsafeDb,
+    /// Held for RAII purposes, protects our lock state.
+    ///
+    /// Contains a handle to the lockfile path, which we might be interested in? I don't know.
+    #[allow(dead_code)]
+    lockfile: LockFile,
+}
+
+impl PwsafeDb {
+    /// Read and decrypt the database, restoring the state of its synchronization.
+    ///
+    /// Does not take the lock, a database that another program is writing is read as it is on
+    /// disk. Fails if the key does not match, or the state record can not be decoded.
+    pub fn open(args: &ArgsPwsafe) -> Result<Self, Report> {
+        let (key, mut reader, fingerprint) = Self::read_file(args)?;
+
+        let header_uuid = reader.header().uuid().map(Uuid::from_bytes);
+
+        let (state, local_diff_base, local_diff, store) = Self::read_state(&mut reader)?;
+        let userinfo = UserInfo::new()?;
+        let store_written = Self::hash_store(&store)?;
+
+        let file_iter = reader.get_iter();
+        if file_iter < MIN_ITER {
+            tracing::warn!(
+                "The file stretches its key only {file_iter} times, rewriting it with {MIN_ITER}"
+            );
+        }
+
+        let remote = {
+            let mut write_data = io::Cursor::new(vec![]);
+            let mut writer = PwsafeWriter::with_iterations(&mut write_data, file_iter, &key)?;
+            writer.reserve(reader.data_len())?;
+
+            reader.restart();
+            DiffableBase::skip_header(&mut reader, |ty, data| {
+                writer.write_field(ty, data)
+            })?;
+
+            writer.finish()?;
+
+            write_data.set_position(0);
+            PwsafeReader::new(write_data, &key).unwrap()
+        };
+
+        let reader_working_copy = {
+            let mut write_data = io::Cursor::new(vec![]);
+            let mut writer = PwsafeWriter::with_iterations(&mut write_data, file_iter, &key)?;
+            writer.reserve(reader.data_len())?;
+
+            let diff = Diff::empty(&local_diff_base);
+            diff.apply(&mut reader, &mut writer, None, Modified::Keep, INCOMING_GROUP)?;
+            writer.finish()?;
+
+            write_data.set_position(0);
+            PwsafeReader::new(write_data, &key).unwrap()
+        };
+
+        let path = Path::new(&args.pwsafe).to_path_buf();
+        let lock = Self::lock_file_name(&path);
+
+        Ok(PwsafeDb {
+            state,
+            remote,
+            local_diff: [local_diff].into_iter().filter(|diff| !diff.is_empty()).collect(),
+            published: 0,
+            key,
+            local_diff_base,
+            store,
+            reader_working_copy,
+            path,
+            lock,
+            userinfo,
+            header_uuid,
+            conflict_policy: ConflictPolicy::default(),
+            incoming_group: INCOMING_GROUP.to_string(),
+            fingerprint,
+            store_written,
+            lock_takeover: Takeover::Never,
+            file_iter,
+        })
+    }
+
+    /// Like [`Self::open`], without stalling the other tasks of the runtime.
+    ///
+    /// Reading and decrypting the file blocks, it is moved off the worker. Requires the
+    /// multi-threaded runtime.
+    pub async fn open_async(args: &ArgsPwsafe) -> Result<Self, Report> {
+        tokio::task::block_in_place(|| Self::open(args))
+    }
+
+    /// Check the structure of the database and our state within it, without modifying either.
+    ///
+    /// Opening the file verifies its HMAC. Other problems are collected in the result, where
+    /// [`Self::open`] would fail on the first.
+    pub fn verify(args: &ArgsPwsafe) -> Result<Verification, Report> {
+        let (_, mut reader, _) = Self::read_file(args)?;
+        let audit = DiffableBase::audit(&mut reader)?;
+
+        let (state, check) = if audit.state_record.fields.is_empty() {
+            (State::default(), StateCheck::Missing)
+        } else {
+            match Self::state_from_record(&audit.state_record) {
+                Ok(state) => (state, StateCheck::Valid),
+                Err(err) => (State::default(), StateCheck::invalid(&err)),
+            }
+        };
+
+        let Audit { records, missing_uuid, duplicate_uuid, .. } = audit;
+        let header = reader.header();
+
+        Ok(Verification {
+            records,
+            missing_uuid,
+            duplicate_uuid,
+            state: check,
+            session: state.session.is_some(),
+            room: state.room.is_some(),
+            last_saved: LastSaved::new(header),
+        })
+    }
+
+    fn read_file(args: &ArgsPwsafe)
+        -> Result<(PwsafeKey, PwsafeReader<io::Cursor<Vec<u8>>>, [u8; 32]), Report>
+    {
+        let key_file = args.passwd_file.as_ref().map(fs::read).transpose()?.map(SecretField::from);
+        let passwd = args.passwd.as_deref().map(str::as_bytes);
+
+        let data = fs::read(&args.pwsafe)?;
+        let fingerprint = Sha256::digest(&data).into();
+        let key = combined_key(key_file.as_deref(), passwd);
+        let reader = PwsafeReader::new(io::Cursor::new(data), &key)?;
+
+        Ok((key, reader, fingerprint))
+    }
+
+    /// Decode a diff in the encoding of [`Diff::serialize`], for this database.
+    pub fn diff(&self, value: serde_json::Value) -> Result<Diff, Report> {
+        self.local_diff_base.deserialize(value)
+    }
+
+    /// Like [`Self::diff`], for a diff that was decoded already.
+    pub fn diff_serial(&self, serial: DiffSerial) -> Diff {
+        self.local_diff_base.diff_of(serial)
+    }
+
+    /// A diff without edits, to fill and then [`PwsafeLock::apply`].
+    pub fn empty_diff(&self) -> Diff {
+        Diff::empty(&self.local_diff_base)
+    }
+
+    /// Run `f` while holding the lock file of the database, as pwsafe itself does when saving.
+    ///
+    /// Fails without calling `f` if another program holds the lock, unless it may be taken over,
+    /// see [`Self::set_lock_takeover`]. The lock is released when `f` returns, successful or not.
+    pub fn with_lock<V>(&mut self, f: impl FnOnce(PwsafeLock) -> Result<V, Report>)
+        -> Result<V, Report>
+    {
+        let lockfile = LockFile::create_or_takeover(
+            self.lock.clone(),
+            &self.userinfo,
+            self.lock_takeover,
+        )?;
+
+        f(PwsafeLock {
+            inner: self,
+            lockfile,
+        })
+    }
+
+    /// Like [`Self::with_lock`], without stalling the other tasks of the runtime.
+    ///
+    /// The whole critical section reads, encrypts and writes the file, it runs with the lock held
+    /// but off the worker. Requires the multi-threaded runtime.
+    pub async fn with_lock_async<V>(&mut self, f: impl FnOnce(PwsafeLock) -> Result<V, Report>)
+        -> Result<V, Report>
+    {
+        tokio::task::block_in_place(|| self.with_lock(f))
+    }
+
+    pub fn session(&self) -> Option<&MatrixSession> {
+        self.state.session.as_ref()
+    }
+
+    pub fn set_session(&mut self, session: MatrixSession) {
+        self.state.session = Some(session);
+    }
+
+    /// Forget the session, together with the crypto state of its device.
+    pub fn clear_session(&mut self) -> Option<MatrixSession> {
+        self.store = PwsafeStore::new_empty();
+        self.state.session.take()
+    }
+
+    pub fn room(&self) -> Option<&OwnedRoomId> {
+        self.state.room.as_ref()
+    }
+
+    pub fn set_room(&mut self, room: OwnedRoomId) {
+        self.state.room = Some(room);
+    }
+
+    pub fn room_alias(&self) -> Option<&OwnedRoomAliasId> {
+        self.state.alias.as_ref()
+    }
+
+    pub fn set_room_alias(&mut self, alias: OwnedRoomAliasId) {
+        self.state.alias = Some(alias);
+    }
+
+    /// Identifies the shared database, the same for all files synchronized through the room.
+    ///
+    /// That of the file that created the room, which is its header UUID until a room is joined.
+    pub fn namespace(&self) -> Option<Uuid> {
+        self.state.namespace.or(self.header_uuid)
+    }
+
+    pub fn set_namespace(&mut self, namespace: Uuid) {
+        self.state.namespace = Some(namespace);
+    }
+
+    /// The key of the file, for authenticating data exchanged outside of the room.
+    pub fn key(&self) -> &PwsafeKey {
+        &self.key
+    }
+
+    /// Use the key of a new password, after the file was changed to it by another program.
+    ///
+    /// The copies of the file kept in memory are encrypted again, later rewrites use the key.
+    pub fn set_key(&mut self, key: PwsafeKey) -> Result<(), Report> {
+        self.remote = reencrypt(&mut self.remote, &key)?;
+        self.reader_working_copy = reencrypt(&mut self.reader_working_copy, &key)?;
+        self.key = key;
+        Ok(())
+    }
+
+    /// Whether [`Self::with_lock`] replaces a lock file that another program left behind.
+    pub fn set_lock_takeover(&mut self, takeover: Takeover) {
+        self.lock_takeover = takeover;
+    }
+
+    pub fn set_conflict_policy(&mut self, policy: ConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    pub fn set_incoming_group(&mut self, group: String) {
+        self.incoming_group = group;
+    }
+
+    /// Link the database to another room.
+    ///
+    /// The history of the previous room is not relevant to the new one, all events in the new room
+    /// are considered anew. Local edits not yet received back from the previous room are
+    /// published again.
+    pub fn migrate_room(&mut self, room: OwnedRoomId, alias: Option<OwnedRoomAliasId>) {
+        self.state.room = Some(room);
+        self.state.alias = alias;
+        self.state.remote_until = None;
+        self.published = 0;
+    }
+
+    /// Unlink the database from its room.
+    ///
+    /// As with [`Self::migrate_room`], local edits are published again to any room linked later.
+    pub fn clear_room(&mut self) -> Option<OwnedRoomId> {
+        self.state.alias = None;
+        self.state.remote_until = None;
+        self.published = 0;
+        self.state.room.take()
+    }
+
+    /// A diff which recreates all entries of the current file from an empty database.
+    pub fn snapshot(&mut self) -> Result<Diff, Report> {
+        Diff::snapshot(&self.local_diff_base, &mut self.reader_working_copy)
+    }
+
+    pub fn remote_until(&self) -> Option<&Timestamp> {
+        self.state.remote_until.as_ref()
+    }
+
+    /// Whether the lock file of the database exists, taken by us or another program.
+    pub fn lock_exists(&self) -> bool {
+        self.lock.exists()
+    }
+
+    /// Who last saved the file, as last read or written.
+    pub fn last_saved(&self) -> LastSaved {
+        LastSaved::new(self.reader_working_copy.header())
+    }
+
+    /// Find a record in the file as it is on disk.
+    pub fn entry(&self, uuid: Uuid) -> Result<Option<Fields>, Report> {
+        let file = fs::File::open(&self.path)?;
+        let mut reader = PwsafeReader::new(file, &self.key)?;
+        DiffableBase::skip_header(&mut reader, |_, _| Ok::<_, Report>(()))?;
+
+        let mut fields = Fields::new();
+
+        while let Some((ty, data)) = reader.read_field()? {
+            if ty != 0xff {
+                fields.insert(ty, data);
+                continue;
+            }
+
+            if fields.get(&0x01).is_some_and(|id| id.as_slice() == uuid.as_bytes()) {
+                return Ok(Some(fields));
+            }
+
+            fields.clear();
+        }
+
+        Ok(None)
+    }
+
+    /// A record of the working copy, without copying any other.
+    ///
+    /// The state of pwsafe-matrix itself is not found, as it is not listed by [`Self::records`].
+    pub fn record(&mut self, uuid: Uuid) -> Result<Option<Fields>, Report> {
+        if uuid == DiffableBase::CRDT_STATE {
+            return Ok(None);
+        }
+
+        let reader = &mut self.reader_working_copy;
+        reader.restart();
+        DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let mut fields = Fields::new();
+
+        while let Some((ty, data)) = reader.read_field()? {
+            if ty != 0xff {
+                fields.insert(ty, data);
+                continue;
+            }
+
+            if fields.get(&0x01).is_some_and(|id| id.as_slice() == uuid.as_bytes()) {
+                return Ok(Some(fields));
+            }
+
+            fields.clear();
+        }
+
+        Ok(None)
+    }
+
+    /// All records of the working copy, except the state of pwsafe-matrix itself.
+    pub fn records(&mut self) -> Result<Vec<Fields>, Report> {
+        let reader = &mut self.reader_working_copy;
+        reader.restart();
+        DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+        let state = DiffableBase::CRDT_STATE;
+        let mut records = vec![];
+        let mut fields = Fields::new();
+
+        while let Some((ty, data)) = reader.read_field()? {
+            if ty != 0xff {
+                fields.insert(ty, data);
+                continue;
+            }
+
+            let record = core::mem::take(&mut fields);
+            if record.get(&0x01).map_or(true, |id| id.as_slice() != state.as_bytes()) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+
+    pub fn store(&self) -> PwsafeStore {
+        self.store.clone()
+    }
+
+    /// A new password for a record of the working copy, and the diff which sets it.
+    pub fn generate_password(&mut self, uuid: Uuid, source: &PolicySource)
+        -> Result<Result<(String, Diff), PasswordRejected>, Report>
+    {
+        let records = self.records()?;
+        let record = records
+            .iter()
+            .find(|fields| fields.get(&0x01).is_some_and(|id| id.as_slice() == uuid.as_bytes()));
+
+        let Some(record) = record else {
+            return Ok(Err(PasswordRejected::UnknownRecord));
+        };
+
+        let header = self.reader_working_copy.header();
+        let text = |ty| record.get(&ty).map(|data| String::from_utf8_lossy(data));
+
+        let policy = match source {
+            PolicySource::Record => match text(0x18) {
+                Some(name) => header.named_policy(&name).cloned(),
+                None => text(0x10).and_then(|policy| {
+                    Policy::parse(&policy, &text(0x16).unwrap_or_default())
+                }),
+            },
+            PolicySource::Named(name) => header.named_policy(name).cloned(),
+            PolicySource::Inline(policy) => Some(policy.clone()),
+        };
+
+        let Some(policy) = policy else {
+            let missing = "There is no such password policy".to_string();
+            return Ok(Err(PasswordRejected::Policy(missing)));
+        };
+
+        let password = match policy.generate() {
+            Ok(password) => password,
+            Err(err) => return Ok(Err(PasswordRejected::Policy(err.to_string()))),
+        };
+
+        let mut diff = self.empty_diff();
+        let edit = diff.edit.entry(uuid).or_default();
+        edit.set(&PwsafeRecordField::Password(password.clone()));
+
+        Ok(Ok((password, diff)))
+    }
+
+    /// Get the lock file, also used by pwsafe itself.
+    ///
+    /// Should only be called after having opened the file, it asserts that the file name is
+    /// plausible.
+    ///
+    /// See: <https://github.com/pwsafe/pwsafe/blob/717c019b93c664876890a41a8f28d5c3eae95ef0/src/os/mac/file.cpp#L235C1-L252>
+    fn lock_file_name(path: &Path) -> PathBuf {
+        let extension = if path.extension().and_then(|x| x.to_str()) == Some(".cfg") {
+            "cfg.plk"
+        } else {
+            ".plk"
+        };
+
+        let mut copy = path.to_path_buf();
+        copy.set_extension(extension);
+        copy
+    }
+
+    fn read_state(reader: &mut PwsafeReader<impl io::Read>)
+        -> Result<(State, DiffableBase, Diff, PwsafeStore), Report>
+    {
+        let diff_base = DiffableBase::default();
+        let initial = diff_base.visit(reader)?;
+        let mut state = Self::state_from_record(&initial.state_record)?;
+        let store = Self::store_from_state(&mut state)?;
+
+        Ok((state, initial.new_base, initial.diff, store))
+    }
+
+    fn state_from_record(record: &RecordDescriptor) -> Result<State, Report> {
+        if record.fields.is_empty() {
+            return Ok(State::default());
+        }
+
+        let serialized = record.fields
+            .iter()
+            .find_map(|field| {
+                if let PwsafeRecordField::Notes(note) = &field.pwsafe {
+                    Some(note)
+                } else {
+                    None
+                }
+            });
+
+        let Some(serialized) = serialized else {
+            return Ok(State::default());
+        };
+
+        let state: State = serde_json::from_str(serialized)?;
+        Ok(state)
+    }
+
+    /// Whether the file stretches its key fewer times than we write it with.
+    pub fn iterations_outdated(&self) -> bool {
+        self.file_iter < MIN_ITER
+    }
+
+    /// Whether the crypto store changed since the file was last read or written.
+    pub fn store_changed(&self) -> Result<bool, Report> {
+        Ok(Self::hash_store(&self.store)? != self.store_written)
+    }
+
+    fn hash_store(store: &PwsafeStore) -> Result<[u8; 32], Report> {
+        let encoded = serde_json::to_vec(&store.to_value()?)?;
+        Ok(Sha256::digest(&encoded).into())
+    }
+
+    /// Restore the crypto store, the state only holds it while being written.
+    fn store_from_state(state: &mut State) -> Result<PwsafeStore, Report> {
+        match state.store.take() {
+            Some(store) => Ok(PwsafeStore::from_value(store)?),
+            None => Ok(PwsafeStore::new_empty()),
+        }
+    }
+
+    /// Create a new diff, by comparing the state of applying all updates with the state read from
+    /// disk.
+    pub fn push_diff_from_remote(&mut self)
+        -> Result<Option<usize>, Report>
+    {
+        let mut write_data = io::Cursor::new(vec![]);
+        let iter = self.reader_working_copy.get_iter();
+        let mut writer = PwsafeWriter::with_iterations(&mut write_data, iter, &self.key)?;
+        writer.reserve(self.reader_working_copy.data_len())?;
+
+        let local_base = self.render_diff_into(&mut writer)?;
+        let local_diff = local_base.visit(&mut self.reader_working_copy)?;
+
+        if local_diff.diff.is_empty() {
+            return Ok(None);
+        }
+
+        self.local_diff.push_back(local_diff.diff);
+        Ok(Some(self.local_diff.len()))
+    }
+
+    /// The number of local edits not yet received back from the room, published or not.
+    pub fn pending_diffs(&self) -> usize {
+        self.local_diff.len()
+    }
+
+    /// The local edits not yet sent into the room, oldest first.
+    pub fn unpublished(&self) -> impl Iterator<Item = &Diff> {
+        self.local_diff.iter().skip(self.published)
+    }
+
+    /// A local edit already sent into the room, oldest first.
+    pub fn published(&self, index: usize) -> Option<&Diff> {
+        self.local_diff.range(..self.published).nth(index)
+    }
+
+    /// Record that the oldest unpublished edit was sent into the room.
+    pub fn mark_published(&mut self) {
+        debug_assert!(self.published < self.local_diff.len());
+        self.published += 1;
+    }
+
+    /// Forget the oldest published edit, once it is part of the shared state.
+    pub fn pop_diff(&mut self) {
+        debug_assert!(self.published > 0);
+        self.local_diff.pop_front();
+        self.published -= 1;
+    }
+
+    fn render_diff_into(&mut self, finally: &mut PwsafeWriter<impl std::io::Write>)
+        -> Result<DiffableBase, Report>
+    {
+        self.state.store = Some(self.store.to_value()?);
+        let state = serde_json::to_string(&self.state);
+        self.state.store = None;
+        let state = state?;
+
+        let mut diffs = self.local_diff.iter();
+        let mut last_diff_modified_with_state = diffs
+            .next_back()
+            .cloned()
+            .unwrap_or_else(|| Diff::empty(&self.local_diff_base));
+
+        // The records are only encrypted once, into the final writer.
+        let mut plain = Plain::read(&mut self.remote, &self.local_diff_base)?;
+        for diff in diffs {
+            plain = diff.apply_plain(plain, None, Modified::Keep, &self.incoming_group)?;
+        }
+
+        // Other clients show who saved the file last, that is now us.
+        let mut header = plain.header().clone();
+        header.touch(self.userinfo.user(), self.userinfo.host(), APPLICATION);
+        finally.write_header(&header)?;
+
+        // Before the last diff takes the records.
+        let update = self.local_diff_base.visit_plain(&plain)?;
+
+        last_diff_modified_with_state.add_state(state);
+        let group = &self.incoming_group;
+        last_diff_modified_with_state
+            .apply_plain_records(plain, finally, None, Modified::Keep, group)?;
+
+        Ok(update.new_base)
+    }
+}
+
+impl Verification {
+    /// Whether no structural problem was found.
+    pub fn is_ok(&self) -> bool {
+        self.missing_uuid == 0
+            && self.duplicate_uuid.is_empty()
+            && !matches!(self.state, StateCheck::Invalid(_))
+    }
+}
+
+impl LastSaved {
+    fn new(header: &PwsafeHeader) -> Self {
+        LastSaved {
+            timestamp: header.last_save(),
+            user: header.last_save_user().map(str::to_owned),
+            host: header.last_save_host().map(str::to_owned),
+            application: header.last_save_what().map(str::to_owned),
+        }
+    }
+}
+
+impl StateCheck {
+    fn invalid(err: &Report) -> Self {
+        match err.downcast_ref::<serde_json::Error>() {
+            Some(err) => StateCheck::Invalid(format!(
+                "{:?} error at line {} column {}",
+                err.classify(),
+                err.line(),
+                err.column(),
+            )),
+            None => StateCheck::Invalid("Not a JSON document".into()),
+        }
+    }
+}
+
+impl PwsafeLock<'_> {
+    /// Re-Read the file, report if there was any change.
+    ///
+    /// The file is only decrypted if its contents differ from when it was last read or written.
+    pub fn refresh(&mut self) -> Result<bool, Report> {
+        let data = fs::read(&self.path)?;
+        let fingerprint: [u8; 32] = Sha256::digest(&data).into();
+
+        if fingerprint == self.fingerprint {
+            return Ok(false);
+        }
+
+        let mut reader = PwsafeReader::new(io::Cursor::new(data), &self.key)?;
+
+        let reader_working_copy = {
+            let mut write_data = io::Cursor::new(vec![]);
+            let iter = reader.get_iter();
+            let mut writer = PwsafeWriter::with_iterations(&mut write_data, iter, &self.key)?;
+            writer.reserve(reader.data_len())?;
+
+            let diff = Diff::empty(&self.local_diff_base);
+            diff.apply(&mut reader, &mut writer, None, Modified::Keep, &self.incoming_group)?;
+            writer.finish()?;
+
+            write_data.set_position(0);
+            PwsafeReader::new(write_data, &self.key).unwrap()
+        };
+
+        self.reader_working_copy = reader_working_copy;
+        self.fingerprint = fingerprint;
+        self.file_iter = reader.get_iter();
+        Ok(true)
+    }
+
+    /// Modify the local file with some diff.
+    ///
+    /// The changed records are modified now, the diff is published with these times.
+    pub fn apply(&mut self, diff: &Diff) -> Result<(), Report> {
+        let mut diff = diff.clone();
+        diff.stamp(Modified::Now);
+        self.inner.local_diff.push_back(diff);
+        Ok(())
+    }
+
+    /// Rewrite the pwsafe file with the in-memory state, report if there was any change.
+    ///
+    /// The file is left untouched if it already contains the same records, so that other clients
+    /// do not see a modification. This restarts the inner reader.
+    pub fn rewrite(&mut self) -> Result<bool, Report> {
+        // Before rendering, a later change of the store is then written by the next rewrite.
+        let store_written = PwsafeDb::hash_store(&self.inner.store)?;
+        let mut write_data = io::Cursor::new(vec![]);
+
+        {
+            let iter = self.inner.reader_working_copy.get_iter();
+            let mut writer = PwsafeWriter::with_iterations(&mut write_data, iter, &self.key)?;
+            writer.reserve(self.inner.reader_working_copy.data_len())?;
+            self.inner.render_diff_into(&mut writer)?;
+            writer.finish()?;
+        }
+
+        let data = write_data.into_inner();
+        let mut rendered = PwsafeReader::new(io::Cursor::new(data.clone()), &self.key)?;
+
+        let unchanged = records(&mut rendered)? == records(&mut self.inner.reader_working_copy)?;
+        if unchanged && !self.inner.iterations_outdated() {
+            tracing::debug!("File unchanged, not rewriting it");
+            self.inner.store_written = store_written;
+            return Ok(false);
+        }
+
+        // Implicitly checked for parent when creating lockfile path..
+        let parent = self.inner.path.parent().unwrap();
+        let mut tempfile = NamedTempFile::new_in(parent)?;
+        io::Write::write_all(&mut tempfile, &data)?;
+
+        // Finally, atomically move to this new path.
+        let stdfile = tempfile.persist(&self.inner.path)?;
+        // And ensure that data and metadata is propagated even if we afterwards release the lock
+        // file, so that the new data is surely read. This waits several milliseconds, callers on
+        // the runtime use `with_lock_async` to do useful IO with the Matrix server meanwhile.
+        stdfile.sync_all()?;
+
+        // What we wrote is what the next refresh would read.
+        rendered.restart();
+        self.inner.reader_working_copy = rendered;
+        self.inner.fingerprint = Sha256::digest(&data).into();
+        self.inner.store_written = store_written;
+        self.inner.file_iter = self.inner.reader_working_copy.get_iter();
+
+        Ok(true)
+    }
+
+    /// Update the database with remote events.
+    pub fn rebase(
+        &mut self,
+        diffs: &[Diff],
+        time: &[Timestamp],
+    ) -> Result<(), Report> {
+        assert_eq!(diffs.len(), time.len());
+
+        let inner = &mut *self.inner;
+        let local = inner.local_diff.iter().collect();
+        let mut conflicts = Conflicts::new(inner.conflict_policy, local, &inner.local_diff_base);
+
+        for (diff, ts) in diffs.iter().zip(time) {
+            let mut write_data = io::Cursor::new(vec![]);
+            let iter = inner.remote.get_iter();
+            let mut writer = PwsafeWriter::with_iterations(&mut write_data, iter, &inner.key)?;
+            writer.reserve(inner.remote.data_len())?;
+
+            conflicts.at = ts.ts_ms;
+            let modified = Modified::At(ts.ts_ms);
+            let group = &inner.incoming_group;
+            diff.apply(&mut inner.remote, &mut writer, Some(&mut conflicts), modified, group)?;
+            writer.finish()?;
+
+            write_data.set_position(0);
+            inner.remote = PwsafeReader::new(write_data, &inner.key)?;
+            inner.state.remote_until = Some(ts.clone());
+        }
+
+        // Applied after all our edits, and published as one of them.
+        let resolution = conflicts.into_resolution();
+        if !resolution.is_empty() {
+            inner.local_diff.push_back(resolution);
+        }
+
+        Ok(())
+    }
+}
+
+impl core::ops::Deref for PwsafeLock<'_> {
+    type Target = PwsafeDb;
+    fn deref(&self) -> &PwsafeDb {
+        &*self.inner
+    }
+}
+
+impl core::ops::DerefMut for PwsafeLock<'_> {
+    fn deref_mut(&mut self) -> &mut PwsafeDb {
+        &mut *self.inner
+    }
+}
+
+/// The key of the contents of a key file, a password, or both with the password after the contents.
+pub fn combined_key(key_file: Option<&[u8]>, passwd: Option<&[u8]>) -> PwsafeKey {
+    let (key_file, passwd) = (key_file.unwrap_or_default(), passwd.unwrap_or_default());
+
+    // Allocated once, so that no copy is left behind when it is overwritten.
+    let mut key = Vec::with_capacity(key_file.len() + passwd.len());
+    key.extend_from_slice(key_file);
+    key.extend_from_slice(passwd);
+
+    PwsafeKey::new(&SecretField::from(key))
+}
+
+/// Copy all fields, including the header, into a database with another key.
+fn reencrypt(reader: &mut PwsafeReader<io::Cursor<Vec<u8>>>, key: &PwsafeKey)
+    -> Result<PwsafeReader<io::Cursor<Vec<u8>>>, Report>
+{
+    let mut write_data = io::Cursor::new(vec![]);
+    let mut writer = PwsafeWriter::with_iterations(&mut write_data, reader.get_iter(), key)?;
+    writer.reserve(reader.data_len())?;
+
+    reader.restart();
+    while let Some((ty, data)) = reader.read_field_secret() {
+        writer.write_field(ty, &data)?;
+    }
+
+    writer.finish()?;
+
+    write_data.set_position(0);
+    Ok(PwsafeReader::new(write_data, key)?)
+}
+
+/// The records of a database, each with its fields sorted.
+///
+/// Fields of edited records are written in any order, this compares equal regardless.
+fn records(
+    reader: &mut PwsafeReader<impl io::Read>,
+) -> Result<Vec<Vec<(u8, SecretField)>>, Report> {
+    reader.restart();
+    DiffableBase::skip_header(reader, |_, _| Ok::<_, Report>(()))?;
+
+    let mut records = vec![];
+    let mut record = vec![];
+
+    while let Some((ty, data)) = reader.read_field_secret() {
+        if ty == 0xff {
+            record.sort();
+            records.push(core::mem::take(&mut record));
+        } else {
+            record.push((ty, data));
+        }
+    }
+
+    Ok(records)
+}
+
+#[derive(Deserialize, Serialize, Default)]
+struct State {
+    /// An existing matrix session related to this pwsafe-matrix database.
+    #[serde(default)]
+    session: Option<MatrixSession>,
+    #[serde(default)]
+    room: Option<OwnedRoomId>,
+    /// The canonical alias of the room, if one was registered on creation.
+    #[serde(default)]
+    alias: Option<OwnedRoomAliasId>,
+    /// The timestamp of the last remote change which should be regarded as considered.
+    #[serde(default)]
+    remote_until: Option<Timestamp>,
+    /// Adopted from the invite when joining, see [`PwsafeDb::namespace`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    namespace: Option<Uuid>,
+    /// The crypto store of the Matrix client, see [`PwsafeStore::to_value`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    store: Option<serde_json::Value>,
+}
@@ -0,0 +1,20 @@
+//! A wrapper for values that must never end up in logs.
+//!
+//! Anything derived from the password or notes fields of an entry should be wrapped before it is
+//! handed to `tracing`, regardless of the level. Logs are regularly shared for debugging.
+use core::fmt;
+
+#[derive(Clone, Copy)]
+pub struct Redacted<T>(pub T);
+
+impl<T> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<T> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
@@ -1,7 +1,8 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::{sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use eyre::Report;
 use matrix_sdk::crypto as matrix_sdk_crypto;
 use matrix_sdk::crypto::types::EventEncryptionAlgorithm;
 use tokio::{sync::Mutex, time::Instant};
@@ -9,20 +10,22 @@ use tokio::{sync::Mutex, time::Instant};
 use matrix_sdk_crypto::{
     olm::{
         Account, InboundGroupSession, OlmMessageHash, OutboundGroupSession, PickledAccount,
-        PickledCrossSigningIdentity, PrivateCrossSigningIdentity, Session,
+        PickledCrossSigningIdentity, PickledInboundGroupSession, PickledOutboundGroupSession,
+        PickledSession, PrivateCrossSigningIdentity, Session,
     },
     store::{
         BackupDecryptionKey, BackupKeys, Changes, CryptoStore, PendingChanges, RoomKeyCounts,
         RoomSettings,
     },
     types::events::room_key_withheld::RoomKeyWithheldEvent,
+    vodozemac::olm::IdentityKeys,
     CryptoStoreError, GossipRequest, GossippedSecret, ReadOnlyDevice, ReadOnlyUserIdentities,
     ReadOnlyUserIdentity, SecretInfo, TrackedUser,
 };
 
 use matrix_sdk::ruma::{
-    events::secret::request::SecretName, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedUserId, RoomId,
-    TransactionId, UserId,
+    events::secret::request::SecretName, DeviceId, OwnedDeviceId, OwnedRoomId, OwnedTransactionId,
+    OwnedUserId, RoomId, TransactionId, UserId,
 };
 
 #[derive(Debug, Clone)]
@@ -40,6 +43,28 @@ struct Inner {
     custom: HashMap<String, Vec<u8>>,
     secrets: Vec<GossippedSecret>,
     users: HashMap<OwnedUserId, UserData>,
+    /// Pickled olm sessions, by the sender key they were established with and their id.
+    #[serde(default)]
+    sessions: HashMap<String, HashMap<String, serde_json::Value>>,
+    /// Pickled inbound megolm sessions, by room and session id.
+    #[serde(default)]
+    inbound_group_sessions: HashMap<OwnedRoomId, HashMap<String, serde_json::Value>>,
+    /// The pickled outbound megolm session of each room.
+    #[serde(default)]
+    outbound_group_sessions: HashMap<OwnedRoomId, serde_json::Value>,
+    /// The sender key and hash of each olm message received, ordered to encode the same each time.
+    #[serde(default)]
+    message_hashes: BTreeSet<(String, String)>,
+    #[serde(default)]
+    identities: HashMap<OwnedUserId, ReadOnlyUserIdentities>,
+    /// Our outgoing key and secret requests, by their request id.
+    #[serde(default)]
+    key_requests: HashMap<OwnedTransactionId, GossipRequest>,
+    /// The reasons room keys were withheld from us, by room and session id.
+    #[serde(default)]
+    withheld_sessions: HashMap<OwnedRoomId, HashMap<String, RoomKeyWithheldEvent>>,
+    #[serde(default)]
+    room_settings: HashMap<OwnedRoomId, RoomSettings>,
     #[serde(skip)]
     locks: Locks,
 }
@@ -62,6 +87,23 @@ impl PwsafeStore {
             inner: Arc::default(),
         }
     }
+
+    /// Restore a store, as serialized by [`Self::to_value`].
+    pub fn from_value(value: serde_json::Value) -> Result<Self, serde_json::Error> {
+        let inner: Inner = serde_json::from_value(value)?;
+
+        Ok(PwsafeStore {
+            inner: Arc::new(Mutex::new(inner)),
+        })
+    }
+
+    /// Serialize the store, to persist it in the state record of the database.
+    ///
+    /// Fails while the client is using the store, the caller should retry later.
+    pub fn to_value(&self) -> Result<serde_json::Value, Report> {
+        let lock = self.inner.try_lock()?;
+        Ok(serde_json::to_value(&*lock)?)
+    }
 }
 
 #[cfg_attr(target_arch = "wasm32", async_trait(?Send))]
@@ -135,39 +177,66 @@ impl CryptoStore for PwsafeStore {
         }
 
         for session in &sessions {
-            todo!()
+            let pickle = serde_json::to_value(session.pickle().await)?;
+            lock.sessions
+                .entry(session.sender_key.to_base64())
+                .or_default()
+                .insert(session.session_id().to_owned(), pickle);
         }
 
         for message in &message_hashes {
-            todo!()
+            lock.message_hashes
+                .insert((message.sender_key.clone(), message.hash.clone()));
         }
 
         for inbound in &inbound_group_sessions {
-            todo!()
+            let pickle = serde_json::to_value(inbound.pickle().await)?;
+            lock.inbound_group_sessions
+                .entry(inbound.room_id().to_owned())
+                .or_default()
+                .insert(inbound.session_id().to_owned(), pickle);
         }
 
         for outbound in &outbound_group_sessions {
-            todo!()
+            let pickle = serde_json::to_value(outbound.pickle().await)?;
+            lock.outbound_group_sessions
+                .insert(outbound.room_id().to_owned(), pickle);
         }
 
-        for key_request in &key_requests {
-            todo!()
+        for key_request in key_requests {
+            lock.key_requests
+                .insert(key_request.request_id.clone(), key_request);
         }
 
-        let _ = identities;
-        let _ = devices;
+        for identity in identities.new.iter().chain(&identities.changed) {
+            lock.identities
+                .insert(identity.user_id().to_owned(), identity.clone());
+        }
 
-        assert!(withheld_session_info.is_empty());
-        let _ = withheld_session_info;
+        for device in devices.new.iter().chain(&devices.changed) {
+            lock.users
+                .entry(device.user_id().to_owned())
+                .or_default()
+                .devices
+                .insert(device.device_id().to_owned(), device.clone());
+        }
 
-        for setting in &room_settings {
-            todo!()
+        for device in &devices.deleted {
+            if let Some(user) = lock.users.get_mut(device.user_id()) {
+                user.devices.remove(device.device_id());
+            }
         }
 
-        for secret in &secrets {
-            todo!()
+        for (room_id, withheld) in withheld_session_info {
+            lock.withheld_sessions
+                .entry(room_id)
+                .or_default()
+                .extend(withheld);
         }
 
+        lock.room_settings.extend(room_settings);
+        lock.secrets.extend(secrets);
+
         if let Some(next_batch_token) = next_batch_token {
             lock.next_batch_token = Some(next_batch_token);
         }
@@ -203,7 +272,31 @@ impl CryptoStore for PwsafeStore {
         &self,
         sender_key: &str,
     ) -> Result<Option<Arc<Mutex<Vec<Session>>>>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        let Some(pickles) = lock.sessions.get(sender_key) else {
+            return Ok(None);
+        };
+
+        let Some((user_id, device_id, identity_keys)) = lock.own_identity()? else {
+            return Ok(None);
+        };
+
+        let sessions = pickles
+            .values()
+            .map(|pickle| {
+                let pickle: PickledSession = serde_json::from_value(pickle.clone())?;
+                let session = Session::from_pickle(
+                    user_id.clone(),
+                    device_id.clone(),
+                    identity_keys.clone(),
+                    pickle,
+                );
+
+                Ok(session)
+            })
+            .collect::<Result<Vec<_>, Self::Error>>()?;
+
+        Ok(Some(Arc::new(Mutex::new(sessions))))
     }
 
     /// Get the inbound group session from our store.
@@ -219,7 +312,18 @@ impl CryptoStore for PwsafeStore {
         room_id: &RoomId,
         session_id: &str,
     ) -> Result<Option<InboundGroupSession>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        let pickle = lock
+            .inbound_group_sessions
+            .get(room_id)
+            .and_then(|sessions| sessions.get(session_id));
+
+        let Some(pickle) = pickle else {
+            return Ok(None);
+        };
+
+        let pickle: PickledInboundGroupSession = serde_json::from_value(pickle.clone())?;
+        Ok(Some(InboundGroupSession::from_pickle(pickle)?))
     }
 
     /// Get withheld info for this key.
@@ -232,18 +336,40 @@ impl CryptoStore for PwsafeStore {
         room_id: &RoomId,
         session_id: &str,
     ) -> Result<Option<RoomKeyWithheldEvent>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        let withheld = lock
+            .withheld_sessions
+            .get(room_id)
+            .and_then(|sessions| sessions.get(session_id))
+            .cloned();
+
+        Ok(withheld)
     }
 
     /// Get all the inbound group sessions we have stored.
     async fn get_inbound_group_sessions(&self) -> Result<Vec<InboundGroupSession>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+
+        lock.inbound_group_sessions
+            .values()
+            .flat_map(HashMap::values)
+            .map(|pickle| {
+                let pickle: PickledInboundGroupSession = serde_json::from_value(pickle.clone())?;
+                Ok(InboundGroupSession::from_pickle(pickle)?)
+            })
+            .collect()
     }
 
     /// Get the number inbound group sessions we have and how many of them are
     /// backed up.
     async fn inbound_group_session_counts(&self) -> Result<RoomKeyCounts, Self::Error> {
-        todo!()
+        let sessions = self.get_inbound_group_sessions().await?;
+        let backed_up = sessions.iter().filter(|session| session.backed_up()).count();
+
+        Ok(RoomKeyCounts {
+            total: sessions.len(),
+            backed_up,
+        })
     }
 
     /// Get all the inbound group sessions we have not backed up yet.
@@ -290,7 +416,18 @@ impl CryptoStore for PwsafeStore {
         &self,
         room_id: &RoomId,
     ) -> Result<Option<OutboundGroupSession>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        let Some(pickle) = lock.outbound_group_sessions.get(room_id) else {
+            return Ok(None);
+        };
+
+        let Some((_, device_id, identity_keys)) = lock.own_identity()? else {
+            return Ok(None);
+        };
+
+        let pickle: PickledOutboundGroupSession = serde_json::from_value(pickle.clone())?;
+        let session = OutboundGroupSession::from_pickle(device_id, identity_keys, pickle)?;
+        Ok(Some(session))
     }
 
     /// Load the list of users whose devices we are keeping track of.
@@ -376,12 +513,15 @@ impl CryptoStore for PwsafeStore {
         &self,
         user_id: &UserId,
     ) -> Result<Option<ReadOnlyUserIdentities>, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        Ok(lock.identities.get(user_id).cloned())
     }
 
     /// Check if a hash for an Olm message stored in the database.
     async fn is_message_known(&self, message_hash: &OlmMessageHash) -> Result<bool, Self::Error> {
-        todo!()
+        let lock = self.inner.lock().await;
+        let key = (message_hash.sender_key.clone(), message_hash.hash.clone());
+        Ok(lock.message_hashes.contains(&key))
     }
 
     /// Get an outgoing secret request that we created that matches the given
@@ -396,12 +536,7 @@ impl CryptoStore for PwsafeStore {
         request_id: &TransactionId,
     ) -> Result<Option<GossipRequest>, Self::Error> {
         let lock = self.inner.lock().await;
-        let requests = lock
-            .secrets
-            .iter()
-            .find(|req| req.gossip_request.request_id == request_id)
-            .map(|req| req.gossip_request.clone());
-        Ok(requests)
+        Ok(lock.key_requests.get(request_id).cloned())
     }
 
     /// Get an outgoing key request that we created that matches the given
@@ -416,10 +551,10 @@ impl CryptoStore for PwsafeStore {
     ) -> Result<Option<GossipRequest>, Self::Error> {
         let lock = self.inner.lock().await;
         let secret_if_found = lock
-            .secrets
-            .iter()
-            .find(|req| req.gossip_request.info == *secret_info)
-            .map(|req| req.gossip_request.clone());
+            .key_requests
+            .values()
+            .find(|req| req.info == *secret_info)
+            .cloned();
         Ok(secret_if_found)
     }
 
@@ -427,10 +562,10 @@ impl CryptoStore for PwsafeStore {
     async fn get_unsent_secret_requests(&self) -> Result<Vec<GossipRequest>, Self::Error> {
         let lock = self.inner.lock().await;
         let not_sent_out = lock
-            .secrets
-            .iter()
-            .filter(|req| !req.gossip_request.sent_out)
-            .map(|req| req.gossip_request.clone())
+            .key_requests
+            .values()
+            .filter(|req| !req.sent_out)
+            .cloned()
             .collect();
         Ok(not_sent_out)
     }
@@ -447,8 +582,7 @@ impl CryptoStore for PwsafeStore {
         request_id: &TransactionId,
     ) -> Result<(), Self::Error> {
         let mut lock = self.inner.lock().await;
-        lock.secrets
-            .retain(|req| req.gossip_request.request_id != request_id);
+        lock.key_requests.remove(request_id);
         Ok(())
     }
 
@@ -484,12 +618,15 @@ impl CryptoStore for PwsafeStore {
     /// * `room_id` - The room id of the room
     async fn get_room_settings(
         &self,
-        _room_id: &RoomId,
+        room_id: &RoomId,
     ) -> Result<Option<RoomSettings>, Self::Error> {
-        Ok(Some(RoomSettings {
+        let lock = self.inner.lock().await;
+        let settings = lock.room_settings.get(room_id).cloned().unwrap_or(RoomSettings {
             algorithm: EventEncryptionAlgorithm::MegolmV1AesSha2,
             only_allow_trusted_devices: true,
-        }))
+        });
+
+        Ok(Some(settings))
     }
 
     /// Get arbitrary data from the store
@@ -554,6 +691,26 @@ impl CryptoStore for PwsafeStore {
     }
 }
 
+impl Inner {
+    /// Our own user, device and identity keys, which sessions are restored with.
+    fn own_identity(
+        &self,
+    ) -> Result<Option<(OwnedUserId, OwnedDeviceId, Arc<IdentityKeys>)>, CryptoStoreError> {
+        let Some(account) = self.account.as_ref() else {
+            return Ok(None);
+        };
+
+        let account: PickledAccount = serde_json::from_value(account.clone())?;
+        let account = Account::from_pickle(account)?;
+
+        Ok(Some((
+            account.user_id().to_owned(),
+            account.device_id().to_owned(),
+            Arc::new(account.identity_keys()),
+        )))
+    }
+}
+
 impl Locks {
     fn try_take(&mut self, lease_duration_ms: u32, key: &str, holder: &str) -> bool {
         let Some((owner, end)) = self.maybe_held.get_mut(key) else {
@@ -0,0 +1,1330 @@
+#[test]
+fn combined_key_order() {
+    use crate::pwsafe::combined_key;
+
+    let key = |key: &[u8]| pwsafer::PwsafeKey::new(key);
+    let file = Some(&b"file"[..]);
+    let passwd = Some(&b"passwd"[..]);
+
+    // Compare through the stretched key of a freshly written database.
+    let roundtrip = |combined: pwsafer::PwsafeKey, plain: pwsafer::PwsafeKey| {
+        let mut db = std::io::Cursor::new(vec![]);
+        let mut writer = pwsafer::PwsafeWriter::new(&mut db, 2048, &combined).unwrap();
+        writer.finish().unwrap();
+        drop(writer);
+        db.set_position(0);
+        pwsafer::PwsafeReader::new(db, &plain).is_ok()
+    };
+
+    assert!(roundtrip(combined_key(file, passwd), key(b"filepasswd")));
+    assert!(!roundtrip(combined_key(file, passwd), key(b"passwdfile")));
+    assert!(roundtrip(combined_key(file, None), key(b"file")));
+    assert!(roundtrip(combined_key(None, passwd), key(b"passwd")));
+}
+
+/// An in-memory database with a minimal header and the given records.
+fn in_memory_safe(
+    key: &pwsafer::PwsafeKey,
+    records: &[&[(u8, &[u8])]],
+) -> pwsafer::PwsafeReader<std::io::Cursor<Vec<u8>>> {
+    let mut write_data = std::io::Cursor::new(vec![]);
+    let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, key).unwrap();
+
+    writer.write_field(0x00, &[0x0e, 0x03]).unwrap();
+    writer.write_field(0xff, &[]).unwrap();
+
+    for record in records {
+        for (ty, data) in record.iter() {
+            writer.write_field(*ty, data).unwrap();
+        }
+
+        writer.write_field(0xff, &[]).unwrap();
+    }
+
+    writer.finish().unwrap();
+    write_data.set_position(0);
+    pwsafer::PwsafeReader::new(write_data, key).unwrap()
+}
+
+/// Visiting a changed database with the base of a previous visit finds exactly the changes.
+#[test]
+fn diff_visit_again() {
+    use crate::diff::DiffableBase;
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let (changed, unchanged) = ([1; 16], [2; 16]);
+    let changed_id = uuid::Uuid::from_bytes(changed).to_string();
+
+    let mut reader = in_memory_safe(&key, &[
+        &[(0x01, &changed), (0x03, b"title"), (0x06, b"password"), (0x14, b"mail")],
+        &[(0x01, &unchanged), (0x03, b"unchanged")],
+    ]);
+    let first = DiffableBase::default().visit(&mut reader).unwrap();
+
+    let mut reader = in_memory_safe(&key, &[
+        &[(0x01, &changed), (0x03, b"title"), (0x06, b"changed"), (0x0d, b"url")],
+        &[(0x01, &unchanged), (0x03, b"unchanged")],
+    ]);
+    let second = first.new_base.visit(&mut reader).unwrap();
+
+    assert_eq!(second.diff.serialize().unwrap(), serde_json::json!({
+        "delete": [],
+        "edit": {
+            changed_id.clone(): {
+                "set": { "6": "Y2hhbmdlZA==", "13": "dXJs" },
+                "delete": [0x14],
+            },
+        },
+    }));
+
+    // The base now points at the new fields.
+    let third = second.new_base.visit(&mut reader).unwrap();
+    assert!(third.diff.is_empty());
+
+    // Growing a record, and deleting another.
+    let mut reader = in_memory_safe(&key, &[
+        &[(0x01, &changed), (0x03, b"title"), (0x06, b"changed"), (0x0d, b"url"), (0x05, b"notes")],
+    ]);
+    let fourth = third.new_base.visit(&mut reader).unwrap();
+
+    assert_eq!(fourth.diff.serialize().unwrap(), serde_json::json!({
+        "delete": [uuid::Uuid::from_bytes(unchanged)],
+        "edit": {
+            changed_id: { "set": { "5": "bm90ZXM=" }, "delete": [] },
+        },
+    }));
+
+    assert!(fourth.new_base.visit(&mut reader).unwrap().diff.is_empty());
+}
+
+/// Every structural problem is reported, not just the first one.
+#[test]
+fn verify_collects_problems() {
+    use crate::pwsafe::{PwsafeDb, StateCheck};
+
+    // The UUID of our state record, see `DiffableBase::CRDT_STATE`.
+    let state = uuid::Uuid::parse_str("02e4d75b-5fde-582e-b10d-409f041c3d34").unwrap();
+    let duplicate = [1; 16];
+
+    let write = |records: &[&[(u8, &[u8])]]| {
+        let key = pwsafer::PwsafeKey::new(b"password");
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+
+        let saved = 1_700_000_000u32.to_le_bytes();
+        writer.write_record(&[
+            (0x00, &[0x0e, 0x03]),
+            (0x04, &saved),
+            (0x06, b"pwsafe V3.66"),
+            (0x07, b"alice"),
+            (0x08, b"laptop"),
+        ]).unwrap();
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+
+        writer.finish().unwrap();
+        file
+    };
+
+    let verify = |file: &tempfile::NamedTempFile| {
+        PwsafeDb::verify(&crate::ArgsPwsafe {
+            pwsafe: file.path().into(),
+            passwd_file: None,
+            passwd: Some("password".into()),
+        })
+    };
+
+    let file = write(&[
+        &[(0x01, &duplicate), (0x03, b"first")],
+        &[(0x03, b"no uuid")],
+        &[(0x01, &duplicate), (0x03, b"second")],
+        &[(0x01, state.as_bytes()), (0x05, br#"{"session": "hunter2"}"#)],
+        &[(0x01, &[2; 16])],
+    ]);
+
+    let verification = verify(&file).unwrap();
+    assert!(!verification.is_ok());
+    assert_eq!(verification.records, 5);
+    assert_eq!(verification.missing_uuid, 1);
+    assert_eq!(verification.duplicate_uuid, [uuid::Uuid::from_bytes(duplicate)]);
+    assert!(matches!(verification.state, StateCheck::Invalid(_)));
+    assert!(!verification.session && !verification.room);
+
+    // The error does not quote the state.
+    let json = serde_json::to_string(&verification).unwrap();
+    assert!(!json.contains("hunter2"), "{json}");
+
+    let file = write(&[&[(0x01, &duplicate)], &[(0x01, state.as_bytes()), (0x05, b"{}")]]);
+    let verification = verify(&file).unwrap();
+    assert!(verification.is_ok());
+    assert_eq!(verification.state, StateCheck::Valid);
+
+    let file = write(&[&[(0x01, &duplicate)]]);
+    let verification = verify(&file).unwrap();
+    assert_eq!(verification.state, StateCheck::Missing);
+
+    // Who last saved it, from the header.
+    assert_eq!(verification.last_saved.timestamp, Some(1_700_000_000));
+    assert_eq!(verification.last_saved.user.as_deref(), Some("alice"));
+    assert_eq!(verification.last_saved.host.as_deref(), Some("laptop"));
+    assert_eq!(verification.last_saved.application.as_deref(), Some("pwsafe V3.66"));
+}
+
+#[test]
+fn unlink_clears_room() {
+    use crate::pwsafe::PwsafeDb;
+
+    let state = uuid::Uuid::parse_str("02e4d75b-5fde-582e-b10d-409f041c3d34").unwrap();
+    let notes = br##"{"room": "!linked:example.org", "alias": "#linked:example.org"}"##;
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+    writer.write_record(&[(0x01, state.as_bytes()), (0x05, notes)]).unwrap();
+    writer.finish().unwrap();
+
+    let args = crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    };
+
+    let mut db = PwsafeDb::open(&args).unwrap();
+    assert!(db.clear_session().is_none());
+    assert_eq!(db.clear_room().unwrap().as_str(), "!linked:example.org");
+    assert!(db.room().is_none() && db.room_alias().is_none() && db.remote_until().is_none());
+    db.with_lock(|mut lock| lock.rewrite()).unwrap();
+
+    let verification = PwsafeDb::verify(&args).unwrap();
+    assert!(verification.is_ok());
+    assert!(!verification.session && !verification.room);
+
+    let db = PwsafeDb::open(&args).unwrap();
+    assert!(db.room().is_none());
+    let record = db.entry(state).unwrap().unwrap();
+    assert!(!String::from_utf8_lossy(&record[&0x05]).contains("linked"));
+}
+
+/// A sync cycle on a file that nobody touched neither decrypts it again nor writes it.
+#[test]
+fn refresh_untouched_file() {
+    use crate::pwsafe::PwsafeDb;
+
+    let state = uuid::Uuid::parse_str("02e4d75b-5fde-582e-b10d-409f041c3d34").unwrap();
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let write = |file: &tempfile::NamedTempFile, title: &[u8]| {
+        let output = std::fs::File::create(file.path()).unwrap();
+        let mut writer = pwsafer::PwsafeWriter::new(output, 2048, &key).unwrap();
+        writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+        writer.write_record(&[(0x01, &[1; 16]), (0x03, title)]).unwrap();
+        writer.write_record(&[(0x01, state.as_bytes()), (0x05, b"{}")]).unwrap();
+        writer.finish().unwrap();
+    };
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    write(&file, b"title");
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    let mut cycle = || db.with_lock(|mut lock| {
+        let changed = lock.refresh()?;
+        if changed {
+            lock.push_diff_from_remote()?;
+        }
+
+        Ok((changed, lock.rewrite()?))
+    }).unwrap();
+
+    let on_disk = || {
+        let modified = std::fs::metadata(file.path()).unwrap().modified().unwrap();
+        (std::fs::read(file.path()).unwrap(), modified)
+    };
+
+    // The first cycle writes our complete state into the file.
+    assert_eq!(cycle(), (false, true));
+    let before = on_disk();
+
+    std::thread::sleep(std::time::Duration::from_millis(10));
+    assert_eq!(cycle(), (false, false));
+    assert!(before == on_disk(), "Untouched file was rewritten");
+
+    // Another client edits the file.
+    write(&file, b"edited");
+    let (changed, _) = cycle();
+    assert!(changed);
+}
+
+/// The records served over HTTP are those of the working copy, without our own state.
+#[test]
+fn records_of_working_copy() {
+    use crate::pwsafe::PwsafeDb;
+
+    let state = uuid::Uuid::parse_str("02e4d75b-5fde-582e-b10d-409f041c3d34").unwrap();
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+    writer.write_record(&[(0x01, &[1; 16]), (0x03, b"title"), (0x06, b"secret")]).unwrap();
+    writer.write_record(&[(0x01, state.as_bytes()), (0x05, b"{}")]).unwrap();
+    writer.write_record(&[(0x01, &[2; 16]), (0x04, b"alice")]).unwrap();
+    writer.finish().unwrap();
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    let mut records = db.records().unwrap();
+    records.sort_by(|a, b| a[&0x01].cmp(&b[&0x01]));
+
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0][&0x03], b"title");
+    assert_eq!(records[0][&0x06], b"secret");
+    assert_eq!(records[1][&0x04], b"alice");
+    // Reading again restarts from the beginning.
+    assert_eq!(db.records().unwrap().len(), 2);
+
+    // Single records are found in the working copy, not the file.
+    drop(file);
+    assert_eq!(db.record(uuid::Uuid::from_bytes([2; 16])).unwrap().unwrap()[&0x04], b"alice");
+    assert_eq!(db.record(uuid::Uuid::from_bytes([1; 16])).unwrap().unwrap()[&0x06], b"secret");
+    assert!(db.record(state).unwrap().is_none());
+    assert!(db.record(uuid::Uuid::from_bytes([3; 16])).unwrap().is_none());
+}
+
+/// Another program changes the password of the file while we have it open.
+#[test]
+fn password_changed() {
+    use crate::pwsafe::PwsafeDb;
+
+    let state = uuid::Uuid::parse_str("02e4d75b-5fde-582e-b10d-409f041c3d34").unwrap();
+    let write = |file: &tempfile::NamedTempFile, passwd: &[u8], title: &[u8]| {
+        let key = pwsafer::PwsafeKey::new(passwd);
+        let output = std::fs::File::create(file.path()).unwrap();
+        let mut writer = pwsafer::PwsafeWriter::new(output, 2048, &key).unwrap();
+        writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+        writer.write_record(&[(0x01, &[1; 16]), (0x03, title)]).unwrap();
+        writer.write_record(&[(0x01, state.as_bytes()), (0x05, b"{}")]).unwrap();
+        writer.finish().unwrap();
+    };
+
+    let file = tempfile::NamedTempFile::new().unwrap();
+    write(&file, b"password", b"title");
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    write(&file, b"changed", b"edited");
+
+    let err = db.with_lock(|mut lock| lock.refresh()).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(pwsafer::ReadError::InvalidPassword)), "{err:?}");
+
+    db.set_key(pwsafer::PwsafeKey::new(b"changed")).unwrap();
+    db.with_lock(|mut lock| {
+        assert!(lock.refresh()?);
+        lock.push_diff_from_remote()?;
+        lock.rewrite()
+    }).unwrap();
+
+    // Only the new password opens the file we wrote.
+    let db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("changed".into()),
+    }).unwrap();
+    assert_eq!(db.entry(uuid::Uuid::from_bytes([1; 16])).unwrap().unwrap()[&0x03], b"edited");
+}
+
+/// A file of an older program, which stretches its key too little, is written with the minimum.
+#[test]
+fn rewrite_raises_iterations() {
+    use crate::pwsafe::PwsafeDb;
+    use pwsafer::generate::{generate_database, Options};
+
+    let options = Options { iterations: 1, ..Options::default() };
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), generate_database(0, 10, &options)).unwrap();
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    assert!(db.iterations_outdated());
+    assert!(db.with_lock(|mut lock| lock.rewrite()).unwrap());
+    assert!(!db.iterations_outdated());
+    assert!(!db.with_lock(|mut lock| lock.rewrite()).unwrap());
+
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let reader = pwsafer::PwsafeReader::new(std::fs::File::open(file.path()).unwrap(), &key).unwrap();
+    assert_eq!(reader.get_iter(), pwsafer::MIN_ITER);
+}
+
+/// A rewritten file tells other clients that we saved it last, keeping the rest of its header.
+#[test]
+fn rewrite_touches_header() {
+    use crate::pwsafe::PwsafeDb;
+    use pwsafer::generate::{generate_database, Options};
+
+    // Outdated iterations, so that the unchanged records are written nonetheless.
+    let options = Options { iterations: 1, ..Options::default() };
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), generate_database(0, 10, &options)).unwrap();
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    }).unwrap();
+
+    assert!(db.with_lock(|mut lock| lock.rewrite()).unwrap());
+
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let mut reader = pwsafer::PwsafeReader::new(std::fs::File::open(file.path()).unwrap(), &key)
+        .unwrap();
+
+    let header = reader.header().clone();
+    assert_eq!(header.version(), Some(pwsafer::PwsafeHeader::VERSION));
+    assert!(header.last_save_what().unwrap().starts_with("pwsafe-matrix V"));
+    assert!(header.last_save_user().is_some());
+    assert!(header.last_save_host().is_some());
+    assert!(header.last_save().is_some());
+
+    // Password Safe expects the version first.
+    assert_eq!(reader.read_field().unwrap(), Some((0x00, vec![0x0e, 0x03])));
+}
+
+/// Rewriting a large file on the runtime does not keep its other tasks from running.
+#[test]
+fn rewrite_does_not_block_runtime() {
+    use crate::pwsafe::PwsafeDb;
+    use pwsafer::generate::{generate_database, Options};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    let options = Options::default();
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), generate_database(0, 5000, &options)).unwrap();
+
+    let args = crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some(String::from_utf8(options.password.clone()).unwrap()),
+    };
+
+    // A single worker, which a blocking rewrite would occupy.
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_time()
+        .build()
+        .unwrap();
+
+    let ticks = std::sync::Arc::new(AtomicUsize::new(0));
+    let counter = ticks.clone();
+
+    let during = rt.block_on(async move {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_millis(1));
+            loop {
+                interval.tick().await;
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        let rewrite = tokio::spawn(async move {
+            let mut db = PwsafeDb::open_async(&args).await?;
+            db.with_lock_async(|mut lock| {
+                let before = ticks.load(Ordering::Relaxed);
+                lock.rewrite()?;
+                // As if the disk was slow.
+                std::thread::sleep(Duration::from_millis(50));
+                Ok(ticks.load(Ordering::Relaxed) - before)
+            }).await
+        });
+
+        rewrite.await.unwrap()
+    }).unwrap();
+
+    assert!(during > 0, "The timer did not fire during the rewrite");
+}
+
+/// The lock of a process that is gone is replaced, that of a running one is not.
+#[test]
+fn lock_takeover() {
+    use crate::lockfile::{LockFile, Takeover, UserInfo};
+
+    let info = UserInfo::new().unwrap();
+    let ours = info.to_string();
+    let (owner, _) = ours.rsplit_once(':').unwrap();
+    // Larger than any pid the kernel hands out.
+    let gone = format!("{owner}:{}", i32::MAX);
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("database.plk");
+
+    std::fs::write(&path, &gone).unwrap();
+    assert!(LockFile::create_or_takeover(path.clone(), &info, Takeover::Never).is_err());
+    let lock = LockFile::create_or_takeover(path.clone(), &info, Takeover::Stale).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), ours);
+    drop(lock);
+    assert!(!path.exists());
+
+    // Still running, it is us.
+    std::fs::write(&path, &ours).unwrap();
+    assert!(LockFile::create_or_takeover(path.clone(), &info, Takeover::Stale).is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), ours);
+
+    // We can not know about processes on other hosts.
+    std::fs::write(&path, format!("someone@elsewhere.example:{}", i32::MAX)).unwrap();
+    assert!(LockFile::create_or_takeover(path.clone(), &info, Takeover::Stale).is_err());
+
+    let _lock = LockFile::create_or_takeover(path.clone(), &info, Takeover::Always).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), ours);
+}
+
+#[derive(Clone, Default)]
+struct CaptureLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for CaptureLog {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn diff_logs_are_redacted() {
+    const SECRET: &str = "correct-horse-battery-staple";
+
+    let key = pwsafer::PwsafeKey::new(b"test");
+    let mut reader = in_memory_safe(&key, &[]);
+
+    let uuid = uuid::Uuid::new_v4();
+    let base = crate::diff::DiffableBase::default();
+    let diff = base
+        .deserialize(serde_json::json!({
+            "delete": [],
+            "edit": {
+                uuid.to_string(): {
+                    "set": { "6": SECRET.as_bytes() },
+                    "delete": [],
+                },
+            },
+        }))
+        .unwrap();
+
+    let log = CaptureLog::default();
+    let subscriber = tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::TRACE)
+        .with_ansi(false)
+        .with_writer({
+            let log = log.clone();
+            move || log.clone()
+        })
+        .finish();
+
+    tracing::subscriber::with_default(subscriber, || {
+        let mut write_data = std::io::Cursor::new(vec![]);
+        let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, &key).unwrap();
+        let keep = crate::diff::Modified::Keep;
+        diff.apply(&mut reader, &mut writer, None, keep, crate::diff::INCOMING_GROUP).unwrap();
+        writer.finish().unwrap();
+    });
+
+    let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+    assert!(log.contains("<redacted>"), "{log}");
+    assert!(!log.contains(SECRET), "{log}");
+    assert!(!log.contains(&format!("{:?}", SECRET.as_bytes())), "{log}");
+}
+
+#[test]
+fn diff_render_redacts_by_default() {
+    const SECRET: &str = "correct-horse-battery-staple";
+
+    let uuid = uuid::Uuid::new_v4();
+    let deleted = uuid::Uuid::new_v4();
+    let base = crate::diff::DiffableBase::default();
+    let diff = base
+        .deserialize(serde_json::json!({
+            "delete": [deleted],
+            "edit": {
+                uuid.to_string(): {
+                    "set": { "6": SECRET.as_bytes() },
+                    "delete": [13],
+                },
+            },
+        }))
+        .unwrap();
+
+    let redacted = diff.render(false).to_string();
+    assert_eq!(
+        redacted,
+        format!("- {deleted}\n~ {uuid}\n    set password: <redacted>\n    delete url\n"),
+    );
+
+    let shown = diff.render(true).to_string();
+    assert!(shown.contains(&format!("set password: {SECRET}")), "{shown}");
+}
+
+/// The diff turning generated records into their mutated version, see `mutate_records`.
+fn generated_changes(
+    records: &[pwsafer::generate::Record],
+    mutated: &[pwsafer::generate::Record],
+    changed: &[usize],
+) -> serde_json::Value {
+    let mut edit = serde_json::Map::new();
+
+    for &index in changed {
+        let uuid = uuid::Uuid::from_slice(&mutated[index][0].1).unwrap();
+
+        let set: serde_json::Map<_, _> = mutated[index]
+            .iter()
+            .map(|(ty, data)| (ty.to_string(), serde_json::json!(data)))
+            .collect();
+
+        let delete: Vec<u8> = records[index]
+            .iter()
+            .map(|(ty, _)| *ty)
+            .filter(|ty| mutated[index].iter().all(|(other, _)| other != ty))
+            .collect();
+
+        edit.insert(uuid.to_string(), serde_json::json!({ "set": set, "delete": delete }));
+    }
+
+    serde_json::json!({ "delete": [], "edit": edit })
+}
+
+/// Apply a diff to a database, and read the result.
+fn applied(
+    diff: &crate::diff::Diff,
+    key: &pwsafer::PwsafeKey,
+    reader: &mut pwsafer::PwsafeReader<std::io::Cursor<Vec<u8>>>,
+) -> pwsafer::PwsafeReader<std::io::Cursor<Vec<u8>>> {
+    let mut write_data = std::io::Cursor::new(vec![]);
+    let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, key).unwrap();
+    let keep = crate::diff::Modified::Keep;
+    diff.apply(reader, &mut writer, None, keep, crate::diff::INCOMING_GROUP).unwrap();
+    writer.finish().unwrap();
+
+    write_data.set_position(0);
+    pwsafer::PwsafeReader::new(write_data, key).unwrap()
+}
+
+/// Visiting a database, applying an empty diff and visiting again finds the same records. And
+/// the changes between generated records turn the database of one into that of the other.
+#[test]
+fn diff_visit_apply_fixed_point() {
+    use crate::diff::{Diff, DiffableBase};
+    use pwsafer::generate::{generate_records, mutate_records, write_database, Options};
+
+    let options = Options::default();
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let base = DiffableBase::default();
+
+    // Each seed is a case of its own, reproduced by the generator.
+    for seed in 0..32u64 {
+        let n = (seed as usize * 7) % 50;
+        let records = generate_records(seed, n, &options);
+        let db = std::io::Cursor::new(write_database(seed, &records, &options));
+        let mut reader = pwsafer::PwsafeReader::new(db, &key).unwrap();
+
+        let first = base.visit(&mut reader).unwrap();
+        let mut rewritten = applied(&Diff::empty(&first.new_base), &key, &mut reader);
+        let second = base.visit(&mut rewritten).unwrap();
+        assert!(first.new_base == second.new_base, "Not a fixed point, seed {seed}");
+
+        let mut mutated = records.clone();
+        let changed = mutate_records(seed, &mut mutated, n.min(seed as usize % 5));
+        let diff = base.deserialize(generated_changes(&records, &mutated, &changed)).unwrap();
+        let mut patched = applied(&diff, &key, &mut reader);
+
+        let expected = std::io::Cursor::new(write_database(seed, &mutated, &options));
+        let mut expected = pwsafer::PwsafeReader::new(expected, &key).unwrap();
+
+        let patched = Diff::snapshot(&base, &mut patched).unwrap().serialize().unwrap();
+        let expected = Diff::snapshot(&base, &mut expected).unwrap().serialize().unwrap();
+        assert_eq!(patched, expected, "Changes not applied, seed {seed}");
+    }
+}
+
+/// Field values are sent as base64, and the arrays of numbers of earlier versions still parse.
+#[test]
+fn diff_field_encodings() {
+    use crate::diff::DiffableBase;
+
+    let base = DiffableBase::default();
+    let entry = "0b7e2a51-8b6f-4c2e-9b59-4d7f5e3c2a10";
+
+    let old = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": [116, 105, 116, 108, 101], "6": [] }, "delete": [4] } },
+    })).unwrap();
+
+    let new = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": "dGl0bGU=", "6": "" }, "delete": [4] } },
+    })).unwrap();
+
+    let encoded = serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": "dGl0bGU=", "6": "" }, "delete": [4] } },
+    });
+
+    assert_eq!(old.serialize().unwrap(), encoded);
+    assert_eq!(new.serialize().unwrap(), encoded);
+
+    let invalid = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": "not base64!" }, "delete": [] } },
+    }));
+    assert!(invalid.is_err());
+}
+
+/// Keys of a diff that are not understood reject it, instead of applying only a part.
+#[test]
+fn diff_unknown_keys() {
+    use crate::diff::{DiffSerial, DiffableBase};
+
+    let base = DiffableBase::default();
+    let entry = "0b7e2a51-8b6f-4c2e-9b59-4d7f5e3c2a10";
+
+    let outer = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": {},
+        "rename": {},
+    }));
+    assert!(outer.is_err());
+
+    let inner = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": {}, "delete": [], "move": "group" } },
+    }));
+    assert!(inner.is_err());
+
+    let serial: DiffSerial = serde_json::from_value(serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": "dGl0bGU=" }, "delete": [] } },
+    })).unwrap();
+    assert_eq!(base.diff_of(serial).records(), 1);
+}
+
+/// The records counted when a diff is accepted by the server.
+#[test]
+fn diff_records_touched() {
+    use crate::diff::DiffableBase;
+
+    let base = DiffableBase::default();
+    let [a, b, c] = [[1; 16], [2; 16], [3; 16]].map(|id| uuid::Uuid::from_bytes(id).to_string());
+
+    let diff = base.deserialize(serde_json::json!({
+        "delete": [a, c],
+        "edit": {
+            a.as_str(): { "set": { "3": "dGl0bGU=" }, "delete": [] },
+            b.as_str(): { "set": {}, "delete": [4] },
+        },
+    })).unwrap();
+    assert_eq!(diff.records(), 3);
+
+    let empty = base.deserialize(serde_json::json!({ "delete": [], "edit": {} })).unwrap();
+    assert_eq!(empty.records(), 0);
+}
+
+/// A diff sent into the room and received back applies exactly as the original.
+///
+/// The writer salts each file and the fields of a record are set in hash map order, so the
+/// records of the results are compared and not their encrypted bytes.
+#[test]
+fn diff_serialize_roundtrip() {
+    use crate::diff::{Diff, DiffableBase};
+    use pwsafer::generate::{generate_records, mutate_records, write_database, Options};
+
+    let options = Options::default();
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let base = DiffableBase::default();
+
+    for seed in 0..8u64 {
+        let records = generate_records(seed, 20, &options);
+        let db = std::io::Cursor::new(write_database(seed, &records, &options));
+        let mut reader = pwsafer::PwsafeReader::new(db, &key).unwrap();
+
+        let mut mutated = records.clone();
+        let changed = mutate_records(seed, &mut mutated, 3);
+        let mut changes = generated_changes(&records, &mutated, &changed);
+
+        // Delete an unchanged record, and create one.
+        let gone = (0..records.len()).find(|index| !changed.contains(index)).unwrap();
+        let deleted = uuid::Uuid::from_slice(&records[gone][0].1).unwrap();
+        changes["delete"] = serde_json::json!([deleted]);
+        changes["edit"][uuid::Uuid::from_bytes([seed as u8; 16]).to_string()] = serde_json::json!({
+            "set": { "3": b"created", "6": b"secret" },
+            "delete": [],
+        });
+
+        let diff = base.deserialize(changes).unwrap();
+        let received = serde_json::to_string(&diff.serialize().unwrap()).unwrap();
+        let received = base.deserialize(serde_json::from_str(&received).unwrap()).unwrap();
+
+        let mut sent = applied(&diff, &key, &mut reader);
+        let mut received = applied(&received, &key, &mut reader);
+
+        let sent = Diff::snapshot(&base, &mut sent).unwrap().serialize().unwrap();
+        let received = Diff::snapshot(&base, &mut received).unwrap().serialize().unwrap();
+        assert_eq!(sent, received, "Not applied the same, seed {seed}");
+        assert!(sent["edit"].get(deleted.to_string()).is_none(), "Not deleted, seed {seed}");
+    }
+}
+
+/// A remote edit to a field that a pending local diff also sets is kept by the policy.
+#[test]
+fn diff_conflict_policies() {
+    use crate::diff::{ConflictPolicy, Conflicts, Diff, DiffableBase, Modified, INCOMING_GROUP};
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let base = DiffableBase::default();
+    let entry = uuid::Uuid::from_bytes([1; 16]);
+    let edit = |title: &[u8]| base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": { entry.to_string(): { "set": { "3": title }, "delete": [] } },
+    })).unwrap();
+
+    let (local, remote, echo) = (edit(b"local"), edit(b"remote"), edit(b"local"));
+
+    let resolve = |policy, remote: &Diff| {
+        let mut reader = in_memory_safe(&key, &[
+            &[(0x01, entry.as_bytes()), (0x03, b"title"), (0x05, b"notes")],
+        ]);
+
+        let mut conflicts = Conflicts::new(policy, vec![&local], &base);
+        conflicts.at = 1234;
+
+        let mut write_data = std::io::Cursor::new(vec![]);
+        let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, &key).unwrap();
+        let keep = Modified::Keep;
+        remote.apply(&mut reader, &mut writer, Some(&mut conflicts), keep, INCOMING_GROUP).unwrap();
+        writer.finish().unwrap();
+
+        write_data.set_position(0);
+        let mut merged = pwsafer::PwsafeReader::new(write_data, &key).unwrap();
+        let resolution = conflicts.into_resolution();
+
+        let mut merged = applied(&local, &key, &mut merged);
+        let mut merged = applied(&resolution, &key, &mut merged);
+        let snapshot = Diff::snapshot(&base, &mut merged).unwrap();
+        (resolution, snapshot.render(true).to_string())
+    };
+
+    let (resolution, _) = resolve(ConflictPolicy::Overwrite, &remote);
+    assert!(resolution.is_empty());
+
+    // Our own edit, received back from the room.
+    let (resolution, _) = resolve(ConflictPolicy::Note, &echo);
+    assert!(resolution.is_empty());
+
+    let (_, merged) = resolve(ConflictPolicy::Note, &remote);
+    assert!(merged.contains("set title: local\n"), "{merged}");
+    assert!(merged.contains("set notes: notes\nconflicted value for title at 1234: remote\n"), "{merged}");
+    assert_eq!(merged.matches("~ ").count(), 1, "{merged}");
+
+    let (_, merged) = resolve(ConflictPolicy::Duplicate, &remote);
+    assert!(merged.contains("set title: local\n"), "{merged}");
+    assert!(merged.contains("set title: remote (conflict)\n"), "{merged}");
+    assert_eq!(merged.matches("set notes: notes\n").count(), 2, "{merged}");
+    assert_eq!(merged.matches("~ ").count(), 2, "{merged}");
+}
+
+/// Records changed by a diff get their modification times, those of a room event are the same
+/// on every device.
+#[test]
+fn diff_apply_modification_times() {
+    use crate::diff::{DiffableBase, Modified, INCOMING_GROUP};
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let base = DiffableBase::default();
+    let [title, password, stamped, created, untouched] =
+        [[1; 16], [2; 16], [3; 16], [4; 16], [5; 16]].map(uuid::Uuid::from_bytes);
+
+    let diff = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": {
+            title.to_string(): { "set": { "3": "dGl0bGU=" }, "delete": [] },
+            password.to_string(): { "set": { "6": "c2VjcmV0" }, "delete": [] },
+            // Set by the pwsafe GUI along with the title, 1000 seconds after the epoch.
+            stamped.to_string(): { "set": { "3": "dGl0bGU=", "12": "6AMAAA==" }, "delete": [] },
+            created.to_string(): { "set": { "4": "YWxpY2U=" }, "delete": [] },
+        },
+    })).unwrap();
+
+    let times = |modified| {
+        let mut reader = in_memory_safe(&key, &[
+            &[(0x01, title.as_bytes()), (0x03, b"old"), (0x0c, &[0; 4])],
+            &[(0x01, password.as_bytes()), (0x06, b"old")],
+            &[(0x01, stamped.as_bytes())],
+            &[(0x01, untouched.as_bytes()), (0x03, b"old")],
+        ]);
+
+        let mut write_data = std::io::Cursor::new(vec![]);
+        let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, &key).unwrap();
+        diff.apply(&mut reader, &mut writer, None, modified, INCOMING_GROUP).unwrap();
+        writer.finish().unwrap();
+
+        write_data.set_position(0);
+        let mut reader = pwsafer::PwsafeReader::new(write_data, &key).unwrap();
+        reader.records()
+            .map(|record| {
+                let record = record.unwrap();
+                let time = |ty| record.field(ty).map(|data| u32::from_le_bytes(data.try_into().unwrap()));
+                let uuid = uuid::Uuid::from_slice(record.field(0x01).unwrap()).unwrap();
+                (uuid, (time(0x08), time(0x0c)))
+            })
+            .collect::<std::collections::HashMap<_, _>>()
+    };
+
+    let at = times(Modified::At(1_700_000_000_999));
+    let now = Some(1_700_000_000);
+    assert_eq!(at[&title], (None, now));
+    assert_eq!(at[&password], (now, now));
+    assert_eq!(at[&stamped], (None, Some(1000)));
+    assert_eq!(at[&created], (None, now));
+    assert_eq!(at[&untouched], (None, None));
+
+    let kept = times(Modified::Keep);
+    assert_eq!(kept[&title], (None, Some(0)));
+    assert_eq!(kept[&password], (None, None));
+    assert_eq!(kept[&created], (None, None));
+}
+
+/// Records created by a diff without a title get one, filed into the incoming group.
+#[test]
+fn diff_apply_repairs_titles() {
+    use crate::diff::{DiffableBase, INCOMING_GROUP};
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let base = DiffableBase::default();
+    let [untitled, grouped, titled, existing] =
+        [[0xab; 16], [2; 16], [3; 16], [4; 16]].map(uuid::Uuid::from_bytes);
+
+    let diff = base.deserialize(serde_json::json!({
+        "delete": [],
+        "edit": {
+            untitled.to_string(): { "set": { "4": "YWxpY2U=", "6": "c2VjcmV0" }, "delete": [] },
+            grouped.to_string(): { "set": { "2": "b3du", "4": "YWxpY2U=" }, "delete": [] },
+            titled.to_string(): { "set": { "3": "dGl0bGU=" }, "delete": [] },
+            existing.to_string(): { "set": { "4": "YWxpY2U=" }, "delete": [] },
+        },
+    })).unwrap();
+
+    let mut reader = in_memory_safe(&key, &[&[(0x01, existing.as_bytes())]]);
+    let mut reader = applied(&diff, &key, &mut reader);
+
+    let records = reader.records()
+        .map(|record| {
+            let record = record.unwrap();
+            let field = |ty| record.field(ty).map(<[u8]>::to_vec);
+            let uuid = uuid::Uuid::from_slice(record.field(0x01).unwrap()).unwrap();
+            (uuid, (field(0x03), field(0x02)))
+        })
+        .collect::<std::collections::HashMap<_, _>>();
+
+    let incoming = Some(INCOMING_GROUP.as_bytes().to_vec());
+    assert_eq!(records[&untitled], (Some(b"synced-abababab".to_vec()), incoming));
+    assert_eq!(records[&grouped], (Some(b"synced-02020202".to_vec()), Some(b"own".to_vec())));
+    assert_eq!(records[&titled], (Some(b"title".to_vec()), None));
+    // Only the records a diff creates are repaired.
+    assert_eq!(records[&existing], (None, None));
+}
+
+/// Open and visit generated databases of growing size.
+///
+/// A benchmark, run it with `cargo test --release -- --ignored diff_scales --nocapture`. Ten
+/// times the records must not take much more than ten times as long.
+#[test]
+#[ignore]
+fn diff_scales() {
+    use crate::diff::DiffableBase;
+    use crate::pwsafe::PwsafeDb;
+    use pwsafer::generate::{generate_database, Options};
+    use std::time::{Duration, Instant};
+
+    let options = Options::default();
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let mut timings: Vec<(Duration, Duration)> = vec![];
+
+    for n in [1_000, 10_000] {
+        let db = generate_database(n as u64, n, &options);
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), &db).unwrap();
+
+        let args = crate::ArgsPwsafe {
+            pwsafe: file.path().into(),
+            passwd_file: None,
+            passwd: Some(String::from_utf8(options.password.clone()).unwrap()),
+        };
+
+        let start = Instant::now();
+        PwsafeDb::open(&args).unwrap();
+        let open = start.elapsed();
+
+        let mut reader = pwsafer::PwsafeReader::new(std::io::Cursor::new(db), &key).unwrap();
+        let start = Instant::now();
+        DiffableBase::default().visit(&mut reader).unwrap();
+        let visit = start.elapsed();
+
+        eprintln!("{n} records: open in {open:?}, visit in {visit:?}");
+        timings.push((open, visit));
+    }
+
+    let [(open_small, visit_small), (open_large, visit_large)] = timings[..] else {
+        unreachable!();
+    };
+
+    assert!(open_large < open_small * 40, "Open scales badly: {timings:?}");
+    assert!(visit_large < visit_small * 40, "Visit scales badly: {timings:?}");
+}
+
+/// Applying diffs to records in memory gives the database that applying them to one encrypted
+/// database after another does.
+#[test]
+fn diff_apply_plain_matches_reader() {
+    use crate::diff::{Diff, DiffableBase, Modified, Plain, INCOMING_GROUP};
+    use pwsafer::generate::{generate_records, mutate_records, write_database, Options};
+
+    let options = Options::default();
+    let key = pwsafer::PwsafeKey::new(&options.password);
+    let base = DiffableBase::default();
+
+    for seed in 0..16u64 {
+        let n = 1 + (seed as usize * 7) % 40;
+        let mut records = generate_records(seed, n, &options);
+        let db = std::io::Cursor::new(write_database(seed, &records, &options));
+        let mut reader = pwsafer::PwsafeReader::new(db, &key).unwrap();
+
+        let mut diffs = vec![];
+        for round in 0..4 {
+            let mut mutated = records.clone();
+            let changed = mutate_records(seed * 4 + round, &mut mutated, n.min(3));
+            diffs.push(base.deserialize(generated_changes(&records, &mutated, &changed)).unwrap());
+            records = mutated;
+        }
+
+        // A record deleted, and one created without a title.
+        let deleted = uuid::Uuid::from_slice(&records[0][0].1).unwrap();
+        let created = uuid::Uuid::from_u128(0x5eed_0000 + u128::from(seed));
+        diffs.push(base.deserialize(serde_json::json!({
+            "delete": [deleted],
+            "edit": { created.to_string(): { "set": { "5": "bm90ZXM=" }, "delete": [] } },
+        })).unwrap());
+
+        let mut plain = Plain::read(&mut reader, &base).unwrap();
+        let mut expected = applied(&Diff::empty(&base), &key, &mut reader);
+        for diff in &diffs {
+            plain = diff.apply_plain(plain, None, Modified::Keep, INCOMING_GROUP).unwrap();
+            expected = applied(diff, &key, &mut expected);
+        }
+
+        let visited = base.visit_plain(&plain).unwrap();
+        let visited_expected = base.visit(&mut expected).unwrap();
+        assert!(visited.new_base == visited_expected.new_base, "Bases differ, seed {seed}");
+
+        let mut write_data = std::io::Cursor::new(vec![]);
+        let mut writer = pwsafer::PwsafeWriter::new(&mut write_data, 2048, &key).unwrap();
+        writer.write_header(plain.header()).unwrap();
+        let keep = Modified::Keep;
+        let empty = Diff::empty(&base);
+        empty.apply_plain_records(plain, &mut writer, None, keep, INCOMING_GROUP).unwrap();
+        writer.finish().unwrap();
+
+        write_data.set_position(0);
+        let mut written = pwsafer::PwsafeReader::new(write_data, &key).unwrap();
+        let written = Diff::snapshot(&base, &mut written).unwrap().serialize().unwrap();
+        let expected = Diff::snapshot(&base, &mut expected).unwrap().serialize().unwrap();
+        assert_eq!(written, expected, "Records differ, seed {seed}");
+    }
+}
+
+/// Rewrite a database of a thousand records with fifty queued local diffs.
+///
+/// A benchmark, run it with `cargo test --release -- --ignored diff_render_queued --nocapture`.
+/// The records are only encrypted once, so this must not take much longer than a rewrite with a
+/// single diff.
+#[test]
+#[ignore]
+fn diff_render_queued() {
+    use crate::pwsafe::PwsafeDb;
+    use pwsafer::generate::{generate_records, write_database, Options};
+    use std::time::Instant;
+
+    let options = Options::default();
+    let records = generate_records(1, 1_000, &options);
+    let file = tempfile::NamedTempFile::new().unwrap();
+    std::fs::write(file.path(), write_database(1, &records, &options)).unwrap();
+
+    let mut db = PwsafeDb::open(&crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some(String::from_utf8(options.password.clone()).unwrap()),
+    }).unwrap();
+
+    let diffs: Vec<_> = records
+        .iter()
+        .step_by(20)
+        .map(|record| {
+            let uuid = uuid::Uuid::from_slice(&record[0].1).unwrap();
+            db.diff(serde_json::json!({
+                "delete": [],
+                "edit": { uuid.to_string(): { "set": { "5": "cXVldWVk" }, "delete": [] } },
+            })).unwrap()
+        })
+        .collect();
+
+    let start = Instant::now();
+    db.with_lock(|mut lock| {
+        lock.apply(&diffs[0])?;
+        lock.rewrite()
+    }).unwrap();
+    let single = start.elapsed();
+
+    let start = Instant::now();
+    db.with_lock(|mut lock| {
+        for diff in &diffs[1..] {
+            lock.apply(diff)?;
+        }
+
+        lock.rewrite()
+    }).unwrap();
+    let queued = start.elapsed();
+
+    eprintln!("1 diff rewritten in {single:?}, {} diffs in {queued:?}", diffs.len());
+    assert!(queued < single * 5, "Queued diffs scale badly: {single:?} and {queued:?}");
+}
+
+/// Custom values are kept by the store, and survive serializing it into the state record.
+#[test]
+fn store_custom_values() {
+    use crate::store::PwsafeStore;
+    use matrix_sdk::crypto::store::CryptoStore as _;
+
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let store = PwsafeStore::new_empty();
+
+    rt.block_on(async {
+        assert_eq!(store.get_custom_value("key").await.unwrap(), None);
+        store.set_custom_value("key", b"value".to_vec()).await.unwrap();
+        store.set_custom_value("removed", b"gone".to_vec()).await.unwrap();
+        assert_eq!(store.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+
+        store.remove_custom_value("removed").await.unwrap();
+        // Removing what is not there is fine, too.
+        store.remove_custom_value("removed").await.unwrap();
+        assert_eq!(store.get_custom_value("removed").await.unwrap(), None);
+    });
+
+    let value = store.to_value().unwrap();
+    let restored = PwsafeStore::from_value(value.clone()).unwrap();
+    assert_eq!(restored.to_value().unwrap(), value);
+
+    rt.block_on(async {
+        assert_eq!(restored.get_custom_value("key").await.unwrap().as_deref(), Some(&b"value"[..]));
+        assert_eq!(restored.get_custom_value("removed").await.unwrap(), None);
+        assert_eq!(restored.next_batch_token().await.unwrap(), None);
+    });
+}
+
+/// The account and backup keys are saved by the store, and restored from the state record.
+#[test]
+fn store_account_roundtrip() {
+    use crate::store::PwsafeStore;
+    use matrix_sdk::crypto::olm::Account;
+    use matrix_sdk::crypto::store::{BackupDecryptionKey, Changes, CryptoStore as _, PendingChanges};
+    use matrix_sdk::ruma::{device_id, room_id, user_id};
+
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let store = PwsafeStore::new_empty();
+    let account = Account::with_device_id(user_id!("@alice:example.org"), device_id!("ALICE"));
+    let identity_keys = account.identity_keys();
+
+    rt.block_on(async {
+        let pending = PendingChanges {
+            account: Some(account),
+        };
+        store.save_pending_changes(pending).await.unwrap();
+
+        let changes = Changes {
+            backup_version: Some("1".into()),
+            backup_decryption_key: Some(BackupDecryptionKey::from_bytes(&[7; 32])),
+            ..Changes::default()
+        };
+        store.save_changes(changes).await.unwrap();
+    });
+
+    let restored = PwsafeStore::from_value(store.to_value().unwrap()).unwrap();
+
+    rt.block_on(async {
+        let account = restored.load_account().await.unwrap().unwrap();
+        assert_eq!(account.identity_keys(), identity_keys);
+
+        let backup = restored.load_backup_keys().await.unwrap();
+        assert_eq!(backup.backup_version.as_deref(), Some("1"));
+        let key = backup.decryption_key.unwrap();
+        assert_eq!(key.to_base64(), BackupDecryptionKey::from_bytes(&[7; 32]).to_base64());
+
+        let room = room_id!("!room:example.org");
+        assert!(restored.get_sessions("unknown").await.unwrap().is_none());
+        assert!(restored.get_outbound_group_session(room).await.unwrap().is_none());
+        assert!(restored.get_inbound_group_session(room, "unknown").await.unwrap().is_none());
+        assert_eq!(restored.inbound_group_session_counts().await.unwrap().total, 0);
+    });
+}
+
+/// Key requests and withheld room keys, as saved when an event can not be decrypted, are kept.
+#[test]
+fn store_key_requests_withheld() {
+    use crate::store::PwsafeStore;
+    use matrix_sdk::crypto::store::{Changes, CryptoStore as _};
+    use matrix_sdk::crypto::types::events::room_key_withheld::RoomKeyWithheldEvent;
+    use matrix_sdk::crypto::{GossipRequest, SecretInfo};
+    use matrix_sdk::ruma::events::secret::request::SecretName;
+    use matrix_sdk::ruma::{room_id, user_id, TransactionId};
+
+    let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let store = PwsafeStore::new_empty();
+    let room = room_id!("!room:example.org");
+
+    let request = GossipRequest {
+        request_recipient: user_id!("@alice:example.org").to_owned(),
+        request_id: TransactionId::new(),
+        info: SecretInfo::SecretRequest(SecretName::CrossSigningMasterKey),
+        sent_out: false,
+    };
+
+    let withheld: RoomKeyWithheldEvent = serde_json::from_value(serde_json::json!({
+        "sender": "@bob:example.org",
+        "type": "m.room_key.withheld",
+        "content": {
+            "algorithm": "m.megolm.v1.aes-sha2",
+            "code": "m.unverified",
+            "reason": "Device not verified",
+            "room_id": room,
+            "session_id": "session",
+            "sender_key": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA",
+        },
+    })).unwrap();
+
+    rt.block_on(async {
+        assert!(store.get_withheld_info(room, "session").await.unwrap().is_none());
+
+        let mut changes = Changes {
+            key_requests: vec![request.clone()],
+            ..Changes::default()
+        };
+
+        changes.withheld_session_info
+            .entry(room.to_owned())
+            .or_default()
+            .insert("session".to_owned(), withheld.clone());
+
+        store.save_changes(changes).await.unwrap();
+    });
+
+    let restored = PwsafeStore::from_value(store.to_value().unwrap()).unwrap();
+
+    rt.block_on(async {
+        let unsent = restored.get_unsent_secret_requests().await.unwrap();
+        assert_eq!(unsent.len(), 1);
+        assert_eq!(unsent[0].request_id, request.request_id);
+
+        let by_info = restored.get_secret_request_by_info(&request.info).await.unwrap();
+        assert_eq!(by_info.unwrap().request_id, request.request_id);
+
+        let info = restored.get_withheld_info(room, "session").await.unwrap().unwrap();
+        assert_eq!(serde_json::to_value(info).unwrap(), serde_json::to_value(&withheld).unwrap());
+        assert!(restored.get_withheld_info(room, "other").await.unwrap().is_none());
+
+        restored.delete_outgoing_secret_requests(&request.request_id).await.unwrap();
+        let deleted = restored.get_outgoing_secret_requests(&request.request_id).await.unwrap();
+        assert!(deleted.is_none());
+    });
+}
+
+/// Passwords generated by the policy of a record, or one of the request, are set by a diff.
+#[test]
+fn generate_password_policies() {
+    use crate::pwsafe::{PasswordRejected, PolicySource, PwsafeDb};
+    use pwsafer::policy::Policy;
+
+    let policies = concat!(
+        "02",
+        "03pin", "0800", "006", "000", "000", "000", "000", "00",
+        "04wifi", "f000", "020", "001", "001", "001", "001", "03#$%",
+    );
+
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let file = tempfile::NamedTempFile::new().unwrap();
+    let mut writer = pwsafer::PwsafeWriter::new(file.reopen().unwrap(), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03]), (0x10, policies.as_bytes())]).unwrap();
+    writer.write_record(&[(0x01, &[1; 16]), (0x03, b"named"), (0x18, b"pin")]).unwrap();
+    writer.write_record(&[
+        (0x01, &[2; 16]),
+        (0x03, b"own"),
+        (0x10, b"1000010000000000003"),
+        (0x16, b"&"),
+    ]).unwrap();
+    writer.write_record(&[(0x01, &[3; 16]), (0x03, b"none"), (0x06, b"old")]).unwrap();
+    writer.finish().unwrap();
+
+    let args = || crate::ArgsPwsafe {
+        pwsafe: file.path().into(),
+        passwd_file: None,
+        passwd: Some("password".into()),
+    };
+
+    let mut db = PwsafeDb::open(&args()).unwrap();
+    let [named, own, none, unknown] = [1, 2, 3, 9].map(|id| uuid::Uuid::from_bytes([id; 16]));
+
+    let mut generate = |uuid, source| db.generate_password(uuid, &source).unwrap();
+
+    let (pin, _) = generate(named, PolicySource::Record).unwrap();
+    assert!(pin.len() == 6 && pin.chars().all(|ch| ch.is_ascii_hexdigit()), "{pin}");
+    let (symbols, _) = generate(own, PolicySource::Record).unwrap();
+    assert_eq!(symbols, "&&&&&&&&&&&&&&&&");
+
+    let (wifi, _) = generate(none, PolicySource::Named("wifi".into())).unwrap();
+    assert_eq!(wifi.chars().count(), 32);
+    assert!(wifi.contains(['#', '$', '%']), "{wifi}");
+
+    let inline = Policy { flags: Policy::USE_DIGITS, length: 4, ..Policy::default() };
+    let (digits, diff) = generate(none, PolicySource::Inline(inline)).unwrap();
+    assert!(digits.len() == 4 && digits.chars().all(|ch| ch.is_ascii_digit()), "{digits}");
+
+    assert!(matches!(generate(none, PolicySource::Record), Err(PasswordRejected::Policy(_))));
+    let missing = PolicySource::Named("missing".into());
+    assert!(matches!(generate(none, missing), Err(PasswordRejected::Policy(_))));
+    let unknown = generate(unknown, PolicySource::Record);
+    assert!(matches!(unknown, Err(PasswordRejected::UnknownRecord)));
+
+    // Only the last generated password is set.
+    db.with_lock(|mut lock| {
+        lock.apply(&diff)?;
+        lock.rewrite()
+    }).unwrap();
+
+    let db = PwsafeDb::open(&args()).unwrap();
+    let record = db.entry(none).unwrap().unwrap();
+    assert_eq!(record[&0x06], digits.as_bytes());
+    assert_eq!(record[&0x03], b"none");
+    assert!(record.contains_key(&0x08));
+}
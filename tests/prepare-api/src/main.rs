@@ -1,12 +1,9 @@
 //! Implement the synapse-based Administrator API, to prepare the Synapse homeserver for local
 //! testing. All relevant configuration is passed via environment variables.
-use std::{fs::File, path::Path};
+use std::{fs::File, path::Path, path::PathBuf};
 use serde::{Deserialize, Serialize};
 
 fn main() -> Result<(), anyhow::Error> {
-    // Get the shared secret..
-    let homeserver = HomeServer::new()?;
-
     let agent = ureq::AgentBuilder::new()
         .build();
 
@@ -23,6 +20,8 @@ fn main() -> Result<(), anyhow::Error> {
         homeserver: address,
         username,
         password,
+        admin,
+        homeserver_config,
     } = {
         let path_err = configuration_path.display().to_string();
 
@@ -32,6 +31,9 @@ fn main() -> Result<(), anyhow::Error> {
         serde_yaml::from_reader(file)?
     };
 
+    // Get the shared secret..
+    let homeserver = HomeServer::new(homeserver_config.as_deref())?;
+
     let register_address = address.join("_synapse/admin/v1/register")?;
 
     let nonce = {
@@ -49,7 +51,7 @@ fn main() -> Result<(), anyhow::Error> {
         username,
         displayname: "Example Is Good".to_string(),
         password,
-        admin: false,
+        admin,
     };
 
     let mac = homeserver.mac(&user);
@@ -74,6 +76,12 @@ struct TestEnv {
     homeserver: url::Url,
     username: String,
     password: String,
+    /// Register a server administrator, such as the one cleaning up after the tests.
+    #[serde(default)]
+    admin: bool,
+    /// The configuration of a homeserver started by the harness, instead of the bundled one.
+    #[serde(default, rename = "homeserver-config")]
+    homeserver_config: Option<PathBuf>,
 }
 
 #[derive(Deserialize)]
@@ -82,13 +90,13 @@ struct RegisterNonce {
 }
 
 impl HomeServer {
-    pub fn new() -> Result<Self, anyhow::Error> {
-        let homeserver = File::open(
-            concat!(
-                env!("CARGO_MANIFEST_DIR"),
-                "/../local/data/homeserver.yaml"
-            )
-        )?;
+    pub fn new(config: Option<&Path>) -> Result<Self, anyhow::Error> {
+        const LOCAL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../local/data/homeserver.yaml");
+
+        let config = config.unwrap_or(Path::new(LOCAL));
+        let homeserver = File::open(config)
+            .map_err(anyhow::Error::from)
+            .map_err(|err| err.context(config.display().to_string()))?;
 
         let homeserver = serde_yaml::from_reader(homeserver)?;
         Ok(homeserver)
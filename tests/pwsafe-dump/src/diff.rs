@@ -0,0 +1,134 @@
+//! Compare the records of two databases, for `--diff`.
+//!
+//! Records are matched by their UUID, and compared field by field. The header is not compared, it
+//! changes with every save. The rendering follows the one of `pwsafe-matrix`:
+//!
+//! ```text
+//! - <uuid of a removed record>
+//! + <uuid of an added record>
+//!     set Title: "all of its fields"
+//! ~ <uuid of a changed record>
+//!     set Password: "<redacted:len=12>"
+//!     delete Url
+//! ```
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+
+use color_eyre::eyre::Error;
+use pwsafer::{PwsafeKey, PwsafeReader, PwsafeRecordField};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{redact_value, SECRET_FIELDS};
+
+/// The fields of each record by type, the records by UUID.
+type Records = BTreeMap<Uuid, BTreeMap<u8, Vec<u8>>>;
+
+/// Render the changes from `base` to `other` as lines of text.
+///
+/// Either file may use its own passphrase and iteration count.
+pub fn diff_files(
+    base: &OsStr,
+    base_key: &PwsafeKey,
+    other: &OsStr,
+    other_key: &PwsafeKey,
+    redact: bool,
+) -> Result<Vec<String>, Error> {
+    let base = read_records(base, base_key)?;
+    let other = read_records(other, other_key)?;
+
+    let mut uuids: Vec<_> = base.keys().chain(other.keys()).collect();
+    uuids.sort();
+    uuids.dedup();
+
+    let mut lines = vec![];
+    let none = BTreeMap::new();
+
+    for uuid in uuids {
+        let (marker, before, after) = match (base.get(uuid), other.get(uuid)) {
+            (Some(_), None) => {
+                lines.push(format!("- {uuid}"));
+                continue;
+            }
+            (None, Some(after)) => ('+', &none, after),
+            (Some(before), Some(after)) => ('~', before, after),
+            (None, None) => unreachable!("the UUID is from either file"),
+        };
+
+        let mut changes = vec![];
+
+        for (&ty, data) in after {
+            if before.get(&ty) != Some(data) {
+                let (name, value) = field(ty, data, redact);
+                changes.push(format!("    set {name}: {value}"));
+            }
+        }
+
+        for (&ty, data) in before {
+            if !after.contains_key(&ty) {
+                let (name, _) = field(ty, data, redact);
+                changes.push(format!("    delete {name}"));
+            }
+        }
+
+        if marker == '+' || !changes.is_empty() {
+            lines.push(format!("{marker} {uuid}"));
+            lines.append(&mut changes);
+        }
+    }
+
+    Ok(lines)
+}
+
+fn read_records(path: &OsStr, key: &PwsafeKey) -> Result<Records, Error> {
+    let display = std::path::Path::new(path).display();
+    let file = fs::File::open(path)?;
+    let mut reader = PwsafeReader::new(file, key)?;
+
+    let mut all = Records::new();
+
+    for (index, record) in reader.records().enumerate() {
+        let record = record.map_err(|err| Error::msg(format!("record {index} of {display}: {err}")))?;
+        let mut fields: BTreeMap<_, _> = record.into_fields().into_iter().collect();
+
+        let Some(uuid) = fields.remove(&0x01) else {
+            return Err(Error::msg(format!(
+                "record {index} of {display} has no UUID"
+            )));
+        };
+
+        let uuid = Uuid::from_slice(&uuid)?;
+        if all.insert(uuid, fields).is_some() {
+            return Err(Error::msg(format!("{display} repeats the UUID {uuid}")));
+        }
+    }
+
+    Ok(all)
+}
+
+/// The name of a field and its value, as in the dump.
+///
+/// Fields that are unknown, or can not be parsed, are shown by their type and always redacted.
+fn field(ty: u8, data: &[u8], redact: bool) -> (String, Value) {
+    let parsed = match PwsafeRecordField::new(ty, data.to_vec()) {
+        Ok(PwsafeRecordField::Blob(..)) | Err(_) => None,
+        Ok(field) => serde_json::to_value(&field).ok(),
+    };
+
+    let Some(mut value) = parsed else {
+        return (
+            format!("field-{ty:#04x}"),
+            Value::String(format!("<redacted:len={}>", data.len())),
+        );
+    };
+
+    if redact && SECRET_FIELDS.contains(&ty) {
+        redact_value(&mut value, data.len());
+    }
+
+    match value {
+        Value::Object(map) => map.into_iter().next().unwrap_or_default(),
+        other => (format!("field-{ty:#04x}"), other),
+    }
+}
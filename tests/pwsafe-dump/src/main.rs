@@ -1,66 +1,414 @@
 //! Dump a password safe file to json.
 //!
-//! This program returns `0` when the file is valid and fully understood. Avoid running it on
+//! The document `{ "header": [..], "records": [{ "uuid": .., "fields": [..] }, ..] }` is written
+//! to stdout, any diagnostics to stderr.
+//!
+//! Fields of an unknown type are dumped as `{ "Unknown": { "type": .., "hex": .. } }`. Those, and
+//! secrets such as passwords, notes and two-factor keys, are replaced by their length, as
+//! `"data": "<redacted:len=..>"`, unless `--no-redact` is given. Diagnostics never include the contents of fields, they name the record
+//! and the field by their index, and the type of the field.
+//!
+//! This program returns `0` when the file is valid and fully understood. With structural problems
+//! or unknown fields it still dumps everything it could parse, but returns `1`. Avoid running it on
 //! sensitive data, the data being decrypted is not kept safe at all.
-use std::{ffi::OsString, fs};
+//!
+//! With `--verify` nothing is dumped. The file is checked and a summary written to stderr, the
+//! exit status is:
+//!
+//! * `0` for a valid file, possibly with unknown fields.
+//! * `1` for a file with unknown fields, with `--strict`.
+//! * `2` for structural errors, such as a failed HMAC, missing mandatory fields or duplicate UUIDs.
+//! * `3` for a wrong passphrase.
+//!
+//! With `--diff other.psafe3` the records of the two files are compared instead, and the changes
+//! printed. This returns `0` for equal records, `1` if there are differences, and `2` if either
+//! file could not be read.
+//!
+//! With `--export xml` or `--export csv` the records are written as Password Safe's XML export, or
+//! as CSV with the `--columns` given. Secrets are redacted as in the dump, unless `--no-redact` is
+//! given.
+//!
+//! The passphrase is taken from one of `--password`, `--key-file` or `--password-stdin`. Without
+//! any of them, from the `PWSAFE_PASSWORD` environment variable or, failing that, it is prompted
+//! for on a terminal.
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    fs,
+    io::{Read as _, Write as _},
+};
 
+use clap::{Parser, ValueEnum};
 use color_eyre::eyre::Error;
-use pwsafer::{PwsafeReader, PwsafeHeaderField, PwsafeRecordField, PwsafeKey};
-use clap::Parser;
+use pwsafer::export::Column;
+use pwsafer::{
+    PwsafeHeaderField, PwsafeKey, PwsafeReader, PwsafeRecord, PwsafeRecordField, ReadError,
+};
+use serde::Serialize;
+use serde_json::Value;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+mod diff;
+
+/// The longest passphrase read from stdin, which is read without reallocating.
+const MAX_STDIN_PASSPHRASE: usize = 4096;
+
+/// The record fields holding secrets: notes, password, password history, two-factor key, credit
+/// card number, verification value and PIN.
+const SECRET_FIELDS: &[u8] = &[0x05, 0x06, 0x0f, 0x1b, 0x1c, 0x1e, 0x1f];
+
+#[derive(Serialize)]
+struct Dump {
+    header: Vec<Value>,
+    records: Vec<Record>,
+}
+
+#[derive(Serialize)]
+struct Record {
+    uuid: Option<Uuid>,
+    /// All other fields, in the order of the file.
+    fields: Vec<Value>,
+}
+
+/// Diagnostics written to stderr so far.
+#[derive(Default)]
+struct Problems {
+    /// The file is not valid.
+    errors: u32,
+    /// The file is valid, but not fully understood.
+    warnings: u32,
+}
+
+impl Problems {
+    fn error(&mut self, message: std::fmt::Arguments) {
+        eprintln!("Error: {message}");
+        self.errors += 1;
+    }
+
+    fn warning(&mut self, message: std::fmt::Arguments) {
+        eprintln!("Warning: {message}");
+        self.warnings += 1;
+    }
+}
 
 fn main() -> Result<(), Error> {
     let args: Args = Args::parse();
     let file = fs::File::open(&args.pwsafe)?;
 
-    let passphrase = match (args.passwd_file, args.passwd) {
-        (Some(file), None) => {
-            let data = fs::read(file)?;
-            PwsafeKey::new(&data)
-        },
-        (None, Some(string)) => {
-            let data = string.as_bytes();
-            PwsafeKey::new(data)
-        },
-        _ => {
-            return Err(Error::msg("Provide exactly one of key-file or password"));
-        },
+    let passphrase = passphrase(&args)?;
+
+    if let Some(other) = &args.diff {
+        return diff(&args, &passphrase, other);
+    }
+
+    let mut reader = match PwsafeReader::new(file, &passphrase) {
+        Ok(reader) => reader,
+        Err(ReadError::InvalidPassword) if args.verify => {
+            eprintln!("Error: wrong passphrase");
+            std::process::exit(3);
+        }
+        Err(err) if args.verify => {
+            eprintln!("Error: {err}");
+            std::process::exit(2);
+        }
+        Err(err) => return Err(err.into()),
     };
 
-    type Printer = dyn FnMut(u8, Vec<u8>) -> bool;
+    if let Some(format) = args.export {
+        return export(&args, &mut reader, format);
+    }
 
-    let mut handle_header = |field: u8, data: Vec<u8>| -> bool {
-        let Ok(header) = PwsafeHeaderField::new(field, data) else {
-            panic!("Bad header field: {field}");
-        };
+    let mut problems = Problems::default();
+
+    let header = match reader.header_record() {
+        Ok(header) => header.into_fields(),
+        Err(_) => {
+            problems.error(format_args!("the header does not end"));
+            vec![]
+        }
+    };
 
-        eprintln!("{header:?}");
-        matches!(header, PwsafeHeaderField::EndOfHeader)
+    let redact = !args.no_redact;
+
+    let mut dump = Dump {
+        header: vec![],
+        records: vec![],
     };
 
-    let mut handle_record = |field: u8, data: Vec<u8>| {
-        let Ok(header) = PwsafeRecordField::new(field, data) else {
-            panic!("Bad header field: {field}");
+    for (position, (ty, data)) in header.into_iter().enumerate() {
+        match PwsafeHeaderField::new(ty, data.clone()) {
+            Ok(PwsafeHeaderField::Blob(..)) => {
+                problems.warning(format_args!(
+                    "header field {position} (type {ty:#04x}) is unknown"
+                ));
+                dump.header.push(unknown(ty, data, redact));
+            }
+            Ok(field) => dump.header.push(serde_json::to_value(&field)?),
+            Err(err) => {
+                problems.error(format_args!(
+                    "header field {position} (type {ty:#04x}): {err}"
+                ));
+            }
+        }
+    }
+
+    let mut uuids = HashSet::new();
+    let mut count = 0;
+
+    for (index, entry) in reader.records().enumerate() {
+        count += 1;
+
+        // Nothing of it is trustworthy, and it is the last one.
+        let Ok(entry) = entry else {
+            problems.error(format_args!("record {index} does not end"));
+            continue;
         };
 
-        eprintln!("{header:?}");
-        false
+        // The fields every record must have, besides its UUID.
+        for (ty, name) in [(0x03, "title"), (0x06, "password")] {
+            if entry.field(ty).is_none() {
+                problems.error(format_args!("record {index} has no {name}"));
+            }
+        }
+
+        let mut record = Record {
+            uuid: None,
+            fields: vec![],
+        };
+        let mut group = None;
+
+        for (position, (ty, data)) in entry.into_fields().into_iter().enumerate() {
+            let len = data.len();
+
+            match PwsafeRecordField::new(ty, data) {
+                Ok(PwsafeRecordField::Uuid(uuid)) if record.uuid.is_none() => {
+                    let uuid = Uuid::from_bytes(uuid);
+                    if !uuids.insert(uuid) {
+                        problems.error(format_args!("record {index} repeats the UUID {uuid}"));
+                    }
+
+                    record.uuid = Some(uuid);
+                }
+                Ok(PwsafeRecordField::Blob(_, data)) => {
+                    problems.warning(format_args!(
+                        "record {index}, field {position} (type {ty:#04x}) is unknown"
+                    ));
+
+                    let value = unknown(ty, data, redact);
+                    if args.wants_field(&value) {
+                        record.fields.push(value);
+                    }
+                }
+                Ok(field) => {
+                    if let PwsafeRecordField::Group(name) = &field {
+                        group = Some(name.clone());
+                    }
+
+                    let mut value = serde_json::to_value(&field)?;
+                    if !args.wants_field(&value) {
+                        continue;
+                    }
+
+                    if redact && SECRET_FIELDS.contains(&ty) {
+                        redact_value(&mut value, len);
+                    }
+
+                    record.fields.push(value);
+                }
+                Err(err) => {
+                    problems.error(format_args!(
+                        "record {index}, field {position} (type {ty:#04x}): {err}"
+                    ));
+                }
+            }
+        }
+
+        if record.uuid.is_none() {
+            problems.error(format_args!("record {index} has no UUID"));
+        }
+
+        if args.wants_record(record.uuid, group.as_deref()) {
+            dump.records.push(record);
+        }
+    }
+
+    if args.verify {
+        let Problems { errors, warnings } = problems;
+        eprintln!("{count} records, {errors} errors, {warnings} warnings");
+
+        if errors > 0 {
+            std::process::exit(2);
+        } else if args.strict && warnings > 0 {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    if args.pretty {
+        serde_json::to_writer_pretty(&mut stdout, &dump)?;
+    } else {
+        serde_json::to_writer(&mut stdout, &dump)?;
+    }
+    writeln!(stdout)?;
+    stdout.flush()?;
+
+    let Problems { errors, warnings } = problems;
+    if errors + warnings > 0 {
+        eprintln!("Found {} problems", errors + warnings);
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Print the changes from the database to `other`, and exit.
+fn diff(args: &Args, passphrase: &PwsafeKey, other: &OsString) -> Result<(), Error> {
+    let other_passphrase = args
+        .other_passwd
+        .as_ref()
+        .map(|string| PwsafeKey::new(string.as_bytes()));
+    let other_passphrase = other_passphrase.as_ref().unwrap_or(passphrase);
+
+    let redact = !args.no_redact;
+    let lines = match diff::diff_files(&args.pwsafe, passphrase, other, other_passphrase, redact) {
+        Ok(lines) => lines,
+        Err(err) => {
+            eprintln!("Error: {err}");
+            std::process::exit(2);
+        }
     };
 
-    let mut handle_field: &mut Printer;
-    let handlers: [&mut Printer; 2] = [&mut handle_header, &mut handle_record];
-    let mut handlers = handlers.into_iter();
+    let mut stdout = std::io::stdout().lock();
+    for line in &lines {
+        writeln!(stdout, "{line}")?;
+    }
+    stdout.flush()?;
 
-    handle_field = handlers.next().unwrap();
-    let mut reader = PwsafeReader::new(file, &passphrase)?;
-    while let Some((field, data)) = reader.read_field() {
-        if handle_field(field, data) {
-            handle_field = handlers.next().unwrap();
+    if !lines.is_empty() {
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Write the records in another format, as long as all of them can be read.
+fn export(
+    args: &Args,
+    reader: &mut PwsafeReader<fs::File>,
+    format: ExportFormat,
+) -> Result<(), Error> {
+    let mut records = reader.records().collect::<Result<Vec<_>, _>>()?;
+
+    if !args.no_redact {
+        records = records.into_iter().map(redact_record).collect();
+    }
+
+    let mut stdout = std::io::stdout().lock();
+    match format {
+        ExportFormat::Xml => pwsafer::export::xml(&records, &mut stdout)?,
+        ExportFormat::Csv => {
+            let columns = args.columns.as_deref().unwrap_or(Column::DEFAULT);
+            pwsafer::export::csv(&records, columns, &mut stdout)?
         }
     }
+    stdout.flush()?;
 
     Ok(())
 }
 
+/// Replace the data of secret fields by their length.
+fn redact_record(record: PwsafeRecord) -> PwsafeRecord {
+    let fields = record
+        .into_fields()
+        .into_iter()
+        .map(|(ty, data)| {
+            if SECRET_FIELDS.contains(&ty) {
+                (ty, format!("<redacted:len={}>", data.len()).into_bytes())
+            } else {
+                (ty, data)
+            }
+        })
+        .collect::<Vec<_>>();
+
+    PwsafeRecord::from(fields)
+}
+
+/// The passphrase from the option that is given, the environment, or the terminal.
+fn passphrase(args: &Args) -> Result<PwsafeKey, Error> {
+    let given = [
+        args.passwd.is_some(),
+        args.passwd_file.is_some(),
+        args.password_stdin,
+    ];
+
+    if given.into_iter().filter(|&given| given).count() > 1 {
+        return Err(Error::msg(
+            "Provide at most one of --password, --key-file or --password-stdin. \
+            Without any, the PWSAFE_PASSWORD environment variable is used or, \
+            on a terminal, the passphrase is asked for",
+        ));
+    }
+
+    let data = if let Some(string) = &args.passwd {
+        Zeroizing::new(string.as_bytes().to_vec())
+    } else if let Some(file) = &args.passwd_file {
+        Zeroizing::new(fs::read(file)?)
+    } else if args.password_stdin {
+        // Allocated up-front, a reallocation would leave behind a copy that is not zeroed.
+        let mut data = Zeroizing::new(Vec::with_capacity(MAX_STDIN_PASSPHRASE + 1));
+        let limit = MAX_STDIN_PASSPHRASE as u64 + 1;
+        std::io::stdin().lock().take(limit).read_to_end(&mut data)?;
+
+        if data.len() > MAX_STDIN_PASSPHRASE {
+            return Err(Error::msg(format!(
+                "The passphrase on stdin is longer than {MAX_STDIN_PASSPHRASE} bytes"
+            )));
+        }
+
+        if data.last() == Some(&b'\n') {
+            data.pop();
+        }
+
+        data
+    } else if let Ok(string) = std::env::var("PWSAFE_PASSWORD") {
+        Zeroizing::new(string.into_bytes())
+    } else if passterm::isatty(passterm::Stream::Stdin) {
+        let string = passterm::prompt_password_stdin(None, passterm::Stream::Stderr)?;
+        Zeroizing::new(string.into_bytes())
+    } else {
+        return Err(Error::msg(
+            "Provide one of --password, --key-file, --password-stdin or PWSAFE_PASSWORD, \
+            or run on a terminal to be asked for the passphrase",
+        ));
+    };
+
+    Ok(PwsafeKey::new(&data))
+}
+
+/// Replace the contents of a field, `{ "Password": ".." }`, by the length of its data.
+fn redact_value(value: &mut Value, len: usize) {
+    if let Some(contents) = value
+        .as_object_mut()
+        .and_then(|map| map.values_mut().next())
+    {
+        *contents = Value::String(format!("<redacted:len={len}>"));
+    }
+}
+
+/// A field of unknown type, with its raw data.
+fn unknown(ty: u8, data: Vec<u8>, redact: bool) -> Value {
+    if redact {
+        let data = format!("<redacted:len={}>", data.len());
+        return serde_json::json!({ "Unknown": { "type": ty, "data": data } });
+    }
+
+    let hex: String = data.iter().map(|byte| format!("{byte:02x}")).collect();
+    serde_json::json!({ "Unknown": { "type": ty, "hex": hex } })
+}
+
 #[derive(Parser, Debug)]
 struct Args {
     #[arg(help = "A pwsafe V3 database")]
@@ -69,4 +417,98 @@ struct Args {
     passwd_file: Option<OsString>,
     #[arg(long = "password")]
     passwd: Option<String>,
+    /// Read the passphrase from stdin, without one trailing newline.
+    #[arg(long = "password-stdin")]
+    password_stdin: bool,
+    /// Indent the JSON for reading.
+    #[arg(long = "pretty")]
+    pretty: bool,
+    /// Replace secrets by their length, the default.
+    #[arg(long = "redact", overrides_with = "no_redact")]
+    redact: bool,
+    /// Dump secrets as they are.
+    #[arg(long = "no-redact", overrides_with = "redact")]
+    no_redact: bool,
+    /// Only dump these record fields, by name such as `title,username,url`.
+    #[arg(long = "fields", value_delimiter = ',')]
+    fields: Option<Vec<String>>,
+    /// Dump the records without any of their fields, only their UUIDs.
+    #[arg(long = "only-uuids", conflicts_with = "fields")]
+    only_uuids: bool,
+    /// Only dump the records with these UUIDs.
+    #[arg(long = "uuid")]
+    uuid: Vec<Uuid>,
+    /// Only dump the records in this group, or its subgroups.
+    #[arg(long = "group")]
+    group: Option<String>,
+    /// Check the file, without dumping anything.
+    #[arg(long = "verify")]
+    verify: bool,
+    /// Treat unknown fields as failures of `--verify`.
+    #[arg(long = "strict", requires = "verify")]
+    strict: bool,
+    /// Print the changes of the records from the database to this one.
+    #[arg(long = "diff", conflicts_with = "verify")]
+    diff: Option<OsString>,
+    /// The password of the `--diff` database, if it differs.
+    #[arg(long = "other-password", requires = "diff")]
+    other_passwd: Option<String>,
+    /// Write the records in this format instead of dumping them.
+    #[arg(long = "export", value_enum, conflicts_with_all = ["verify", "diff"])]
+    export: Option<ExportFormat>,
+    /// The columns of the CSV export, by name such as `group,title,password`.
+    #[arg(long = "columns", value_delimiter = ',', requires = "export")]
+    columns: Option<Vec<Column>>,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ExportFormat {
+    /// The XML export of Password Safe.
+    Xml,
+    /// Comma separated values, a header row and a row for each record.
+    Csv,
+}
+
+impl Args {
+    /// Whether to dump a field, given as `{ "Name": .. }`.
+    ///
+    /// Names compare without case, `-` and `_`, so `email-address` selects `EmailAddress`.
+    fn wants_field(&self, value: &Value) -> bool {
+        if self.only_uuids {
+            return false;
+        }
+
+        let Some(fields) = &self.fields else {
+            return true;
+        };
+
+        let normalize = |name: &str| -> String {
+            name.chars()
+                .filter(|ch| !matches!(ch, '-' | '_'))
+                .flat_map(char::to_lowercase)
+                .collect()
+        };
+
+        let Some(name) = value.as_object().and_then(|map| map.keys().next()) else {
+            return false;
+        };
+
+        let name = normalize(name);
+        fields.iter().any(|wanted| normalize(wanted) == name)
+    }
+
+    fn wants_record(&self, uuid: Option<Uuid>, group: Option<&str>) -> bool {
+        let by_uuid = self.uuid.is_empty() || uuid.is_some_and(|uuid| self.uuid.contains(&uuid));
+
+        // Subgroups are separated by dots.
+        let by_group = self.group.as_deref().is_none_or(|wanted| {
+            group.is_some_and(|group| {
+                group
+                    .strip_prefix(wanted)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+            })
+        });
+
+        by_uuid && by_group
+    }
 }
@@ -0,0 +1,563 @@
+use std::process::Command;
+
+/// The database of the reader's own tests, with the password `password`.
+const FIXTURE: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../third-party/pwsafer/tests/pwsafe.psafe3"
+);
+
+fn dump(args: &[&str]) -> std::process::Output {
+    Command::new(env!("CARGO_BIN_EXE_pwsafe-dump"))
+        .args(args)
+        .env_remove("PWSAFE_PASSWORD")
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn golden() {
+    let output = dump(&["--pretty", "--password", "password", FIXTURE]);
+    assert!(output.status.success(), "{output:?}");
+
+    assert!(output.stderr.is_empty(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, include_str!("pwsafe.json"));
+
+    // The same document, on one line.
+    let compact = dump(&["--password", "password", FIXTURE]);
+    assert!(compact.status.success(), "{compact:?}");
+    assert_eq!(compact.stdout.iter().filter(|&&b| b == b'\n').count(), 1);
+
+    let compact: serde_json::Value = serde_json::from_slice(&compact.stdout).unwrap();
+    let pretty: serde_json::Value = serde_json::from_str(&stdout).unwrap();
+    assert_eq!(compact, pretty);
+}
+
+#[test]
+fn wrong_password() {
+    let output = dump(&["--password", "wrong", FIXTURE]);
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
+
+/// Write a database with the password `password` to a new temporary file.
+fn write_database(name: &str, records: &[&[(u8, &[u8])]]) -> std::path::PathBuf {
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let mut writer = pwsafer::PwsafeWriter::new(vec![], 2048, &key).unwrap();
+
+    writer.write_field(0x00, &[0x0d, 0x03]).unwrap();
+    writer.write_field(0xff, &[]).unwrap();
+
+    for record in records {
+        for (ty, data) in record.iter() {
+            writer.write_field(*ty, data).unwrap();
+        }
+
+        writer.write_field(0xff, &[]).unwrap();
+    }
+
+    writer.finish().unwrap();
+    let (_, raw) = writer.take();
+
+    let path = std::env::temp_dir().join(format!("pwsafe-dump-{}-{name}", std::process::id()));
+    std::fs::write(&path, raw).unwrap();
+    path
+}
+
+const WEB: [u8; 16] = *b"web-entry-uuid-1";
+const MAIL: [u8; 16] = *b"mail-entry-uuid2";
+
+fn secrets_database(name: &str) -> std::path::PathBuf {
+    write_database(
+        name,
+        &[
+            &[
+                (0x01, &WEB),
+                (0x02, b"infra.web"),
+                (0x03, b"nginx"),
+                (0x04, b"www-data"),
+                (0x06, b"hunter2-password"),
+                (0x05, b"recovery-notes"),
+                (0x0d, b"https://example.com"),
+                (0x1b, b"totp-key"),
+            ],
+            &[
+                (0x01, &MAIL),
+                (0x02, b"infrastructure"),
+                (0x03, b"postfix"),
+                (0x06, b"mail-password"),
+            ],
+        ],
+    )
+}
+
+#[test]
+fn redacted_by_default() {
+    let path = secrets_database("redacted.psafe3");
+    let path = path.to_str().unwrap();
+
+    for args in [
+        &["--password", "password", path][..],
+        &["--redact", "--password", "password", path],
+    ] {
+        let output = dump(args);
+        assert!(output.status.success(), "{output:?}");
+
+        let all = [output.stdout, output.stderr].concat();
+        let all = String::from_utf8(all).unwrap();
+
+        for secret in [
+            "hunter2-password",
+            "recovery-notes",
+            "totp-key",
+            "mail-password",
+        ] {
+            assert!(!all.contains(secret), "{secret} in {all}");
+        }
+
+        assert!(all.contains(r#"{"Password":"<redacted:len=16>"}"#), "{all}");
+        assert!(all.contains(r#"{"Notes":"<redacted:len=14>"}"#), "{all}");
+        assert!(
+            all.contains(r#"{"TwoFactorKey":"<redacted:len=8>"}"#),
+            "{all}"
+        );
+        assert!(all.contains("www-data"), "{all}");
+    }
+
+    let output = dump(&["--no-redact", "--password", "password", path]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.contains(r#"{"Password":"hunter2-password"}"#),
+        "{stdout}"
+    );
+    assert!(stdout.contains("recovery-notes"), "{stdout}");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn filters() {
+    let path = secrets_database("filters.psafe3");
+    let path = path.to_str().unwrap();
+
+    let records = |args: &[&str]| -> Vec<serde_json::Value> {
+        let output = dump(&[args, &["--password", "password", path]].concat());
+        assert!(output.status.success(), "{output:?}");
+        let dump: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        dump["records"].as_array().unwrap().clone()
+    };
+
+    let all = records(&[]);
+    assert_eq!(all.len(), 2);
+
+    let fields = records(&["--fields", "title,username,URL"]);
+    assert_eq!(
+        fields[0]["fields"],
+        serde_json::json!([
+            { "Title": "nginx" },
+            { "Username": "www-data" },
+            { "Url": "https://example.com" },
+        ])
+    );
+    assert_eq!(
+        fields[1]["fields"],
+        serde_json::json!([{ "Title": "postfix" }])
+    );
+
+    let only_uuids = records(&["--only-uuids"]);
+    assert_eq!(only_uuids.len(), 2);
+    assert!(only_uuids.iter().all(|record| record["uuid"].is_string()));
+    assert!(only_uuids.iter().all(|record| record["fields"] == serde_json::json!([])));
+
+    let mail = uuid::Uuid::from_bytes(MAIL).to_string();
+    let by_uuid = records(&["--uuid", &mail]);
+    assert_eq!(by_uuid.len(), 1);
+    assert_eq!(by_uuid[0]["uuid"], mail.as_str());
+
+    // Subgroups are included, but not groups that only share a prefix.
+    let by_group = records(&["--group", "infra"]);
+    assert_eq!(by_group.len(), 1);
+    assert_eq!(by_group[0]["uuid"], uuid::Uuid::from_bytes(WEB).to_string());
+    assert!(records(&["--group", "infra.web", "--uuid", &mail]).is_empty());
+
+    let _ = std::fs::remove_file(path);
+}
+
+/// The exit code and stderr of `--verify`, which must not write to stdout.
+fn verify(args: &[&str]) -> (i32, String) {
+    let output = dump(&[&["--verify"], args].concat());
+    assert!(output.stdout.is_empty(), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    (output.status.code().unwrap(), stderr)
+}
+
+#[test]
+fn verify_clean() {
+    let (code, stderr) = verify(&["--strict", "--password", "password", FIXTURE]);
+    assert_eq!(code, 0, "{stderr}");
+    assert_eq!(stderr, "1 records, 0 errors, 0 warnings\n");
+
+    let (code, stderr) = verify(&["--password", "wrong", FIXTURE]);
+    assert_eq!(code, 3, "{stderr}");
+}
+
+#[test]
+fn verify_warnings() {
+    let path = write_database(
+        "unknown.psafe3",
+        &[&[
+            (0x01, b"unknown-field-id"),
+            (0x03, b"title"),
+            (0x06, b"password"),
+            (0x50, b"from the future"),
+        ]],
+    );
+    let path = path.to_str().unwrap();
+
+    let (code, stderr) = verify(&["--password", "password", path]);
+    assert_eq!(code, 0, "{stderr}");
+    assert!(
+        stderr.contains("record 0, field 3 (type 0x50) is unknown"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.ends_with("1 records, 0 errors, 1 warnings\n"),
+        "{stderr}"
+    );
+
+    let (code, stderr) = verify(&["--strict", "--password", "password", path]);
+    assert_eq!(code, 1, "{stderr}");
+
+    // Dumping it is not fully understood either.
+    let output = dump(&["--password", "password", path]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn verify_errors() {
+    let path = write_database(
+        "errors.psafe3",
+        &[
+            &[
+                (0x01, b"duplicate-uuid-1"),
+                (0x03, b"first"),
+                (0x06, b"password"),
+            ],
+            &[(0x01, b"duplicate-uuid-1"), (0x03, b"second")],
+        ],
+    );
+    let path = path.to_str().unwrap();
+
+    let (code, stderr) = verify(&["--password", "password", path]);
+    assert_eq!(code, 2, "{stderr}");
+    assert!(stderr.contains("record 1 has no password"), "{stderr}");
+    assert!(stderr.contains("record 1 repeats the UUID"), "{stderr}");
+    assert!(
+        stderr.ends_with("2 records, 2 errors, 0 warnings\n"),
+        "{stderr}"
+    );
+
+    // Flip a bit of the encrypted data, after the 152 bytes of the preamble.
+    let mut raw = std::fs::read(FIXTURE).unwrap();
+    raw[152 + 16] ^= 1;
+    std::fs::write(path, raw).unwrap();
+
+    let (code, stderr) = verify(&["--password", "password", path]);
+    assert_eq!(code, 2, "{stderr}");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn truncated_record() {
+    let key = pwsafer::PwsafeKey::new(b"password");
+    let mut writer = pwsafer::PwsafeWriter::new(vec![], 2048, &key).unwrap();
+
+    writer.write_field(0x00, &[0x0d, 0x03]).unwrap();
+    writer.write_field(0xff, &[]).unwrap();
+    for (ty, data) in [(0x01, &b"complete-record1"[..]), (0x03, b"first"), (0x06, b"password")] {
+        writer.write_field(ty, data).unwrap();
+    }
+    writer.write_field(0xff, &[]).unwrap();
+    // The data ends without the end of this record.
+    writer.write_field(0x01, b"truncated-record").unwrap();
+    writer.finish().unwrap();
+
+    let (_, raw) = writer.take();
+    let path = std::env::temp_dir().join(format!("pwsafe-dump-{}-truncated.psafe3", std::process::id()));
+    std::fs::write(&path, raw).unwrap();
+    let path = path.to_str().unwrap();
+
+    let (code, stderr) = verify(&["--password", "password", path]);
+    assert_eq!(code, 2, "{stderr}");
+    assert!(stderr.contains("record 1 does not end"), "{stderr}");
+    assert!(
+        stderr.ends_with("2 records, 1 errors, 0 warnings\n"),
+        "{stderr}"
+    );
+
+    // Only the complete record is dumped.
+    let output = dump(&["--password", "password", path]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let dumped: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(dumped["records"].as_array().unwrap().len(), 1);
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn password_sources() {
+    let from_env = Command::new(env!("CARGO_BIN_EXE_pwsafe-dump"))
+        .args(["--verify", FIXTURE])
+        .env("PWSAFE_PASSWORD", "password")
+        .output()
+        .unwrap();
+    assert!(from_env.status.success(), "{from_env:?}");
+
+    // The options take precedence over the environment.
+    let from_option = Command::new(env!("CARGO_BIN_EXE_pwsafe-dump"))
+        .args(["--verify", "--password", "password", FIXTURE])
+        .env("PWSAFE_PASSWORD", "wrong")
+        .output()
+        .unwrap();
+    assert!(from_option.status.success(), "{from_option:?}");
+
+    // No terminal to ask on.
+    let output = dump(&["--verify", FIXTURE]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("PWSAFE_PASSWORD"), "{stderr}");
+
+    let output = dump(&["--password", "password", "--password-stdin", FIXTURE]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    for option in [
+        "--password",
+        "--key-file",
+        "--password-stdin",
+        "PWSAFE_PASSWORD",
+    ] {
+        assert!(stderr.contains(option), "{option} in {stderr}");
+    }
+}
+
+#[test]
+fn password_stdin() {
+    let with_stdin = |input: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_pwsafe-dump"))
+            .args(["--verify", "--password-stdin", FIXTURE])
+            .env_remove("PWSAFE_PASSWORD")
+            .stdin(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        let mut stdin = child.stdin.take().unwrap();
+        std::io::Write::write_all(&mut stdin, input).unwrap();
+        drop(stdin);
+
+        child.wait_with_output().unwrap().status.code().unwrap()
+    };
+
+    assert_eq!(with_stdin(b"password"), 0);
+    assert_eq!(with_stdin(b"password\n"), 0);
+    // Only a single newline is stripped.
+    assert_eq!(with_stdin(b"password\n\n"), 3);
+    assert_eq!(with_stdin(b"password "), 3);
+}
+
+/// A copy of the fixture with another password and iteration count, one record edited and one added.
+fn edited_fixture(name: &str) -> std::path::PathBuf {
+    let file = std::fs::File::open(FIXTURE).unwrap();
+    let mut reader =
+        pwsafer::PwsafeReader::new(file, &pwsafer::PwsafeKey::new(b"password")).unwrap();
+
+    let key = pwsafer::PwsafeKey::new(b"other");
+    let mut writer = pwsafer::PwsafeWriter::new(vec![], 4096, &key).unwrap();
+
+    while let Some((ty, data)) = reader.read_field() {
+        match ty {
+            // The password.
+            0x06 => writer.write_field(ty, b"changed").unwrap(),
+            // The password expiry interval.
+            0x11 => {}
+            _ => writer.write_field(ty, &data).unwrap(),
+        }
+    }
+
+    writer.write_field(0x01, b"new-record-uuid1").unwrap();
+    writer.write_field(0x03, b"added").unwrap();
+    writer.write_field(0x06, b"new-password").unwrap();
+    writer.write_field(0xff, &[]).unwrap();
+
+    writer.finish().unwrap();
+    let (_, raw) = writer.take();
+
+    let path = std::env::temp_dir().join(format!("pwsafe-dump-{}-{name}", std::process::id()));
+    std::fs::write(&path, raw).unwrap();
+    path
+}
+
+#[test]
+fn diff() {
+    let output = dump(&["--diff", FIXTURE, "--password", "password", FIXTURE]);
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert!(output.stdout.is_empty(), "{output:?}");
+
+    let path = edited_fixture("diff.psafe3");
+    let path = path.to_str().unwrap();
+    let added = uuid::Uuid::from_bytes(*b"new-record-uuid1");
+
+    let output = dump(&[
+        "--diff",
+        path,
+        "--other-password",
+        "other",
+        "--password",
+        "password",
+        FIXTURE,
+    ]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        format!(
+            "~ 1209a0ac-5cd0-4afc-98f7-dfec6e165042\n    \
+            set Password: \"<redacted:len=7>\"\n    \
+            delete PasswordExpiryInterval\n\
+            + {added}\n    \
+            set Title: \"added\"\n    \
+            set Password: \"<redacted:len=12>\"\n"
+        ),
+    );
+
+    let output = dump(&[
+        "--no-redact",
+        "--diff",
+        path,
+        "--other-password",
+        "other",
+        "--password",
+        "password",
+        FIXTURE,
+    ]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("set Password: \"changed\""), "{stdout}");
+
+    // The edited copy does not open with the same password.
+    let output = dump(&["--diff", path, "--password", "password", FIXTURE]);
+    assert_eq!(output.status.code(), Some(2), "{output:?}");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn export() {
+    let path = secrets_database("export.psafe3");
+    let path = path.to_str().unwrap();
+
+    let output = dump(&["--export", "csv", "--password", "password", path]);
+    assert!(output.status.success(), "{output:?}");
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        concat!(
+            "group,title,username,password,url,notes\r\n",
+            "infra.web,nginx,www-data,<redacted:len=16>,https://example.com,<redacted:len=14>\r\n",
+            "infrastructure,postfix,,<redacted:len=13>,,\r\n",
+        ),
+    );
+
+    let output = dump(&[
+        "--export",
+        "csv",
+        "--columns",
+        "title,password,uuid",
+        "--no-redact",
+        "--password",
+        "password",
+        path,
+    ]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("title,password,uuid\r\nnginx,hunter2-password,"), "{stdout}");
+
+    let output = dump(&["--export", "xml", "--password", "password", path]);
+    assert!(output.status.success(), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("<title>postfix</title>"), "{stdout}");
+    assert!(stdout.contains("<password>&lt;redacted:len=13&gt;</password>"), "{stdout}");
+    assert!(!stdout.contains("mail-password"), "{stdout}");
+
+    let output = dump(&["--export", "csv", "--columns", "website", "--password", "password", path]);
+    assert!(!output.status.success(), "{output:?}");
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[test]
+fn diagnostics() {
+    let path = write_database(
+        "diagnostics.psafe3",
+        &[
+            &[
+                (0x01, b"malformed-fields"),
+                (0x03, b"first"),
+                (0x06, b"password"),
+                (0x50, b"from the future"),
+                // A time field of three bytes, instead of four.
+                (0x07, b"\x01\x02\x03"),
+            ],
+            &[
+                (0x01, b"valid-record-uid"),
+                (0x03, b"second"),
+                (0x06, b"password"),
+            ],
+        ],
+    );
+    let path = path.to_str().unwrap();
+
+    let output = dump(&["--password", "password", path]);
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("Warning: record 0, field 3 (type 0x50) is unknown"),
+        "{stderr}"
+    );
+    assert!(
+        stderr.contains("Error: record 0, field 4 (type 0x07): "),
+        "{stderr}"
+    );
+    assert!(!stderr.contains("future"), "{stderr}");
+
+    // Both records are dumped, the unknown field as is.
+    let dumped: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    let records = dumped["records"].as_array().unwrap();
+    assert_eq!(records.len(), 2);
+    assert_eq!(
+        records[0]["fields"][2],
+        serde_json::json!({ "Unknown": { "type": 0x50, "data": "<redacted:len=15>" } })
+    );
+    assert_eq!(
+        records[1]["fields"][0],
+        serde_json::json!({ "Title": "second" })
+    );
+
+    let output = dump(&["--no-redact", "--password", "password", path]);
+    let output: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(
+        output["records"][0]["fields"][2]["Unknown"],
+        serde_json::json!({ "type": 0x50, "hex": "66726f6d2074686520667574757265" })
+    );
+
+    let (code, stderr) = verify(&["--password", "password", path]);
+    assert_eq!(code, 2, "{stderr}");
+    assert!(
+        stderr.ends_with("2 records, 1 errors, 1 warnings\n"),
+        "{stderr}"
+    );
+
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,436 @@
+//! Run `sync` for two databases in the same room at once, and send edits to either of them.
+//!
+//! The environment of the first is read from `PWSAFE_MATRIX_TESTS_PATH`, of the second from
+//! `PWSAFE_MATRIX_TESTS_PEER_PATH`. Both daemons are stopped after the instructions, and their
+//! output is printed if anything fails or takes too long.
+use std::{fs::File, io::Read as _, io::Write as _, path::Path, path::PathBuf};
+use std::collections::HashMap;
+use std::process::{Child, Stdio};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use pwsafe_matrix_core::{DiffEditSerial, DiffSerial};
+use serde::Deserialize;
+use uuid::Uuid;
+
+pub const EXE_PWSAFE_MATRIX: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_pwsafe-matrix");
+
+/// How long a daemon may take to serve, and to exit when stopped.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+fn main() -> Result<std::process::ExitCode, anyhow::Error> {
+    let a = Daemon::start("a", "PWSAFE_MATRIX_TESTS_PATH")?;
+    let b = match Daemon::start("b", "PWSAFE_MATRIX_TESTS_PEER_PATH") {
+        Ok(b) => b,
+        Err(err) => {
+            a.fail();
+            return Err(err);
+        }
+    };
+
+    let instructions: Vec<TestInstruction> = if let Some(input)
+        = std::env::args_os().nth(1)
+    {
+        let path = std::path::PathBuf::from(input);
+        serde_json::from_reader(File::open(path)?)?
+    } else {
+        vec![]
+    };
+
+    for instruction in instructions {
+        if let Err(err) = test_execute(instruction, &a, &b) {
+            a.fail();
+            b.fail();
+            return Err(err);
+        }
+    }
+
+    let (a, b) = (a.stop(), b.stop());
+    if a && b {
+        Ok(std::process::ExitCode::SUCCESS)
+    } else {
+        Ok(std::process::ExitCode::FAILURE)
+    }
+}
+
+/// A `sync` process, and everything it wrote to stderr so far.
+struct Daemon {
+    name: &'static str,
+    child: Child,
+    stderr: Arc<Mutex<Vec<u8>>>,
+    /// Reads stderr until the daemon exits.
+    capture: Option<std::thread::JoinHandle<()>>,
+    server_address: String,
+    server_token: String,
+}
+
+impl Daemon {
+    fn start(name: &'static str, var: &str) -> Result<Self, anyhow::Error> {
+        let configuration_path = std::env::var_os(var)
+            .map(|var| Path::new(&var).to_path_buf())
+            .ok_or_else(|| anyhow::Error::msg(format!("Set `{var}` to a test environment")))?;
+
+        let TestEnv {
+            homeserver,
+            username,
+            pwsafe_db,
+            pwsafe_password,
+            server_address,
+            server_token,
+        } = {
+            let path_err = configuration_path.display().to_string();
+
+            let file = File::open(&configuration_path)
+                .map_err(anyhow::Error::from)
+                .map_err(|err| err.context(path_err))?;
+            serde_yaml::from_reader(file)?
+        };
+
+        let pwsafe_db = configuration_path
+            .parent()
+            .unwrap()
+            .join(&pwsafe_db);
+
+        // See the `sync` test for the choice of arguments.
+        let mut child = std::process::Command::new(EXE_PWSAFE_MATRIX)
+            .arg("sync")
+            .args(["--homeserver", homeserver.as_str()])
+            .args(["--user", username.as_str()])
+            .arg("--password-from-stdin")
+            .args(["--server-http-authorization", server_token.as_str()])
+            .args(["--server-address", server_address.as_str()])
+            .arg("--server-ready")
+            // Nothing verifies the devices of the two users with each other.
+            .arg("--allow-unverified-devices")
+            .arg(pwsafe_db)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        writeln!(child.stdin.take().unwrap(), "{pwsafe_password}")?;
+
+        let stderr = Arc::new(Mutex::new(vec![]));
+        let mut pipe = child.stderr.take().unwrap();
+        let capture = stderr.clone();
+
+        let capture = std::thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            while let Ok(len @ 1..) = pipe.read(&mut buffer) {
+                capture.lock().unwrap().extend_from_slice(&buffer[..len]);
+            }
+        });
+
+        let mut stdout = child.stdout.take().unwrap();
+        let daemon = Daemon {
+            name,
+            child,
+            stderr,
+            capture: Some(capture),
+            server_address,
+            server_token,
+        };
+
+        // The status byte is written once the server is listening.
+        let (ready, is_ready) = mpsc::channel();
+        std::thread::spawn(move || {
+            let _ = ready.send(stdout.read_exact(&mut [0x0]).is_ok());
+        });
+
+        if is_ready.recv_timeout(TIMEOUT) != Ok(true) {
+            daemon.fail();
+            return Err(anyhow::Error::msg(format!("Sync `{name}` did not start serving")));
+        }
+
+        let health = format!("http://{}/health", daemon.server_address);
+        if let Err(err) = ureq::get(&health)
+            .set("Authorization", daemon.server_token.as_str())
+            .timeout(TIMEOUT)
+            .call()
+        {
+            daemon.fail();
+            return Err(err.into());
+        }
+
+        Ok(daemon)
+    }
+
+    /// Stop the daemon, returning if it exited successfully in time.
+    fn stop(mut self) -> bool {
+        let stop = format!("http://{}/stop", self.server_address);
+        let stopped = ureq::post(&stop)
+            .set("Authorization", self.server_token.as_str())
+            .timeout(TIMEOUT)
+            .call();
+
+        let start = Instant::now();
+        let status = loop {
+            match self.child.try_wait() {
+                Ok(Some(status)) => break Some(status),
+                Ok(None) if stopped.is_ok() && start.elapsed() < TIMEOUT => {
+                    std::thread::sleep(Duration::from_millis(100));
+                }
+                Ok(None) | Err(_) => break None,
+            }
+        };
+
+        if status.is_some_and(|status| status.success()) {
+            return true;
+        }
+
+        eprintln!("Sync `{}` did not stop successfully: {status:?}", self.name);
+        self.fail();
+        false
+    }
+
+    /// Kill the daemon, and print its output.
+    fn fail(mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+
+        if let Some(capture) = self.capture.take() {
+            let _ = capture.join();
+        }
+
+        let stderr = self.stderr.lock().unwrap();
+        eprintln!("--- stderr of sync `{}`", self.name);
+        eprintln!("{}", String::from_utf8_lossy(&stderr));
+        eprintln!("--- end of sync `{}`", self.name);
+    }
+}
+
+fn test_execute(instruction: TestInstruction, a: &Daemon, b: &Daemon)
+    -> Result<(), anyhow::Error>
+{
+    let peer = |on: Peer| match on {
+        Peer::A => a,
+        Peer::B => b,
+    };
+
+    match instruction {
+        TestInstruction::CreateEntry { on, uuid, title, username, password } => {
+            let set = [
+                (FieldType::Uuid, Vec::from(uuid.into_bytes())),
+                (FieldType::Title, title.into_bytes()),
+                (FieldType::Username, username.into_bytes()),
+                (FieldType::Password, password.into_bytes()),
+            ];
+
+            peer(on).send_diff(&edit_diff(uuid, set, []))
+        },
+        TestInstruction::EditEntry { on, uuid, set, delete_fields } => {
+            let set = set.into_iter().map(|(ty, value)| (ty, value.into_bytes()));
+            peer(on).send_diff(&edit_diff(uuid, set, delete_fields))
+        },
+        TestInstruction::DeleteEntry { on, uuid } => {
+            let diff = DiffSerial {
+                delete: [uuid].into_iter().collect(),
+                ..DiffSerial::default()
+            };
+
+            peer(on).send_diff(&diff)
+        },
+        TestInstruction::AssertEntry { on, uuid, expect } => {
+            let daemon = peer(on);
+            let fields = daemon.entry(uuid)?;
+
+            check_entry(uuid, expect.as_ref(), fields.as_ref())
+                .map_err(|err| err.context(format!("On sync `{}`", daemon.name)))
+        },
+        TestInstruction::Wait { seconds } => {
+            std::thread::sleep(Duration::from_secs_f32(seconds));
+            Ok(())
+        },
+    }
+}
+
+impl Daemon {
+    fn send_diff(&self, diff: &DiffSerial) -> Result<(), anyhow::Error> {
+        let url = format!("http://{}/diff", self.server_address);
+        let json = serde_json::to_string(diff)?;
+
+        let ok = ureq::post(&url)
+            .set("Authorization", self.server_token.as_str())
+            .set("Content-Type", "application/json")
+            .timeout(TIMEOUT)
+            .send_string(&json)?;
+
+        if ok.status() != 200 {
+            return Err(anyhow::Error::msg(format!("Diff of sync `{}`: {ok:?}", self.name)));
+        }
+
+        Ok(())
+    }
+
+    /// The fields of an entry by their type, or `None` if the database does not contain it.
+    fn entry(&self, uuid: Uuid) -> Result<Option<HashMap<u8, Vec<u8>>>, anyhow::Error> {
+        let url = format!("http://{}/entry", self.server_address);
+
+        let response = ureq::get(&url)
+            .query("uuid", &uuid.to_string())
+            .set("Authorization", self.server_token.as_str())
+            .timeout(TIMEOUT)
+            .call();
+
+        match response {
+            Ok(response) => {
+                let entry: Entry = response.into_json()?;
+                Ok(Some(entry.fields))
+            },
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Compare the fields named by the expectation. A `null` field must be missing, a `null`
+/// expectation means the entry must be missing.
+fn check_entry(
+    uuid: Uuid,
+    expect: Option<&HashMap<FieldType, Option<String>>>,
+    fields: Option<&HashMap<u8, Vec<u8>>>,
+) -> Result<(), anyhow::Error> {
+    let (expect, fields) = match (expect, fields) {
+        (None, None) => return Ok(()),
+        (Some(_), None) => return Err(anyhow::Error::msg(format!("Entry {uuid} is missing"))),
+        (None, Some(_)) => return Err(anyhow::Error::msg(format!("Entry {uuid} was not deleted"))),
+        (Some(expect), Some(fields)) => (expect, fields),
+    };
+
+    let mut mismatches = vec![];
+    for (&ty, expected) in expect {
+        let found = fields.get(&(ty as u8)).map(|data| String::from_utf8_lossy(data));
+
+        if found.as_deref() != expected.as_deref() {
+            mismatches.push(format!("{ty:?} is {found:?}, expected {expected:?}"));
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!("Entry {uuid} differs: {}", mismatches.join(", "))))
+    }
+}
+
+#[derive(Deserialize)]
+struct TestEnv {
+    homeserver: String,
+    username: String,
+    #[serde(rename = "pwsafe-db")]
+    pwsafe_db: PathBuf,
+    #[serde(rename = "pwsafe-password")]
+    pwsafe_password: String,
+    #[serde(rename = "pwsafe-matrix-server-http-authorization")]
+    server_token: String,
+    #[serde(rename = "pwsafe-matrix-server-address")]
+    server_address: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+#[serde(tag = "kind")]
+enum TestInstruction {
+    CreateEntry {
+        on: Peer,
+        uuid: uuid::Uuid,
+        title: String,
+        username: String,
+        password: String,
+    },
+    /// Set and remove fields of an entry, by their name.
+    EditEntry {
+        on: Peer,
+        uuid: uuid::Uuid,
+        #[serde(default)]
+        set: HashMap<FieldType, String>,
+        #[serde(default, rename = "delete-fields")]
+        delete_fields: Vec<FieldType>,
+    },
+    DeleteEntry {
+        on: Peer,
+        uuid: uuid::Uuid,
+    },
+    /// Read the entry from the database of one peer, see [`check_entry`].
+    AssertEntry {
+        on: Peer,
+        uuid: uuid::Uuid,
+        expect: Option<HashMap<FieldType, Option<String>>>,
+    },
+    /// Give the sync processes time to exchange events through the homeserver.
+    Wait {
+        seconds: f32,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum Peer {
+    A,
+    B,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    fields: HashMap<u8, Vec<u8>>,
+}
+
+/// The record fields by the names that `pwsafe-matrix` renders in diffs.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+#[repr(u8)]
+pub enum FieldType {
+    Uuid = 0x01,
+    Group = 0x02,
+    Title = 0x03,
+    Username = 0x04,
+    Notes = 0x05,
+    Password = 0x06,
+    Url = 0x0d,
+    Email = 0x14,
+}
+
+/// A diff changing only one entry.
+fn edit_diff(
+    uuid: Uuid,
+    set: impl IntoIterator<Item = (FieldType, Vec<u8>)>,
+    delete: impl IntoIterator<Item = FieldType>,
+) -> DiffSerial {
+    let edit = DiffEditSerial {
+        set: set.into_iter().map(|(ty, value)| (ty as u8, value)).collect(),
+        delete: delete.into_iter().map(|ty| ty as u8).collect(),
+    };
+
+    DiffSerial {
+        edit: [(uuid, edit)].into_iter().collect(),
+        ..DiffSerial::default()
+    }
+}
+
+#[test]
+fn instructions_parse() {
+    let instructions: Vec<TestInstruction> = serde_json::from_str(r#"[
+        { "kind": "create-entry", "on": "a", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "title": "t", "username": "alice", "password": "secret" },
+        { "kind": "edit-entry", "on": "b", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "set": { "notes": "edited" }, "delete-fields": ["username"] },
+        { "kind": "assert-entry", "on": "a", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "expect": { "notes": "edited", "username": null } },
+        { "kind": "delete-entry", "on": "a", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31" },
+        { "kind": "assert-entry", "on": "b", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "expect": null }
+    ]"#).unwrap();
+
+    let [
+        TestInstruction::CreateEntry { on: Peer::A, .. },
+        TestInstruction::EditEntry { on: Peer::B, set, delete_fields, .. },
+        TestInstruction::AssertEntry { on: Peer::A, expect: Some(expect), .. },
+        TestInstruction::DeleteEntry { on: Peer::A, .. },
+        TestInstruction::AssertEntry { on: Peer::B, expect: None, .. },
+    ] = &instructions[..] else {
+        panic!("Instructions parsed to the wrong kinds or peers");
+    };
+
+    assert_eq!(set[&FieldType::Notes], "edited");
+    assert_eq!(delete_fields, &[FieldType::Username]);
+    assert_eq!(expect[&FieldType::Username], None);
+
+    let no_peer = r#"[{ "kind": "delete-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31" }]"#;
+    assert!(serde_json::from_str::<Vec<TestInstruction>>(no_peer).is_err());
+}
@@ -1,6 +1,6 @@
 //! Implement the synapse-based Administrator API, to prepare the Synapse homeserver for local
 //! testing. All relevant configuration is passed via environment variables.
-use std::{fs::File, path::Path, path::PathBuf};
+use std::{fs::File, io::Write as _, path::Path, path::PathBuf, process::Stdio};
 use serde::Deserialize;
 
 pub const EXE_PWSAFE_MATRIX: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_pwsafe-matrix");
@@ -15,6 +15,8 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
             |var| Path::new(&var).to_path_buf(),
         );
 
+    let alias = std::env::args_os().nth(1);
+
     let TestEnv {
         homeserver: address,
         username,
@@ -35,14 +37,30 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
         .unwrap()
         .join(&pwsafe_db);
 
-    let cmd = std::process::Command::new(EXE_PWSAFE_MATRIX)
+    let mut cmd = std::process::Command::new(EXE_PWSAFE_MATRIX);
+    cmd
         .arg("create")
         .arg(pwsafe_db)
-        .args(["--password", pwsafe_password.as_str()])
+        .arg("--password-from-stdin")
         .args(["--homeserver", &address.as_str()])
         .args(["--user", &username])
-        .args(["--matrix-password", &password])
-        .output()?;
+        .args(["--matrix-password", &password]);
+
+    if let Some(alias) = alias {
+        cmd.arg("--room-alias").arg(alias);
+    }
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Keep the password out of the arguments, which other users of the host can see.
+    writeln!(child.stdin.take().unwrap(), "{pwsafe_password}")?;
+    let cmd = child.wait_with_output()?;
+    // Forward the room information, the harness might want to inspect it.
+    std::io::Write::write_all(&mut std::io::stdout(), &cmd.stdout)?;
 
     if !cmd.status.success() {
         eprintln!("{:?}", String::from_utf8_lossy(&cmd.stderr));
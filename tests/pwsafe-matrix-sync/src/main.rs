@@ -1,9 +1,10 @@
 //! Implement the synapse-based Administrator API, to prepare the Synapse homeserver for local
 //! testing. All relevant configuration is passed via environment variables.
-use std::{fs::File, io::Read as _, path::Path, path::PathBuf};
+use std::{fs::File, io::Read as _, io::Write as _, path::Path, path::PathBuf};
 use std::collections::HashMap;
 
-use serde::{Deserialize, Serialize};
+use pwsafe_matrix_core::{DiffEditSerial, DiffSerial};
+use serde::Deserialize;
 use uuid::Uuid;
 
 pub const EXE_PWSAFE_MATRIX: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_pwsafe-matrix");
@@ -39,7 +40,14 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
         .unwrap()
         .join(&pwsafe_db);
 
-    let mut cmd = std::process::Command::new(EXE_PWSAFE_MATRIX)
+    // With a path, the server listens on a unix socket there and trusts our user instead.
+    let server = match std::env::var_os("PWSAFE_MATRIX_TEST_SERVER_UNIX") {
+        Some(path) => Server::Unix { path: PathBuf::from(path) },
+        None => Server::Tcp { address: server_address, token: server_token },
+    };
+
+    let mut command = std::process::Command::new(EXE_PWSAFE_MATRIX);
+    command
         .arg("sync")
         // These would be restored from session, but the homeserver calls itself by the domain
         // configured in the file (synapse.hardmo.de) which is wrong. We want to reach it under the
@@ -48,22 +56,37 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
         .args(["--homeserver", homeserver.as_str()])
         .args(["--user", username.as_str()])
         // The rest of the arguments are most relevant.
-        .args(["--password", pwsafe_password.as_str()])
-        .args(["--server-http-authorization", server_token.as_str()])
-        .args(["--server-address", server_address.as_str()])
+        .arg("--password-from-stdin")
         .arg("--server-ready")
+        .arg("--follow-upgrades")
         .arg(pwsafe_db)
+        .stdin(std::process::Stdio::piped())
         .stdout(std::process::Stdio::piped())
-        .stderr(std::process::Stdio::inherit())
-        .spawn()?;
+        .stderr(std::process::Stdio::inherit());
+
+    match &server {
+        Server::Tcp { address, token } => command
+            .args(["--server-http-authorization", token.as_str()])
+            .args(["--server-address", address.as_str()]),
+        Server::Unix { path } => command
+            .arg("--server-unix")
+            .arg(path)
+            .arg("--server-trust-uid"),
+    };
+
+    let mut cmd = command.spawn()?;
+
+    // Keep the password out of the arguments, which other users of the host can see.
+    writeln!(cmd.stdin.take().unwrap(), "{pwsafe_password}")?;
 
     let mut stdout = cmd.stdout.take().unwrap();
     stdout.read_exact(&mut [0x0])?;
 
-    let health = format!("http://{server_address}/health");
-    let _health = ureq::get(&health)
-        .set("Authorization", server_token.as_str())
-        .call()?;
+    if let Server::Unix { path } = &server {
+        check_socket(path)?;
+    }
+
+    let _health = server.get("/health")?.expect(200)?;
 
     let instructions: Vec<TestInstruction> = if let Some(input)
         = std::env::args_os().nth(1)
@@ -75,13 +98,14 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
     };
 
     for instruction in instructions {
-        test_execute(instruction, &server_address, &server_token)?;
+        if let Err(err) = test_execute(instruction, &server) {
+            let _ = cmd.kill();
+            let _ = cmd.wait();
+            return Err(err);
+        }
     }
 
-    let stop = format!("http://{server_address}/stop");
-    let _stop = ureq::post(&stop)
-        .set("Authorization", server_token.as_str())
-        .call()?;
+    let _stop = server.request("POST", "/stop", None, &[])?.expect(200)?;
 
     let cmd = cmd.wait()?;
 
@@ -93,56 +117,176 @@ fn main() -> Result<std::process::ExitCode, anyhow::Error> {
     }
 }
 
-fn test_execute(instruction: TestInstruction, server_address: &str, server_token: &str)
-    -> Result<(), anyhow::Error>
-{
+fn test_execute(instruction: TestInstruction, server: &Server) -> Result<(), anyhow::Error> {
     match instruction {
         TestInstruction::CreateEntry { uuid, username, password } => {
-            let url = format!("http://{server_address}/diff");
-
-            let diff = Diff {
-                delete: vec![],
-                edit: [
-                    (
-                        uuid,
-                        DiffEdit {
-                            delete: vec![],
-                            set: [
-                                (
-                                    FieldType::Uuid as u8,
-                                    Vec::from(uuid.into_bytes()),
-                                ),
-                                (
-                                    FieldType::Username as u8,
-                                    username.into_bytes(),
-                                ),
-                                (
-                                    FieldType::Password as u8,
-                                    password.into_bytes(),
-                                ),
-                            ].into_iter().collect(),
-                        }
-                    )
-                ].into_iter().collect()
-            };
+            let set = [
+                (FieldType::Uuid, Vec::from(uuid.into_bytes())),
+                (FieldType::Username, username.into_bytes()),
+                (FieldType::Password, password.into_bytes()),
+            ];
 
-            let json = serde_json::to_string(&diff)?;
+            send_diff(server, &edit_diff(uuid, set, []))
+        },
+        TestInstruction::EditEntry { uuid, set, delete_fields } => {
+            let set = set.into_iter().map(|(ty, value)| (ty, value.into_bytes()));
+            send_diff(server, &edit_diff(uuid, set, delete_fields))
+        },
+        TestInstruction::DeleteEntry { uuid } => {
+            let diff = DiffSerial {
+                delete: [uuid].into_iter().collect(),
+                ..DiffSerial::default()
+            };
 
-            let ok = ureq::post(&url)
-                .set("Authorization", server_token)
-                .set("Content-Type", "application/json")
-                .send_string(&json)?;
+            send_diff(server, &diff)
+        },
+        TestInstruction::RejectDiff { body, path } => {
+            let body = serde_json::to_string(&body)?;
+            reject_diff(server, &body, path.as_deref())
+        },
+        TestInstruction::RejectOversizedDiff { uuid, size } => {
+            let set = [(FieldType::Notes, vec![b'n'; size])];
+            let body = serde_json::to_string(&edit_diff(uuid, set, []))?;
+            reject_diff(server, &body, None)
+        },
+        TestInstruction::AssertEntry { uuid, expect } => {
+            let fields = get_entry(server, uuid)?;
+            check_entry(uuid, expect.as_ref(), fields.as_ref())
+        },
+        TestInstruction::GeneratePassword { uuid, policy, length } => {
+            let password = generate_password(server, uuid, policy)?;
 
-            if ok.status() != 200 {
-                eprintln!("{:?}", ok);
-                panic!();
+            let generated = password.chars().count();
+            if length.is_some_and(|length| generated != length) {
+                let msg = format!("Generated password of length {generated}, expected {length:?}");
+                return Err(anyhow::Error::msg(msg));
             }
 
+            // The password is set by the time the request is answered.
+            let expect = [(FieldType::Password, Some(password))].into_iter().collect();
+            let fields = get_entry(server, uuid)?;
+            check_entry(uuid, Some(&expect), fields.as_ref())
+        },
+        TestInstruction::AssertStatus { applied_diffs, lock_exists } => {
+            let status = get_status(server)?;
+            check_status(&status, applied_diffs, lock_exists)
+        },
+        TestInstruction::Wait { seconds } => {
+            std::thread::sleep(std::time::Duration::from_secs_f32(seconds));
             Ok(())
         },
     }
 }
 
+fn send_diff(server: &Server, diff: &DiffSerial) -> Result<(), anyhow::Error> {
+    let json = serde_json::to_string(diff)?;
+    server.post("/diff", &json)?.expect(200)?;
+    Ok(())
+}
+
+/// Post a body to the diff endpoint, which must reject it as a bad request.
+fn reject_diff(server: &Server, body: &str, path: Option<&str>) -> Result<(), anyhow::Error> {
+    let response = server.post("/diff", body)?;
+
+    if response.status != 400 {
+        let msg = format!("Diff answered with {}, expected a rejection", response.status);
+        return Err(anyhow::Error::msg(msg));
+    }
+
+    let rejected: Rejected = response.json()?;
+    if path.is_some_and(|path| rejected.path.as_deref() != Some(path)) {
+        let msg = format!("Rejected at {:?}, expected {path:?}", rejected.path);
+        return Err(anyhow::Error::msg(msg));
+    }
+
+    Ok(())
+}
+
+fn generate_password(server: &Server, uuid: Uuid, policy: Option<serde_json::Value>)
+    -> Result<String, anyhow::Error>
+{
+    let json = serde_json::to_string(&serde_json::json!({ "uuid": uuid, "policy": policy }))?;
+
+    let generated: Generated = server.post("/generate", &json)?.expect(200)?.json()?;
+    Ok(generated.password)
+}
+
+/// The fields of an entry by their type, or `None` if the database does not contain it.
+fn get_entry(server: &Server, uuid: Uuid) -> Result<Option<HashMap<u8, Vec<u8>>>, anyhow::Error> {
+    let path = format!("/entry?uuid={uuid}");
+    let response = server.request("GET", &path, None, &[("X-Reveal-Secrets", "true")])?;
+
+    if response.status == 404 {
+        return Ok(None);
+    }
+
+    let entry: Entry = response.expect(200)?.json()?;
+    Ok(Some(entry.fields))
+}
+
+fn get_status(server: &Server) -> Result<Status, anyhow::Error> {
+    server.get("/status")?.expect(200)?.json()
+}
+
+/// The socket of the server must be private to our user.
+fn check_socket(path: &Path) -> Result<(), anyhow::Error> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let mode = std::fs::metadata(path)?.permissions().mode() & 0o777;
+    if mode != 0o600 {
+        return Err(anyhow::Error::msg(format!("Socket {} has mode {mode:o}", path.display())));
+    }
+
+    Ok(())
+}
+
+/// Compare the counters of the status, and the lock file if that is expected.
+fn check_status(status: &Status, applied_diffs: Option<u64>, lock_exists: Option<bool>)
+    -> Result<(), anyhow::Error>
+{
+    if applied_diffs.is_some_and(|applied| status.applied_diffs != applied) {
+        let msg = format!("Applied {} diffs, expected {applied_diffs:?}", status.applied_diffs);
+        return Err(anyhow::Error::msg(msg));
+    }
+
+    if lock_exists.is_some_and(|exists| status.lock_exists != exists) {
+        let msg = format!("Lock file exists: {}, expected {lock_exists:?}", status.lock_exists);
+        return Err(anyhow::Error::msg(msg));
+    }
+
+    Ok(())
+}
+
+/// Compare the fields named by the expectation. A `null` field must be missing, a `null`
+/// expectation means the entry must be missing.
+fn check_entry(
+    uuid: Uuid,
+    expect: Option<&HashMap<FieldType, Option<String>>>,
+    fields: Option<&HashMap<u8, Vec<u8>>>,
+) -> Result<(), anyhow::Error> {
+    let (expect, fields) = match (expect, fields) {
+        (None, None) => return Ok(()),
+        (Some(_), None) => return Err(anyhow::Error::msg(format!("Entry {uuid} is missing"))),
+        (None, Some(_)) => return Err(anyhow::Error::msg(format!("Entry {uuid} was not deleted"))),
+        (Some(expect), Some(fields)) => (expect, fields),
+    };
+
+    let mut mismatches = vec![];
+    for (&ty, expected) in expect {
+        let found = fields.get(&(ty as u8)).map(|data| String::from_utf8_lossy(data));
+
+        if found.as_deref() != expected.as_deref() {
+            mismatches.push(format!("{ty:?} is {found:?}, expected {expected:?}"));
+        }
+    }
+
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::Error::msg(format!("Entry {uuid} differs: {}", mismatches.join(", "))))
+    }
+}
+
 #[derive(Deserialize)]
 struct TestEnv {
     homeserver: String,
@@ -157,6 +301,24 @@ struct TestEnv {
     server_address: String,
 }
 
+/// How the server of the sync process is reached.
+enum Server {
+    Tcp {
+        address: String,
+        token: String,
+    },
+    /// Without a token, the sync process trusts the user of this process.
+    Unix {
+        path: PathBuf,
+    },
+}
+
+/// The status and body of an answer, also of errors.
+struct Response {
+    status: u16,
+    body: Vec<u8>,
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(tag = "kind")]
@@ -165,25 +327,318 @@ enum TestInstruction {
         uuid: uuid::Uuid,
         username: String,
         password: String,
-    }
+    },
+    /// Set and remove fields of an entry, by their name.
+    EditEntry {
+        uuid: uuid::Uuid,
+        #[serde(default)]
+        set: HashMap<FieldType, String>,
+        #[serde(default, rename = "delete-fields")]
+        delete_fields: Vec<FieldType>,
+    },
+    DeleteEntry {
+        uuid: uuid::Uuid,
+    },
+    /// Post any body as a diff, see [`reject_diff`]. The rejection must name the path, if given.
+    RejectDiff {
+        body: serde_json::Value,
+        #[serde(default)]
+        path: Option<String>,
+    },
+    /// Post a diff creating an entry with notes of this many bytes, which is too large.
+    RejectOversizedDiff {
+        uuid: uuid::Uuid,
+        size: usize,
+    },
+    /// Read the entry from the database of the sync process, see [`check_entry`].
+    AssertEntry {
+        uuid: uuid::Uuid,
+        expect: Option<HashMap<FieldType, Option<String>>>,
+    },
+    /// Generate a new password for the entry, which must then have it.
+    ///
+    /// The policy is a name, an inline policy as the server takes it, or missing for the one of
+    /// the entry.
+    GeneratePassword {
+        uuid: uuid::Uuid,
+        #[serde(default)]
+        policy: Option<serde_json::Value>,
+        #[serde(default)]
+        length: Option<usize>,
+    },
+    /// Read the status of the sync process, see [`check_status`].
+    AssertStatus {
+        #[serde(default, rename = "applied-diffs")]
+        applied_diffs: Option<u64>,
+        #[serde(default, rename = "lock-exists")]
+        lock_exists: Option<bool>,
+    },
+    /// Give the sync process time to receive events from the homeserver.
+    Wait {
+        seconds: f32,
+    },
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    fields: HashMap<u8, Vec<u8>>,
+}
+
+/// The part of the status the instructions check.
+#[derive(Deserialize)]
+struct Status {
+    applied_diffs: u64,
+    lock_exists: bool,
 }
 
-#[derive(Serialize)]
-pub struct Diff {
-    delete: Vec<Uuid>,
-    edit: HashMap<Uuid, DiffEdit>,
+/// The answer of the server to a diff it does not apply.
+#[derive(Deserialize)]
+struct Rejected {
+    #[serde(default)]
+    path: Option<String>,
 }
 
-#[derive(Serialize)]
-pub struct DiffEdit {
-    set: HashMap<u8, Vec<u8>>,
-    delete: Vec<u8>,
+#[derive(Deserialize)]
+struct Generated {
+    password: String,
 }
 
+/// The record fields by the names that `pwsafe-matrix` renders in diffs.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
 #[repr(u8)]
 pub enum FieldType {
     Uuid = 0x01,
+    Group = 0x02,
+    Title = 0x03,
     Username = 0x04,
     Notes = 0x05,
     Password = 0x06,
+    Url = 0x0d,
+    Email = 0x14,
+}
+
+impl Server {
+    fn get(&self, path: &str) -> Result<Response, anyhow::Error> {
+        self.request("GET", path, None, &[])
+    }
+
+    fn post(&self, path: &str, json: &str) -> Result<Response, anyhow::Error> {
+        self.request("POST", path, Some(json), &[])
+    }
+
+    fn request(&self, method: &str, path: &str, json: Option<&str>, headers: &[(&str, &str)])
+        -> Result<Response, anyhow::Error>
+    {
+        let (address, token) = match self {
+            Server::Tcp { address, token } => (address, token),
+            Server::Unix { path: socket } => {
+                return request_unix(socket, method, path, json, headers);
+            },
+        };
+
+        let request = ureq::request(method, &format!("http://{address}{path}"))
+            .set("Authorization", token);
+        let request = headers.iter().fold(request, |request, (name, value)| {
+            request.set(name, value)
+        });
+
+        let result = match json {
+            Some(json) => request.set("Content-Type", "application/json").send_string(json),
+            None => request.call(),
+        };
+
+        match result {
+            Ok(response) | Err(ureq::Error::Status(_, response)) => {
+                let status = response.status();
+                let mut body = vec![];
+                response.into_reader().read_to_end(&mut body)?;
+                Ok(Response { status, body })
+            },
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+impl Response {
+    fn expect(self, status: u16) -> Result<Self, anyhow::Error> {
+        if self.status != status {
+            let body = String::from_utf8_lossy(&self.body);
+            let msg = format!("Answered with {} instead of {status}: {body}", self.status);
+            return Err(anyhow::Error::msg(msg));
+        }
+
+        Ok(self)
+    }
+
+    fn json<T: serde::de::DeserializeOwned>(&self) -> Result<T, anyhow::Error> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+}
+
+/// Send a request over a unix socket, which `ureq` does not connect to.
+///
+/// As HTTP/1.0, the server closes the connection after the response and never chunks it.
+fn request_unix(
+    socket: &Path,
+    method: &str,
+    path: &str,
+    json: Option<&str>,
+    headers: &[(&str, &str)],
+) -> Result<Response, anyhow::Error> {
+    let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+    let body = json.unwrap_or("");
+    let headers: String = headers.iter()
+        .map(|(name, value)| format!("{name}: {value}\r\n"))
+        .collect();
+
+    write!(
+        stream,
+        "{method} {path} HTTP/1.0\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+        {headers}Content-Length: {}\r\n\r\n{body}",
+        body.len(),
+    )?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response)?;
+    parse_response(&response)
+}
+
+/// Split a response into its status and body, ignoring all headers.
+fn parse_response(response: &[u8]) -> Result<Response, anyhow::Error> {
+    let invalid = || anyhow::Error::msg("Invalid HTTP response");
+
+    let end = response.windows(4).position(|w| w == b"\r\n\r\n").ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..end])?;
+    let status = head.split(' ').nth(1).and_then(|code| code.parse().ok()).ok_or_else(invalid)?;
+
+    Ok(Response { status, body: response[end + 4..].to_vec() })
+}
+
+/// A diff changing only one entry.
+fn edit_diff(
+    uuid: Uuid,
+    set: impl IntoIterator<Item = (FieldType, Vec<u8>)>,
+    delete: impl IntoIterator<Item = FieldType>,
+) -> DiffSerial {
+    let edit = DiffEditSerial {
+        set: set.into_iter().map(|(ty, value)| (ty as u8, value)).collect(),
+        delete: delete.into_iter().map(|ty| ty as u8).collect(),
+    };
+
+    DiffSerial {
+        edit: [(uuid, edit)].into_iter().collect(),
+        ..DiffSerial::default()
+    }
+}
+
+#[test]
+fn instructions_parse() {
+    let instructions: Vec<TestInstruction> = serde_json::from_str(r#"[
+        { "kind": "create-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "username": "alice", "password": "secret" },
+        { "kind": "edit-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "set": { "title": "edited", "url": "https://example.com" }, "delete-fields": ["password"] },
+        { "kind": "edit-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "delete-fields": ["notes"] },
+        { "kind": "assert-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "expect": { "title": "edited", "password": null } },
+        { "kind": "delete-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31" },
+        { "kind": "reject-diff", "body": { "delete": [], "edit": [] }, "path": "edit" },
+        { "kind": "reject-diff", "body": [] },
+        { "kind": "reject-oversized-diff", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "size": 2048 },
+        { "kind": "assert-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "expect": null },
+        { "kind": "wait", "seconds": 0.5 },
+        { "kind": "generate-password", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "policy": { "length": 20, "digits": 2 }, "length": 20 },
+        { "kind": "generate-password", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31" },
+        { "kind": "assert-status", "applied-diffs": 2, "lock-exists": false },
+        { "kind": "assert-status" }
+    ]"#).unwrap();
+
+    let [
+        TestInstruction::CreateEntry { .. },
+        TestInstruction::EditEntry { set, delete_fields, .. },
+        TestInstruction::EditEntry { set: no_set, .. },
+        TestInstruction::AssertEntry { expect: Some(expect), .. },
+        TestInstruction::DeleteEntry { .. },
+        TestInstruction::RejectDiff { path: Some(path), .. },
+        TestInstruction::RejectDiff { path: None, .. },
+        TestInstruction::RejectOversizedDiff { size: 2048, .. },
+        TestInstruction::AssertEntry { expect: None, .. },
+        TestInstruction::Wait { .. },
+        TestInstruction::GeneratePassword { policy: Some(policy), length: Some(20), .. },
+        TestInstruction::GeneratePassword { policy: None, length: None, .. },
+        TestInstruction::AssertStatus { applied_diffs: Some(2), lock_exists: Some(false) },
+        TestInstruction::AssertStatus { applied_diffs: None, lock_exists: None },
+    ] = &instructions[..] else {
+        panic!("Instructions parsed to the wrong kinds");
+    };
+
+    assert_eq!(set[&FieldType::Title], "edited");
+    assert_eq!(set[&FieldType::Url], "https://example.com");
+    assert_eq!(delete_fields, &[FieldType::Password]);
+    assert!(no_set.is_empty());
+    assert_eq!(expect[&FieldType::Title].as_deref(), Some("edited"));
+    assert_eq!(expect[&FieldType::Password], None);
+    assert_eq!(policy["digits"], 2);
+    assert_eq!(path, "edit");
+
+    let unknown = r#"[{ "kind": "edit-entry", "uuid": "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31", "set": { "colour": "red" } }]"#;
+    assert!(serde_json::from_str::<Vec<TestInstruction>>(unknown).is_err());
+}
+
+#[test]
+fn diff_uses_field_types() {
+    let uuid = Uuid::from_u128(1);
+    let diff = edit_diff(uuid, [(FieldType::Title, b"title".to_vec())], [FieldType::Url]);
+
+    let json = serde_json::to_value(&diff).unwrap();
+    assert_eq!(json["edit"][uuid.to_string()]["set"]["3"], "dGl0bGU=");
+    assert_eq!(json["edit"][uuid.to_string()]["delete"], serde_json::json!([0x0d]));
+}
+
+#[test]
+fn responses_parse() {
+    let response = parse_response(b"HTTP/1.0 400 Bad Request\r\ncontent-length: 15\r\n\r\n\
+        {\"path\":\"edit\"}").unwrap();
+    assert_eq!(response.status, 400);
+
+    let rejected: Rejected = response.json().unwrap();
+    assert_eq!(rejected.path.as_deref(), Some("edit"));
+
+    let empty = parse_response(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+    assert_eq!(empty.status, 200);
+    assert!(empty.body.is_empty());
+
+    assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+    assert!(response.expect(200).is_err());
+}
+
+#[test]
+fn status_checks() {
+    let status = Status { applied_diffs: 2, lock_exists: false };
+
+    assert!(check_status(&status, None, None).is_ok());
+    assert!(check_status(&status, Some(2), Some(false)).is_ok());
+    assert!(check_status(&status, Some(1), None).is_err());
+    assert!(check_status(&status, None, Some(true)).is_err());
+}
+
+#[test]
+fn entry_checks() {
+    let uuid = Uuid::from_u128(1);
+    let fields: HashMap<u8, Vec<u8>> = [(0x03, b"title".to_vec())].into_iter().collect();
+
+    let expect = |pairs: &[(FieldType, Option<&str>)]| -> HashMap<_, _> {
+        pairs.iter().map(|(ty, value)| (*ty, value.map(String::from))).collect()
+    };
+
+    let matching = expect(&[(FieldType::Title, Some("title")), (FieldType::Password, None)]);
+    assert!(check_entry(uuid, Some(&matching), Some(&fields)).is_ok());
+
+    let wrong = expect(&[(FieldType::Title, Some("other"))]);
+    assert!(check_entry(uuid, Some(&wrong), Some(&fields)).is_err());
+
+    let present = expect(&[(FieldType::Username, Some("alice"))]);
+    assert!(check_entry(uuid, Some(&present), Some(&fields)).is_err());
+
+    assert!(check_entry(uuid, None, None).is_ok());
+    assert!(check_entry(uuid, None, Some(&fields)).is_err());
+    assert!(check_entry(uuid, Some(&matching), None).is_err());
 }
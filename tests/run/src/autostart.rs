@@ -0,0 +1,205 @@
+//! Start the homeserver of `local/kube.yml` for the tests, with `PWSAFE_MATRIX_TEST_AUTOSTART=1`.
+//!
+//! The pod is played with `podman` from a copy of the bundled definition, under a name unique to
+//! the test process and with the homeserver on a free port. Its configuration is the one of
+//! `local/data` with a fresh registration secret, written next to the copied definition. Tests
+//! running at the same time share one pod, which is torn down when the last of their harnesses is
+//! dropped.
+//!
+//! With `PWSAFE_MATRIX_TEST_EPHEMERAL=1`, [`Harness::ephemeral`](crate::Harness::ephemeral) starts
+//! a dedicated pod for its harness instead. `PWSAFE_MATRIX_TEST_SYNAPSE_IMAGE` replaces the image
+//! of the homeserver in both cases, so that runs can test different versions side by side.
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use color_eyre::{eyre::Error, section::Section};
+use tempfile::TempDir;
+
+const LOCAL: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../local");
+
+/// How long Synapse, and its database, may take to answer after starting the pod.
+const STARTUP: Duration = Duration::from_secs(180);
+
+/// A running pod, removed on drop.
+pub struct Pod {
+    pub homeserver_domain: url::Url,
+    /// The configuration the homeserver was started with, holding its registration secret.
+    pub homeserver_config: PathBuf,
+    /// Started for a single harness, see [`dedicated`].
+    pub dedicated: bool,
+    name: String,
+    /// The definition that was played, to take the same pod down, and the configuration.
+    files: TempDir,
+}
+
+/// If the tests should start their own homeserver.
+pub fn enabled() -> bool {
+    std::env::var_os("PWSAFE_MATRIX_TEST_AUTOSTART").is_some_and(|var| var == "1")
+}
+
+/// If a harness asking for it gets a homeserver of its own.
+pub fn ephemeral() -> bool {
+    std::env::var_os("PWSAFE_MATRIX_TEST_EPHEMERAL").is_some_and(|var| var == "1")
+}
+
+/// The pod of this process, started if no other harness holds it.
+pub fn shared() -> Result<Arc<Pod>, Error> {
+    static SHARED: Mutex<Weak<Pod>> = Mutex::new(Weak::new());
+
+    let mut shared = SHARED.lock().unwrap_or_else(|poison| poison.into_inner());
+    if let Some(pod) = shared.upgrade() {
+        return Ok(pod);
+    }
+
+    let name = format!("pwsafe-matrix-tests-{}", std::process::id());
+    let pod = Arc::new(Pod::start(name, false)?);
+    *shared = Arc::downgrade(&pod);
+    Ok(pod)
+}
+
+/// A pod for one harness only, sharing no state with any other test.
+pub fn dedicated() -> Result<Arc<Pod>, Error> {
+    static STARTED: AtomicUsize = AtomicUsize::new(0);
+
+    let count = STARTED.fetch_add(1, Ordering::Relaxed);
+    let name = format!("pwsafe-matrix-tests-{}-{count}", std::process::id());
+    Ok(Arc::new(Pod::start(name, true)?))
+}
+
+impl Pod {
+    fn start(name: String, dedicated: bool) -> Result<Self, Error> {
+        let local = Path::new(LOCAL).canonicalize()?;
+        let files = tempfile::tempdir()?;
+
+        // The port is only reserved until the pod binds it, there is no way to hand it over.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+
+        let definition = std::fs::read_to_string(local.join("kube.yml"))?;
+        let mut kube: serde_yaml::Value = serde_yaml::from_str(&definition)?;
+        kube["metadata"]["name"] = name.as_str().into();
+
+        let image = std::env::var("PWSAFE_MATRIX_TEST_SYNAPSE_IMAGE").ok();
+
+        for container in kube["spec"]["containers"].as_sequence_mut().into_iter().flatten() {
+            for mapping in container["ports"].as_sequence_mut().into_iter().flatten() {
+                mapping["hostPort"] = port.into();
+            }
+
+            match &image {
+                Some(image) if container["name"] == "synapse" => {
+                    container["image"] = image.as_str().into();
+                }
+                _ => {}
+            }
+        }
+
+        let homeserver_config = files.path().join("homeserver.yaml");
+        write_config(&local.join("data/homeserver.yaml"), &homeserver_config)?;
+
+        // Relative to the definition, which is played from somewhere else.
+        for volume in kube["spec"]["volumes"].as_sequence_mut().into_iter().flatten() {
+            let Some(path) = volume["hostPath"]["path"].as_str() else {
+                continue;
+            };
+
+            let path = if volume["name"] == "synapse-deployment-cfg" {
+                homeserver_config.display().to_string()
+            } else {
+                local.join(path).display().to_string()
+            };
+
+            volume["hostPath"]["path"] = path.into();
+        }
+
+        let file = std::fs::File::create(files.path().join("kube.yml"))?;
+        serde_yaml::to_writer(file, &kube)?;
+
+        let homeserver_domain = format!("http://localhost:{port}").parse()?;
+        let pod = Pod {
+            homeserver_domain,
+            homeserver_config,
+            dedicated,
+            name,
+            files,
+        };
+
+        let output = Command::new("podman")
+            .args(["play", "kube"])
+            .arg(pod.kube())
+            .output()
+            .map_err(Error::from)
+            .map_err(|err| err.note("Starting the homeserver requires `podman`"))?;
+
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+            return Err(Error::msg("Failed to play the homeserver pod").section(stderr));
+        }
+
+        pod.wait_ready()?;
+        Ok(pod)
+    }
+
+    fn kube(&self) -> PathBuf {
+        self.files.path().join("kube.yml")
+    }
+
+    /// Poll the homeserver until it answers.
+    fn wait_ready(&self) -> Result<(), Error> {
+        let versions = self.homeserver_domain.join("_matrix/client/versions")?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(5))
+            .build();
+        let start = Instant::now();
+
+        loop {
+            match agent.get(versions.as_str()).call() {
+                Ok(response) if response.status() == 200 => return Ok(()),
+                Ok(_) | Err(_) if start.elapsed() < STARTUP => {
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+                Ok(response) => {
+                    return Err(ureq::Error::Status(response.status(), response))?;
+                }
+                Err(err) => {
+                    let hint = format!("Inspect the pod with `podman pod logs {}`", self.name);
+                    return Err(Error::from(err).suggestion(hint));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Pod {
+    fn drop(&mut self) {
+        let status = Command::new("podman")
+            .args(["play", "kube", "--down"])
+            .arg(self.kube())
+            .output();
+
+        if !status.is_ok_and(|output| output.status.success()) {
+            eprintln!("Failed to remove the pod {}, remove it by hand", self.name);
+        }
+    }
+}
+
+/// Copy the configuration of the homeserver, with a registration secret of its own.
+fn write_config(template: &Path, path: &Path) -> Result<(), Error> {
+    use core::iter::repeat_with;
+
+    let template = std::fs::read_to_string(template)?;
+    let mut config: serde_yaml::Value = serde_yaml::from_str(&template)?;
+    let secret: String = repeat_with(fastrand::alphanumeric).take(32).collect();
+    config["registration_shared_secret"] = secret.into();
+
+    let file = std::fs::File::create(path)?;
+    serde_yaml::to_writer(&file, &config)?;
+
+    // Synapse runs as another user in the container, like the bundled file it must be readable.
+    use std::os::unix::fs::PermissionsExt as _;
+    file.set_permissions(std::fs::Permissions::from_mode(0o644))?;
+
+    Ok(())
+}
@@ -0,0 +1,113 @@
+//! Remove what the tests left on the homeserver, through the Synapse admin API.
+//!
+//! The administrator is registered with the shared secret, like the test users, on first use.
+//! Each user of a test is deactivated and erased, after purging all rooms it is still joined to.
+//! That includes the rooms created by the test, and the rooms they were upgraded to.
+use std::path::PathBuf;
+use color_eyre::eyre::Error;
+
+use crate::{TestEnv, EXE_PREPARE_API};
+
+/// Login as the administrator, registering it if this is the first time.
+pub fn admin_token(
+    homeserver: &url::Url,
+    homeserver_config: Option<PathBuf>,
+) -> Result<String, Error> {
+    let admin = TestEnv::admin(homeserver.clone(), homeserver_config);
+
+    if let Ok(token) = admin.access_token() {
+        return Ok(token);
+    }
+
+    let env_file = admin.to_disk()?;
+    let output = std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(Error::msg(format!("Registering the administrator failed: {stderr}")));
+    }
+
+    admin.access_token()
+}
+
+/// Deactivate the users, by their local name, and purge their rooms.
+pub fn remove<'a>(
+    homeserver: &url::Url,
+    homeserver_config: Option<PathBuf>,
+    users: impl IntoIterator<Item = &'a String>,
+) -> Result<(), Error> {
+    let token = admin_token(homeserver, homeserver_config)?;
+    let authorization = format!("Bearer {token}");
+
+    // Everyone is on the server of the administrator.
+    let whoami = homeserver.join("_matrix/client/v3/account/whoami")?;
+    let response: serde_json::Value = ureq::get(whoami.as_str())
+        .set("Authorization", &authorization)
+        .call()?
+        .into_json()?;
+
+    let Some((_, server_name)) = response["user_id"].as_str().and_then(|id| id.split_once(':'))
+    else {
+        return Err(Error::msg("The administrator has no user id"));
+    };
+
+    let mut failures = vec![];
+
+    for username in users {
+        let user_id = format!("@{username}:{server_name}");
+
+        if let Err(err) = remove_user(homeserver, &authorization, &user_id) {
+            eprintln!("Could not remove the test user {user_id}: {err}");
+            failures.push(user_id);
+        }
+    }
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::msg(format!("Not removed: {failures:?}")))
+    }
+}
+
+fn remove_user(homeserver: &url::Url, authorization: &str, user_id: &str) -> Result<(), Error> {
+    let user = encode(user_id);
+    let joined = homeserver.join(&format!("_synapse/admin/v1/users/{user}/joined_rooms"))?;
+
+    let rooms: serde_json::Value = match ureq::get(joined.as_str())
+        .set("Authorization", authorization)
+        .call()
+    {
+        Ok(response) => response.into_json()?,
+        // The test never got to register the user.
+        Err(ureq::Error::Status(404, _)) => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+
+    for room_id in rooms["joined_rooms"].as_array().into_iter().flatten() {
+        let Some(room_id) = room_id.as_str() else {
+            continue;
+        };
+
+        let room = homeserver.join(&format!("_synapse/admin/v1/rooms/{}", encode(room_id)))?;
+        if let Err(err) = ureq::delete(room.as_str())
+            .set("Authorization", authorization)
+            .send_json(serde_json::json!({ "purge": true }))
+        {
+            eprintln!("Could not purge the test room {room_id}: {err}");
+        }
+    }
+
+    let deactivate = homeserver.join(&format!("_synapse/admin/v1/deactivate/{user}"))?;
+    ureq::post(deactivate.as_str())
+        .set("Authorization", authorization)
+        .send_json(serde_json::json!({ "erase": true }))?;
+
+    Ok(())
+}
+
+/// Percent-encode an identifier for a path segment, it contains `@`, `!` and `:`.
+fn encode(id: &str) -> String {
+    url::form_urlencoded::byte_serialize(id.as_bytes()).collect()
+}
@@ -1,15 +1,34 @@
-use std::path::PathBuf;
-use color_eyre::{eyre::Error, section::Section};
+use std::collections::BTreeSet;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::{Arc, Mutex};
+use color_eyre::{eyre::Error, section::Section, SectionExt};
 use serde::Serialize;
 use tempfile::NamedTempFile;
 
+use crate::{autostart, cleanup};
+
 /// Holds the resources we need in the test suite for the executable(s) controlling one password
 /// database.
 pub struct Harness {
     pub homeserver_domain: url::Url,
     pub pwsafe_user_1: NamedTempFile,
+    /// The homeserver started for the tests, if any.
+    pub(crate) pod: Option<Arc<autostart::Pod>>,
+    /// The users of its test environments, removed with their rooms when dropped.
+    pub(crate) users: Users,
 }
 
+/// The names of users that executables were told to register, see [`TestEnv::to_disk`].
+pub(crate) type Users = Arc<Mutex<BTreeSet<String>>>;
+
+/// A bound port, until its address is handed to an executable.
+type Reservation = Arc<Mutex<Option<TcpListener>>>;
+
+/// Keys of the environment file that are not shown in reports.
+const SECRET_KEYS: &[&str] = &["password", "pwsafe-password", "pwsafe-matrix-server-http-authorization"];
+
 /// The descriptor for the executables to use.
 #[derive(Serialize, Clone)]
 #[serde(rename_all = "kebab-case")]
@@ -21,6 +40,21 @@ pub struct TestEnv {
     pub pwsafe_password: String,
     pub pwsafe_matrix_server_http_authorization: String,
     pub pwsafe_matrix_server_address: String,
+    /// Register the user as a server administrator.
+    pub admin: bool,
+    /// The configuration of a homeserver started by the harness, with its registration secret.
+    ///
+    /// Without it, the configuration in `local/data` is used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub homeserver_config: Option<PathBuf>,
+    /// Keeps the port of the server address bound, so no other test picks it.
+    ///
+    /// Released when the environment is written for the executables, see [`Self::to_disk`].
+    #[serde(skip)]
+    server_reservation: Reservation,
+    /// Shared with the harness that cleans up after the test.
+    #[serde(skip)]
+    users: Users,
 }
 
 impl Harness {
@@ -46,7 +80,12 @@ impl Harness {
         let pwsafe_user_1 = NamedTempFile::new()?;
         std::fs::copy(PWSAFE_TEMPLATE, pwsafe_user_1.path())?;
 
-        Ok(Harness { homeserver_domain, pwsafe_user_1 })
+        Ok(Harness {
+            homeserver_domain,
+            pwsafe_user_1,
+            pod: None,
+            users: Users::default(),
+        })
     }
 }
 
@@ -57,6 +96,8 @@ impl TestEnv {
         let username = repeat_with(fastrand::alphanumeric).take(16).collect();
         let password = repeat_with(fastrand::alphanumeric).take(16).collect();
         let token = repeat_with(fastrand::alphanumeric).take(16).collect();
+        let (pwsafe_matrix_server_address, server_reservation) = reserve_address()
+            .expect("Reserving a local port for the server");
 
         TestEnv {
             homeserver: harness.homeserver_domain.clone(),
@@ -65,7 +106,28 @@ impl TestEnv {
             pwsafe_db: harness.pwsafe_user_1.path().to_path_buf(),
             pwsafe_password: "pwsafe-matrix-test".into(),
             pwsafe_matrix_server_http_authorization: token,
-            pwsafe_matrix_server_address: "127.0.0.1:9001".into(),
+            pwsafe_matrix_server_address,
+            admin: false,
+            homeserver_config: harness.homeserver_config(),
+            server_reservation,
+            users: harness.users.clone(),
+        }
+    }
+
+    /// The administrator cleaning up after the tests, the same for all of them.
+    pub(crate) fn admin(homeserver: url::Url, homeserver_config: Option<PathBuf>) -> Self {
+        TestEnv {
+            homeserver,
+            username: "pwsafe-matrix-test-admin".into(),
+            password: "pwsafe-matrix-test-admin-password".into(),
+            pwsafe_db: PathBuf::new(),
+            pwsafe_password: String::new(),
+            pwsafe_matrix_server_http_authorization: String::new(),
+            pwsafe_matrix_server_address: String::new(),
+            admin: true,
+            homeserver_config,
+            server_reservation: Arc::default(),
+            users: Users::default(),
         }
     }
 
@@ -73,20 +135,55 @@ impl TestEnv {
         let pwsafe_user_1 = NamedTempFile::new()?;
         std::fs::copy(&self.pwsafe_db, pwsafe_user_1.path())?;
 
+        // Both can run their server at the same time.
+        let (pwsafe_matrix_server_address, server_reservation) = reserve_address()?;
+
         let env = TestEnv {
             pwsafe_db: pwsafe_user_1.path().to_path_buf(),
+            pwsafe_matrix_server_address,
+            server_reservation,
             ..self.clone()
         };
 
+        // The original harness keeps any homeserver it started running, and cleans up the users
+        // of both environments.
         let harness = Harness {
             homeserver_domain: self.homeserver.clone(),
             pwsafe_user_1,
+            pod: None,
+            users: Users::default(),
         };
 
         Ok((harness, env))
     }
 
+    /// Login as the test user, to act on the homeserver on their behalf.
+    pub fn access_token(&self) -> Result<String, Error> {
+        let login = self.homeserver.join("_matrix/client/v3/login")?;
+
+        let response: serde_json::Value = ureq::post(login.as_str())
+            .send_json(serde_json::json!({
+                "type": "m.login.password",
+                "identifier": { "type": "m.id.user", "user": self.username },
+                "password": self.password,
+            }))?
+            .into_json()?;
+
+        let Some(token) = response["access_token"].as_str() else {
+            return Err(Error::msg("Login response without access token"));
+        };
+
+        Ok(token.to_owned())
+    }
+
     pub fn to_disk(&self) -> Result<NamedTempFile, Error> {
+        // Whoever reads the file is about to bind the server address.
+        self.server_reservation.lock().unwrap().take();
+        // And possibly register the user.
+        if !self.admin {
+            self.users.lock().unwrap().insert(self.username.clone());
+        }
+
         let parent = 'a: {
             let fallback = std::env::temp_dir;
             let Some(own) = std::env::args_os().nth(0) else {
@@ -112,10 +209,152 @@ impl TestEnv {
     }
 }
 
+impl Harness {
+    /// A harness with a homeserver of its own, with `PWSAFE_MATRIX_TEST_EPHEMERAL=1`.
+    ///
+    /// The homeserver is started on a free port and removed when the harness is dropped, so no
+    /// state of other tests or earlier runs is visible to it. Without the variable, this is the
+    /// same as [`Harness::default`], for hosts without a container runtime.
+    pub fn ephemeral() -> Self {
+        if !autostart::ephemeral() {
+            return Harness::default();
+        }
+
+        super::with_themed_errors();
+
+        let harness = autostart::dedicated().and_then(|pod| {
+            let mut harness = Harness::validate(pod.homeserver_domain.to_string())?;
+            harness.pod = Some(pod);
+            Ok(harness)
+        });
+
+        harness.unwrap_or_else(|err| panic!("{err:?}"))
+    }
+
+    /// The configuration of the homeserver, if the harness started it.
+    pub fn homeserver_config(&self) -> Option<PathBuf> {
+        self.pod.as_ref().map(|pod| pod.homeserver_config.clone())
+    }
+
+    /// Run an executable of the tests, panicking with a readable report if it fails.
+    pub fn run_checked(cmd: &mut Command) -> Output {
+        super::with_themed_errors();
+
+        match Harness::checked(cmd) {
+            Ok(output) => output,
+            Err(err) => panic!("{err:?}"),
+        }
+    }
+
+    /// The output of a successful run, or a report with everything the executable was given and
+    /// printed.
+    pub(crate) fn checked(cmd: &mut Command) -> Result<Output, Error> {
+        let program = Path::new(cmd.get_program())
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        let output = cmd
+            .output()
+            .map_err(Error::from)
+            .map_err(|err| err.note(format!("Running `{program}`")))?;
+
+        if output.status.success() {
+            return Ok(output);
+        }
+
+        let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+
+        let mut report = Error::msg(format!("`{program}` failed, {}", output.status))
+            .section(stdout.header("Stdout:"))
+            .section(stderr.header("Stderr:"));
+
+        // The test environments, as the executable read them.
+        for (var, value) in cmd.get_envs() {
+            let (Some(var), Some(path)) = (var.to_str(), value) else {
+                continue;
+            };
+
+            if !var.starts_with("PWSAFE_MATRIX_TESTS_") {
+                continue;
+            }
+
+            let env = redacted_env(Path::new(path));
+            report = report.section(env.header(format!("{var}:")));
+        }
+
+        Err(report)
+    }
+
+    /// Deactivate the users of the test environments, and purge their rooms.
+    ///
+    /// Failures are only logged, they must not hide the outcome of the test itself. Called on drop.
+    pub fn cleanup(&self) {
+        let users = core::mem::take(&mut *self.users.lock().unwrap_or_else(|err| err.into_inner()));
+
+        // A homeserver of its own is removed with everything on it.
+        if users.is_empty() || self.pod.as_ref().is_some_and(|pod| pod.dedicated) {
+            return;
+        }
+
+        let config = self.homeserver_config();
+        if let Err(err) = cleanup::remove(&self.homeserver_domain, config, &users) {
+            eprintln!("Cleanup of test users {users:?} failed: {err:?}");
+        }
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        self.cleanup();
+    }
+}
+
+/// The contents of an environment file, without the passwords and tokens.
+fn redacted_env(path: &Path) -> String {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) => return format!("<unreadable {}: {err}>", path.display()),
+    };
+
+    let Ok(mut env) = serde_yaml::from_str::<serde_yaml::Value>(&contents) else {
+        return format!("<not an environment {}>", path.display());
+    };
+
+    if let Some(env) = env.as_mapping_mut() {
+        for (key, value) in env.iter_mut() {
+            if key.as_str().is_some_and(|key| SECRET_KEYS.contains(&key)) {
+                *value = "<redacted>".into();
+            }
+        }
+    }
+
+    serde_yaml::to_string(&env).unwrap_or_default()
+}
+
+/// Bind a port chosen by the system, so that tests do not collide when running in parallel.
+fn reserve_address() -> Result<(String, Reservation), Error> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let address = listener.local_addr()?.to_string();
+    Ok((address, Arc::new(Mutex::new(Some(listener)))))
+}
+
 impl Default for Harness {
     fn default() -> Self {
         super::with_themed_errors();
 
+        if autostart::enabled() {
+            let harness = autostart::shared().and_then(|pod| {
+                let mut harness = Harness::validate(pod.homeserver_domain.to_string())?;
+                harness.pod = Some(pod);
+                Ok(harness)
+            });
+
+            return harness.unwrap_or_else(|err| panic!("{err:?}"));
+        }
+
         let default = std::env::var("PWSAFE_MATRIX_TEST_SERVER")
             .unwrap_or_else(|_| "http://localhost:8080".into());
 
@@ -144,7 +383,9 @@ impl Default for Harness {
                     }
                 );
 
-                let err = err.suggestion(hint);
+                let err = err
+                    .suggestion(hint)
+                    .suggestion("Or let the tests start it, with `PWSAFE_MATRIX_TEST_AUTOSTART=1`");
                 panic!("{err:?}")
             }
         }
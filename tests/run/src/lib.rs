@@ -1,11 +1,16 @@
+mod autostart;
+mod cleanup;
 mod harness;
 pub use crate::harness::{Harness, TestEnv};
 
+pub const EXE_PWSAFE_MATRIX: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_pwsafe-matrix");
 pub const EXE_PREPARE_API: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_PREPARE_API_pwsafe-matrix-prepare-api");
+pub const EXE_CONVERGE: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_TEST_CONVERGE_pwsafe-matrix-test-converge");
 pub const EXE_CREATE: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_TEST_CREATE_pwsafe-matrix-test-create");
 pub const EXE_INVITE: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_TEST_INVITE_pwsafe-matrix-test-invite");
 pub const EXE_JOIN: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_TEST_JOIN_pwsafe-matrix-test-join");
 pub const EXE_SYNC: &str = env!("CARGO_BIN_FILE_PWSAFE_MATRIX_TEST_SYNC_pwsafe-matrix-test-sync");
+pub const EXE_DUMP: &str = env!("CARGO_BIN_FILE_PWSAFE_DUMP_pwsafe-dump");
 
 /// Some functions that tests should ensure to call, to ensure errors are formatted for joy.
 pub(crate) fn with_themed_errors() {
@@ -19,23 +24,242 @@ pub(crate) fn with_themed_errors() {
     });
 }
 
+#[test]
+fn cli_schema_covers_test_flags() {
+    const TEST_BINARIES: &[(&str, &str)] = &[
+        ("create", include_str!("../../pwsafe-matrix-create/src/main.rs")),
+        ("invite", include_str!("../../pwsafe-matrix-invite/src/main.rs")),
+        ("join", include_str!("../../pwsafe-matrix-join/src/main.rs")),
+        ("sync", include_str!("../../pwsafe-matrix-sync/src/main.rs")),
+        ("sync", include_str!("../../pwsafe-matrix-converge/src/main.rs")),
+    ];
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("--dump-cli-schema")
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let schema: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+
+    for (subcommand, source) in TEST_BINARIES {
+        let Some(description) = schema["subcommands"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|cmd| cmd["name"] == *subcommand)
+        else {
+            panic!("Subcommand `{subcommand}` missing from schema");
+        };
+
+        let known: Vec<_> = description["args"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|arg| arg["long"].as_str())
+            .collect();
+
+        // Every other part of a split at quotes is the content of a string literal.
+        let used = source
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .filter(|lit| lit.starts_with("--"));
+
+        for flag in used {
+            assert!(
+                known.contains(&&flag[2..]),
+                "Flag `{flag}` used in test of `{subcommand}` but not known to the binary",
+            );
+        }
+    }
+}
+
+#[test]
+fn server_addresses_are_reserved() {
+    // No homeserver is contacted.
+    let harness = Harness {
+        homeserver_domain: "http://localhost:8080".parse().unwrap(),
+        pwsafe_user_1: tempfile::NamedTempFile::new().unwrap(),
+        pod: None,
+        users: Default::default(),
+    };
+
+    let env0 = TestEnv::new_arbitrary(&harness);
+    let (_harness1, env1) = env0.fork_harness().unwrap();
+    assert_ne!(env0.pwsafe_matrix_server_address, env1.pwsafe_matrix_server_address);
+
+    let address = env0.pwsafe_matrix_server_address.as_str();
+    assert!(std::net::TcpListener::bind(address).is_err(), "{address} is not reserved");
+
+    let _env_file = env0.to_disk().unwrap();
+    std::net::TcpListener::bind(address).unwrap();
+
+    // Nothing was registered, there is nothing to clean up.
+    harness.users.lock().unwrap().clear();
+}
+
 #[test]
 fn responds() {
     let _harness = Harness::default();
 }
 
+/// With `PWSAFE_MATRIX_TEST_AUTOSTART=1`, the harness runs the homeserver itself.
+#[test]
+fn autostart() {
+    if !autostart::enabled() {
+        return;
+    }
+
+    let harness = Harness::default();
+    let Some(pod) = harness.pod.clone() else {
+        panic!("The homeserver was not started by the harness");
+    };
+
+    let versions = harness.homeserver_domain.join("_matrix/client/versions").unwrap();
+    ureq::get(versions.as_str()).call().unwrap();
+
+    drop((harness, pod));
+
+    // Unless another test still holds it, the pod is removed.
+    let again = Harness::default();
+    let versions = again.homeserver_domain.join("_matrix/client/versions").unwrap();
+    ureq::get(versions.as_str()).call().unwrap();
+}
+
+/// With `PWSAFE_MATRIX_TEST_EPHEMERAL=1`, each such harness runs a homeserver of its own.
+#[test]
+fn ephemeral() {
+    if !autostart::ephemeral() {
+        return;
+    }
+
+    let harness = Harness::ephemeral();
+    let other = Harness::ephemeral();
+    assert_ne!(harness.homeserver_domain, other.homeserver_domain);
+    assert_ne!(harness.homeserver_config(), other.homeserver_config());
+
+    // Registration uses the secret of the started homeserver.
+    let env = TestEnv::new_arbitrary(&harness);
+    assert_eq!(env.homeserver, harness.homeserver_domain);
+    let env_file = env.to_disk().unwrap();
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+    env.access_token().unwrap();
+
+    // Users are not shared between the homeservers.
+    let mut login = env.clone();
+    login.homeserver = other.homeserver_domain.clone();
+    assert!(login.access_token().is_err());
+
+    let versions = harness.homeserver_domain.join("_matrix/client/versions").unwrap();
+    drop(harness);
+    assert!(ureq::get(versions.as_str()).call().is_err(), "Homeserver still up after drop");
+}
+
 #[test]
 fn register() {
     let harness = Harness::default();
     let env = TestEnv::new_arbitrary(&harness);
     let env_file = env.to_disk().unwrap();
 
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+}
+
+#[test]
+fn failing_child_report() {
+    // No homeserver is contacted.
+    let harness = Harness {
+        homeserver_domain: "http://localhost:8080".parse().unwrap(),
+        pwsafe_user_1: tempfile::NamedTempFile::new().unwrap(),
+        pod: None,
+        users: Default::default(),
+    };
+
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+    harness.users.lock().unwrap().clear();
+
+    with_themed_errors();
+    let report = Harness::checked(std::process::Command::new("sh")
+        .args(["-c", "echo 'created room'; echo 'no such room' >&2; exit 3"])
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()))
+        .unwrap_err();
+    let report = format!("{report:?}");
+
+    // Other sections, such as the location, are up to the hook.
+    let headers: Vec<_> = report
+        .lines()
+        .filter(|line| ["Stdout:", "Stderr:", "PWSAFE_MATRIX_TESTS_PATH:"].contains(line))
+        .collect();
+    assert_eq!(headers, ["Stdout:", "Stderr:", "PWSAFE_MATRIX_TESTS_PATH:"], "{report}");
+
+    let body = |header: &str| -> Vec<&str> {
+        report
+            .lines()
+            .skip_while(|line| *line != header)
+            .skip(1)
+            .take_while(|line| line.starts_with("   "))
+            .map(str::trim)
+            .collect()
+    };
+
+    assert!(report.contains("`sh` failed, exit status: 3"), "{report}");
+    assert_eq!(body("Stdout:"), ["created room"], "{report}");
+    assert_eq!(body("Stderr:"), ["no such room"], "{report}");
+
+    let env_body = body("PWSAFE_MATRIX_TESTS_PATH:");
+    assert!(env_body.contains(&format!("username: {}", env.username).as_str()), "{report}");
+    assert!(env_body.contains(&"password: <redacted>"), "{report}");
+    assert!(env_body.contains(&"pwsafe-password: <redacted>"), "{report}");
+
+    for secret in [&env.password, &env.pwsafe_password, &env.pwsafe_matrix_server_http_authorization] {
+        assert!(!report.contains(secret.as_str()), "Secret `{secret}` in {report}");
+    }
+}
+
+#[test]
+fn cleanup() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+
     let output = std::process::Command::new(EXE_PREPARE_API)
         .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
         .output()
         .unwrap();
+    assert!(output.status.success(), "{:?}", output);
 
+    let output = std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .output()
+        .unwrap();
     assert!(output.status.success(), "{:?}", output);
+
+    let listed = |token: &str| -> Vec<serde_json::Value> {
+        let users = harness
+            .homeserver_domain
+            .join(&format!("_synapse/admin/v2/users?name={}", env.username))
+            .unwrap();
+
+        let response: serde_json::Value = ureq::get(users.as_str())
+            .set("Authorization", &format!("Bearer {token}"))
+            .call()
+            .unwrap()
+            .into_json()
+            .unwrap();
+
+        response["users"].as_array().unwrap().clone()
+    };
+
+    let config = harness.homeserver_config();
+    let token = cleanup::admin_token(&harness.homeserver_domain, config).unwrap();
+    assert_eq!(listed(&token).len(), 1);
+
+    harness.cleanup();
+    assert!(listed(&token).is_empty(), "Test user remains after cleanup");
+    assert!(env.access_token().is_err(), "Deactivated user can still log in");
 }
 
 #[test]
@@ -44,6 +268,21 @@ fn create() {
     let env = TestEnv::new_arbitrary(&harness);
     let env_file = env.to_disk().unwrap();
 
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    Harness::run_checked(std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+}
+
+#[test]
+fn create_with_alias() {
+    use core::iter::repeat_with;
+
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+
     let output = std::process::Command::new(EXE_PREPARE_API)
         .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
         .output()
@@ -51,12 +290,31 @@ fn create() {
 
     assert!(output.status.success(), "{:?}", output);
 
+    let alias: String = repeat_with(fastrand::alphanumeric).take(16).collect();
     let output = std::process::Command::new(EXE_CREATE)
         .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .arg(&alias)
         .output()
         .unwrap();
 
     assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    let room_id = lines.next().expect("Room id printed");
+    let full_alias = lines.next().expect("Room alias printed");
+    assert!(full_alias.starts_with(&format!("#{alias}:")), "{full_alias}");
+
+    let directory = format!("_matrix/client/v3/directory/room/{}", full_alias.replace('#', "%23"));
+    let resolve = harness.homeserver_domain.join(&directory).unwrap();
+
+    let response: serde_json::Value = ureq::get(resolve.as_str())
+        .call()
+        .unwrap()
+        .into_json()
+        .unwrap();
+
+    assert_eq!(response["room_id"].as_str(), Some(room_id));
 }
 
 #[test]
@@ -69,6 +327,41 @@ fn join() {
     let env_file0 = env0.to_disk().unwrap();
     let env_file1 = env1.to_disk().unwrap();
 
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path()));
+
+    Harness::run_checked(std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path()));
+
+    let invite = tempfile::NamedTempFile::new().unwrap();
+
+    Harness::run_checked(std::process::Command::new(EXE_INVITE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .arg(invite.path()));
+
+    Harness::run_checked(std::process::Command::new(EXE_JOIN)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file1.path())
+        .arg(invite.path()));
+
+    // The joined database has the session and room to sync with.
+    let mut stop_instructions = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut stop_instructions, b"[]").unwrap();
+
+    Harness::run_checked(std::process::Command::new(EXE_SYNC)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file1.path())
+        .arg(stop_instructions.path()));
+}
+
+#[test]
+fn sync() {
+    let harness0 = Harness::default();
+    let env0 = TestEnv::new_arbitrary(&harness0);
+
+    let (harness1, mut env1) = env0.fork_harness().unwrap();
+
+    let env_file0 = env0.to_disk().unwrap();
+    let env_file1 = env1.to_disk().unwrap();
+
     let output = std::process::Command::new(EXE_PREPARE_API)
         .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
         .output()
@@ -81,6 +374,69 @@ fn join() {
         .unwrap();
     assert!(output.status.success(), "{:?}", output);
 
+    let mut stop_instructions = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut stop_instructions, b"[]").unwrap();
+
+    let output = std::process::Command::new(EXE_SYNC)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .arg(stop_instructions.path())
+        .stderr(std::process::Stdio::inherit())
+        .status()
+        .unwrap();
+    assert!(output.success(), "{:?}", output);
+}
+
+/// Two users, each with their own database and `sync`, see the entries created by the other.
+#[test]
+fn converge() {
+    use core::iter::repeat_with;
+
+    let harness0 = Harness::default();
+    let env0 = TestEnv::new_arbitrary(&harness0);
+
+    let (_harness1, mut env1) = env0.fork_harness().unwrap();
+    env1.username = repeat_with(fastrand::alphanumeric).take(16).collect();
+    env1.password = repeat_with(fastrand::alphanumeric).take(16).collect();
+
+    let env_file0 = env0.to_disk().unwrap();
+    let env_file1 = env1.to_disk().unwrap();
+
+    for env_file in [&env_file0, &env_file1] {
+        let output = std::process::Command::new(EXE_PREPARE_API)
+            .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+    }
+
+    let output = std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let room_id = stdout.lines().next().expect("Room id printed");
+
+    // The room is private, the second user must be invited to join.
+    let whoami = harness0.homeserver_domain.join("_matrix/client/v3/account/whoami").unwrap();
+    let response: serde_json::Value = ureq::get(whoami.as_str())
+        .set("Authorization", &format!("Bearer {}", env1.access_token().unwrap()))
+        .call()
+        .unwrap()
+        .into_json()
+        .unwrap();
+    let user_id = response["user_id"].as_str().unwrap();
+
+    let invite = harness0
+        .homeserver_domain
+        .join(&format!("_matrix/client/v3/rooms/{room_id}/invite"))
+        .unwrap();
+    ureq::post(invite.as_str())
+        .set("Authorization", &format!("Bearer {}", env0.access_token().unwrap()))
+        .send_json(serde_json::json!({ "user_id": user_id }))
+        .unwrap();
+
     let invite = tempfile::NamedTempFile::new().unwrap();
 
     let output = std::process::Command::new(EXE_INVITE)
@@ -96,38 +452,437 @@ fn join() {
         .output()
         .unwrap();
     assert!(output.status.success(), "{:?}", output);
+
+    let from_a = "5d1c4e0a-3f6b-4f7e-8a2d-6b9c0e1f2a31";
+    let from_b = "9e8d7c6b-5a4f-4e3d-9c2b-1a0f9e8d7c6b";
+
+    let mut instructions = tempfile::NamedTempFile::new().unwrap();
+    let steps = serde_json::json!([
+        { "kind": "create-entry", "on": "a", "uuid": from_a, "title": "from a", "username": "alice", "password": "secret-a" },
+        { "kind": "wait", "seconds": 10.0 },
+        { "kind": "create-entry", "on": "b", "uuid": from_b, "title": "from b", "username": "bob", "password": "secret-b" },
+        { "kind": "wait", "seconds": 10.0 },
+        { "kind": "assert-entry", "on": "b", "uuid": from_a, "expect": { "title": "from a", "username": "alice" } },
+        { "kind": "assert-entry", "on": "a", "uuid": from_b, "expect": { "title": "from b", "username": "bob" } },
+        // Edits of one peer apply to the entries created by the other.
+        { "kind": "edit-entry", "on": "b", "uuid": from_a, "set": { "notes": "edited by b" }, "delete-fields": ["username"] },
+        { "kind": "delete-entry", "on": "a", "uuid": from_b },
+        { "kind": "wait", "seconds": 10.0 },
+        { "kind": "assert-entry", "on": "a", "uuid": from_a, "expect": { "title": "from a", "notes": "edited by b", "username": null } },
+        { "kind": "assert-entry", "on": "b", "uuid": from_b, "expect": null },
+    ]);
+    serde_json::to_writer(&mut instructions, &steps).unwrap();
+
+    let output = std::process::Command::new(EXE_CONVERGE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .env("PWSAFE_MATRIX_TESTS_PEER_PATH", env_file1.path())
+        .arg(instructions.path())
+        .stderr(std::process::Stdio::inherit())
+        .status()
+        .unwrap();
+    assert!(output.success(), "{:?}", output);
+
+    let records = |env: &TestEnv| -> Vec<serde_json::Value> {
+        let output = std::process::Command::new(EXE_DUMP)
+            .args(["--no-redact", "--password", &env.pwsafe_password])
+            .arg(&env.pwsafe_db)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+
+        let dump: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+        dump["records"].as_array().unwrap().clone()
+    };
+
+    let (records0, records1) = (records(&env0), records(&env1));
+
+    let find = |records: &[serde_json::Value], uuid: &str| {
+        records.iter().find(|record| record["uuid"] == uuid).cloned()
+    };
+
+    let (Some(edited0), Some(edited1)) = (find(&records0, from_a), find(&records1, from_a)) else {
+        panic!("Entry {from_a} missing in {records0:?} or {records1:?}");
+    };
+
+    assert_eq!(edited0["fields"], edited1["fields"], "Entry {from_a} differs");
+    assert_eq!(find(&records0, from_b), None, "Entry {from_b} not deleted");
+    assert_eq!(find(&records1, from_b), None, "Entry {from_b} not deleted");
 }
 
+/// Every kind of instruction against a single `sync`, checked through its server.
 #[test]
-fn sync() {
-    let harness0 = Harness::default();
-    let env0 = TestEnv::new_arbitrary(&harness0);
+fn sync_instructions() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
 
-    let (harness1, mut env1) = env0.fork_harness().unwrap();
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
 
-    let env_file0 = env0.to_disk().unwrap();
-    let env_file1 = env1.to_disk().unwrap();
+    Harness::run_checked(std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    let entry = "3c9a1f7e-2b4d-4e6a-8c1f-5d7b9e2a4c6f";
+
+    let mut instructions = tempfile::NamedTempFile::new().unwrap();
+    let steps = serde_json::json!([
+        { "kind": "assert-status", "applied-diffs": 0 },
+        { "kind": "create-entry", "uuid": entry, "username": "alice", "password": "secret" },
+        { "kind": "assert-status", "applied-diffs": 1, "lock-exists": false },
+        { "kind": "assert-entry", "uuid": entry, "expect": { "username": "alice", "password": "secret" } },
+        // Created without a title, it is filed as an incoming entry.
+        { "kind": "assert-entry", "uuid": entry, "expect": { "title": "synced-3c9a1f7e", "group": "pwsafe-matrix.incoming" } },
+        { "kind": "edit-entry", "uuid": entry, "set": { "title": "edited", "url": "https://example.com" }, "delete-fields": ["password"] },
+        { "kind": "assert-entry", "uuid": entry, "expect": { "title": "edited", "url": "https://example.com", "username": "alice", "password": null } },
+        { "kind": "generate-password", "uuid": entry, "policy": { "length": 20, "lowercase": 1, "digits": 2 }, "length": 20 },
+        { "kind": "assert-status", "applied-diffs": 3 },
+        // Rejected by the server as a whole, none of them is applied.
+        { "kind": "reject-diff", "body": { "delete": [], "edit": { entry: { "set": ["c2VjcmV0"], "delete": [] } } }, "path": format!("edit.{entry}.set") },
+        { "kind": "reject-diff", "body": { "delete": [], "edit": {}, "rename": {} }, "path": "rename" },
+        { "kind": "reject-diff", "body": { "delete": [], "edit": { entry: { "set": { "255": "" }, "delete": [] } } }, "path": format!("edit.{entry}.set.255") },
+        // Encoded in base64, these notes exceed the default limit of 1 MiB.
+        { "kind": "reject-oversized-diff", "uuid": entry, "size": 800 * 1024 },
+        { "kind": "assert-status", "applied-diffs": 3 },
+        { "kind": "delete-entry", "uuid": entry },
+        { "kind": "assert-entry", "uuid": entry, "expect": null },
+    ]);
+    serde_json::to_writer(&mut instructions, &steps).unwrap();
+
+    Harness::run_checked(std::process::Command::new(EXE_SYNC)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .arg(instructions.path()));
+}
+
+/// The server on a unix socket, authorized by the user of the test instead of the token.
+#[test]
+fn sync_instructions_unix() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    Harness::run_checked(std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    let entry = "7e4b2c9d-1a3f-4b5e-9c8d-2f6a0b1c3d5e";
+
+    let mut instructions = tempfile::NamedTempFile::new().unwrap();
+    let steps = serde_json::json!([
+        { "kind": "create-entry", "uuid": entry, "username": "alice", "password": "secret" },
+        { "kind": "assert-entry", "uuid": entry, "expect": { "username": "alice", "password": "secret" } },
+        { "kind": "reject-diff", "body": { "delete": [], "edit": { entry: { "set": ["c2VjcmV0"], "delete": [] } } }, "path": format!("edit.{entry}.set") },
+        { "kind": "reject-oversized-diff", "uuid": entry, "size": 800 * 1024 },
+        { "kind": "assert-status", "applied-diffs": 1 },
+    ]);
+    serde_json::to_writer(&mut instructions, &steps).unwrap();
+
+    let socket = tempfile::tempdir().unwrap();
+    Harness::run_checked(std::process::Command::new(EXE_SYNC)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .env("PWSAFE_MATRIX_TEST_SERVER_UNIX", socket.path().join("sync.sock"))
+        .arg(instructions.path()));
+
+    // Removed again when the sync stops.
+    assert!(!socket.path().join("sync.sock").exists());
+}
+
+#[test]
+fn sync_follows_upgrade() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
 
     let output = std::process::Command::new(EXE_PREPARE_API)
-        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
         .output()
         .unwrap();
     assert!(output.status.success(), "{:?}", output);
 
     let output = std::process::Command::new(EXE_CREATE)
-        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
         .output()
         .unwrap();
     assert!(output.status.success(), "{:?}", output);
 
-    let mut stop_instructions = tempfile::NamedTempFile::new().unwrap();
-    std::io::Write::write_all(&mut stop_instructions, b"[]").unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let room_id = stdout.lines().next().expect("Room id printed");
+
+    let token = env.access_token().unwrap();
+    let upgrade = harness
+        .homeserver_domain
+        .join(&format!("_matrix/client/v3/rooms/{room_id}/upgrade"))
+        .unwrap();
+
+    let response: serde_json::Value = ureq::post(upgrade.as_str())
+        .set("Authorization", &format!("Bearer {token}"))
+        .send_json(serde_json::json!({ "new_version": "10" }))
+        .unwrap()
+        .into_json()
+        .unwrap();
+    let successor = response["replacement_room"].as_str().unwrap().to_owned();
+
+    let mut instructions = tempfile::NamedTempFile::new().unwrap();
+    std::io::Write::write_all(&mut instructions, br#"[{ "kind": "wait", "seconds": 5.0 }]"#).unwrap();
 
     let output = std::process::Command::new(EXE_SYNC)
-        .env("PWSAFE_MATRIX_TESTS_PATH", env_file0.path())
-        .arg(stop_instructions.path())
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .arg(instructions.path())
         .stderr(std::process::Stdio::inherit())
         .status()
         .unwrap();
     assert!(output.success(), "{:?}", output);
+
+    let invite = tempfile::NamedTempFile::new().unwrap();
+    let output = std::process::Command::new(EXE_INVITE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .arg(invite.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let invite: serde_json::Value = serde_json::from_reader(std::fs::File::open(invite.path()).unwrap()).unwrap();
+    assert_eq!(invite["room"].as_str(), Some(successor.as_str()));
+}
+
+#[test]
+fn watch_prints_diff() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+
+    let output = std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path())
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let room_id = stdout.lines().next().expect("Room id printed");
+    let before = std::fs::read(&env.pwsafe_db).unwrap();
+
+    let mut watcher = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("watch")
+        .arg(&env.pwsafe_db)
+        .args(["--password", &env.pwsafe_password])
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::inherit())
+        .spawn()
+        .unwrap();
+
+    // Publish a diff as another client would, directly through the client API. The values are
+    // arrays of numbers, as earlier versions sent them, which must still be accepted.
+    let entry = "0b7e2a51-8b6f-4c2e-9b59-4d7f5e3c2a10";
+    let diff = serde_json::json!({
+        "delete": [],
+        "edit": { entry: { "set": { "3": b"watched".to_vec() }, "delete": [] } },
+    });
+
+    let token = env.access_token().unwrap();
+    let txn: String = core::iter::repeat_with(fastrand::alphanumeric).take(16).collect();
+    let send = harness
+        .homeserver_domain
+        .join(&format!("_matrix/client/v3/rooms/{room_id}/send/de.pwsafe.matrix.diff/{txn}"))
+        .unwrap();
+
+    ureq::put(send.as_str())
+        .set("Authorization", &format!("Bearer {token}"))
+        .send_json(serde_json::json!({ "diff": diff }))
+        .unwrap();
+
+    std::thread::sleep(std::time::Duration::from_secs(10));
+    watcher.kill().unwrap();
+    let output = watcher.wait_with_output().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("~ {entry}")), "{stdout}");
+    assert!(stdout.contains("set title: <redacted>"), "{stdout}");
+    assert!(!stdout.contains("watched"), "{stdout}");
+
+    assert_eq!(before, std::fs::read(&env.pwsafe_db).unwrap(), "Watch modified the database");
+}
+
+#[test]
+fn unlink() {
+    let harness = Harness::default();
+    let env = TestEnv::new_arbitrary(&harness);
+    let env_file = env.to_disk().unwrap();
+
+    Harness::run_checked(std::process::Command::new(EXE_PREPARE_API)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    let output = Harness::run_checked(std::process::Command::new(EXE_CREATE)
+        .env("PWSAFE_MATRIX_TESTS_PATH", env_file.path()));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let room_id = stdout.lines().next().expect("Room id printed").to_owned();
+
+    let output = Harness::run_checked(std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("unlink")
+        .arg(&env.pwsafe_db)
+        .args(["--password", &env.pwsafe_password])
+        .args(["--homeserver", env.homeserver.as_str()])
+        .args(["--user", &env.username])
+        .args(["--matrix-password", &env.password])
+        .args(["--leave-room", "--logout"]));
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains(&format!("Removed room {room_id}")), "{stdout}");
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("sync")
+        .arg(&env.pwsafe_db)
+        .args(["--password", &env.pwsafe_password])
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "{:?}", output);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("does not contain matrix credentials"), "{stderr}");
+
+    let output = Harness::run_checked(std::process::Command::new(EXE_DUMP)
+        .args(["--no-redact", "--password", &env.pwsafe_password])
+        .arg(&env.pwsafe_db));
+
+    let dump = String::from_utf8(output.stdout).unwrap();
+    assert!(!dump.contains(&room_id), "{dump}");
+}
+
+fn template_copy() -> tempfile::NamedTempFile {
+    const PWSAFE_TEMPLATE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../pwsafe.psafe3");
+    let copy = tempfile::NamedTempFile::new().unwrap();
+    std::fs::copy(PWSAFE_TEMPLATE, copy.path()).unwrap();
+    copy
+}
+
+#[test]
+fn exit_code_usage() {
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+}
+
+#[test]
+fn exit_code_wrong_password() {
+    let pwsafe = template_copy();
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("invite")
+        .arg(pwsafe.path())
+        .args(["--password", "definitely-not-the-password"])
+        .args(["--file", "-"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+}
+
+#[test]
+fn unlock_key_file_password_or_both() {
+    let pwsafe = template_copy();
+    let key_file = tempfile::NamedTempFile::new().unwrap();
+
+    let status = |key: &[u8], args: &[&str]| {
+        std::fs::write(key_file.path(), key).unwrap();
+        std::process::Command::new(EXE_PWSAFE_MATRIX)
+            .arg("status")
+            .arg(pwsafe.path())
+            .arg("--key-file")
+            .arg(key_file.path())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = status(b"pwsafe-matrix-test", &[]);
+    assert!(output.status.success(), "{:?}", output);
+
+    // The contents of the key file come first, then the password.
+    let output = status(b"pwsafe-matrix-", &["--password", "test"]);
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = status(b"test", &["--password", "pwsafe-matrix-"]);
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+
+    let output = status(b"pwsafe-matrix-", &[]);
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+}
+
+#[test]
+fn unlock_password_from_stdin() {
+    use std::io::Write as _;
+    let pwsafe = template_copy();
+
+    let mut child = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("status")
+        .arg(pwsafe.path())
+        .arg("--password-from-stdin")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    writeln!(child.stdin.take().unwrap(), "pwsafe-matrix-test").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("status")
+        .arg(pwsafe.path())
+        .args(["--password", "pwsafe-matrix-test"])
+        .arg("--password-from-stdin")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+
+    // Neither a password nor a key file.
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("status")
+        .arg(pwsafe.path())
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2), "{:?}", output);
+}
+
+#[test]
+fn exit_code_missing_file() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("invite")
+        .arg(dir.path().join("does-not-exist.psafe3"))
+        .args(["--password", "pwsafe-matrix-test"])
+        .args(["--file", "-"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4), "{:?}", output);
+}
+
+#[test]
+fn exit_code_unreachable_homeserver() {
+    let pwsafe = template_copy();
+    // Bind and drop, so that nothing is listening on the port.
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let output = std::process::Command::new(EXE_PWSAFE_MATRIX)
+        .arg("create")
+        .arg(pwsafe.path())
+        .args(["--password", "pwsafe-matrix-test"])
+        .args(["--homeserver", &format!("http://127.0.0.1:{port}")])
+        .args(["--user", "nobody"])
+        .args(["--matrix-password", "nothing"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(6), "{:?}", output);
 }
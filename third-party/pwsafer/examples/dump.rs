@@ -15,7 +15,6 @@ fn main() {
     let file = BufReader::new(File::open(filename).unwrap());
     let key = PwsafeKey::new(password.as_bytes());
     let mut db = PwsafeReader::new(file, &key).unwrap();
-    db.read_version().unwrap();
 
     loop {
         let (field_type, field_data) = db.read_field().unwrap();
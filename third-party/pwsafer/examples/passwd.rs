@@ -17,7 +17,7 @@ fn main() {
     let mut wdb = PwsafeWriter::new(wfile, rdb.get_iter(), &PwsafeKey::new(b"test")).unwrap();
 
     while let Some((field_type, field_data)) = rdb.read_field() {
-        wdb.write_field(field_type, &field_data);
+        wdb.write_field(field_type, &field_data).unwrap();
     }
 
     wdb.finish().unwrap();
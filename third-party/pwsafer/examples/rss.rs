@@ -0,0 +1,81 @@
+// Compares the peak memory of reading a large database eagerly and incrementally.
+//
+// Run each read in its own process, the peak is per process:
+//
+//   cargo run --release --example rss write /tmp/large.psafe3
+//   cargo run --release --example rss read /tmp/large.psafe3 eager
+//   cargo run --release --example rss read /tmp/large.psafe3 incremental
+//
+// The peak resident set size is read from `/proc/self/status`, hence Linux only.
+
+use pwsafer::{PwsafeKey, PwsafeReader, PwsafeWriter};
+use rand::RngCore;
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::time::Instant;
+
+/// 50 attachments of 1MB each.
+const FIELDS: usize = 50;
+const FIELD_LEN: usize = 1 << 20;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let key = PwsafeKey::new(b"password");
+
+    match (args[1].as_str(), &args[2..]) {
+        ("write", [path]) => write(path, &key),
+        ("read", [path, mode]) => read(path, mode, &key),
+        _ => panic!("Usage: rss write <path> | rss read <path> eager|incremental"),
+    }
+}
+
+fn write(path: &str, key: &PwsafeKey) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut db = PwsafeWriter::new(file, 2048, key).unwrap();
+
+    db.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+
+    let mut data = vec![0; FIELD_LEN];
+    for idx in 0..FIELDS {
+        rand::thread_rng().fill_bytes(&mut data);
+        let title = format!("attachment {idx}");
+        db.write_record(&[(0x03, title.as_bytes()), (0x42, &data)])
+            .unwrap();
+    }
+
+    db.finish().unwrap();
+}
+
+fn read(path: &str, mode: &str, key: &PwsafeKey) {
+    let file = BufReader::new(File::open(path).unwrap());
+    let baseline = peak_rss();
+    let start = Instant::now();
+
+    let mut db = match mode {
+        "eager" => PwsafeReader::new(file, key).unwrap(),
+        "incremental" => PwsafeReader::new_incremental(file, key).unwrap(),
+        _ => panic!("Unknown mode {mode}, use eager or incremental"),
+    };
+
+    let mut total = 0;
+    while let Some((_, data)) = db.read_field() {
+        total += data.len();
+    }
+
+    println!(
+        "{mode}: read {total} bytes in {:?}, peak RSS {} kB (from {baseline} kB)",
+        start.elapsed(),
+        peak_rss(),
+    );
+}
+
+/// The high water mark of the resident set, in kB.
+fn peak_rss() -> u64 {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse().ok())
+        .expect("VmHWM in /proc/self/status")
+}
@@ -0,0 +1,58 @@
+// Compares writing a large database with and without reserving the buffer of its fields.
+//
+// Run as: cargo run --release --features generate --example write [records] [rounds]
+//
+// The records are written again as pwsafe-matrix does on each change, knowing the data length of
+// the database it read. With the reservation the buffer is allocated once, without it the buffer
+// is moved to a new allocation each time it is full. Preparing the fields is timed separately,
+// encrypting them in `finish` takes the same time either way.
+
+use pwsafer::generate::{generate_records, write_database, Options, Record};
+use pwsafer::{PwsafeHeader, PwsafeKey, PwsafeReader, PwsafeWriter};
+use std::env;
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let n_records = args.get(1).map_or(10_000, |n| n.parse().unwrap());
+    let rounds = args.get(2).map_or(20, |n| n.parse().unwrap());
+
+    let options = Options::default();
+    let records = generate_records(0, n_records, &options);
+    let db = write_database(0, &records, &options);
+
+    let key = PwsafeKey::new(&options.password);
+    let reader = PwsafeReader::new(Cursor::new(db), &key).unwrap();
+    let data_len = reader.data_len();
+
+    println!("{n_records} records, {data_len} bytes of fields, best of {rounds} rounds");
+
+    for (name, reserve) in [("grown", None), ("reserved", Some(data_len))] {
+        let times: Vec<_> = (0..rounds).map(|_| write(&key, &records, reserve)).collect();
+        let fields = times.iter().map(|(fields, _)| fields).min().unwrap();
+        let total = times.iter().map(|(_, total)| total).min().unwrap();
+
+        println!("{name}: fields prepared in {fields:?}, written in {total:?}");
+    }
+}
+
+/// The time to prepare all fields, and to also encrypt them, without stretching the key.
+fn write(key: &PwsafeKey, records: &[Record], reserve: Option<usize>) -> (Duration, Duration) {
+    let mut db = PwsafeWriter::new(Vec::new(), 2048, key).unwrap();
+    let start = Instant::now();
+
+    if let Some(bytes) = reserve {
+        db.reserve(bytes).unwrap();
+    }
+
+    db.write_header(&PwsafeHeader::new()).unwrap();
+    for record in records {
+        let fields: Vec<_> = record.iter().map(|(ty, data)| (*ty, &data[..])).collect();
+        db.write_record(&fields).unwrap();
+    }
+
+    let fields = start.elapsed();
+    db.finish().unwrap();
+    (fields, start.elapsed())
+}
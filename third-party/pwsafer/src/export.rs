@@ -0,0 +1,278 @@
+//! Export records as Password Safe's XML, or as CSV for other tools.
+//!
+//! The XML follows `pwsafe.xsd` of Password Safe, so that its import and other tools reading its
+//! export understand it. Groups are written as stored, their levels separated by dots. Times are
+//! written in UTC as `YYYY-MM-DDThh:mm:ss`, without a zone as Password Safe does.
+//!
+//! Text fields that are not valid UTF-8 are exported lossily. Characters that XML can not
+//! represent, control characters besides tab and line breaks, are replaced by `U+FFFD`.
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+use crate::record::PwsafeRecord;
+
+/// Elements the schema requires of each entry, even if empty.
+const REQUIRED_ELEMENTS: &[&str] = &["title", "password"];
+
+/// A column of the CSV export, each a field of the records.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+    Group,
+    Title,
+    Username,
+    Password,
+    Url,
+    Autotype,
+    Notes,
+    Uuid,
+    CreationTime,
+    LastAccessTime,
+    PasswordExpiryTime,
+    PasswordModificationTime,
+    LastModificationTime,
+    PasswordExpiryInterval,
+    RunCommand,
+    Email,
+    Symbols,
+}
+
+/// The name of a column that does not exist.
+#[derive(Debug)]
+pub struct UnknownColumn(pub String);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Uuid,
+    /// Seconds since the epoch.
+    Time,
+    Number,
+}
+
+impl Column {
+    /// The columns written by default: where an entry is, and how to log in.
+    pub const DEFAULT: &'static [Column] = &[
+        Column::Group,
+        Column::Title,
+        Column::Username,
+        Column::Password,
+        Column::Url,
+        Column::Notes,
+    ];
+
+    /// All columns, in the order of the elements of an XML entry.
+    pub const ALL: &'static [Column] = &[
+        Column::Group,
+        Column::Title,
+        Column::Username,
+        Column::Password,
+        Column::Url,
+        Column::Autotype,
+        Column::Notes,
+        Column::Uuid,
+        Column::CreationTime,
+        Column::LastAccessTime,
+        Column::PasswordExpiryTime,
+        Column::PasswordModificationTime,
+        Column::LastModificationTime,
+        Column::PasswordExpiryInterval,
+        Column::RunCommand,
+        Column::Email,
+        Column::Symbols,
+    ];
+
+    /// The name in the header row, that of its XML element.
+    pub fn name(self) -> &'static str {
+        self.element().0
+    }
+
+    /// The element, the type of its field and how the field is written.
+    fn element(self) -> (&'static str, u8, Format) {
+        match self {
+            Column::Group => ("group", 0x02, Format::Text),
+            Column::Title => ("title", 0x03, Format::Text),
+            Column::Username => ("username", 0x04, Format::Text),
+            Column::Password => ("password", 0x06, Format::Text),
+            Column::Url => ("url", 0x0d, Format::Text),
+            Column::Autotype => ("autotype", 0x0e, Format::Text),
+            Column::Notes => ("notes", 0x05, Format::Text),
+            Column::Uuid => ("uuid", 0x01, Format::Uuid),
+            Column::CreationTime => ("ctimex", 0x07, Format::Time),
+            Column::LastAccessTime => ("atimex", 0x09, Format::Time),
+            Column::PasswordExpiryTime => ("xtimex", 0x0a, Format::Time),
+            Column::PasswordModificationTime => ("pmtimex", 0x08, Format::Time),
+            Column::LastModificationTime => ("rmtimex", 0x0c, Format::Time),
+            Column::PasswordExpiryInterval => ("xtime_interval", 0x11, Format::Number),
+            Column::RunCommand => ("runcommand", 0x12, Format::Text),
+            Column::Email => ("email", 0x14, Format::Text),
+            Column::Symbols => ("symbols", 0x16, Format::Text),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = UnknownColumn;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .iter()
+            .copied()
+            .find(|column| column.name().eq_ignore_ascii_case(name))
+            .ok_or_else(|| UnknownColumn(name.to_owned()))
+    }
+}
+
+impl fmt::Display for UnknownColumn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown column {:?}, expected one of ", self.0)?;
+        let names: Vec<_> = Column::ALL.iter().map(|column| column.name()).collect();
+        write!(f, "{}", names.join(", "))
+    }
+}
+
+impl std::error::Error for UnknownColumn {}
+
+/// Write the records as the XML export of Password Safe.
+pub fn xml<'r>(
+    records: impl IntoIterator<Item = &'r PwsafeRecord>,
+    mut out: impl Write,
+) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#)?;
+    writeln!(
+        out,
+        concat!(
+            r#"<passwordsafe delimiter="^" "#,
+            r#"xmlns:xsi="http://www.w3.org/2001/XMLSchema-instance" "#,
+            r#"xsi:noNamespaceSchemaLocation="pwsafe.xsd">"#
+        )
+    )?;
+
+    for (id, record) in records.into_iter().enumerate() {
+        writeln!(out, r#"  <entry id="{}">"#, id + 1)?;
+
+        for column in Column::ALL {
+            let (name, ty, format) = column.element();
+            let value = format_field(record, ty, format);
+
+            if value.is_empty() && !REQUIRED_ELEMENTS.contains(&name) {
+                continue;
+            }
+
+            writeln!(out, "    <{name}>{}</{name}>", escape_xml(&value))?;
+        }
+
+        writeln!(out, "  </entry>")?;
+    }
+
+    writeln!(out, "</passwordsafe>")
+}
+
+/// Write the records as CSV, a header row and then one row for each record.
+///
+/// A field the record does not have is an empty cell. Cells are quoted where needed, as in RFC
+/// 4180, and rows end with CRLF.
+pub fn csv<'r>(
+    records: impl IntoIterator<Item = &'r PwsafeRecord>,
+    columns: &[Column],
+    mut out: impl Write,
+) -> io::Result<()> {
+    let header: Vec<_> = columns.iter().map(|column| column.name().to_owned()).collect();
+    write_row(&mut out, &header)?;
+
+    for record in records {
+        let row: Vec<_> = columns
+            .iter()
+            .map(|column| {
+                let (_, ty, format) = column.element();
+                format_field(record, ty, format)
+            })
+            .collect();
+
+        write_row(&mut out, &row)?;
+    }
+
+    Ok(())
+}
+
+fn write_row(out: &mut impl Write, cells: &[String]) -> io::Result<()> {
+    let cells: Vec<_> = cells.iter().map(|cell| escape_csv(cell)).collect();
+    write!(out, "{}\r\n", cells.join(","))
+}
+
+/// The first field of this type, empty if the record has none or it is malformed.
+fn format_field(record: &PwsafeRecord, ty: u8, format: Format) -> String {
+    let Some(data) = record.field(ty) else {
+        return String::new();
+    };
+
+    match format {
+        Format::Text => String::from_utf8_lossy(data).into_owned(),
+        Format::Uuid => match <[u8; 16]>::try_from(data) {
+            Ok(uuid) => uuid.iter().map(|byte| format!("{byte:02x}")).collect(),
+            Err(_) => String::new(),
+        },
+        Format::Time => match <[u8; 4]>::try_from(data) {
+            Ok(time) => format_time(u32::from_le_bytes(time)),
+            Err(_) => String::new(),
+        },
+        Format::Number => match <[u8; 4]>::try_from(data) {
+            Ok(number) => u32::from_le_bytes(number).to_string(),
+            Err(_) => String::new(),
+        },
+    }
+}
+
+/// Seconds since the epoch as `YYYY-MM-DDThh:mm:ss`, in UTC.
+pub(crate) fn format_time(time: u32) -> String {
+    let days = time / 86400;
+    let seconds = time % 86400;
+
+    // Days to the civil calendar, from Howard Hinnant's `civil_from_days`.
+    let days = i64::from(days) + 719468;
+    let era = days / 146097;
+    let day_of_era = days % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Parsers turn a literal carriage return into a line feed.
+            '\r' => escaped.push_str("&#13;"),
+            '\t' | '\n' => escaped.push(ch),
+            '\0'..='\u{1f}' | '\u{fffe}' | '\u{ffff}' => escaped.push('\u{fffd}'),
+            ch => escaped.push(ch),
+        }
+    }
+
+    escaped
+}
+
+fn escape_csv(cell: &str) -> String {
+    if !cell.contains([',', '"', '\r', '\n']) {
+        return cell.to_owned();
+    }
+
+    format!("\"{}\"", cell.replace('"', "\"\""))
+}
@@ -41,7 +41,7 @@ fn parse_u16(data: &[u8]) -> Result<u16> {
         return Err(Error::InvalidLength { ty: None, len: data.len(), expected: 2 });
     };
 
-    Ok(u16::from_be_bytes(bytes))
+    Ok(u16::from_le_bytes(bytes))
 }
 
 fn parse_u32(ty: u8, data: &[u8]) -> Result<u32> {
@@ -49,11 +49,12 @@ fn parse_u32(ty: u8, data: &[u8]) -> Result<u32> {
         return Err(Error::InvalidLength { ty: Some(ty), len: data.len(), expected: 4 });
     };
 
-    Ok(u32::from_be_bytes(bytes))
+    Ok(u32::from_le_bytes(bytes))
 }
 
 /// Password Safe header field.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PwsafeHeaderField {
     /// Version
     Version(u16),
@@ -89,8 +90,8 @@ pub enum PwsafeHeaderField {
     Yubico(String),
     /// Timestamp of last master password change
     LastMasterPasswordChange(u32),
-    /// Unknown field type stored as-is
-    Blob(Vec<u8>),
+    /// Unknown field type and its data, stored as-is
+    Blob(u8, Vec<u8>),
     /// End of header
     EndOfHeader,
 }
@@ -172,14 +173,42 @@ impl PwsafeHeaderField {
                 PwsafeHeaderField::LastMasterPasswordChange(timestamp)
             }
             0xff => PwsafeHeaderField::EndOfHeader,
-            _ => PwsafeHeaderField::Blob(data),
+            _ => PwsafeHeaderField::Blob(field_type, data),
         };
         Ok(res)
     }
+
+    /// The type and data of the field as stored, the inverse of [`Self::new`].
+    ///
+    /// A blob with the type of a known field reads back as that field.
+    pub fn to_raw(&self) -> (u8, Vec<u8>) {
+        match self {
+            PwsafeHeaderField::Version(version) => (0x00, version.to_le_bytes().to_vec()),
+            PwsafeHeaderField::Uuid(uuid) => (0x01, uuid.to_vec()),
+            PwsafeHeaderField::Preferences(s) => (0x02, s.as_bytes().to_vec()),
+            PwsafeHeaderField::TreeDisplayStatus(s) => (0x03, s.as_bytes().to_vec()),
+            PwsafeHeaderField::LastSaveTimestamp(timestamp) => (0x04, timestamp.to_le_bytes().to_vec()),
+            PwsafeHeaderField::LastSaveWho(s) => (0x05, s.as_bytes().to_vec()),
+            PwsafeHeaderField::LastSaveWhat(s) => (0x06, s.as_bytes().to_vec()),
+            PwsafeHeaderField::LastSaveUser(s) => (0x07, s.as_bytes().to_vec()),
+            PwsafeHeaderField::LastSaveHost(s) => (0x08, s.as_bytes().to_vec()),
+            PwsafeHeaderField::DatabaseName(s) => (0x09, s.as_bytes().to_vec()),
+            PwsafeHeaderField::DatabaseDescription(s) => (0x0a, s.as_bytes().to_vec()),
+            PwsafeHeaderField::DatabaseFilters(s) => (0x0b, s.as_bytes().to_vec()),
+            PwsafeHeaderField::RecentlyUsedEntries(s) => (0x0f, s.as_bytes().to_vec()),
+            PwsafeHeaderField::NamedPasswordPolicies(s) => (0x10, s.as_bytes().to_vec()),
+            PwsafeHeaderField::EmptyGroups(s) => (0x11, s.as_bytes().to_vec()),
+            PwsafeHeaderField::Yubico(s) => (0x12, s.as_bytes().to_vec()),
+            PwsafeHeaderField::LastMasterPasswordChange(timestamp) => (0x13, timestamp.to_le_bytes().to_vec()),
+            PwsafeHeaderField::Blob(ty, data) => (*ty, data.clone()),
+            PwsafeHeaderField::EndOfHeader => (0xff, vec![]),
+        }
+    }
 }
 
 /// Password Safe record field.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PwsafeRecordField {
     /// UUID
     Uuid([u8; 16]),
@@ -241,8 +270,8 @@ pub enum PwsafeRecordField {
     CreditCardPin(String),
     /// QR code
     QrCode(String),
-    /// Unknown field type stored as-is
-    Blob(Vec<u8>),
+    /// Unknown field type and its data, stored as-is
+    Blob(u8, Vec<u8>),
     /// End of record
     EndOfRecord,
 }
@@ -376,8 +405,48 @@ impl PwsafeRecordField {
                 PwsafeRecordField::QrCode(s)
             }
             0xff => PwsafeRecordField::EndOfRecord,
-            _ => PwsafeRecordField::Blob(data),
+            _ => PwsafeRecordField::Blob(field_type, data),
         };
         Ok(res)
     }
+
+    /// The type and data of the field as stored, the inverse of [`Self::new`].
+    ///
+    /// A blob with the type of a known field reads back as that field.
+    pub fn to_raw(&self) -> (u8, Vec<u8>) {
+        match self {
+            PwsafeRecordField::Uuid(uuid) => (0x01, uuid.to_vec()),
+            PwsafeRecordField::Group(s) => (0x02, s.as_bytes().to_vec()),
+            PwsafeRecordField::Title(s) => (0x03, s.as_bytes().to_vec()),
+            PwsafeRecordField::Username(s) => (0x04, s.as_bytes().to_vec()),
+            PwsafeRecordField::Notes(s) => (0x05, s.as_bytes().to_vec()),
+            PwsafeRecordField::Password(s) => (0x06, s.as_bytes().to_vec()),
+            PwsafeRecordField::CreationTime(timestamp) => (0x07, timestamp.to_le_bytes().to_vec()),
+            PwsafeRecordField::PasswordModificationTime(timestamp) => (0x08, timestamp.to_le_bytes().to_vec()),
+            PwsafeRecordField::LastAccessTime(timestamp) => (0x09, timestamp.to_le_bytes().to_vec()),
+            PwsafeRecordField::PasswordExpiryTime(timestamp) => (0x0a, timestamp.to_le_bytes().to_vec()),
+            PwsafeRecordField::LastModificationTime(timestamp) => (0x0c, timestamp.to_le_bytes().to_vec()),
+            PwsafeRecordField::Url(s) => (0x0d, s.as_bytes().to_vec()),
+            PwsafeRecordField::Autotype(s) => (0x0e, s.as_bytes().to_vec()),
+            PwsafeRecordField::PasswordHistory(s) => (0x0f, s.as_bytes().to_vec()),
+            PwsafeRecordField::PasswordPolicy(s) => (0x10, s.as_bytes().to_vec()),
+            PwsafeRecordField::PasswordExpiryInterval(days) => (0x11, days.to_le_bytes().to_vec()),
+            PwsafeRecordField::RunCommand(s) => (0x12, s.as_bytes().to_vec()),
+            PwsafeRecordField::DoubleClickAction(action) => (0x13, action.to_le_bytes().to_vec()),
+            PwsafeRecordField::EmailAddress(s) => (0x14, s.as_bytes().to_vec()),
+            PwsafeRecordField::ProtectedEntry(protected) => (0x15, vec![*protected]),
+            PwsafeRecordField::OwnSymbolsForPassword(s) => (0x16, s.as_bytes().to_vec()),
+            PwsafeRecordField::ShiftDoubleClickAction(action) => (0x17, action.to_le_bytes().to_vec()),
+            PwsafeRecordField::PasswordPolicyName(s) => (0x18, s.as_bytes().to_vec()),
+            PwsafeRecordField::EntryKeyboardShortcut(shortcut) => (0x19, shortcut.to_le_bytes().to_vec()),
+            PwsafeRecordField::TwoFactorKey(key) => (0x1b, key.clone()),
+            PwsafeRecordField::CreditCardNumber(s) => (0x1c, s.as_bytes().to_vec()),
+            PwsafeRecordField::CreditCardExpiration(s) => (0x1d, s.as_bytes().to_vec()),
+            PwsafeRecordField::CreditCardVerifValue(s) => (0x1e, s.as_bytes().to_vec()),
+            PwsafeRecordField::CreditCardPin(s) => (0x1f, s.as_bytes().to_vec()),
+            PwsafeRecordField::QrCode(s) => (0x20, s.as_bytes().to_vec()),
+            PwsafeRecordField::Blob(ty, data) => (*ty, data.clone()),
+            PwsafeRecordField::EndOfRecord => (0xff, vec![]),
+        }
+    }
 }
@@ -0,0 +1,298 @@
+//! Deterministic databases of any size, for tests and benchmarks.
+//!
+//! Records look like those of a real database: most are in one of a few nested groups, some have
+//! notes ranging from a line to several pages, a url, an email address or a password history. The
+//! same seed always produces the same records and, for the same version of `rand`, the same bytes.
+//! Since even the salt is derived from the seed, never store real passwords with these functions.
+//!
+//! ```
+//! use pwsafer::generate::{generate_database, Options};
+//!
+//! let options = Options::default();
+//! let db = generate_database(42, 100, &options);
+//! assert_eq!(db, generate_database(42, 100, &options));
+//! ```
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+
+use crate::writer::Randomness;
+use crate::{PwsafeHeader, PwsafeKey, PwsafeWriter};
+
+/// The fields of one record, type and data in file order, without the end of record.
+pub type Record = Vec<(u8, Vec<u8>)>;
+
+/// How the generated records are distributed.
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub password: Vec<u8>,
+    /// Also counts below [`MIN_ITER`](crate::MIN_ITER), as in files of older programs.
+    pub iterations: u32,
+    /// The number of distinct groups, nested up to three levels deep.
+    pub groups: usize,
+    /// The chance of a record to have notes, in percent.
+    pub notes: u8,
+    /// The chance of a record to have a password history, in percent.
+    pub history: u8,
+}
+
+/// Lowercase words for groups, titles, names and notes.
+const WORDS: &[&str] = &[
+    "mail", "bank", "work", "home", "server", "router", "forum", "shop", "cloud", "backup",
+    "games", "travel", "insurance", "health", "school", "library", "github", "wiki", "vpn",
+    "printer", "phone", "tax", "energy", "water", "garden", "music", "video", "news", "chat",
+    "calendar", "photos", "family",
+];
+
+/// The characters of generated passwords.
+const PASSWORD_CHARACTERS: &[u8] =
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789!#$%&()*+,-./:;<=>?@[]^_{|}~";
+
+/// Some time in 2017, so that all timestamps are plausible.
+const EPOCH: u32 = 1_500_000_000;
+const YEAR: u32 = 365 * 24 * 60 * 60;
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            password: b"password".to_vec(),
+            iterations: 2048,
+            groups: 16,
+            notes: 40,
+            history: 25,
+        }
+    }
+}
+
+/// The bytes of a database with `n_records` generated records.
+pub fn generate_database(seed: u64, n_records: usize, options: &Options) -> Vec<u8> {
+    let records = generate_records(seed, n_records, options);
+    write_database(seed, &records, options)
+}
+
+/// Records with realistic contents, each with a unique UUID.
+pub fn generate_records(seed: u64, n_records: usize, options: &Options) -> Vec<Record> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    let groups: Vec<String> = (0..options.groups)
+        .map(|_| {
+            let depth = rng.gen_range(1..=3);
+            let path: Vec<_> = (0..depth).map(|_| *WORDS.choose(&mut rng).unwrap()).collect();
+            path.join(".")
+        })
+        .collect();
+
+    (0..n_records)
+        .map(|_| generate_record(&mut rng, &groups, options))
+        .collect()
+}
+
+/// Write records into a database, as the only contents besides a minimal header.
+///
+/// The salt, keys and padding are derived from `seed`.
+pub fn write_database(seed: u64, records: &[Record], options: &Options) -> Vec<u8> {
+    let key = PwsafeKey::new(&options.password);
+    let rng = Randomness::Seeded(Box::new(StdRng::seed_from_u64(seed)));
+
+    let mut writer = PwsafeWriter::with_randomness(vec![], options.iterations, &key, rng)
+        .expect("writing to memory does not fail");
+
+    write_all(&mut writer, records).expect("writing to memory does not fail");
+    writer.take().1
+}
+
+fn write_all(writer: &mut PwsafeWriter<Vec<u8>>, records: &[Record]) -> std::io::Result<()> {
+    writer.write_header(&PwsafeHeader::new())?;
+
+    for record in records {
+        for (ty, data) in record {
+            writer.write_field(*ty, data)?;
+        }
+
+        writer.write_field(0xff, &[])?;
+    }
+
+    writer.finish()
+}
+
+/// Change `k` distinct records, returning their sorted indices.
+///
+/// Each record gets one of: a new password, moved to the history if it has one; a new title;
+/// new notes; or its url removed or added. UUIDs are never changed. Panics if there are fewer
+/// than `k` records.
+pub fn mutate_records(seed: u64, records: &mut [Record], k: usize) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut chosen = rand::seq::index::sample(&mut rng, records.len(), k).into_vec();
+    chosen.sort_unstable();
+
+    for &index in &chosen {
+        let record = &mut records[index];
+        let before = record.clone();
+
+        while *record == before {
+            match rng.gen_range(0..4) {
+                0 => {
+                    let now = EPOCH + rng.gen_range(YEAR..2 * YEAR);
+                    let password = password(&mut rng);
+
+                    if let Some(old) = field(record, 0x06) {
+                        if let Some(history) = field_mut(record, 0x0f) {
+                            *history = push_history(history, now, &old);
+                        }
+                    }
+
+                    set(record, 0x06, password);
+                    set(record, 0x08, now.to_le_bytes().to_vec());
+                }
+                1 => set(record, 0x03, title(&mut rng).into_bytes()),
+                2 => set(record, 0x05, notes(&mut rng).into_bytes()),
+                _ => {
+                    if field(record, 0x0d).is_some() {
+                        record.retain(|(ty, _)| *ty != 0x0d);
+                    } else {
+                        set(record, 0x0d, url(&mut rng).into_bytes());
+                    }
+                }
+            }
+        }
+    }
+
+    chosen
+}
+
+fn generate_record(rng: &mut StdRng, groups: &[String], options: &Options) -> Record {
+    let mut uuid: [u8; 16] = rng.gen();
+    // A version 4, variant 1 UUID, as generated by Password Safe.
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    let created = EPOCH + rng.gen_range(0..YEAR);
+    let modified = created + rng.gen_range(0..YEAR);
+
+    let mut record: Record = vec![(0x01, uuid.to_vec())];
+
+    if rng.gen_bool(0.8) {
+        if let Some(group) = groups.choose(rng) {
+            record.push((0x02, group.clone().into_bytes()));
+        }
+    }
+
+    record.push((0x03, title(rng).into_bytes()));
+    record.push((0x04, username(rng).into_bytes()));
+    record.push((0x06, password(rng)));
+    record.push((0x07, created.to_le_bytes().to_vec()));
+    record.push((0x08, modified.to_le_bytes().to_vec()));
+    record.push((0x0c, modified.to_le_bytes().to_vec()));
+
+    if rng.gen_bool(0.6) {
+        record.push((0x0d, url(rng).into_bytes()));
+    }
+
+    if rng.gen_bool(0.3) {
+        let email = format!("{}@example.org", username(rng));
+        record.push((0x14, email.into_bytes()));
+    }
+
+    if rng.gen_ratio(options.notes.min(100).into(), 100) {
+        record.push((0x05, notes(rng).into_bytes()));
+    }
+
+    if rng.gen_ratio(options.history.min(100).into(), 100) {
+        let mut history = b"1".to_vec();
+        history.extend_from_slice(format!("{:02x}00", rng.gen_range(1..=10)).as_bytes());
+
+        for _ in 0..rng.gen_range(0..=3) {
+            let time = created + rng.gen_range(0..=modified - created);
+            history = push_history(&history, time, &password(rng));
+        }
+
+        record.push((0x0f, history));
+    }
+
+    record
+}
+
+fn title(rng: &mut StdRng) -> String {
+    let word = WORDS.choose(rng).unwrap();
+
+    if rng.gen_bool(0.5) {
+        format!("{word} {}", rng.gen_range(1..100))
+    } else {
+        word.to_string()
+    }
+}
+
+fn username(rng: &mut StdRng) -> String {
+    format!("{}{}", WORDS.choose(rng).unwrap(), rng.gen_range(0..1000))
+}
+
+fn password(rng: &mut StdRng) -> Vec<u8> {
+    let len = rng.gen_range(8..=40);
+    (0..len)
+        .map(|_| *PASSWORD_CHARACTERS.choose(rng).unwrap())
+        .collect()
+}
+
+fn url(rng: &mut StdRng) -> String {
+    format!("https://{}.example.com/login", WORDS.choose(rng).unwrap())
+}
+
+/// Mostly a line or a paragraph, sometimes several pages.
+fn notes(rng: &mut StdRng) -> String {
+    let len = match rng.gen_range(0..100) {
+        0..=69 => rng.gen_range(10..100),
+        70..=94 => rng.gen_range(100..1000),
+        _ => rng.gen_range(1000..8000),
+    };
+
+    let mut notes = String::new();
+    while notes.len() < len {
+        notes.push_str(WORDS.choose(rng).unwrap());
+        notes.push(if rng.gen_bool(0.1) { '\n' } else { ' ' });
+    }
+
+    notes
+}
+
+/// Append a password to a history field, `fmmnn` followed by `TTTTTTTTLLLL<password>` entries.
+///
+/// The oldest entries are dropped when the maximum `mm` is reached.
+fn push_history(history: &[u8], time: u32, password: &[u8]) -> Vec<u8> {
+    let max = hex_at(history, 1..3);
+    let mut count = hex_at(history, 3..5) + 1;
+    let mut entries = history[5..].to_vec();
+
+    entries.extend_from_slice(format!("{time:08x}{:04x}", password.len()).as_bytes());
+    entries.extend_from_slice(password);
+
+    while count > max {
+        let len = hex_at(&entries, 8..12);
+        entries.drain(..12 + len);
+        count -= 1;
+    }
+
+    let mut updated = format!("{}{max:02x}{count:02x}", history[0] as char).into_bytes();
+    updated.extend_from_slice(&entries);
+    updated
+}
+
+fn hex_at(data: &[u8], range: std::ops::Range<usize>) -> usize {
+    let digits = std::str::from_utf8(&data[range]).unwrap();
+    usize::from_str_radix(digits, 16).unwrap()
+}
+
+fn field(record: &Record, ty: u8) -> Option<Vec<u8>> {
+    record.iter().find(|(field, _)| *field == ty).map(|(_, data)| data.clone())
+}
+
+fn field_mut(record: &mut Record, ty: u8) -> Option<&mut Vec<u8>> {
+    record.iter_mut().find(|(field, _)| *field == ty).map(|(_, data)| data)
+}
+
+/// Replace the field, or add it at the end.
+fn set(record: &mut Record, ty: u8, data: Vec<u8>) {
+    match field_mut(record, ty) {
+        Some(field) => *field = data,
+        None => record.push((ty, data)),
+    }
+}
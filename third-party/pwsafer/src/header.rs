@@ -0,0 +1,238 @@
+use std::str::Chars;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::field::PwsafeHeaderField;
+use crate::policy::Policy;
+use crate::reader;
+
+/// The known fields of the header, parsed when the database is read.
+///
+/// Fields that can not be parsed are left out, [`crate::PwsafeReader::header_fields`] reports
+/// them. Of fields given more than once, the first counts. Fields without an accessor are kept as
+/// they are, to write them back with [`crate::PwsafeWriter::write_header`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PwsafeHeader {
+    version: Option<u16>,
+    uuid: Option<[u8; 16]>,
+    last_save: Option<u32>,
+    last_save_user: Option<String>,
+    last_save_host: Option<String>,
+    last_save_what: Option<String>,
+    database_name: Option<String>,
+    database_description: Option<String>,
+    named_policies: Vec<NamedPolicy>,
+    /// The fields without an accessor, in the order they were read.
+    other: Vec<PwsafeHeaderField>,
+}
+
+/// A password policy stored in the header, to be referenced by name from records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct NamedPolicy {
+    pub name: String,
+    pub policy: Policy,
+}
+
+impl PwsafeHeader {
+    /// The version written when a header has none, that of the format this crate implements.
+    pub const VERSION: u16 = 0x030e;
+
+    /// The header of a new database, with only the version.
+    pub fn new() -> Self {
+        PwsafeHeader {
+            version: Some(Self::VERSION),
+            ..PwsafeHeader::default()
+        }
+    }
+
+    pub(crate) fn from_fields(
+        fields: impl Iterator<Item = reader::Result<PwsafeHeaderField>>,
+    ) -> Self {
+        let mut header = PwsafeHeader::default();
+
+        for field in fields.flatten() {
+            match field {
+                PwsafeHeaderField::Version(version) => {
+                    header.version.get_or_insert(version);
+                }
+                PwsafeHeaderField::Uuid(uuid) => {
+                    header.uuid.get_or_insert(uuid);
+                }
+                PwsafeHeaderField::LastSaveTimestamp(timestamp) => {
+                    header.last_save.get_or_insert(timestamp);
+                }
+                PwsafeHeaderField::LastSaveUser(user) => {
+                    header.last_save_user.get_or_insert(user);
+                }
+                PwsafeHeaderField::LastSaveHost(host) => {
+                    header.last_save_host.get_or_insert(host);
+                }
+                PwsafeHeaderField::LastSaveWhat(what) => {
+                    header.last_save_what.get_or_insert(what);
+                }
+                PwsafeHeaderField::DatabaseName(name) => {
+                    header.database_name.get_or_insert(name);
+                }
+                PwsafeHeaderField::DatabaseDescription(description) => {
+                    header.database_description.get_or_insert(description);
+                }
+                PwsafeHeaderField::NamedPasswordPolicies(ref policies)
+                    if header.raw_policies().is_none() =>
+                {
+                    header.named_policies = NamedPolicy::parse_all(policies).unwrap_or_default();
+                    header.other.push(field);
+                }
+                PwsafeHeaderField::NamedPasswordPolicies(_) | PwsafeHeaderField::EndOfHeader => {}
+                other => header.other.push(other),
+            }
+        }
+
+        header
+    }
+
+    /// The version of the format, `0x03xx` for version 3.
+    pub fn version(&self) -> Option<u16> {
+        self.version
+    }
+
+    /// The UUID of the database.
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.uuid
+    }
+
+    /// When the database was last saved, in seconds since the epoch.
+    pub fn last_save(&self) -> Option<u32> {
+        self.last_save
+    }
+
+    /// The user that last saved the database.
+    pub fn last_save_user(&self) -> Option<&str> {
+        self.last_save_user.as_deref()
+    }
+
+    /// The host the database was last saved on.
+    pub fn last_save_host(&self) -> Option<&str> {
+        self.last_save_host.as_deref()
+    }
+
+    /// The application, and its version, that last saved the database.
+    pub fn last_save_what(&self) -> Option<&str> {
+        self.last_save_what.as_deref()
+    }
+
+    pub fn database_name(&self) -> Option<&str> {
+        self.database_name.as_deref()
+    }
+
+    pub fn database_description(&self) -> Option<&str> {
+        self.database_description.as_deref()
+    }
+
+    /// The named password policies, none if they can not be parsed.
+    pub fn named_policies(&self) -> &[NamedPolicy] {
+        &self.named_policies
+    }
+
+    /// The named password policy of records that refer to it by this name.
+    pub fn named_policy(&self, name: &str) -> Option<&Policy> {
+        let named = self.named_policies.iter().find(|named| named.name == name)?;
+        Some(&named.policy)
+    }
+
+    /// Record a save now, by `application` of `user` on `host`.
+    ///
+    /// Replaces the older field of who saved the database, which would otherwise disagree.
+    pub fn touch(&mut self, user: &str, host: &str, application: &str) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+
+        self.last_save = Some(u32::try_from(now).unwrap_or(u32::MAX));
+        self.last_save_user = Some(user.to_owned());
+        self.last_save_host = Some(host.to_owned());
+        self.last_save_what = Some(application.to_owned());
+        self.other
+            .retain(|field| !matches!(field, PwsafeHeaderField::LastSaveWho(_)));
+    }
+
+    /// The fields to store, without the end of header.
+    ///
+    /// The version comes first, [`Self::VERSION`] if there is none, then the fields ordered by
+    /// their type as Password Safe writes them.
+    pub fn fields(&self) -> Vec<PwsafeHeaderField> {
+        let version = self.version.unwrap_or(Self::VERSION);
+        let mut fields = vec![PwsafeHeaderField::Version(version)];
+
+        let typed = [
+            self.uuid.map(PwsafeHeaderField::Uuid),
+            self.last_save.map(PwsafeHeaderField::LastSaveTimestamp),
+            self.last_save_what.clone().map(PwsafeHeaderField::LastSaveWhat),
+            self.last_save_user.clone().map(PwsafeHeaderField::LastSaveUser),
+            self.last_save_host.clone().map(PwsafeHeaderField::LastSaveHost),
+            self.database_name.clone().map(PwsafeHeaderField::DatabaseName),
+            self.database_description.clone().map(PwsafeHeaderField::DatabaseDescription),
+        ];
+
+        let mut rest: Vec<_> = typed.into_iter().flatten().chain(self.other.clone()).collect();
+        // Stable, fields of the same type keep their order.
+        rest.sort_by_key(|field| field.to_raw().0);
+        fields.extend(rest);
+        fields
+    }
+
+    fn raw_policies(&self) -> Option<&str> {
+        self.other.iter().find_map(|field| match field {
+            PwsafeHeaderField::NamedPasswordPolicies(policies) => Some(policies.as_str()),
+            _ => None,
+        })
+    }
+}
+
+impl NamedPolicy {
+    /// Parse the policies, stored as their count and then each policy, see `formatV3.txt`.
+    ///
+    /// Numbers are in hex digits of a fixed width, names and symbols are prefixed by their
+    /// length in characters.
+    fn parse_all(data: &str) -> Option<Vec<Self>> {
+        let mut chars = data.chars();
+        let count = take_hex(&mut chars, 2)?;
+
+        let policies = (0..count)
+            .map(|_| {
+                let name_len = take_hex(&mut chars, 2)?;
+                let name = take_str(&mut chars, name_len)?;
+
+                let policy = Policy::take(&mut chars)?;
+                let symbols_len = take_hex(&mut chars, 2)?;
+                let symbols = take_str(&mut chars, symbols_len)?;
+
+                Some(NamedPolicy {
+                    name,
+                    policy: Policy { symbols, ..policy },
+                })
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        // Anything left over means we did not understand the format.
+        chars.next().is_none().then_some(policies)
+    }
+}
+
+/// The number in the next `digits` hex digits.
+pub(crate) fn take_hex(chars: &mut Chars, digits: usize) -> Option<u16> {
+    let hex: String = chars.by_ref().take(digits).collect();
+
+    if hex.len() != digits || !hex.chars().all(|ch| ch.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u16::from_str_radix(&hex, 16).ok()
+}
+
+/// The next `len` characters.
+fn take_str(chars: &mut Chars, len: u16) -> Option<String> {
+    let len = usize::from(len);
+    let taken: String = chars.by_ref().take(len).collect();
+    (taken.chars().count() == len).then_some(taken)
+}
@@ -1,16 +1,29 @@
 use crate::secrets_vec::SecretArray;
+use hmac::{Hmac, Mac};
 use sha2::{Digest, Sha256};
 
 pub struct PwsafeKey {
     /// The digested password, not yet salted and iterated.
     prepared_password: Sha256,
+    /// Whether the password was empty, see [`Self::is_empty_password`].
+    empty: bool,
 }
 
 impl PwsafeKey {
     pub fn new(password: &[u8]) -> Self {
         let mut prepared_password = Sha256::default();
         prepared_password.update(password);
-        PwsafeKey { prepared_password }
+        PwsafeKey {
+            prepared_password,
+            empty: password.is_empty(),
+        }
+    }
+
+    /// Whether the key is of an empty password.
+    ///
+    /// Databases may well use one, but a prompt answered with nothing was more likely cancelled.
+    pub fn is_empty_password(&self) -> bool {
+        self.empty
     }
 
     pub fn hash(&self, salt: &[u8], iter: u32) -> SecretArray<32> {
@@ -30,4 +43,23 @@ impl PwsafeKey {
 
         boxed
     }
+
+    /// An HMAC-SHA256 of `message`, keyed with the password stretched by [`Self::hash`].
+    ///
+    /// Authenticates data kept outside of a database to anyone knowing its password.
+    pub fn mac(&self, salt: &[u8], iter: u32, message: &[u8]) -> [u8; 32] {
+        self.keyed_mac(salt, iter, message).finalize().into_bytes().into()
+    }
+
+    /// Check a tag of [`Self::mac`], in constant time.
+    pub fn verify_mac(&self, salt: &[u8], iter: u32, message: &[u8], tag: &[u8]) -> bool {
+        self.keyed_mac(salt, iter, message).verify_slice(tag).is_ok()
+    }
+
+    fn keyed_mac(&self, salt: &[u8], iter: u32, message: &[u8]) -> Hmac<Sha256> {
+        let key = self.hash(salt, iter);
+        let mut mac = key.with_buf(|key| Hmac::<Sha256>::new_from_slice(key).unwrap());
+        mac.update(message);
+        mac
+    }
 }
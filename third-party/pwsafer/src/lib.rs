@@ -12,10 +12,22 @@
 //!
 //! At this time only version 3 database format is supported.
 //!
-//! High-level interfaces to parse records are not implemented (yet).
+//! Besides reading field by field, the reader groups the fields into [`PwsafeRecord`]s. Their
+//! fields are parsed on request, the raw data is kept to write them back unchanged. The known
+//! fields of the header are parsed into a [`PwsafeHeader`] right away. Fields that hold secrets are
+//! read as a [`SecretField`], which is overwritten when dropped. The [`export`] module
+//! writes records as Password Safe's XML, or as CSV. The [`policy`] module generates passwords
+//! following the password policies of a database.
+pub mod export;
 mod field;
+#[cfg(feature = "generate")]
+pub mod generate;
+mod header;
 mod key;
+pub mod policy;
 mod reader;
+mod record;
+mod secret_field;
 mod secrets_vec;
 #[cfg(test)]
 mod tests;
@@ -23,8 +35,15 @@ mod writer;
 
 pub use self::field::PwsafeHeaderField;
 pub use self::field::PwsafeRecordField;
+pub use self::header::{NamedPolicy, PwsafeHeader};
 pub use self::key::PwsafeKey;
-pub use self::reader::PwsafeReader;
-pub use self::writer::PwsafeWriter;
+pub use self::reader::{HeaderFields, PwsafeReader, Records};
+pub use self::record::PwsafeRecord;
+pub use self::secret_field::SecretField;
+pub use self::writer::{PwsafeWriter, MIN_ITER};
+/// Memory for decrypted data of applications, locked and protected like that of the reader.
+pub use self::secrets_vec::SecretBuffer;
 
+pub use field::Error as FieldError;
 pub use reader::Error as ReadError;
+pub use writer::Error as WriteError;
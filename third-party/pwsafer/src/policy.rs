@@ -0,0 +1,169 @@
+//! Generate passwords that follow a password policy, as Password Safe does.
+//!
+//! A policy is either stored in the header by name, see [`crate::PwsafeHeader::named_policies`],
+//! or in a record of its own. The character sets are those of Password Safe, including the sets
+//! of its easy-vision variant which leaves out characters that are easily confused.
+use std::fmt;
+use std::str::Chars;
+
+use rand::rngs::OsRng;
+use rand::seq::SliceRandom;
+use rand::{CryptoRng, Rng};
+
+use crate::header::take_hex;
+
+const LOWERCASE: &str = "abcdefghijklmnopqrstuvwxyz";
+const UPPERCASE: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const DIGITS: &str = "0123456789";
+const SYMBOLS: &str = "+-=_@#$%^&;:,.<>/~\\[](){}?!|*";
+const HEX_DIGITS: &str = "0123456789abcdef";
+
+const EASY_VISION_LOWERCASE: &str = "abcdefghijkmnopqrstuvwxyz";
+const EASY_VISION_UPPERCASE: &str = "ABCDEFGHJKLMNPQRTUVWXY";
+const EASY_VISION_DIGITS: &str = "346789";
+const EASY_VISION_SYMBOLS: &str = "+-=_@#$%^&<>/~\\?*";
+
+/// The rules for generating a password.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Policy {
+    /// The character classes to use and how, such as [`Policy::USE_LOWERCASE`].
+    pub flags: u16,
+    pub length: u16,
+    pub min_lowercase: u16,
+    pub min_uppercase: u16,
+    pub min_digits: u16,
+    pub min_symbols: u16,
+    /// The symbols to use instead of the default ones, if not empty.
+    pub symbols: String,
+}
+
+/// Why no password follows a policy.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The policy uses no character class.
+    NoCharacters,
+    /// The length is shorter than the minimums of the character classes, or zero.
+    TooShort { length: u16, minimum: u32 },
+    /// Pronounceable passwords are not implemented.
+    Pronounceable,
+}
+
+impl Policy {
+    pub const USE_LOWERCASE: u16 = 0x8000;
+    pub const USE_UPPERCASE: u16 = 0x4000;
+    pub const USE_DIGITS: u16 = 0x2000;
+    pub const USE_SYMBOLS: u16 = 0x1000;
+    /// Only hex digits, the other classes and their minimums are ignored.
+    pub const USE_HEX_DIGITS: u16 = 0x0800;
+    pub const USE_EASY_VISION: u16 = 0x0400;
+    pub const MAKE_PRONOUNCEABLE: u16 = 0x0200;
+
+    /// Parse the policy of a record, with the symbols of their own field.
+    ///
+    /// The policy is its flags in four hex digits, then the length and the minimums in three.
+    pub fn parse(data: &str, symbols: &str) -> Option<Self> {
+        let mut chars = data.chars();
+        let policy = Policy::take(&mut chars)?;
+
+        chars.next().is_none().then(|| Policy {
+            symbols: symbols.to_owned(),
+            ..policy
+        })
+    }
+
+    /// The flags, the length and the minimums, which named policies store the same way.
+    pub(crate) fn take(chars: &mut Chars) -> Option<Self> {
+        Some(Policy {
+            flags: take_hex(chars, 4)?,
+            length: take_hex(chars, 3)?,
+            min_lowercase: take_hex(chars, 3)?,
+            min_uppercase: take_hex(chars, 3)?,
+            min_digits: take_hex(chars, 3)?,
+            min_symbols: take_hex(chars, 3)?,
+            symbols: String::new(),
+        })
+    }
+
+    /// Whether the flags have this one set.
+    pub fn uses(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    /// Generate a password with the randomness of the operating system.
+    pub fn generate(&self) -> Result<String, Error> {
+        self.generate_with(&mut OsRng)
+    }
+
+    /// Generate a password, with the minimum of each class and the rest from all of them.
+    pub fn generate_with(&self, rng: &mut (impl Rng + CryptoRng)) -> Result<String, Error> {
+        if self.uses(Self::MAKE_PRONOUNCEABLE) {
+            return Err(Error::Pronounceable);
+        }
+
+        let classes = self.classes();
+        if classes.is_empty() {
+            return Err(Error::NoCharacters);
+        }
+
+        let minimum = classes.iter().map(|(_, min)| u32::from(*min)).sum::<u32>().max(1);
+        if minimum > u32::from(self.length) {
+            return Err(Error::TooShort { length: self.length, minimum });
+        }
+
+        let mut password = vec![];
+        for (class, min) in &classes {
+            password.extend((0..*min).map(|_| class.choose(rng).unwrap()));
+        }
+
+        let all: Vec<_> = classes.iter().flat_map(|(class, _)| class).collect();
+        while password.len() < usize::from(self.length) {
+            password.push(*all.choose(rng).unwrap());
+        }
+
+        password.shuffle(rng);
+        Ok(password.into_iter().collect())
+    }
+
+    /// The characters of each class used, with its minimum.
+    fn classes(&self) -> Vec<(Vec<char>, u16)> {
+        if self.uses(Self::USE_HEX_DIGITS) {
+            return vec![(HEX_DIGITS.chars().collect(), 0)];
+        }
+
+        let easy_vision = self.uses(Self::USE_EASY_VISION);
+        let set = |standard, easy| if easy_vision { easy } else { standard };
+
+        let symbols = match self.symbols.as_str() {
+            "" => set(SYMBOLS, EASY_VISION_SYMBOLS),
+            symbols => symbols,
+        };
+
+        let classes = [
+            (Self::USE_LOWERCASE, set(LOWERCASE, EASY_VISION_LOWERCASE), self.min_lowercase),
+            (Self::USE_UPPERCASE, set(UPPERCASE, EASY_VISION_UPPERCASE), self.min_uppercase),
+            (Self::USE_DIGITS, set(DIGITS, EASY_VISION_DIGITS), self.min_digits),
+            (Self::USE_SYMBOLS, symbols, self.min_symbols),
+        ];
+
+        classes
+            .into_iter()
+            .filter(|(flag, _, _)| self.uses(*flag))
+            .map(|(_, class, min)| (class.chars().collect(), min))
+            .collect()
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NoCharacters => write!(f, "the policy uses no characters"),
+            Error::TooShort { length, minimum } => {
+                write!(f, "the length {length} is less than the required {minimum} characters")
+            }
+            Error::Pronounceable => write!(f, "pronounceable passwords are not supported"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
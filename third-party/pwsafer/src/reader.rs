@@ -1,4 +1,3 @@
-use core::marker::PhantomData;
 
 use block_padding::ZeroPadding;
 use byteorder::{LittleEndian, ReadBytesExt};
@@ -6,6 +5,7 @@ use hmac::{digest::MacError, Hmac, Mac};
 use sha2::{Digest, Sha256};
 use std::fmt;
 use std::io::{self, Read, Seek};
+use std::sync::Arc;
 use twofish::cipher::crypto_common::generic_array::GenericArray;
 use twofish::cipher::{
     crypto_common::{KeyInit, KeyIvInit},
@@ -13,9 +13,12 @@ use twofish::cipher::{
 };
 use twofish::Twofish;
 
-use crate::field::PwsafeHeaderField;
+use crate::field::{self, PwsafeHeaderField};
+use crate::header::PwsafeHeader;
 use crate::key::PwsafeKey;
-use crate::secrets_vec::{SecretBuffer, SecretCursor};
+use crate::record::PwsafeRecord;
+use crate::secret_field::SecretField;
+use crate::secrets_vec::{SecretArray, SecretBuffer, SecretCursor};
 
 /// A specialized `Result` type for Password Safe database reader.
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -35,6 +38,10 @@ pub enum Error {
     IoError(io::Error),
     /// HMAC error.
     MacError(MacError),
+    /// A header field that could not be parsed.
+    InvalidField { ty: u8, source: field::Error },
+    /// The data ends within a record, or within the header.
+    TruncatedRecord,
 }
 
 impl fmt::Display for Error {
@@ -46,6 +53,10 @@ impl fmt::Display for Error {
             Error::InvalidCipherKey => write!(f, "Invalid block cipher key"),
             Error::IoError(ref e) => e.fmt(f),
             Error::MacError(ref e) => e.fmt(f),
+            Error::InvalidField { ty, ref source } => {
+                write!(f, "Invalid field of type {ty:#04x}: {source}")
+            }
+            Error::TruncatedRecord => write!(f, "Data ends before the end of record"),
         }
     }
 }
@@ -81,20 +92,62 @@ type HmacSha256 = Hmac<Sha256>;
 /// let mut db = PwsafeReader::new(file, &key).unwrap();
 /// let version = db.read_version().unwrap();
 /// println!("Version is {:x}", version);
+/// println!("Last saved by {:?}", db.header().last_save_what());
 /// while let Some((field_type, field_data)) = db.read_field() {
 ///     println!("Read field of type {} and length {}", field_type, field_data.len());
 /// }
 /// ```
 pub struct PwsafeReader<R> {
     inner: R,
-    cursor: SecretCursor,
+    cursor: FieldCursor,
+    /// The header, parsed when the data was read.
+    header: PwsafeHeader,
     /// Number of iterations
     iter: u32,
+    /// Decrypt each field as it is read, see [`Self::new_incremental`].
+    incremental: bool,
 }
 
 pub struct ReaderFork<'pw> {
-    cursor: SecretCursor,
-    reader: PhantomData<&'pw SecretCursor>,
+    cursor: FieldCursor,
+    header: &'pw PwsafeHeader,
+}
+
+/// The parsed fields of the header, see [`PwsafeReader::header_fields`].
+pub struct HeaderFields<'pw> {
+    cursor: &'pw mut FieldCursor,
+    done: bool,
+}
+
+/// The records after the header, see [`PwsafeReader::records`].
+pub struct Records<'pw> {
+    cursor: &'pw mut FieldCursor,
+    done: bool,
+}
+
+/// The position in the fields, and where they are read from.
+#[derive(Clone)]
+enum FieldCursor {
+    /// All fields, decrypted when the database was read.
+    Decrypted(SecretCursor),
+    /// The encrypted fields, each decrypted when it is read.
+    Encrypted {
+        fields: Arc<EncryptedFields>,
+        pos: usize,
+    },
+}
+
+// Safety: as for `SecretCursor`, the secret memory of the fields is only borrowed while one of
+// them is read, by the thread owning the cursor.
+unsafe impl Send for FieldCursor {}
+
+/// The CBC encrypted fields, with what is needed to decrypt any block of them.
+///
+/// Both the key and the blocks are kept in memory that is overwritten when dropped.
+struct EncryptedFields {
+    key: SecretArray<32>,
+    iv: [u8; 16],
+    blocks: SecretBuffer,
 }
 
 struct NextBufferedField<'slice> {
@@ -112,12 +165,36 @@ impl<R> PwsafeReader<R> {
     where
         R: Read,
     {
-        let (iter, buffer) = Self::read_from(&mut inner, key)?;
+        let (iter, buffer) = Self::read_from(&mut inner, key, false)?;
 
         Ok(PwsafeReader {
             inner,
+            header: buffer.parse_header(),
             cursor: buffer,
             iter,
+            incremental: false,
+        })
+    }
+
+    /// Creates a new `PwsafeReader` that keeps the data encrypted, decrypting only the field
+    /// being read.
+    ///
+    /// No plaintext of the whole database is ever held, which matters for large databases. The
+    /// HMAC is still verified before this returns, in one pass that decrypts and discards each
+    /// field. Every read decrypts again, so iterating is slower than with [`Self::new`]. The
+    /// returned fields are plain vectors, as with any reader.
+    pub fn new_incremental(mut inner: R, key: &PwsafeKey) -> Result<Self>
+    where
+        R: Read,
+    {
+        let (iter, buffer) = Self::read_from(&mut inner, key, true)?;
+
+        Ok(PwsafeReader {
+            inner,
+            header: buffer.parse_header(),
+            cursor: buffer,
+            iter,
+            incremental: true,
         })
     }
 
@@ -125,12 +202,14 @@ impl<R> PwsafeReader<R> {
     pub fn from_locked(inner: R) -> Self {
         PwsafeReader {
             inner,
-            cursor: SecretCursor::default(),
+            cursor: FieldCursor::default(),
+            header: PwsafeHeader::default(),
             iter: 0,
+            incremental: false,
         }
     }
 
-    fn read_from(inner: &mut R, key: &PwsafeKey) -> Result<(u32, SecretCursor)>
+    fn read_from(inner: &mut R, key: &PwsafeKey, incremental: bool) -> Result<(u32, FieldCursor)>
     where
         R: Read,
     {
@@ -196,6 +275,39 @@ impl<R> PwsafeReader<R> {
             return Err(Error::InvalidTag);
         };
 
+        if incremental {
+            let tail = buffer.split_off(data_len);
+            let (eof, inner_mac) = tail.split_at(16);
+
+            if eof != EOF {
+                return Err(Error::InvalidTag);
+            };
+
+            let mut key = SecretArray::zero();
+            key.with_buf_mut(|key| key.copy_from_slice(&k));
+
+            let fields = EncryptedFields {
+                key,
+                iv,
+                blocks: SecretBuffer::with_encrypted_data_destructive(&mut buffer),
+            };
+
+            let mut hmac: HmacSha256 = Mac::new_from_slice(&l).unwrap();
+            let mut pos = 0;
+            // Each field is overwritten as it is dropped.
+            while let Some((_, data)) = fields.read(&mut pos) {
+                hmac.update(&data);
+            }
+            hmac.verify_slice(inner_mac)?;
+
+            let cursor = FieldCursor::Encrypted {
+                fields: Arc::new(fields),
+                pos: 0,
+            };
+
+            return Ok((iter, cursor));
+        }
+
         let mut buffer = SecretBuffer::with_encrypted_data_destructive(&mut buffer);
 
         buffer.with_buf_mut(|buffer| {
@@ -227,7 +339,7 @@ impl<R> PwsafeReader<R> {
             Ok(())
         })?;
 
-        let cursor = SecretCursor::from(buffer);
+        let cursor = FieldCursor::Decrypted(SecretCursor::from(buffer));
 
         Ok((iter, cursor))
     }
@@ -238,8 +350,9 @@ impl<R> PwsafeReader<R> {
         R: Read + Seek,
     {
         self.inner.seek(std::io::SeekFrom::Start(0))?;
-        let (iter, buffer) = Self::read_from(&mut self.inner, key)?;
+        let (iter, buffer) = Self::read_from(&mut self.inner, key, self.incremental)?;
         self.iter = iter;
+        self.header = buffer.parse_header();
         self.cursor = buffer;
 
         Ok(())
@@ -249,71 +362,309 @@ impl<R> PwsafeReader<R> {
     ///
     /// Before entries can be re-iterated, the data needs to be [`Self::reread`].
     pub fn lock(&mut self) {
-        self.cursor = SecretCursor::default();
+        self.cursor = FieldCursor::default();
+        self.header = PwsafeHeader::default();
     }
 
     /// Reset the reader position of the iterator.
     pub fn restart(&mut self) {
-        self.cursor.set_position(0);
+        self.cursor.restart();
     }
 
     /// Fork the reader into one advancing the buffer contents independently.
+    ///
+    /// The fork starts at the first field, wherever this reader is. It shares the decrypted
+    /// contents, or the encrypted ones of an incremental reader. Neither is the underlying reader
+    /// duplicated nor the data read again.
     pub fn fork(&self) -> ReaderFork<'_> {
+        let mut cursor = self.cursor.clone();
+        cursor.restart();
+
         ReaderFork {
-            cursor: self.cursor.clone(),
-            reader: PhantomData,
+            cursor,
+            header: &self.header,
         }
     }
 
-    /// Reads the database version field.
+    /// The database version, from the parsed [`Self::header`].
+    ///
+    /// Does not move the position of [`Self::read_field`].
     pub fn read_version(&mut self) -> Result<u16> {
-        let (field_type, data) = self.read_field().unwrap();
-        let field = PwsafeHeaderField::new(field_type, data);
-        if let Ok(PwsafeHeaderField::Version(version)) = field {
-            return Ok(version);
-        }
-        Err(Error::InvalidHeader)
+        self.header.version().ok_or(Error::InvalidHeader)
     }
 
     /// Reads a field.
     ///
-    /// Returns field type and contents or `None` if EOF block is encountered.
+    /// Returns field type and contents or `None` if EOF block is encountered. The contents are a
+    /// plain copy of the decrypted data, which remains in memory after it is dropped. See
+    /// [`Self::read_field_secret`] for fields that may hold secrets.
     pub fn read_field(&mut self) -> Option<(u8, Vec<u8>)> {
+        read_cursor(&mut self.cursor).map(|(ty, data)| (ty, data.into_vec()))
+    }
+
+    /// Reads a field, into data that is overwritten when dropped.
+    ///
+    /// As [`Self::read_field`] otherwise.
+    pub fn read_field_secret(&mut self) -> Option<(u8, SecretField)> {
         read_cursor(&mut self.cursor)
     }
 
+    /// The known fields of the header, parsed when the database was read.
+    ///
+    /// Empty for a database that is locked.
+    pub fn header(&self) -> &PwsafeHeader {
+        &self.header
+    }
+
     /// Returns the number of iterations used for key stretching.
     pub fn get_iter(&self) -> u32 {
         self.iter
     }
+
+    /// The length of all fields, as encrypted in the file.
+    ///
+    /// Writing the same fields again takes as many bytes, see [`PwsafeWriter::reserve`]. Zero for
+    /// a database that is locked.
+    ///
+    /// [`PwsafeWriter::reserve`]: crate::PwsafeWriter::reserve
+    pub fn data_len(&self) -> usize {
+        self.cursor.data_len()
+    }
+
+    /// The fields of the header, parsed, from the start of the data.
+    ///
+    /// Ends before the end of header. A field that can not be parsed is an error, iteration goes
+    /// on with the next one.
+    pub fn header_fields(&mut self) -> HeaderFields<'_> {
+        self.restart();
+
+        HeaderFields {
+            cursor: &mut self.cursor,
+            done: false,
+        }
+    }
+
+    /// The fields of the header as they are stored, to write them back unchanged.
+    pub fn header_record(&mut self) -> Result<PwsafeRecord> {
+        self.restart();
+        read_record(&mut self.cursor).unwrap_or(Err(Error::InvalidHeader))
+    }
+
+    /// All records after the header, from the start of the data.
+    ///
+    /// A record that is not terminated before the data ends is an error, after which the
+    /// iteration ends.
+    pub fn records(&mut self) -> Records<'_> {
+        self.restart();
+        Records::after_header(&mut self.cursor)
+    }
 }
 
 impl ReaderFork<'_> {
-    /// Reads the database version field.
+    /// The database version, see [`PwsafeReader::read_version`].
     pub fn read_version(&mut self) -> Result<u16> {
-        let (field_type, data) = self.read_field().unwrap();
-        let field = PwsafeHeaderField::new(field_type, data);
-        if let Ok(PwsafeHeaderField::Version(version)) = field {
-            return Ok(version);
-        }
-        Err(Error::InvalidHeader)
+        self.header.version().ok_or(Error::InvalidHeader)
     }
 
-    /// Reads a field.
+    /// Reads a field, see [`PwsafeReader::read_field`] about the copy it returns.
     ///
     /// Returns field type and contents or `None` if EOF block is encountered.
     pub fn read_field(&mut self) -> Option<(u8, Vec<u8>)> {
+        read_cursor(&mut self.cursor).map(|(ty, data)| (ty, data.into_vec()))
+    }
+
+    /// Reads a field, into data that is overwritten when dropped.
+    pub fn read_field_secret(&mut self) -> Option<(u8, SecretField)> {
         read_cursor(&mut self.cursor)
     }
+
+    /// All records after the header, see [`PwsafeReader::records`].
+    pub fn records(&mut self) -> Records<'_> {
+        self.cursor.restart();
+        Records::after_header(&mut self.cursor)
+    }
 }
 
-fn read_cursor(cursor: &mut SecretCursor) -> Option<(u8, Vec<u8>)> {
+impl Iterator for HeaderFields<'_> {
+    type Item = Result<PwsafeHeaderField>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let Some((ty, data)) = read_cursor(self.cursor) else {
+            self.done = true;
+            return Some(Err(Error::TruncatedRecord));
+        };
+
+        match PwsafeHeaderField::new(ty, data.into_vec()) {
+            Ok(PwsafeHeaderField::EndOfHeader) => {
+                self.done = true;
+                None
+            }
+            Ok(field) => Some(Ok(field)),
+            Err(source) => Some(Err(Error::InvalidField { ty, source })),
+        }
+    }
+}
+
+impl<'pw> Records<'pw> {
+    fn after_header(cursor: &'pw mut FieldCursor) -> Self {
+        // Without an end of header there are no records, only a truncated header.
+        let done = !matches!(read_record(cursor), Some(Ok(_)));
+        Records { cursor, done }
+    }
+}
+
+impl Iterator for Records<'_> {
+    type Item = Result<PwsafeRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let record = read_record(self.cursor);
+        self.done = !matches!(record, Some(Ok(_)));
+        record
+    }
+}
+
+/// The fields up to the next end of record, `None` if the data ends before any field.
+fn read_record(cursor: &mut FieldCursor) -> Option<Result<PwsafeRecord>> {
+    let mut record = PwsafeRecord::default();
+    let mut empty = true;
+
+    loop {
+        match read_cursor(cursor) {
+            Some((0xff, _)) => return Some(Ok(record)),
+            Some((ty, data)) => record.push(ty, data.into_vec()),
+            None if empty => return None,
+            None => return Some(Err(Error::TruncatedRecord)),
+        }
+
+        empty = false;
+    }
+}
+
+impl FieldCursor {
+    fn restart(&mut self) {
+        match self {
+            FieldCursor::Decrypted(cursor) => cursor.set_position(0),
+            FieldCursor::Encrypted { pos, .. } => *pos = 0,
+        }
+    }
+
+    /// The length of the encrypted fields, without the end of file and the HMAC.
+    fn data_len(&self) -> usize {
+        match self {
+            // Decrypted in place, with the end of file and the HMAC behind the fields.
+            FieldCursor::Decrypted(cursor) => cursor.buffer_len().saturating_sub(48),
+            FieldCursor::Encrypted { fields, .. } => fields.blocks.len(),
+        }
+    }
+
+    /// The parsed header, read by a copy of this cursor.
+    fn parse_header(&self) -> PwsafeHeader {
+        let mut cursor = self.clone();
+        cursor.restart();
+
+        PwsafeHeader::from_fields(HeaderFields {
+            cursor: &mut cursor,
+            done: false,
+        })
+    }
+}
+
+impl Default for FieldCursor {
+    fn default() -> Self {
+        FieldCursor::Decrypted(SecretCursor::default())
+    }
+}
+
+impl EncryptedFields {
+    /// Decrypt the field at `pos`, and advance past it.
+    fn read(&self, pos: &mut usize) -> Option<(u8, SecretField)> {
+        // Each borrow of the secret memory changes its protection, so both are borrowed once.
+        let cipher = self.key.with_buf(|key| Twofish::new(key.into()));
+        self.blocks.with_buf(|blocks| self.read_blocks(&cipher, blocks, pos))
+    }
+
+    /// Decrypt the block at `pos`, which CBC chains to the block before it.
+    fn block(&self, cipher: &Twofish, blocks: &[u8], pos: usize) -> [u8; 16] {
+        let mut block: [u8; 16] = blocks[pos..][..16].try_into().unwrap();
+        cipher.decrypt_block(GenericArray::from_mut_slice(&mut block));
+
+        let previous = match pos.checked_sub(16) {
+            Some(start) => &blocks[start..pos],
+            None => &self.iv[..],
+        };
+
+        for (byte, chained) in block.iter_mut().zip(previous) {
+            *byte ^= chained;
+        }
+
+        block
+    }
+
+    /// Decrypt the field at `pos` of the blocks.
+    ///
+    /// Laid out as by [`next_buffered_field`]: the first block holds the length, the type and up
+    /// to 11 bytes of data, each following block up to 16 more.
+    fn read_blocks(
+        &self,
+        cipher: &Twofish,
+        blocks: &[u8],
+        pos: &mut usize,
+    ) -> Option<(u8, SecretField)> {
+        if blocks.len().checked_sub(*pos)? < 16 {
+            return None;
+        }
+
+        let mut first = self.block(cipher, blocks, *pos);
+        if first == EOF {
+            return None;
+        }
+
+        let field_length = u32::from_le_bytes(first[..4].try_into().unwrap()) as usize;
+        let field_type = first[4];
+
+        // A corrupted length, reaching past the data. Its HMAC won't verify either.
+        let count = 1 + field_length.saturating_sub(11).div_ceil(16);
+        if (blocks.len() - *pos) / 16 < count {
+            return None;
+        }
+
+        // Filled within its capacity, no reallocation leaves a copy.
+        let mut data = Vec::with_capacity(field_length);
+        data.extend_from_slice(&first[5..][..field_length.min(11)]);
+        first.fill(0);
+
+        for idx in 1..count {
+            let mut block = self.block(cipher, blocks, *pos + 16 * idx);
+            let len = (field_length - data.len()).min(16);
+            data.extend_from_slice(&block[..len]);
+            block.fill(0);
+        }
+
+        *pos += 16 * count;
+        Some((field_type, SecretField::from(data)))
+    }
+}
+
+fn read_cursor(cursor: &mut FieldCursor) -> Option<(u8, SecretField)> {
+    let cursor = match cursor {
+        FieldCursor::Decrypted(cursor) => cursor,
+        FieldCursor::Encrypted { fields, pos } => return fields.read(pos),
+    };
+
     cursor.with_buf(|tail, consume| {
         let Some(field) = next_buffered_field(tail) else {
             return None;
         };
 
-        let data = field.field_data.to_vec();
+        let data = SecretField::copy_from(field.field_data);
         let field_type = field.field_type;
         *consume += field.len;
 
@@ -341,7 +692,8 @@ fn next_buffered_field<'slice>(data: &'slice [u8]) -> Option<NextBufferedField<'
 
     // Make sure all variables are in sync, not end up out-of-bounds, and do not wrap.
     while remaining > 11 {
-        block_tail = &block_tail[16..];
+        // A corrupted length, reaching past the data. Its HMAC won't verify either.
+        block_tail = block_tail.get(16..)?;
         remaining = remaining.saturating_sub(16);
     }
 
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use crate::field::{self, PwsafeRecordField};
+
+/// All fields of one record, as they are stored.
+///
+/// The types and data are kept as read, so that a record can be written back unchanged even with
+/// fields this crate does not know. The end of record is not included.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct PwsafeRecord {
+    fields: Vec<(u8, Vec<u8>)>,
+}
+
+impl PwsafeRecord {
+    /// Type and data of each field, in file order.
+    pub fn fields(&self) -> &[(u8, Vec<u8>)] {
+        &self.fields
+    }
+
+    pub fn into_fields(self) -> Vec<(u8, Vec<u8>)> {
+        self.fields
+    }
+
+    /// The data of the first field of this type.
+    pub fn field(&self, ty: u8) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(field, _)| *field == ty)
+            .map(|(_, data)| data.as_slice())
+    }
+
+    /// The UUID of the record, if it has one of the correct length.
+    pub fn uuid(&self) -> Option<[u8; 16]> {
+        self.field(0x01)?.try_into().ok()
+    }
+
+    /// Parse each field, in file order.
+    pub fn parse(&self) -> impl Iterator<Item = field::Result<PwsafeRecordField>> + '_ {
+        self.fields
+            .iter()
+            .map(|(ty, data)| PwsafeRecordField::new(*ty, data.clone()))
+    }
+
+    pub(crate) fn push(&mut self, ty: u8, data: Vec<u8>) {
+        self.fields.push((ty, data));
+    }
+}
+
+impl From<Vec<(u8, Vec<u8>)>> for PwsafeRecord {
+    fn from(fields: Vec<(u8, Vec<u8>)>) -> Self {
+        PwsafeRecord { fields }
+    }
+}
+
+/// Shows the types and lengths of the fields, never their data.
+impl fmt::Debug for PwsafeRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.fields.iter().map(|(ty, data)| (ty, data.len())))
+            .finish()
+    }
+}
@@ -0,0 +1,88 @@
+//! The data of single fields, overwritten before it is freed.
+use core::ops::Deref;
+use core::sync::atomic::{compiler_fence, Ordering};
+use std::fmt;
+
+/// The data of a field, overwritten with zeros when dropped.
+///
+/// Returned by [`PwsafeReader::read_field_secret`](crate::PwsafeReader::read_field_secret). Never
+/// grows, so no reallocation leaves a copy behind. Copies taken out of it, such as by
+/// [`Self::into_vec`] or by parsing the field, are not covered. Formatting shows only the length.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+pub struct SecretField(Vec<u8>);
+
+#[cfg(test)]
+thread_local! {
+    /// The bytes overwritten by dropped fields on this thread, to test that drop does so.
+    pub(crate) static ZEROED: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+}
+
+impl SecretField {
+    /// Copy the data, into an allocation of exactly its length.
+    pub fn copy_from(data: &[u8]) -> Self {
+        SecretField(data.to_vec())
+    }
+
+    /// The data as a plain vector, which is not overwritten when dropped.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        core::mem::take(&mut self.0)
+    }
+
+    /// Overwrite all of the memory with zeros, keeping the length.
+    fn wipe(&mut self) {
+        for byte in self.0.iter_mut() {
+            // Safety: a valid and aligned reference.
+            unsafe { core::ptr::write_volatile(byte, 0) };
+        }
+
+        for byte in self.0.spare_capacity_mut() {
+            // Safety: as above, and any byte is a valid `MaybeUninit<u8>`.
+            unsafe { core::ptr::write_volatile(byte.as_mut_ptr(), 0) };
+        }
+
+        // The writes are not elided because the memory is freed right after.
+        compiler_fence(Ordering::SeqCst);
+
+        #[cfg(test)]
+        ZEROED.with(|zeroed| zeroed.set(zeroed.get() + self.0.capacity()));
+    }
+}
+
+impl Deref for SecretField {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretField {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Takes over the allocation, without a copy.
+impl From<Vec<u8>> for SecretField {
+    fn from(data: Vec<u8>) -> Self {
+        SecretField(data)
+    }
+}
+
+impl Clone for SecretField {
+    fn clone(&self) -> Self {
+        SecretField::copy_from(&self.0)
+    }
+}
+
+impl Drop for SecretField {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl fmt::Debug for SecretField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SecretField({} bytes)", self.0.len())
+    }
+}
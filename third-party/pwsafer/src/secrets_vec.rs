@@ -1,5 +1,6 @@
 //! An appendable version of `secrets::SecretVec`.
 use secrets::{SecretBox, SecretVec};
+use std::io;
 use std::sync::Arc;
 
 pub struct SecretBuffer {
@@ -41,16 +42,48 @@ impl SecretBuffer {
         }
     }
 
+    /// Append the data.
+    ///
+    /// Panics if the length overflows, see [`Self::try_reserve`] to check beforehand.
     pub fn extend_from_slice(&mut self, data: &[u8]) {
-        if let Some(newlen) = self.needs_grow(data) {
-            self.relocate(newlen);
-        }
+        self.extend_with(data.len(), |into| into.copy_from_slice(data))
+            .expect("capacity overflow");
+    }
 
-        let mut inner = self.inner.borrow_mut();
-        let len = data.len();
+    /// Append `len` bytes, filled in place by `fill`.
+    ///
+    /// The data is not copied through other memory first, and the buffer is borrowed only once.
+    /// Fails, without any change, if the length overflows.
+    pub fn extend_with<T>(
+        &mut self,
+        len: usize,
+        fill: impl FnOnce(&mut [u8]) -> T,
+    ) -> io::Result<T> {
+        self.try_reserve(len)?;
 
-        inner[self.len..][..len].copy_from_slice(data);
+        let mut inner = self.inner.borrow_mut();
+        let result = fill(&mut inner[self.len..][..len]);
         self.len += len;
+
+        Ok(result)
+    }
+
+    pub fn with_buf<T>(&self, cb: impl FnOnce(&[u8]) -> T) -> T {
+        let head = self.inner.borrow();
+        cb(&head[..self.len])
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// The bytes that can be appended before the buffer is moved to a larger allocation.
+    pub fn capacity(&self) -> usize {
+        self.inner.len()
     }
 
     pub fn with_buf_mut<T>(&mut self, cb: impl FnOnce(&mut [u8]) -> T) -> T {
@@ -59,6 +92,15 @@ impl SecretBuffer {
         cb(head)
     }
 
+    /// Make room for `extra` more bytes, unless the length overflows.
+    pub fn try_reserve(&mut self, extra: usize) -> io::Result<()> {
+        if let Some(newlen) = Self::needs_grow_to(self.inner.len(), self.len, extra)? {
+            self.relocate(newlen);
+        }
+
+        Ok(())
+    }
+
     fn relocate(&mut self, newlen: usize) {
         let copy = self.inner.len().min(newlen);
         let mut new: SecretVec<u8> = SecretVec::zero(newlen);
@@ -72,25 +114,28 @@ impl SecretBuffer {
         self.inner = new;
     }
 
-    fn needs_grow(&self, data: &[u8]) -> Option<usize> {
-        Self::needs_grow_to(self.inner.len(), self.len, data.len())
-    }
-
-    fn needs_grow_to(capacity: usize, len: usize, extra: usize) -> Option<usize> {
-        let new_len = len.checked_add(extra).expect("capacity overflow");
+    pub(crate) fn needs_grow_to(
+        capacity: usize,
+        len: usize,
+        extra: usize,
+    ) -> io::Result<Option<usize>> {
         const GROWTH_FACTOR: usize = 2;
+        let overflow = || io::Error::new(io::ErrorKind::OutOfMemory, "capacity overflow");
+
+        let new_len = len.checked_add(extra).ok_or_else(overflow)?;
 
         if capacity >= new_len {
-            return None;
+            return Ok(None);
         }
 
+        // Doubling may overflow before the length does, then grow just enough.
         let new_cap = capacity
             .checked_mul(GROWTH_FACTOR)
-            .expect("capacity overflow")
+            .unwrap_or(new_len)
             .max(new_len);
 
         // Grow, at least to 32 if necessary.
-        Some(new_cap.max(32))
+        Ok(Some(new_cap.max(32)))
     }
 }
 
@@ -126,7 +171,8 @@ impl Clone for SecretBuffer {
     fn clone_from(&mut self, from: &SecretBuffer) {
         debug_assert!(from.len <= from.inner.len());
 
-        if let Some(new_cap) = Self::needs_grow_to(self.inner.len(), 0, from.len) {
+        // Starting from zero, the length of an existing buffer does not overflow.
+        if let Ok(Some(new_cap)) = Self::needs_grow_to(self.inner.len(), 0, from.len) {
             self.relocate(new_cap);
         }
 
@@ -157,6 +203,11 @@ impl SecretCursor {
     pub fn set_position(&mut self, pos: usize) {
         self.pos = pos;
     }
+
+    /// The length of the whole buffer, independent of the position.
+    pub fn buffer_len(&self) -> usize {
+        self.buffer.len
+    }
 }
 
 impl Default for SecretBuffer {
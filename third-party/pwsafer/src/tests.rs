@@ -8,8 +8,8 @@ fn roundtrip() {
     const DUMMY_FIELD: u8 = 0x42;
     const DUMMY_DATA: &[u8] = b"dummy";
 
-    let mut writer = PwsafeWriter::new(inner, 32, &key).unwrap();
-    writer.write_field(DUMMY_FIELD, DUMMY_DATA);
+    let mut writer = PwsafeWriter::new(inner, 2048, &key).unwrap();
+    writer.write_field(DUMMY_FIELD, DUMMY_DATA).unwrap();
     writer.finish().unwrap();
 
     let (_, mut inner) = writer.take();
@@ -21,3 +21,1064 @@ fn roundtrip() {
     assert_eq!(ty, DUMMY_FIELD);
     assert_eq!(data, DUMMY_DATA);
 }
+
+#[test]
+fn writer_iterations_floor() {
+    use crate::writer::{Error, MIN_ITER};
+
+    let key = PwsafeKey::new(b"password");
+
+    for iter in [0, 1, MIN_ITER - 1] {
+        let err = PwsafeWriter::new(vec![], iter, &key).err().unwrap();
+        assert!(matches!(err, Error::TooFewIterations(n) if n == iter), "{err:?}");
+    }
+
+    for (iter, expected) in [(1, MIN_ITER), (MIN_ITER, MIN_ITER), (4096, 4096)] {
+        let inner = std::io::Cursor::new(vec![]);
+        let mut writer = PwsafeWriter::with_iterations(inner, iter, &key).unwrap();
+        writer.write_field(0x42, b"dummy").unwrap();
+        writer.finish().unwrap();
+
+        let (_, mut inner) = writer.take();
+        inner.set_position(0);
+
+        let reader = PwsafeReader::new(inner, &key).unwrap();
+        assert_eq!(reader.get_iter(), expected, "writing with {iter}");
+    }
+}
+
+#[test]
+fn roundtrip_long_fields() {
+    let key = PwsafeKey::new(b"password");
+
+    // Data spanning the first block, full blocks, and a partial block.
+    for len in [11, 12, 27, 43, 60] {
+        let data: Vec<u8> = (0..len).collect();
+
+        let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+        writer.write_field(0x42, &data).unwrap();
+        writer.finish().unwrap();
+
+        let (_, mut inner) = writer.take();
+        inner.set_position(0);
+
+        let mut reader = PwsafeReader::new(inner, &key).unwrap();
+        let (_, read) = reader.read_field().unwrap();
+        assert_eq!(read, data, "field of length {len}");
+    }
+}
+
+#[test]
+fn write_record_roundtrip() {
+    let key = PwsafeKey::new(b"password");
+    let fields: [(u8, &[u8]); 3] = [(0x01, &[3; 16]), (0x03, b"title"), (0x06, &[b'x'; 40])];
+
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    writer.write_record(&[(0x00, &[0x0e, 0x03])]).unwrap();
+    writer.write_record(&fields).unwrap();
+    writer.write_record(&[]).unwrap();
+    writer.finish().unwrap();
+
+    let (_, mut inner) = writer.take();
+    inner.set_position(0);
+
+    let mut reader = PwsafeReader::new(inner, &key).unwrap();
+    let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 2);
+
+    let read: Vec<_> = records[0].fields().iter().map(|(ty, data)| (*ty, &data[..])).collect();
+    assert_eq!(read, fields);
+    assert!(records[1].fields().is_empty());
+}
+
+#[test]
+fn oversized_fields() {
+    use crate::secrets_vec::SecretBuffer;
+    use crate::writer::field_length;
+
+    assert_eq!(field_length(0).unwrap(), 0);
+    assert_eq!(field_length(u32::MAX as usize).unwrap(), u32::MAX);
+
+    let err = field_length(u32::MAX as usize + 1).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+
+    // Growing the buffer past the address space is an error, not a panic.
+    let err = SecretBuffer::needs_grow_to(64, 32, usize::MAX).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::OutOfMemory);
+    assert_eq!(SecretBuffer::needs_grow_to(usize::MAX - 1, 1, 0).unwrap(), None);
+    assert_eq!(
+        SecretBuffer::needs_grow_to(usize::MAX / 2 + 1, 0, usize::MAX / 2 + 2).unwrap(),
+        Some(usize::MAX / 2 + 2)
+    );
+}
+
+#[test]
+fn buffer_reserve() {
+    use crate::secrets_vec::SecretBuffer;
+
+    let mut buffer = SecretBuffer::new();
+    buffer.try_reserve(1000).unwrap();
+    assert_eq!(buffer.capacity(), 1000);
+
+    // Filled in pieces, as fields are written, without moving.
+    for piece in (0..1000u32).collect::<Vec<_>>().chunks(10) {
+        let piece: Vec<u8> = piece.iter().map(|&byte| byte as u8).collect();
+        buffer.extend_from_slice(&piece);
+    }
+
+    assert_eq!((buffer.len(), buffer.capacity()), (1000, 1000));
+    buffer.with_buf(|data| assert_eq!(data[999], (999 % 256) as u8));
+
+    buffer.extend_from_slice(&[0]);
+    assert_eq!(buffer.capacity(), 2000);
+}
+
+#[test]
+fn fork_reads_independently() {
+    let key = PwsafeKey::new(b"password");
+    let fields: Vec<(u8, Vec<u8>)> = (0..6).map(|i| (0x40 + i, vec![i; 20 * i as usize])).collect();
+
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    for (ty, data) in &fields {
+        writer.write_field(*ty, data).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let (_, mut inner) = writer.take();
+    inner.set_position(0);
+
+    let mut reader = PwsafeReader::new(inner, &key).unwrap();
+    let read_all = |read: &mut dyn FnMut() -> Option<(u8, Vec<u8>)>| {
+        core::iter::from_fn(read).collect::<Vec<_>>()
+    };
+
+    // Fork in the middle of iterating.
+    assert_eq!(reader.read_field().as_ref(), Some(&fields[0]));
+    assert_eq!(reader.read_field().as_ref(), Some(&fields[1]));
+
+    let mut fork = reader.fork();
+    let mut other = reader.fork();
+
+    // Each fork sees everything, and advancing one does not move the other.
+    assert_eq!(read_all(&mut || fork.read_field()), fields);
+    assert_eq!(other.read_field().as_ref(), Some(&fields[0]));
+    assert_eq!(read_all(&mut || other.read_field()), fields[1..]);
+
+    // The original continues where it was.
+    assert_eq!(read_all(&mut || reader.read_field()), fields[2..]);
+
+    reader.restart();
+    assert_eq!(read_all(&mut || reader.read_field()), fields);
+}
+
+/// Write the fields, a `0xff` ends the header and each record.
+fn database(fields: &[(u8, &[u8])]) -> PwsafeReader<std::io::Cursor<Vec<u8>>> {
+    let key = PwsafeKey::new(b"password");
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    for (ty, data) in fields {
+        writer.write_field(*ty, data).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let (_, mut inner) = writer.take();
+    inner.set_position(0);
+    PwsafeReader::new(inner, &key).unwrap()
+}
+
+#[test]
+fn records_and_header() {
+    use crate::{PwsafeHeaderField, PwsafeRecordField, ReadError};
+
+    let mut reader = database(&[
+        (0x00, &[0x0e, 0x03]),
+        (0x09, b"name"),
+        (0x04, b"\x01"),
+        (0xff, b""),
+        (0x01, &[7; 16]),
+        (0x03, b"first"),
+        (0x42, b"unknown"),
+        (0xff, b""),
+        (0x03, b"second"),
+        (0xff, b""),
+    ]);
+
+    let header: Vec<_> = reader.header_fields().collect();
+    assert!(matches!(
+        header[..],
+        [
+            Ok(PwsafeHeaderField::Version(0x030e)),
+            Ok(PwsafeHeaderField::DatabaseName(_)),
+            Err(ReadError::InvalidField { ty: 0x04, .. }),
+        ]
+    ), "{header:?}");
+
+    let raw = reader.header_record().unwrap();
+    assert_eq!(raw.field(0x04), Some(&b"\x01"[..]));
+    assert_eq!(raw.fields().len(), 3);
+
+    // Independent of where the reader was.
+    reader.read_field();
+    let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 2);
+
+    assert_eq!(records[0].uuid(), Some([7; 16]));
+    assert_eq!(
+        records[0].fields(),
+        [(0x01, vec![7; 16]), (0x03, b"first".to_vec()), (0x42, b"unknown".to_vec())]
+    );
+    assert!(matches!(
+        records[0].parse().collect::<Vec<_>>()[..],
+        [Ok(PwsafeRecordField::Uuid(_)), Ok(PwsafeRecordField::Title(_)), Ok(PwsafeRecordField::Blob(0x42, _))]
+    ));
+
+    assert_eq!(records[1].uuid(), None);
+    assert_eq!(records[1].field(0x03), Some(&b"second"[..]));
+
+    // Forks read the same records.
+    let forked: Vec<_> = reader.fork().records().map(Result::unwrap).collect();
+    assert_eq!(forked, records);
+}
+
+/// The header of the bundled database, as saved by the pwsafe application.
+#[test]
+fn header_of_bundled_database() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/pwsafe.psafe3");
+    let file = std::fs::File::open(path).unwrap();
+    let mut reader = PwsafeReader::new(file, &PwsafeKey::new(b"password")).unwrap();
+
+    let header = reader.header().clone();
+    assert_eq!(header.version(), Some(0x030d));
+    assert_eq!(
+        header.uuid(),
+        Some([131, 248, 217, 73, 220, 186, 72, 173, 180, 236, 242, 61, 249, 15, 4, 174])
+    );
+    assert_eq!(header.last_save(), Some(1632081688));
+    assert_eq!(header.last_save_user(), Some("gabriel"));
+    assert_eq!(header.last_save_host(), Some("Jeff"));
+    assert_eq!(header.last_save_what(), Some("pwsafe V1.04"));
+    assert_eq!(header.database_name(), None);
+    assert!(header.named_policies().is_empty());
+
+    // Without moving the cursor, the version is still the first field.
+    assert_eq!(reader.read_version().unwrap(), 0x030d);
+    assert_eq!(reader.fork().read_version().unwrap(), 0x030d);
+    assert_eq!(reader.read_field().unwrap().0, 0x00);
+
+    reader.lock();
+    assert_eq!(reader.header(), &crate::PwsafeHeader::default());
+}
+
+#[test]
+fn header_named_policies() {
+    let policies = concat!(
+        "02",
+        // Name, flags, length, lower, upper, digits, symbols, then the special symbols.
+        "03pin", "0800", "006", "000", "000", "006", "000", "00",
+        "04wifi", "f000", "020", "001", "001", "001", "001", "03#$%",
+    );
+
+    let reader = database(&[
+        (0x00, &[0x0d, 0x03]),
+        (0x09, b"name"),
+        (0x10, policies.as_bytes()),
+        (0xff, b""),
+    ]);
+
+    let header = reader.header();
+    assert_eq!(header.database_name(), Some("name"));
+
+    let [pin, wifi] = header.named_policies() else {
+        panic!("{:?}", header.named_policies());
+    };
+    assert_eq!((pin.name.as_str(), pin.policy.flags, pin.policy.length), ("pin", 0x0800, 6));
+    assert_eq!(pin.policy.min_digits, 6);
+    assert_eq!(pin.policy.symbols, "");
+    assert_eq!(wifi.name, "wifi");
+
+    let wifi = header.named_policy("wifi").unwrap();
+    assert_eq!((wifi.flags, wifi.length), (0xf000, 0x20));
+    assert_eq!([wifi.min_lowercase, wifi.min_uppercase, wifi.min_symbols], [1, 1, 1]);
+    assert_eq!(wifi.symbols, "#$%");
+    assert!(header.named_policy("other").is_none());
+
+    // Malformed policies are left out, as is the field that does not parse.
+    let reader = database(&[
+        (0x00, &[0x0d, 0x03]),
+        (0x04, b"\x01"),
+        (0x10, b"01"),
+        (0xff, b""),
+    ]);
+    assert_eq!(reader.header().version(), Some(0x030d));
+    assert_eq!(reader.header().last_save(), None);
+    assert!(reader.header().named_policies().is_empty());
+}
+
+/// Headers are written as Password Safe does, the version first and the end of header last.
+#[test]
+fn write_header_layout() {
+    use crate::PwsafeHeaderField;
+
+    let mut reader = database(&[
+        (0x09, b"name"),
+        (0x42, b"unknown"),
+        (0x05, b"0004userhost"),
+        (0x10, b"00"),
+        (0x01, &[7; 16]),
+        (0x00, &[0x0d, 0x03]),
+        (0x06, b"old"),
+        (0x02, b"B 24 1"),
+        (0xff, b""),
+        (0x03, b"title"),
+        (0xff, b""),
+    ]);
+
+    let before = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let mut header = reader.header().clone();
+    header.touch("user", "host", "pwsafer test");
+
+    let key = PwsafeKey::new(b"password");
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    writer.write_header(&header).unwrap();
+    for record in reader.records() {
+        for (ty, data) in record.unwrap().fields() {
+            writer.write_field(*ty, data).unwrap();
+        }
+        writer.write_field(0xff, &[]).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let (_, mut inner) = writer.take();
+    inner.set_position(0);
+    let mut written = PwsafeReader::new(inner, &key).unwrap();
+
+    let mut types = vec![];
+    loop {
+        let (ty, _) = written.read_field().unwrap();
+        types.push(ty);
+        if ty == 0xff {
+            break;
+        }
+    }
+
+    // The older field of who saved it is replaced.
+    assert_eq!(types, [0x00, 0x01, 0x02, 0x04, 0x06, 0x07, 0x08, 0x09, 0x10, 0x42, 0xff]);
+
+    let header = written.header();
+    assert_eq!(header.version(), Some(0x030d));
+    assert_eq!(header.uuid(), Some([7; 16]));
+    assert_eq!(header.last_save_what(), Some("pwsafer test"));
+    assert_eq!(header.last_save_user(), Some("user"));
+    assert_eq!(header.last_save_host(), Some("host"));
+    assert!(header.last_save().is_some_and(|time| u64::from(time) >= before));
+    assert_eq!(header.database_name(), Some("name"));
+    assert!(header.fields().contains(&PwsafeHeaderField::Blob(0x42, b"unknown".to_vec())));
+
+    let records: Vec<_> = written.records().map(Result::unwrap).collect();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].field(0x03), Some(&b"title"[..]));
+}
+
+/// The header of a database written by Password Safe reads back the same.
+#[test]
+fn write_header_roundtrip() {
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/pwsafe.psafe3");
+    let file = std::fs::File::open(path).unwrap();
+    let key = PwsafeKey::new(b"password");
+    let mut reader = PwsafeReader::new(file, &key).unwrap();
+
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    writer.write_header(reader.header()).unwrap();
+    writer.finish().unwrap();
+
+    let (_, mut inner) = writer.take();
+    inner.set_position(0);
+    let mut written = PwsafeReader::new(inner, &key).unwrap();
+    assert_eq!(written.header(), reader.header());
+
+    // The same fields, in the order of their types.
+    let mut original: Vec<_> = reader.header_fields().map(Result::unwrap).collect();
+    original.sort_by_key(|field| field.to_raw().0);
+    let rewritten: Vec<_> = written.header_fields().map(Result::unwrap).collect();
+    assert_eq!(rewritten, original);
+
+    // A new header only has the version.
+    assert_eq!(
+        crate::PwsafeHeader::new().fields(),
+        [crate::PwsafeHeaderField::Version(crate::PwsafeHeader::VERSION)]
+    );
+}
+
+#[test]
+fn truncated_records() {
+    use crate::ReadError;
+
+    let mut reader = database(&[
+        (0x00, &[0x0e, 0x03]),
+        (0xff, b""),
+        (0x03, b"complete"),
+        (0xff, b""),
+        (0x03, b"truncated"),
+    ]);
+
+    let mut records = reader.records();
+    assert_eq!(records.next().unwrap().unwrap().field(0x03), Some(&b"complete"[..]));
+    assert!(matches!(records.next(), Some(Err(ReadError::TruncatedRecord))));
+    assert!(records.next().is_none());
+
+    // A header without its end has no records.
+    let mut reader = database(&[(0x00, &[0x0e, 0x03])]);
+    assert!(matches!(
+        reader.header_fields().collect::<Vec<_>>()[..],
+        [Ok(_), Err(ReadError::TruncatedRecord)]
+    ));
+    assert!(matches!(reader.header_record(), Err(ReadError::TruncatedRecord)));
+    assert!(reader.records().next().is_none());
+
+    let mut reader = database(&[]);
+    assert!(matches!(reader.header_record(), Err(ReadError::InvalidHeader)));
+    assert!(reader.records().next().is_none());
+}
+
+#[test]
+fn fields_to_raw() {
+    use crate::{FieldError, PwsafeHeaderField, PwsafeRecordField};
+
+    // Of each length a number or UUID may have and some besides, text in UTF-8 or not.
+    let samples: &[&[u8]] = &[
+        b"",
+        b"\x01",
+        b"\x01\x02",
+        b"odd",
+        b"\x01\x02\x03\x04",
+        &[0x42; 16],
+        b"seventeen bytes!!",
+        "gr\u{fc}n".as_bytes(),
+        b"\xff\xfe",
+        b"half \xc3",
+    ];
+
+    for ty in 0..=0xff {
+        for &data in samples {
+            let raw = (ty, data.to_vec());
+
+            match PwsafeHeaderField::new(ty, data.to_vec()) {
+                Ok(PwsafeHeaderField::EndOfHeader) => assert_eq!(ty, 0xff),
+                Ok(field) => {
+                    assert_eq!(field.to_raw(), raw, "{field:?}");
+                    let (ty, data) = field.to_raw();
+                    assert_eq!(PwsafeHeaderField::new(ty, data).unwrap(), field);
+                }
+                Err(FieldError::InvalidLength { .. } | FieldError::FromUtf8Error(_)) => {}
+                Err(err) => panic!("Unexpected error for {ty:#04x}: {err}"),
+            }
+
+            match PwsafeRecordField::new(ty, data.to_vec()) {
+                Ok(PwsafeRecordField::EndOfRecord) => assert_eq!(ty, 0xff),
+                Ok(field) => {
+                    assert_eq!(field.to_raw(), raw, "{field:?}");
+                    let (ty, data) = field.to_raw();
+                    assert_eq!(PwsafeRecordField::new(ty, data).unwrap(), field);
+                }
+                Err(FieldError::InvalidLength { .. } | FieldError::FromUtf8Error(_)) => {}
+                Err(err) => panic!("Unexpected error for {ty:#04x}: {err}"),
+            }
+        }
+    }
+
+    // Integers are little-endian, text must be UTF-8.
+    let field = PwsafeRecordField::new(0x07, vec![0x01, 0x02, 0x03, 0x04]).unwrap();
+    assert_eq!(field, PwsafeRecordField::CreationTime(0x04030201));
+    assert!(PwsafeRecordField::new(0x03, b"\xff".to_vec()).is_err());
+    assert!(PwsafeRecordField::new(0x15, vec![]).is_err());
+
+    let end = PwsafeRecordField::EndOfRecord.to_raw();
+    assert_eq!(end, (0xff, vec![]));
+    assert_eq!(PwsafeHeaderField::EndOfHeader.to_raw(), end);
+}
+
+#[test]
+fn incremental_matches_eager() {
+    use crate::ReadError;
+
+    let key = PwsafeKey::new(b"password");
+    let fields: Vec<(u8, Vec<u8>)> = [0, 1, 11, 12, 27, 28, 100]
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| (0x40 + i as u8, vec![i as u8; len]))
+        .chain([(0xff, vec![]), (0x03, b"record".to_vec()), (0xff, vec![])])
+        .collect();
+
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    for (ty, data) in &fields {
+        writer.write_field(*ty, data).unwrap();
+    }
+    writer.finish().unwrap();
+
+    let (_, inner) = writer.take();
+    let db = inner.into_inner();
+
+    let mut eager = PwsafeReader::new(std::io::Cursor::new(&db), &key).unwrap();
+    let mut reader = PwsafeReader::new_incremental(std::io::Cursor::new(&db), &key).unwrap();
+    assert_eq!(reader.get_iter(), eager.get_iter());
+
+    // Keeping its fields in secret memory, it still moves between threads as the eager one.
+    fn is_send<T: Send>(_: &T) {}
+    is_send(&reader);
+
+    let read_all = |read: &mut dyn FnMut() -> Option<(u8, Vec<u8>)>| {
+        core::iter::from_fn(read).collect::<Vec<_>>()
+    };
+
+    assert_eq!(reader.read_field().as_ref(), Some(&fields[0]));
+    let mut fork = reader.fork();
+    assert_eq!(read_all(&mut || fork.read_field()), fields);
+    assert_eq!(read_all(&mut || reader.read_field()), fields[1..]);
+
+    reader.restart();
+    assert_eq!(read_all(&mut || reader.read_field()), read_all(&mut || eager.read_field()));
+
+    let records: Vec<_> = reader.records().map(Result::unwrap).collect();
+    let expected: Vec<_> = eager.records().map(Result::unwrap).collect();
+    assert_eq!(records, expected);
+    assert_eq!(reader.header_record().unwrap(), eager.header_record().unwrap());
+
+    // Relocking and rereading keeps the mode.
+    reader.lock();
+    assert!(reader.read_field().is_none());
+    reader.reread(&key).unwrap();
+    assert_eq!(read_all(&mut || reader.read_field()), fields);
+
+    let wrong = PwsafeReader::new_incremental(std::io::Cursor::new(&db), &PwsafeKey::new(b"wrong"));
+    assert!(matches!(wrong, Err(ReadError::InvalidPassword)));
+
+    // Flip the data of the second field through the CBC chain, after the 152 bytes of preamble.
+    // The HMAC is verified before reading.
+    let mut corrupt = db.clone();
+    corrupt[152 + 5] ^= 1;
+    let incremental = PwsafeReader::new_incremental(std::io::Cursor::new(&corrupt), &key);
+    assert!(matches!(incremental, Err(ReadError::MacError(_))));
+    let eager = PwsafeReader::new(std::io::Cursor::new(&corrupt), &key);
+    assert!(matches!(eager, Err(ReadError::MacError(_))));
+}
+
+#[test]
+fn read_field_secret_roundtrip() {
+    let key = PwsafeKey::new(b"password");
+    let fields: Vec<(u8, Vec<u8>)> = [0, 11, 12, 100]
+        .iter()
+        .enumerate()
+        .map(|(i, &len)| (0x40 + i as u8, vec![i as u8 + 1; len]))
+        .collect();
+
+    let mut writer = PwsafeWriter::new(std::io::Cursor::new(vec![]), 2048, &key).unwrap();
+    for (ty, data) in &fields {
+        writer.write_field(*ty, data).unwrap();
+    }
+    writer.finish().unwrap();
+    let db = writer.take().1.into_inner();
+
+    // Both from the decrypted data and decrypted as read, from the reader and from a fork.
+    let eager = PwsafeReader::new(std::io::Cursor::new(&db), &key).unwrap();
+    let incremental = PwsafeReader::new_incremental(std::io::Cursor::new(&db), &key).unwrap();
+
+    for mut reader in [eager, incremental] {
+        let mut fork = reader.fork();
+        let forked: Vec<_> = core::iter::from_fn(|| fork.read_field_secret()).collect();
+        let read: Vec<_> = core::iter::from_fn(|| reader.read_field_secret()).collect();
+        assert_eq!(forked, read);
+
+        let read: Vec<_> = read.into_iter().map(|(ty, data)| (ty, data.into_vec())).collect();
+        assert_eq!(read, fields);
+    }
+
+    assert_eq!(format!("{:?}", crate::SecretField::copy_from(b"secret")), "SecretField(6 bytes)");
+}
+
+#[test]
+fn secret_field_zeroed_on_drop() {
+    use crate::secret_field::ZEROED;
+    use crate::SecretField;
+
+    let zeroed = || ZEROED.with(core::cell::Cell::get);
+    let before = zeroed();
+
+    // Spare capacity is overwritten as well.
+    let mut data = Vec::with_capacity(32);
+    data.extend_from_slice(b"password");
+    let field = SecretField::from(data);
+    let copy = field.clone();
+    assert_eq!(*copy, *b"password");
+
+    drop(field);
+    assert_eq!(zeroed() - before, 32);
+    drop(copy);
+    assert_eq!(zeroed() - before, 40);
+
+    // Taken out of the field, the data is no longer overwritten.
+    let plain = SecretField::copy_from(b"plain").into_vec();
+    assert_eq!(zeroed() - before, 40);
+    assert_eq!(plain, b"plain");
+}
+
+#[cfg(feature = "generate")]
+fn read_records(db: Vec<u8>, password: &[u8]) -> Vec<crate::generate::Record> {
+    let key = PwsafeKey::new(password);
+    let mut reader = PwsafeReader::new(std::io::Cursor::new(db), &key).unwrap();
+
+    reader
+        .records()
+        .map(|record| record.unwrap().into_fields())
+        .collect()
+}
+
+/// Check the format `fmmnn` followed by `nn` entries of `TTTTTTTTLLLL<password>`.
+#[cfg(feature = "generate")]
+fn assert_history(history: &str) {
+    let hex = |digits: &str| usize::from_str_radix(digits, 16).unwrap();
+
+    let (max, count) = (hex(&history[1..3]), hex(&history[3..5]));
+    assert!(count <= max, "{history}");
+
+    let mut entries = &history[5..];
+    for _ in 0..count {
+        let len = hex(&entries[8..12]);
+        entries = &entries[12 + len..];
+    }
+
+    assert_eq!(entries, "", "{history}");
+}
+
+#[cfg(feature = "generate")]
+#[test]
+fn generate_roundtrip() {
+    use crate::generate::{generate_records, write_database, Options};
+    use crate::PwsafeRecordField;
+
+    let options = Options::default();
+    let records = generate_records(7, 200, &options);
+    let db = write_database(7, &records, &options);
+
+    let read = read_records(db, &options.password);
+    assert_eq!(read, records);
+
+    for (ty, data) in records.iter().flatten() {
+        match PwsafeRecordField::new(*ty, data.clone()).unwrap() {
+            PwsafeRecordField::Blob(..) => panic!("Unknown field type {ty:#04x}"),
+            PwsafeRecordField::PasswordHistory(history) => assert_history(&history),
+            _ => {}
+        }
+    }
+
+    let mut uuids: Vec<_> = records.iter().map(|record| &record[0]).collect();
+    uuids.sort();
+    uuids.dedup();
+    assert_eq!(uuids.len(), records.len(), "Records with the same UUID");
+}
+
+/// The data length of a reader is what writing its fields again takes.
+#[cfg(feature = "generate")]
+#[test]
+fn writer_reserve_data_len() {
+    use crate::generate::{generate_records, write_database, Options};
+    use crate::PwsafeHeader;
+
+    let options = Options::default();
+    let records = generate_records(3, 300, &options);
+    let db = write_database(3, &records, &options);
+    let key = PwsafeKey::new(&options.password);
+
+    let cursor = || std::io::Cursor::new(db.clone());
+    let reader = PwsafeReader::new(cursor(), &key).unwrap();
+    let incremental = PwsafeReader::new_incremental(cursor(), &key).unwrap();
+    // Everything but the preamble, the end of file and the HMAC.
+    assert_eq!(reader.data_len(), db.len() - 152 - 48);
+    assert_eq!(incremental.data_len(), reader.data_len());
+    assert_eq!(PwsafeReader::from_locked(std::io::empty()).data_len(), 0);
+
+    let mut writer = PwsafeWriter::new(vec![], 2048, &key).unwrap();
+    writer.reserve(reader.data_len()).unwrap();
+    writer.write_header(&PwsafeHeader::new()).unwrap();
+
+    for record in &records {
+        let fields: Vec<_> = record.iter().map(|(ty, data)| (*ty, &data[..])).collect();
+        writer.write_record(&fields).unwrap();
+    }
+
+    writer.finish().unwrap();
+    let (_, written) = writer.take();
+
+    assert_eq!(written.len(), db.len());
+    assert_eq!(read_records(written, &options.password), records);
+}
+
+#[cfg(feature = "generate")]
+#[test]
+fn generate_deterministic() {
+    use crate::generate::{generate_database, Options};
+
+    let options = Options::default();
+    assert_eq!(generate_database(1, 50, &options), generate_database(1, 50, &options));
+    assert_ne!(generate_database(1, 50, &options), generate_database(2, 50, &options));
+
+    let other = Options {
+        password: b"other".to_vec(),
+        iterations: 4096,
+        ..Options::default()
+    };
+
+    let db = generate_database(1, 50, &other);
+    assert_eq!(db[36..40], 4096u32.to_le_bytes());
+    assert_eq!(read_records(db, b"other").len(), 50);
+}
+
+#[cfg(feature = "generate")]
+#[test]
+fn generate_distribution() {
+    use crate::generate::{generate_records, Options};
+    use std::collections::HashSet;
+
+    let options = Options::default();
+    let records = generate_records(3, 1000, &options);
+
+    let with = |ty: u8| records.iter().filter(|record| record.iter().any(|(field, _)| *field == ty)).count();
+    let groups: HashSet<_> = records
+        .iter()
+        .flatten()
+        .filter(|(ty, _)| *ty == 0x02)
+        .map(|(_, data)| data)
+        .collect();
+
+    assert!((1..=options.groups).contains(&groups.len()), "{} groups", groups.len());
+    assert!((300..500).contains(&with(0x05)), "{} with notes", with(0x05));
+    assert!((150..350).contains(&with(0x0f)), "{} with history", with(0x0f));
+
+    let longest_notes = records
+        .iter()
+        .flatten()
+        .filter(|(ty, _)| *ty == 0x05)
+        .map(|(_, data)| data.len())
+        .max();
+    assert!(longest_notes > Some(1000), "{longest_notes:?}");
+
+    let none = Options {
+        notes: 0,
+        history: 0,
+        ..Options::default()
+    };
+
+    let records = generate_records(3, 100, &none);
+    assert!(records.iter().flatten().all(|(ty, _)| ![0x05, 0x0f].contains(ty)));
+}
+
+#[cfg(feature = "generate")]
+#[test]
+fn generate_mutate() {
+    use crate::generate::{generate_records, mutate_records, Options};
+    use crate::PwsafeRecordField;
+
+    let options = Options::default();
+    let records = generate_records(5, 100, &options);
+
+    for k in [0, 1, 10, 100] {
+        let mut mutated = records.clone();
+        let changed = mutate_records(k as u64, &mut mutated, k);
+        assert_eq!(changed.len(), k);
+        assert!(changed.windows(2).all(|pair| pair[0] < pair[1]), "{changed:?}");
+
+        for (index, (before, after)) in records.iter().zip(&mutated).enumerate() {
+            assert_eq!(before != after, changed.contains(&index), "record {index}");
+            assert_eq!(before[0], after[0], "UUID of record {index} changed");
+        }
+
+        for (ty, data) in mutated.iter().flatten().filter(|(ty, _)| *ty == 0x0f) {
+            let Ok(PwsafeRecordField::PasswordHistory(history)) = PwsafeRecordField::new(*ty, data.clone()) else {
+                panic!("Invalid history {data:?}");
+            };
+
+            assert_history(&history);
+        }
+    }
+
+    let mut again = records.clone();
+    let mut mutated = records.clone();
+    mutate_records(9, &mut again, 10);
+    mutate_records(9, &mut mutated, 10);
+    assert_eq!(again, mutated);
+}
+
+#[test]
+fn key_mac() {
+    let key = PwsafeKey::new(b"password");
+    let tag = key.mac(b"salt", 16, b"message");
+
+    assert!(key.verify_mac(b"salt", 16, b"message", &tag));
+    assert!(!key.verify_mac(b"salt", 16, b"massage", &tag));
+    assert!(!key.verify_mac(b"pepper", 16, b"message", &tag));
+    assert!(!PwsafeKey::new(b"other").verify_mac(b"salt", 16, b"message", &tag));
+    assert!(!key.verify_mac(b"salt", 16, b"message", &tag[..16]));
+}
+
+/// Records with special characters, missing fields and all kinds of exported fields.
+fn export_database() -> Vec<crate::PwsafeRecord> {
+    let mut reader = database(&[
+        (0x00, &[0x0e, 0x03]),
+        (0xff, b""),
+        (0x01, &[0xab; 16]),
+        (0x02, b"work.servers"),
+        (0x03, b"<db> & \"friends\""),
+        (0x04, b"admin"),
+        (0x06, b"p,w\"d"),
+        (0x05, b"line one\r\nline two ]]> \x07"),
+        (0x07, &1632081688u32.to_le_bytes()),
+        (0x0c, &951782400u32.to_le_bytes()),
+        (0x11, &30u32.to_le_bytes()),
+        (0xff, b""),
+        // Neither title nor password, nor anything else.
+        (0x01, &[0xcd; 16]),
+        (0xff, b""),
+        (0x03, b"mail"),
+        (0x06, b"secret"),
+        (0x14, b"me@example.org"),
+        (0xff, b""),
+    ]);
+
+    reader.records().map(Result::unwrap).collect()
+}
+
+/// The XML export has the structure that `pwsafe.xsd` requires.
+#[test]
+fn export_xml_schema() {
+    use crate::export::Column;
+
+    let mut out = vec![];
+    crate::export::xml(&export_database(), &mut out).unwrap();
+    let xml = String::from_utf8(out).unwrap();
+
+    // The sequence of elements of an entry, as in the schema.
+    let order: Vec<_> = Column::ALL.iter().map(|column| column.name()).collect();
+
+    let declaration = r#"<?xml version="1.0" encoding="UTF-8" standalone="yes"?>"#;
+    assert!(xml.starts_with(declaration), "{xml}");
+
+    // The open elements, with where their content starts.
+    let mut open: Vec<(&str, usize)> = vec![];
+    let mut entries: Vec<Vec<(&str, &str)>> = vec![];
+    let mut pos = declaration.len();
+
+    while let Some(start) = xml[pos..].find('<').map(|start| pos + start) {
+        let text = &xml[pos..start];
+        // Only entities, never a raw markup character.
+        assert!(!text.contains('>'), "{text}");
+        assert!(text.split('&').skip(1).all(|entity| {
+            ["amp;", "lt;", "gt;", "quot;", "apos;", "#13;"].iter().any(|e| entity.starts_with(e))
+        }), "{text}");
+
+        let end = start + xml[start..].find('>').unwrap();
+        let tag = &xml[start + 1..end];
+        pos = end + 1;
+
+        if let Some(name) = tag.strip_prefix('/') {
+            let (opened, content) = open.pop().expect("closing tag without opening");
+            assert_eq!(opened, name);
+            if open.len() == 2 {
+                entries.last_mut().unwrap().push((name, &xml[content..start]));
+            }
+            continue;
+        }
+
+        let name = tag.split(' ').next().unwrap();
+        match open.len() {
+            0 => assert_eq!(name, "passwordsafe"),
+            1 => {
+                assert_eq!(name, "entry");
+                assert!(tag.starts_with(&format!("entry id=\"{}\"", entries.len() + 1)), "{tag}");
+                entries.push(vec![]);
+            }
+            2 => assert!(order.contains(&name), "{name}"),
+            _ => panic!("nested too deep: {tag}"),
+        }
+
+        open.push((name, pos));
+    }
+
+    assert!(open.is_empty(), "{open:?}");
+    assert!(xml.contains(r#"<passwordsafe delimiter="^" "#), "{xml}");
+    assert_eq!(entries.len(), 3);
+
+    for entry in &entries {
+        let names: Vec<_> = entry.iter().map(|&(name, _)| name).collect();
+        let mut sorted = names.clone();
+        sorted.sort_by_key(|name| order.iter().position(|o| o == name));
+        assert_eq!(names, sorted, "in the order of the schema");
+        // Required, even where the record has neither.
+        assert!(names.contains(&"title") && names.contains(&"password"), "{names:?}");
+    }
+
+    let first: std::collections::HashMap<_, _> = entries[0].iter().copied().collect();
+    assert_eq!(first["group"], "work.servers");
+    assert_eq!(first["title"], "&lt;db&gt; &amp; &quot;friends&quot;");
+    assert_eq!(first["notes"], "line one&#13;\nline two ]]&gt; \u{fffd}");
+    assert_eq!(first["uuid"], "ab".repeat(16));
+    // The lexical form of `xs:dateTime`.
+    assert_eq!(first["ctimex"], "2021-09-19T20:01:28");
+    assert_eq!(first["rmtimex"], "2000-02-29T00:00:00");
+    assert_eq!(first["xtime_interval"], "30");
+
+    let uuid = "cd".repeat(16);
+    assert_eq!(entries[1], [("title", ""), ("password", ""), ("uuid", uuid.as_str())]);
+}
+
+#[test]
+fn export_csv_columns() {
+    use crate::export::Column;
+
+    let columns = [Column::Title, Column::Username, Column::Password, Column::Email];
+    let mut out = vec![];
+    crate::export::csv(&export_database(), &columns, &mut out).unwrap();
+
+    // Missing fields are empty cells, in place.
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        concat!(
+            "title,username,password,email\r\n",
+            "\"<db> & \"\"friends\"\"\",admin,\"p,w\"\"d\",\r\n",
+            ",,,\r\n",
+            "mail,,secret,me@example.org\r\n",
+        )
+    );
+
+    let mut out = vec![];
+    crate::export::csv(&export_database()[..1], &[Column::Notes, Column::CreationTime], &mut out)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "notes,ctimex\r\n\"line one\r\nline two ]]> \x07\",2021-09-19T20:01:28\r\n",
+    );
+
+    assert_eq!("URL".parse::<Column>().unwrap(), Column::Url);
+    assert!("website".parse::<Column>().is_err());
+}
+
+#[test]
+fn key_empty_password() {
+    assert!(PwsafeKey::new(b"").is_empty_password());
+    assert!(!PwsafeKey::new(b"password").is_empty_password());
+    assert!(!PwsafeKey::new(b" ").is_empty_password());
+}
+
+/// Generated passwords have the minimum of each class, and any character of the classes used.
+#[test]
+fn policy_class_minimums() {
+    use crate::policy::Policy;
+    use rand::{rngs::StdRng, SeedableRng};
+    use std::collections::HashMap;
+
+    let mut rng = StdRng::seed_from_u64(0x2040);
+    let policy = Policy {
+        flags: Policy::USE_LOWERCASE | Policy::USE_UPPERCASE | Policy::USE_DIGITS
+            | Policy::USE_SYMBOLS,
+        length: 12,
+        min_lowercase: 2,
+        min_uppercase: 3,
+        min_digits: 4,
+        min_symbols: 1,
+        symbols: String::new(),
+    };
+
+    let mut seen = HashMap::<char, usize>::new();
+    for _ in 0..2000 {
+        let password = policy.generate_with(&mut rng).unwrap();
+        assert_eq!(password.chars().count(), 12);
+
+        let count = |class: fn(&char) -> bool| password.chars().filter(class).count();
+        assert!(count(char::is_ascii_lowercase) >= 2, "{password}");
+        assert!(count(char::is_ascii_uppercase) >= 3, "{password}");
+        assert!(count(char::is_ascii_digit) >= 4, "{password}");
+        assert!(count(char::is_ascii_punctuation) >= 1, "{password}");
+
+        for ch in password.chars() {
+            *seen.entry(ch).or_default() += 1;
+        }
+    }
+
+    // All letters, digits and the 29 default symbols, each about as often as the others of its
+    // class.
+    assert_eq!(seen.len(), 26 + 26 + 10 + 29);
+    assert!(seen.keys().all(|ch| ch.is_ascii_graphic()));
+
+    let classes: [fn(&char) -> bool; 4] = [
+        char::is_ascii_lowercase,
+        char::is_ascii_uppercase,
+        char::is_ascii_digit,
+        char::is_ascii_punctuation,
+    ];
+
+    for class in classes {
+        let counts: Vec<_> = seen.iter().filter(|(ch, _)| class(ch)).map(|(_, n)| *n).collect();
+        let (min, max) = (counts.iter().min().unwrap(), counts.iter().max().unwrap());
+        assert!(min * 2 > *max, "{counts:?}");
+    }
+}
+
+/// The variants of policies, and those no password can follow.
+#[test]
+fn policy_variants() {
+    use crate::policy::{Error, Policy};
+    use rand::{rngs::StdRng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0x2040);
+    let all = Policy::USE_LOWERCASE | Policy::USE_UPPERCASE | Policy::USE_DIGITS
+        | Policy::USE_SYMBOLS;
+
+    let flags = all | Policy::USE_EASY_VISION;
+    let easy_vision = Policy { flags, length: 64, ..Policy::default() };
+    for _ in 0..200 {
+        let password = easy_vision.generate_with(&mut rng).unwrap();
+        assert!(!password.contains(['l', 'I', 'O', 'S', 'Z', '0', '1', '2', '5', '|', '!']));
+    }
+
+    let symbols = Policy {
+        flags: Policy::USE_SYMBOLS,
+        length: 32,
+        symbols: "#$%".into(),
+        ..Policy::default()
+    };
+    let password = symbols.generate_with(&mut rng).unwrap();
+    assert!(password.chars().all(|ch| "#$%".contains(ch)), "{password}");
+
+    // Hex digits ignore the other classes and their minimums.
+    let flags = all | Policy::USE_HEX_DIGITS;
+    let hex = Policy { flags, length: 40, min_uppercase: 3, ..symbols };
+    let password = hex.generate_with(&mut rng).unwrap();
+    assert_eq!(password.len(), 40);
+    assert!(password.chars().all(|ch| ch.is_ascii_hexdigit() && !ch.is_ascii_uppercase()));
+
+    let short = Policy {
+        flags: all,
+        length: 3,
+        min_digits: 2,
+        min_symbols: 2,
+        ..Policy::default()
+    };
+    assert_eq!(short.generate(), Err(Error::TooShort { length: 3, minimum: 4 }));
+    let empty = Policy { flags: all, ..Policy::default() };
+    assert_eq!(empty.generate(), Err(Error::TooShort { length: 0, minimum: 1 }));
+    let none = Policy { length: 8, ..Policy::default() };
+    assert_eq!(none.generate(), Err(Error::NoCharacters));
+    let pronounceable = Policy { flags: all | Policy::MAKE_PRONOUNCEABLE, ..easy_vision };
+    assert_eq!(pronounceable.generate(), Err(Error::Pronounceable));
+
+    // As stored in a record, with the symbols in a field of their own.
+    let parsed = Policy::parse("f400010001002003004", "#$%").unwrap();
+    assert_eq!((parsed.flags, parsed.length), (all | Policy::USE_EASY_VISION, 16));
+    assert_eq!([parsed.min_lowercase, parsed.min_uppercase], [1, 2]);
+    assert_eq!([parsed.min_digits, parsed.min_symbols], [3, 4]);
+    assert_eq!(parsed.symbols, "#$%");
+    assert_eq!(parsed.generate_with(&mut rng).unwrap().chars().count(), 16);
+    assert!(Policy::parse("f40001000100200300", "").is_none());
+    assert!(Policy::parse("f4000100010020030040", "").is_none());
+}
@@ -3,6 +3,7 @@ use byteorder::{LittleEndian, WriteBytesExt};
 use hmac::{Hmac, Mac};
 use rand::{rngs::OsRng, RngCore};
 use sha2::{Digest, Sha256};
+use std::fmt;
 use std::io::{self, Write};
 use std::result::Result;
 use twofish::cipher::crypto_common::generic_array::GenericArray;
@@ -12,19 +13,51 @@ use twofish::cipher::{
 };
 use twofish::Twofish;
 
+use crate::header::PwsafeHeader;
 use crate::key::PwsafeKey;
 use crate::secrets_vec::SecretBuffer;
 
 type TwofishCbc = cbc::Encryptor<Twofish>;
 type HmacSha256 = Hmac<Sha256>;
 
+/// The fewest iterations of key stretching the format allows.
+pub const MIN_ITER: u32 = 2048;
+
+#[derive(Debug)]
+/// Password Safe database writer error.
+pub enum Error {
+    /// An I/O error.
+    IoError(io::Error),
+    /// Fewer iterations than [`MIN_ITER`], the key would hardly be stretched.
+    TooFewIterations(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(ref e) => e.fmt(f),
+            Error::TooFewIterations(iter) => {
+                write!(f, "{iter} iterations are fewer than the minimum of {MIN_ITER}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::IoError(err)
+    }
+}
+
 /// Password safe writer.
 ///
 /// # Examples
 ///
 /// An example shows how to create an empty database.
 /// ```no_run
-/// use pwsafer::{PwsafeKey, PwsafeWriter};
+/// use pwsafer::{PwsafeHeader, PwsafeKey, PwsafeWriter};
 /// use std::fs::File;
 /// use std::io::BufWriter;
 ///
@@ -33,10 +66,10 @@ type HmacSha256 = Hmac<Sha256>;
 ///
 /// let file = BufWriter::new(File::create(filename).unwrap());
 /// let mut db = PwsafeWriter::new(file, 2048, &key).unwrap();
-/// let version = [0x0eu8, 0x03u8];
-/// let empty = [0u8, 0];
-/// db.write_field(0x00, &version); // Version field
-/// db.write_field(0xff, &empty); // End of header
+/// let mut header = PwsafeHeader::new();
+/// header.touch("user", "host", "example");
+/// db.write_header(&header).unwrap(); // Version, save metadata and end of header
+/// db.write_record(&[(0x03, b"title"), (0x06, b"secret")]).unwrap(); // One entry
 /// db.finish().unwrap(); // EOF and HMAC
 /// ```
 pub struct PwsafeWriter<W> {
@@ -45,18 +78,57 @@ pub struct PwsafeWriter<W> {
     k: [u8; 32],
     iv: [u8; 16],
     hmac: HmacSha256,
+    rng: Randomness,
+}
+
+/// The source of salt, keys and padding.
+pub(crate) enum Randomness {
+    Os,
+    /// Reproducible files, never to be used for real passwords.
+    #[cfg(feature = "generate")]
+    Seeded(Box<rand::rngs::StdRng>),
 }
 
 impl<W> PwsafeWriter<W> {
     /// Creates a new `PwsafeWriter` with the given password.
-    pub fn new(mut inner: W, iter: u32, key: &PwsafeKey) -> Result<Self, io::Error>
+    ///
+    /// Fails if `iter` is below [`MIN_ITER`].
+    pub fn new(inner: W, iter: u32, key: &PwsafeKey) -> Result<Self, Error>
+    where
+        W: Write,
+    {
+        if iter < MIN_ITER {
+            return Err(Error::TooFewIterations(iter));
+        }
+
+        Self::with_randomness(inner, iter, key, Randomness::Os)
+    }
+
+    /// Creates a new `PwsafeWriter`, raising `iter` to [`MIN_ITER`] if it is lower.
+    ///
+    /// For writing back a database with the count it was read with, see
+    /// [`PwsafeReader::get_iter`](crate::PwsafeReader::get_iter), where older files may have used
+    /// fewer.
+    pub fn with_iterations(inner: W, iter: u32, key: &PwsafeKey) -> Result<Self, Error>
+    where
+        W: Write,
+    {
+        Self::new(inner, iter.max(MIN_ITER), key)
+    }
+
+    pub(crate) fn with_randomness(
+        mut inner: W,
+        iter: u32,
+        key: &PwsafeKey,
+        mut rng: Randomness,
+    ) -> Result<Self, Error>
     where
         W: Write,
     {
         inner.write_all(b"PWS3")?;
 
         let mut salt = [0u8; 32];
-        OsRng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut salt);
         inner.write_all(&salt)?;
         inner.write_u32::<LittleEndian>(iter)?;
 
@@ -68,15 +140,15 @@ impl<W> PwsafeWriter<W> {
             let hash = hasher.finalize();
             inner.write_all(&hash)?;
 
-            Ok::<_, io::Error>(Twofish::new((&*key).into()))
+            Ok::<_, Error>(Twofish::new((&*key).into()))
         })?;
 
         let mut k = [0u8; 32];
         let mut l = [0u8; 32];
         let mut iv = [0u8; 16];
-        OsRng.fill_bytes(&mut k);
-        OsRng.fill_bytes(&mut l);
-        OsRng.fill_bytes(&mut iv);
+        rng.fill_bytes(&mut k);
+        rng.fill_bytes(&mut l);
+        rng.fill_bytes(&mut iv);
 
         let mut k_ = k.clone();
         let mut l_ = l.clone();
@@ -104,44 +176,63 @@ impl<W> PwsafeWriter<W> {
             k,
             iv,
             hmac: sha256_hmac,
+            rng,
         };
         Ok(w)
     }
 
+    /// Make room for `bytes` more bytes of encrypted fields.
+    ///
+    /// Without it, the buffer of fields is moved to a larger allocation as it fills up. Writing a
+    /// database again, reserve the [`data_len`](crate::PwsafeReader::data_len) of its reader.
+    pub fn reserve(&mut self, bytes: usize) -> Result<(), io::Error> {
+        self.buffer.try_reserve(bytes)
+    }
+
     /// Prepares one field.
-    pub fn write_field(&mut self, field_type: u8, data: &[u8]) {
-        // The block which may be partially rng filled.
-        let i;
-        let mut block = [0u8; 16];
-        block[..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
-        block[4] = field_type;
-
-        self.hmac.update(&data);
-
-        if data.len() > 11 {
-            let (front, tail) = data.split_at(11);
-            block[5..].copy_from_slice(front);
-            self.buffer.extend_from_slice(&block);
-
-            let remainder = tail.chunks_exact(16).remainder();
-            let raw_len = tail.len() - remainder.len();
-            debug_assert!(raw_len % 16 == 0);
-            self.buffer.extend_from_slice(&data[..raw_len]);
-
-            if remainder.len() == 0 {
-                return;
-            }
+    ///
+    /// Fails, without any change, if the data does not fit the length of a field or the buffer can
+    /// not grow to hold it.
+    pub fn write_field(&mut self, field_type: u8, data: &[u8]) -> Result<(), io::Error> {
+        let len = field_length(data.len())?;
+        // The first block holds 11 bytes, each further one 16.
+        let blocks = 1 + data.len().saturating_sub(11).div_ceil(16);
+        let rng = &mut self.rng;
 
-            i = remainder.len();
-            block[..remainder.len()].copy_from_slice(remainder);
-        } else {
-            let len = data.len();
-            i = 5 + len;
-            block[5..][..len].copy_from_slice(data);
-        };
+        // Written in place, the data is not copied through a block on the stack.
+        self.buffer.extend_with(16 * blocks, |into| {
+            let (prefix, rest) = into.split_at_mut(5);
+            prefix[..4].copy_from_slice(&len.to_le_bytes());
+            prefix[4] = field_type;
+
+            let (field, padding) = rest.split_at_mut(data.len());
+            field.copy_from_slice(data);
+            rng.fill_bytes(padding); // Pad the last block with random bytes
+        })?;
 
-        OsRng.fill_bytes(&mut block[i..16]); // Pad with random bytes
-        self.buffer.extend_from_slice(&block);
+        self.hmac.update(data);
+        Ok(())
+    }
+
+    /// Prepares the fields of the header, followed by its end.
+    ///
+    /// See [`PwsafeHeader::fields`] for their order.
+    pub fn write_header(&mut self, header: &PwsafeHeader) -> Result<(), io::Error> {
+        for field in header.fields() {
+            let (field_type, data) = field.to_raw();
+            self.write_field(field_type, &data)?;
+        }
+
+        self.write_field(0xff, &[])
+    }
+
+    /// Prepares the fields of one record, followed by its end.
+    pub fn write_record(&mut self, fields: &[(u8, &[u8])]) -> Result<(), io::Error> {
+        for &(field_type, data) in fields {
+            self.write_field(field_type, data)?;
+        }
+
+        self.write_field(0xff, &[])
     }
 
     /// Encrypts/Writes all fields, EOF block and HMAC.
@@ -174,8 +265,29 @@ impl<W> PwsafeWriter<W> {
             k: self.k,
             iv: self.iv,
             hmac: self.hmac,
+            rng: self.rng,
         };
 
         (writer, self.inner)
     }
 }
+
+/// The length prefix of a field with this much data.
+pub(crate) fn field_length(len: usize) -> Result<u32, io::Error> {
+    u32::try_from(len).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("field of {len} bytes exceeds the maximum length of {}", u32::MAX),
+        )
+    })
+}
+
+impl Randomness {
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            Randomness::Os => OsRng.fill_bytes(dest),
+            #[cfg(feature = "generate")]
+            Randomness::Seeded(rng) => rng.fill_bytes(dest),
+        }
+    }
+}